@@ -1,301 +1,898 @@
+use crate::error::{ApiError, ApiErrorKind};
+use crate::image::inline_local_images;
+use crate::provider::{ProviderRequest, provider_for};
+use crate::retry::{RetryPolicy, is_retryable_error, is_retryable_status, parse_retry_after};
+use crate::stream::SseDecoder;
+use crate::think::{ThinkTagParser, split_think_tags};
+use crate::timeout::Timeouts;
+use crate::transport::{HttpTransport, RecordingTransport, Transport, TransportResponse};
 use crate::types::*;
 use futures::StreamExt;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
 use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
 
 #[derive(Debug, Error)]
 pub enum LlmError {
     #[error("HTTP error: {0}")]
     Http(#[from] reqwest::Error),
     #[error("API error: {0}")]
-    Api(String),
+    Api(ApiError),
     #[error("Missing API key for env var: {0}")]
     MissingApiKey(String),
     #[error("JSON error: {0}")]
     Json(#[from] serde_json::Error),
+    #[error("Image error: {0}")]
+    Image(String),
+    #[error("Timed out: {0}")]
+    Timeout(String),
+    /// A transport failure that did not come from reqwest (e.g. scripted).
+    #[error("Transport error: {0}")]
+    Transport(String),
 }
 
 #[derive(Clone)]
 pub struct LlmClient {
-    http: reqwest::Client,
+    transport: Arc<dyn Transport>,
+    /// Whether `transport` is the `HttpTransport` the client built itself.
+    default_transport: bool,
+    retry: RetryPolicy,
+    timeouts: Timeouts,
 }
 
 impl LlmClient {
     pub fn new() -> Self {
+        let timeouts = Timeouts::default();
         Self {
-            http: reqwest::Client::new(),
+            transport: Arc::new(HttpTransport::new(&timeouts)),
+            default_transport: true,
+            retry: RetryPolicy::default(),
+            timeouts,
         }
     }
 
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Set the timeouts. The default HTTP transport is rebuilt with the new
+    /// connect timeout; a transport set with `with_transport` or
+    /// `recording_to` is kept.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        if self.default_transport {
+            self.transport = Arc::new(HttpTransport::new(&timeouts));
+        }
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Send requests through `transport` instead of HTTP (e.g. a `ScriptedTransport`).
+    pub fn with_transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = transport;
+        self.default_transport = false;
+        self
+    }
+
+    /// Append every exchange made from now on to a JSONL fixture at `path`.
+    pub fn recording_to(mut self, path: impl Into<PathBuf>) -> Self {
+        self.transport = Arc::new(RecordingTransport::new(self.transport, path));
+        self.default_transport = false;
+        self
+    }
+
     /// Stream a chat completion, sending events through the channel.
+    ///
+    /// Cancelling `cancel` drops the connection and returns what was received
+    /// so far as an `Ok` message; half-streamed tool calls are discarded.
     pub async fn stream(
         &self,
         model: &Model,
         messages: &[Message],
         tools: &[ToolDef],
         tx: mpsc::UnboundedSender<StreamEvent>,
+        cancel: &CancellationToken,
     ) -> Result<Message, LlmError> {
-        let api_key = model
-            .api_key()
-            .ok_or_else(|| LlmError::MissingApiKey(model.api_key_env.clone()))?;
-
-        let url = format!("{}/chat/completions", model.base_url.trim_end_matches('/'));
-
-        let openai_messages: Vec<serde_json::Value> =
-            messages.iter().map(message_to_openai).collect();
-
-        let openai_tools: Option<Vec<serde_json::Value>> = if tools.is_empty() {
-            None
-        } else {
-            Some(tools.iter().map(tool_to_openai).collect())
-        };
-
-        let body = ChatRequest {
-            model: model.id.clone(),
-            messages: openai_messages,
-            tools: openai_tools,
-            stream: true,
+        let api_key = require_api_key(model)?;
+        let provider = provider_for(model.provider);
+        let messages = inline_local_images(messages).await?;
+        let request = provider.chat_request(model, &api_key, &messages, tools, true);
+
+        let mut reply = ReplyBuilder::default();
+        let resp = tokio::select! {
+            biased;
+            _ = cancel.cancelled() => return Ok(reply.cancel(&tx)),
+            resp = self.send(request, Some(&tx)) => resp?,
         };
 
-        let resp = self
-            .http
-            .post(&url)
-            .header("Authorization", format!("Bearer {api_key}"))
-            .header("Content-Type", "application/json")
-            .json(&body)
-            .send()
-            .await?;
-
-        if !resp.status().is_success() {
-            let status = resp.status();
-            let body = resp.text().await.unwrap_or_default();
-            return Err(LlmError::Api(format!("{status}: {body}")));
+        let mut decoder = provider.stream_decoder();
+        let mut sse = SseDecoder::new();
+
+        let first_token = self.timeouts.first_token();
+        let first_token_deadline = first_token.map(|limit| Instant::now() + limit);
+        let mut byte_stream = resp.body;
+        loop {
+            let (deadline, limit, waiting_for) = if reply.has_output() {
+                let idle = self.timeouts.idle();
+                (
+                    idle.map(|limit| Instant::now() + limit),
+                    idle,
+                    "the next chunk",
+                )
+            } else {
+                (first_token_deadline, first_token, "the first token")
+            };
+            let next = tokio::select! {
+                biased;
+                _ = cancel.cancelled() => return Ok(reply.cancel(&tx)),
+                next = within(deadline, byte_stream.next()) => next,
+            };
+            let Some(next) = next else {
+                return Err(timeout_error(limit, waiting_for));
+            };
+            let Some(chunk) = next else {
+                break;
+            };
+            for sse_event in sse.push(&chunk?) {
+                for event in decoder.decode(&sse_event.data) {
+                    reply.handle(event, &tx);
+                }
+            }
+        }
+        if let Some(sse_event) = sse.finish() {
+            for event in decoder.decode(&sse_event.data) {
+                reply.handle(event, &tx);
+            }
         }
 
-        // Read SSE stream
-        let mut text_content = String::new();
-        let mut tool_calls: Vec<ToolCall> = Vec::new();
-        // Buffer for accumulating tool call arguments by index
-        let mut tc_args: Vec<String> = Vec::new();
+        Ok(reply.finish(&tx))
+    }
 
-        let mut byte_stream = resp.bytes_stream();
-        let mut line_buf = String::new();
+    /// Non-streaming completion (for simple use cases).
+    pub async fn complete(
+        &self,
+        model: &Model,
+        messages: &[Message],
+        tools: &[ToolDef],
+    ) -> Result<Message, LlmError> {
+        let api_key = require_api_key(model)?;
+        let provider = provider_for(model.provider);
+        let messages = inline_local_images(messages).await?;
+        let request = provider.chat_request(model, &api_key, &messages, tools, false);
+        let resp = self.send(request, None).await?;
+
+        let json = resp.json().await?;
+        provider
+            .parse_completion(&json)
+            .map(move_think_tags_to_reasoning)
+    }
 
-        while let Some(chunk) = byte_stream.next().await {
-            let chunk = chunk?;
-            let chunk_str = String::from_utf8_lossy(&chunk);
-            line_buf.push_str(&chunk_str);
+    /// Test API connection by hitting the models endpoint.
+    /// Returns Ok(()) on success, Err on failure.
+    pub async fn test_connection(&self, model: &Model) -> Result<(), LlmError> {
+        let api_key = require_api_key(model)?;
+        let request = provider_for(model.provider).models_request(model, &api_key);
+        self.send(request, None).await?;
+        Ok(())
+    }
 
-            // Process complete lines
-            while let Some(newline_pos) = line_buf.find('\n') {
-                let line = line_buf[..newline_pos].trim().to_string();
-                line_buf = line_buf[newline_pos + 1..].to_string();
+    /// List available models from the API.
+    /// Returns a vec of (model_id, owned_by) tuples.
+    pub async fn list_models(&self, model: &Model) -> Result<Vec<(String, String)>, LlmError> {
+        let api_key = require_api_key(model)?;
+        let provider = provider_for(model.provider);
+        let request = provider.models_request(model, &api_key);
+        let resp = self.send(request, None).await?;
+
+        let json = resp.json().await?;
+        let mut models = provider.parse_models(&json);
+        models.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(models)
+    }
 
-                if line.is_empty() || !line.starts_with("data: ") {
-                    continue;
+    /// Execute a provider request, retrying transient failures per the retry policy.
+    /// Each retry is announced on `events` (if given) before sleeping.
+    /// Non-2xx responses that exhaust the retries map to `LlmError::Api`.
+    async fn send(
+        &self,
+        request: ProviderRequest,
+        events: Option<&mpsc::UnboundedSender<StreamEvent>>,
+    ) -> Result<TransportResponse, LlmError> {
+        let mut attempt = 0;
+        loop {
+            let limit = self.timeouts.first_token();
+            let deadline = limit.map(|limit| Instant::now() + limit);
+            // No headers in time counts as a dropped connection.
+            let sent = within(deadline, self.transport.send(&request))
+                .await
+                .unwrap_or_else(|| Err(timeout_error(limit, "a response")));
+            let (reason, delay) = match sent {
+                Ok(resp) if resp.status.is_success() => return Ok(resp),
+                Ok(resp) => {
+                    let status = resp.status;
+                    let retry_after = resp
+                        .header(reqwest::header::RETRY_AFTER.as_str())
+                        .and_then(parse_retry_after);
+                    let delay = (is_retryable_status(status) && attempt < self.retry.max_retries)
+                        .then(|| self.retry.delay_for(attempt + 1, retry_after))
+                        .flatten();
+                    // Includes a server asking for a longer wait than the
+                    // policy allows, so a fallback model can take over.
+                    let Some(delay) = delay else {
+                        let headers = resp.headers.clone();
+                        let body = resp.text().await.unwrap_or_default();
+                        return Err(LlmError::Api(ApiError::from_response(
+                            status.as_u16(),
+                            &headers,
+                            &body,
+                        )));
+                    };
+                    (status.to_string(), delay)
                 }
-
-                if let Some(event) = crate::stream::parse_sse_line(&line) {
-                    match &event {
-                        StreamEvent::TextDelta(text) => {
-                            text_content.push_str(text);
-                        }
-                        StreamEvent::ToolCallStart { index, id, name } => {
-                            while tool_calls.len() <= *index {
-                                tool_calls.push(ToolCall {
-                                    id: String::new(),
-                                    function: FunctionCall {
-                                        name: String::new(),
-                                        arguments: String::new(),
-                                    },
-                                });
-                                tc_args.push(String::new());
-                            }
-                            tool_calls[*index].id = id.clone();
-                            tool_calls[*index].function.name = name.clone();
-                        }
-                        StreamEvent::ToolCallDelta { index, arguments } => {
-                            while tc_args.len() <= *index {
-                                tc_args.push(String::new());
-                            }
-                            tc_args[*index].push_str(arguments);
-                        }
-                        StreamEvent::Done => {}
-                        StreamEvent::Error(e) => {
-                            let _ = tx.send(StreamEvent::Error(e.clone()));
-                        }
+                Err(e) => {
+                    if !e.is_transient() || attempt >= self.retry.max_retries {
+                        return Err(e);
                     }
-                    let _ = tx.send(event);
+                    (e.to_string(), self.retry.backoff(attempt + 1))
                 }
+            };
+
+            attempt += 1;
+            if let Some(tx) = events {
+                let _ = tx.send(StreamEvent::Retry {
+                    attempt,
+                    max_retries: self.retry.max_retries,
+                    delay,
+                    reason,
+                });
             }
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+/// Accumulates a streamed reply while forwarding its events.
+#[derive(Default)]
+struct ReplyBuilder {
+    think: ThinkTagParser,
+    text: String,
+    reasoning: String,
+    tool_calls: Vec<ToolCall>,
+}
+
+impl ReplyBuilder {
+    /// Whether anything worth waiting for has arrived yet.
+    fn has_output(&self) -> bool {
+        !self.text.is_empty() || !self.reasoning.is_empty() || !self.tool_calls.is_empty()
+    }
+
+    fn handle(&mut self, event: StreamEvent, tx: &mpsc::UnboundedSender<StreamEvent>) {
+        for event in split_think(&mut self.think, event) {
+            self.apply(event, tx);
         }
+    }
 
-        // Finalize tool call arguments
-        for (i, args) in tc_args.into_iter().enumerate() {
-            if i < tool_calls.len() {
-                tool_calls[i].function.arguments = args;
+    fn apply(&mut self, event: StreamEvent, tx: &mpsc::UnboundedSender<StreamEvent>) {
+        // Drop the blank lines models put between reasoning and the answer.
+        let event = match event {
+            StreamEvent::TextDelta(text) if self.text.is_empty() && !self.reasoning.is_empty() => {
+                let text = text.trim_start();
+                if text.is_empty() {
+                    return;
+                }
+                StreamEvent::TextDelta(text.to_string())
+            }
+            event => event,
+        };
+        match &event {
+            StreamEvent::TextDelta(text) => self.text.push_str(text),
+            StreamEvent::ReasoningDelta(text) => self.reasoning.push_str(text),
+            StreamEvent::ToolCallStart { index, id, name } => {
+                let slot = self.tool_slot(*index);
+                slot.id = id.clone();
+                slot.function.name = name.clone();
             }
+            StreamEvent::ToolCallDelta { index, arguments } => {
+                self.tool_slot(*index)
+                    .function
+                    .arguments
+                    .push_str(arguments);
+            }
+            StreamEvent::Done
+            | StreamEvent::Error(_)
+            | StreamEvent::Usage(_)
+            | StreamEvent::Retry { .. } => {}
         }
+        let _ = tx.send(event);
+    }
 
+    fn tool_slot(&mut self, index: usize) -> &mut ToolCall {
+        while self.tool_calls.len() <= index {
+            self.tool_calls.push(ToolCall {
+                id: String::new(),
+                function: FunctionCall {
+                    name: String::new(),
+                    arguments: String::new(),
+                },
+            });
+        }
+        &mut self.tool_calls[index]
+    }
+
+    /// Flush held-back text and build the final message.
+    fn finish(mut self, tx: &mpsc::UnboundedSender<StreamEvent>) -> Message {
+        let rest = self.think.flush();
+        if !rest.reasoning.is_empty() {
+            self.apply(StreamEvent::ReasoningDelta(rest.reasoning), tx);
+        }
+        if !rest.content.is_empty() {
+            self.apply(StreamEvent::TextDelta(rest.content), tx);
+        }
         let _ = tx.send(StreamEvent::Done);
 
-        if tool_calls.is_empty() {
-            Ok(Message::assistant(text_content))
+        // Arguments for a call whose start never arrived cannot be executed.
+        self.tool_calls.retain(|tc| !tc.function.name.is_empty());
+        let msg = if self.tool_calls.is_empty() {
+            Message::assistant(self.text)
         } else {
-            Ok(Message::assistant_with_tools(text_content, tool_calls))
+            Message::assistant_with_tools(self.text, self.tool_calls)
+        };
+        msg.with_reasoning(self.reasoning)
+    }
+
+    /// Finish a cancelled stream. Tool calls may have truncated arguments, so
+    /// only text and reasoning are kept.
+    fn cancel(mut self, tx: &mpsc::UnboundedSender<StreamEvent>) -> Message {
+        self.tool_calls.clear();
+        self.finish(tx)
+    }
+}
+
+/// Route a text delta through the think-tag parser; other events pass through.
+fn split_think(parser: &mut ThinkTagParser, event: StreamEvent) -> Vec<StreamEvent> {
+    let StreamEvent::TextDelta(text) = event else {
+        return vec![event];
+    };
+    let split = parser.push(&text);
+    let mut events = Vec::new();
+    if !split.reasoning.is_empty() {
+        events.push(StreamEvent::ReasoningDelta(split.reasoning));
+    }
+    if !split.content.is_empty() {
+        events.push(StreamEvent::TextDelta(split.content));
+    }
+    events
+}
+
+/// Move inline `<think>` blocks of a complete reply into its reasoning.
+fn move_think_tags_to_reasoning(msg: Message) -> Message {
+    let Message::Assistant {
+        content,
+        reasoning,
+        tool_calls,
+    } = msg
+    else {
+        return msg;
+    };
+    let split = split_think_tags(&content);
+    if split.reasoning.is_empty() {
+        return Message::Assistant {
+            content,
+            reasoning,
+            tool_calls,
+        };
+    }
+    let reasoning = match reasoning {
+        Some(native) => format!("{native}\n\n{}", split.reasoning),
+        None => split.reasoning,
+    };
+    Message::Assistant {
+        content: split.content,
+        reasoning: Some(reasoning),
+        tool_calls,
+    }
+}
+
+/// Await `fut` until `deadline` (if any); `None` means it timed out.
+async fn within<F: Future>(deadline: Option<Instant>, fut: F) -> Option<F::Output> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, fut).await.ok(),
+        None => Some(fut.await),
+    }
+}
+
+fn timeout_error(limit: Option<Duration>, waiting_for: &str) -> LlmError {
+    let secs = limit.unwrap_or_default().as_secs_f64();
+    LlmError::Timeout(format!("waiting for {waiting_for} ({secs}s)"))
+}
+
+fn require_api_key(model: &Model) -> Result<String, LlmError> {
+    model
+        .api_key()
+        .ok_or_else(|| LlmError::MissingApiKey(model.api_key_env.clone()))
+}
+
+impl LlmError {
+    /// Classify any client error into the API error taxonomy.
+    pub fn kind(&self) -> ApiErrorKind {
+        match self {
+            Self::Api(e) => e.kind,
+            Self::MissingApiKey(_) => ApiErrorKind::Auth,
+            Self::Image(_) => ApiErrorKind::InvalidRequest,
+            Self::Timeout(_) | Self::Transport(_) => ApiErrorKind::Server,
+            Self::Http(e) if e.is_timeout() || e.is_connect() => ApiErrorKind::Server,
+            Self::Http(_) | Self::Json(_) => ApiErrorKind::Unknown,
         }
     }
 
-    /// Non-streaming completion (for simple use cases).
-    pub async fn complete(
-        &self,
-        model: &Model,
-        messages: &[Message],
-        tools: &[ToolDef],
-    ) -> Result<Message, LlmError> {
-        let api_key = model
-            .api_key()
-            .ok_or_else(|| LlmError::MissingApiKey(model.api_key_env.clone()))?;
+    /// Failures worth retrying: dropped connections and timeouts.
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::Http(e) => is_retryable_error(e),
+            Self::Timeout(_) | Self::Transport(_) => true,
+            Self::Api(_) | Self::MissingApiKey(_) | Self::Json(_) | Self::Image(_) => false,
+        }
+    }
 
-        let url = format!("{}/chat/completions", model.base_url.trim_end_matches('/'));
+    /// Flatten into an `ApiError`, keeping the parsed details when there are any.
+    pub fn into_api_error(self) -> ApiError {
+        match self {
+            Self::Api(e) => e,
+            other => ApiError::new(other.kind(), other.to_string()),
+        }
+    }
+}
+
+impl Default for LlmClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-        let openai_messages: Vec<serde_json::Value> =
-            messages.iter().map(message_to_openai).collect();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Minimal HTTP/1.1 server answering each connection with the next scripted response.
+    /// Resolves to the number of requests served.
+    async fn mock_server(responses: Vec<String>) -> (String, tokio::task::JoinHandle<usize>) {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind mock server");
+        let addr = listener.local_addr().expect("mock server addr");
+        let handle = tokio::spawn(async move {
+            let mut served = 0;
+            for response in responses {
+                let Ok((mut socket, _)) = listener.accept().await else {
+                    break;
+                };
+                read_request(&mut socket).await;
+                let _ = socket.write_all(response.as_bytes()).await;
+                let _ = socket.shutdown().await;
+                served += 1;
+            }
+            served
+        });
+        (format!("http://{addr}/v1"), handle)
+    }
 
-        let openai_tools: Option<Vec<serde_json::Value>> = if tools.is_empty() {
-            None
-        } else {
-            Some(tools.iter().map(tool_to_openai).collect())
-        };
+    async fn read_request(socket: &mut tokio::net::TcpStream) {
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 4096];
+        loop {
+            let Ok(n) = socket.read(&mut chunk).await else {
+                return;
+            };
+            if n == 0 {
+                return;
+            }
+            buf.extend_from_slice(&chunk[..n]);
+            let text = String::from_utf8_lossy(&buf);
+            if let Some(header_end) = text.find("\r\n\r\n") {
+                let content_length = text[..header_end]
+                    .lines()
+                    .find_map(|l| {
+                        let (k, v) = l.split_once(':')?;
+                        k.eq_ignore_ascii_case("content-length")
+                            .then(|| v.trim().parse::<usize>().ok())?
+                    })
+                    .unwrap_or(0);
+                if buf.len() >= header_end + 4 + content_length {
+                    return;
+                }
+            }
+        }
+    }
 
-        let body = serde_json::json!({
-            "model": model.id,
-            "messages": openai_messages,
-            "tools": openai_tools,
-            "stream": false,
+    /// Server that sends `head` on the first connection and then goes silent.
+    /// Resolves to whether the client closed the connection within five seconds.
+    async fn stalling_server(head: &'static str) -> (String, tokio::task::JoinHandle<bool>) {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind stalling server");
+        let addr = listener.local_addr().expect("stalling server addr");
+        let handle = tokio::spawn(async move {
+            let Ok((mut socket, _)) = listener.accept().await else {
+                return false;
+            };
+            read_request(&mut socket).await;
+            let _ = socket.write_all(head.as_bytes()).await;
+            let mut buf = [0u8; 64];
+            let closed = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+                while let Ok(n) = socket.read(&mut buf).await {
+                    if n == 0 {
+                        break;
+                    }
+                }
+            });
+            closed.await.is_ok()
         });
+        (format!("http://{addr}/v1"), handle)
+    }
 
-        let resp = self
-            .http
-            .post(&url)
-            .header("Authorization", format!("Bearer {api_key}"))
-            .json(&body)
-            .send()
-            .await?;
-
-        if !resp.status().is_success() {
-            let status = resp.status();
-            let body = resp.text().await.unwrap_or_default();
-            return Err(LlmError::Api(format!("{status}: {body}")));
+    const SSE_HEAD: &str =
+        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nConnection: close\r\n\r\n";
+    const SSE_PARTIAL: &str = concat!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nConnection: close\r\n\r\n",
+        "data: {\"choices\":[{\"delta\":{\"content\":\"你好\"}}]}\n\n",
+        "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"id\":\"c1\",",
+        "\"function\":{\"name\":\"memory_search\",\"arguments\":\"{\\\"qu\"}}]}}]}\n\n",
+    );
+
+    fn short_timeouts(first_token_ms: u64, idle_ms: u64) -> Timeouts {
+        Timeouts {
+            connect_ms: 1000,
+            first_token_ms,
+            idle_ms,
         }
+    }
 
-        let json: serde_json::Value = resp.json().await?;
-        let choice = &json["choices"][0]["message"];
-
-        let content = choice["content"]
-            .as_str()
-            .unwrap_or_default()
-            .to_string();
-
-        let tool_calls = if let Some(tcs) = choice.get("tool_calls").and_then(|t| t.as_array()) {
-            tcs.iter()
-                .filter_map(|tc| {
-                    Some(ToolCall {
-                        id: tc.get("id")?.as_str()?.to_string(),
-                        function: FunctionCall {
-                            name: tc.get("function")?.get("name")?.as_str()?.to_string(),
-                            arguments: tc
-                                .get("function")?
-                                .get("arguments")?
-                                .as_str()?
-                                .to_string(),
-                        },
-                    })
-                })
-                .collect()
-        } else {
-            vec![]
-        };
+    fn http_response(status: &str, headers: &[(&str, &str)], body: &str) -> String {
+        let mut out = format!(
+            "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            body.len()
+        );
+        for (k, v) in headers {
+            out.push_str(&format!("{k}: {v}\r\n"));
+        }
+        out.push_str("\r\n");
+        out.push_str(body);
+        out
+    }
 
-        if tool_calls.is_empty() {
-            Ok(Message::assistant(content))
-        } else {
-            Ok(Message::assistant_with_tools(content, tool_calls))
+    fn test_model(base_url: String, key_env: &str) -> Model {
+        // SAFETY: each test uses its own env var name.
+        unsafe {
+            std::env::set_var(key_env, "sk-test");
+        }
+        Model {
+            id: "mock-model".to_string(),
+            base_url,
+            api_key_env: key_env.to_string(),
+            provider: ProviderKind::OpenAi,
+            sampling: SamplingParams::default(),
+            stream_usage: None,
         }
     }
 
-    /// Test API connection by hitting the /models endpoint.
-    /// Returns Ok(()) on success, Err on failure.
-    pub async fn test_connection(&self, model: &Model) -> Result<(), LlmError> {
-        let api_key = model
-            .api_key()
-            .ok_or_else(|| LlmError::MissingApiKey(model.api_key_env.clone()))?;
-
-        let url = format!("{}/models", model.base_url.trim_end_matches('/'));
-
-        let resp = self
-            .http
-            .get(&url)
-            .header("Authorization", format!("Bearer {api_key}"))
-            .send()
-            .await?;
-
-        if !resp.status().is_success() {
-            let status = resp.status();
-            let body = resp.text().await.unwrap_or_default();
-            return Err(LlmError::Api(format!("{status}: {body}")));
+    fn fast_retry(max_retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            initial_backoff_ms: 1,
+            max_backoff_ms: 5,
+            jitter: 0.0,
+            respect_retry_after: true,
         }
+    }
 
-        Ok(())
+    const SSE_OK: &str =
+        "data: {\"choices\":[{\"delta\":{\"content\":\"你好\"}}]}\n\ndata: [DONE]\n\n";
+
+    #[tokio::test]
+    async fn stream_retries_rate_limits_and_server_errors() {
+        let (base_url, server) = mock_server(vec![
+            http_response("429 Too Many Requests", &[("Retry-After", "0")], "{}"),
+            http_response("503 Service Unavailable", &[], "{}"),
+            http_response("200 OK", &[("Content-Type", "text/event-stream")], SSE_OK),
+        ])
+        .await;
+        let model = test_model(base_url, "LIMERENCE_TEST_RETRY_KEY");
+        let client = LlmClient::new().with_retry(fast_retry(3));
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let msg = client
+            .stream(
+                &model,
+                &[Message::user("hi")],
+                &[],
+                tx,
+                &CancellationToken::new(),
+            )
+            .await
+            .expect("stream should succeed after retries");
+        assert_eq!(msg.content_text(), "你好");
+        assert_eq!(server.await.expect("server task"), 3);
+
+        let mut retries = Vec::new();
+        while let Ok(event) = rx.try_recv() {
+            if let StreamEvent::Retry {
+                attempt,
+                reason,
+                delay,
+                ..
+            } = event
+            {
+                retries.push((attempt, reason, delay));
+            }
+        }
+        assert_eq!(retries.len(), 2);
+        assert_eq!(retries[0].0, 1);
+        assert!(retries[0].1.contains("429"));
+        assert_eq!(retries[0].2, std::time::Duration::ZERO);
+        assert!(retries[1].1.contains("503"));
     }
 
-    /// List available models from the API.
-    /// Returns a vec of (model_id, owned_by) tuples.
-    pub async fn list_models(
-        &self,
-        model: &Model,
-    ) -> Result<Vec<(String, String)>, LlmError> {
-        let api_key = model
-            .api_key()
-            .ok_or_else(|| LlmError::MissingApiKey(model.api_key_env.clone()))?;
-
-        let url = format!("{}/models", model.base_url.trim_end_matches('/'));
-
-        let resp = self
-            .http
-            .get(&url)
-            .header("Authorization", format!("Bearer {api_key}"))
-            .send()
-            .await?;
-
-        if !resp.status().is_success() {
-            let status = resp.status();
-            let body = resp.text().await.unwrap_or_default();
-            return Err(LlmError::Api(format!("{status}: {body}")));
+    #[tokio::test]
+    async fn client_errors_are_not_retried() {
+        let (base_url, server) = mock_server(vec![
+            http_response("401 Unauthorized", &[], "{\"error\":\"bad key\"}"),
+            http_response("200 OK", &[], SSE_OK),
+        ])
+        .await;
+        let model = test_model(base_url, "LIMERENCE_TEST_NO_RETRY_KEY");
+        let client = LlmClient::new().with_retry(fast_retry(3));
+
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let err = client
+            .stream(
+                &model,
+                &[Message::user("hi")],
+                &[],
+                tx,
+                &CancellationToken::new(),
+            )
+            .await
+            .expect_err("401 should fail immediately");
+        assert!(err.to_string().contains("401"));
+        assert_eq!(err.kind(), ApiErrorKind::Auth);
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn long_retry_after_is_not_waited_out() {
+        let (base_url, server) = mock_server(vec![
+            http_response("429 Too Many Requests", &[("Retry-After", "3600")], "{}"),
+            http_response("200 OK", &[], SSE_OK),
+        ])
+        .await;
+        let model = test_model(base_url, "LIMERENCE_TEST_LONG_RETRY_AFTER_KEY");
+        let client = LlmClient::new().with_retry(fast_retry(3));
+
+        let err = client
+            .complete(&model, &[Message::user("hi")], &[])
+            .await
+            .expect_err("an hour-long Retry-After should fail at once");
+        assert_eq!(err.kind(), ApiErrorKind::RateLimited);
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_retries() {
+        let (base_url, server) = mock_server(vec![
+            http_response("500 Internal Server Error", &[], "boom"),
+            http_response("502 Bad Gateway", &[], "boom"),
+        ])
+        .await;
+        let model = test_model(base_url, "LIMERENCE_TEST_GIVE_UP_KEY");
+        let client = LlmClient::new().with_retry(fast_retry(1));
+
+        let err = client
+            .complete(&model, &[Message::user("hi")], &[])
+            .await
+            .expect_err("should give up");
+        assert!(err.to_string().contains("502"));
+        assert_eq!(server.await.expect("server task"), 2);
+    }
+
+    #[tokio::test]
+    async fn stream_splits_think_tags_into_reasoning() {
+        let sse = concat!(
+            "data: {\"choices\":[{\"delta\":{\"content\":\"<thi\"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"nk>她想喝咖啡</think>\\n\\n\"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"来一杯拿铁？\"}}]}\n\n",
+            "data: [DONE]\n\n",
+        );
+        let (base_url, server) = mock_server(vec![http_response("200 OK", &[], sse)]).await;
+        let model = test_model(base_url, "LIMERENCE_TEST_THINK_KEY");
+        let client = LlmClient::new().with_retry(RetryPolicy::none());
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let msg = client
+            .stream(
+                &model,
+                &[Message::user("hi")],
+                &[],
+                tx,
+                &CancellationToken::new(),
+            )
+            .await
+            .expect("stream");
+        assert_eq!(msg.content_text(), "来一杯拿铁？");
+        assert_eq!(msg.reasoning(), Some("她想喝咖啡"));
+
+        let mut text = String::new();
+        while let Ok(event) = rx.try_recv() {
+            if let StreamEvent::TextDelta(delta) = event {
+                text.push_str(&delta);
+            }
         }
+        assert_eq!(text, "来一杯拿铁？");
+        server.abort();
+    }
 
-        let json: serde_json::Value = resp.json().await?;
-        let data = json["data"].as_array();
+    #[tokio::test]
+    async fn stalled_streams_time_out() {
+        // No headers at all.
+        let (base_url, server) = stalling_server("").await;
+        let model = test_model(base_url, "LIMERENCE_TEST_NO_HEADERS_KEY");
+        let client = LlmClient::new()
+            .with_retry(RetryPolicy::none())
+            .with_timeouts(short_timeouts(100, 0));
+        let err = client
+            .complete(&model, &[Message::user("hi")], &[])
+            .await
+            .expect_err("response should time out");
+        assert!(err.to_string().contains("a response"), "{err}");
+        assert!(server.await.expect("server task"));
+
+        // Headers arrive, then nothing: the first-token timeout fires.
+        let (base_url, server) = stalling_server(SSE_HEAD).await;
+        let model = test_model(base_url, "LIMERENCE_TEST_FIRST_TOKEN_KEY");
+        let client = LlmClient::new()
+            .with_retry(RetryPolicy::none())
+            .with_timeouts(short_timeouts(100, 0));
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let err = client
+            .stream(
+                &model,
+                &[Message::user("hi")],
+                &[],
+                tx,
+                &CancellationToken::new(),
+            )
+            .await
+            .expect_err("first token should time out");
+        assert!(matches!(err, LlmError::Timeout(_)), "{err}");
+        assert!(err.to_string().contains("first token"));
+        assert_eq!(err.kind(), ApiErrorKind::Server);
+        assert!(server.await.expect("server task"));
+
+        // Output started, then the stream went quiet: the idle timeout fires.
+        let (base_url, server) = stalling_server(SSE_PARTIAL).await;
+        let model = test_model(base_url, "LIMERENCE_TEST_IDLE_KEY");
+        let client = LlmClient::new()
+            .with_retry(RetryPolicy::none())
+            .with_timeouts(short_timeouts(0, 100));
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let err = client
+            .stream(
+                &model,
+                &[Message::user("hi")],
+                &[],
+                tx,
+                &CancellationToken::new(),
+            )
+            .await
+            .expect_err("idle stream should time out");
+        assert!(err.to_string().contains("next chunk"), "{err}");
+        assert!(server.await.expect("server task"));
+    }
 
-        let mut models: Vec<(String, String)> = data
-            .map(|arr| {
-                arr.iter()
-                    .filter_map(|m| {
-                        let id = m["id"].as_str()?.to_string();
-                        let owned_by = m["owned_by"].as_str().unwrap_or("").to_string();
-                        Some((id, owned_by))
-                    })
-                    .collect()
-            })
-            .unwrap_or_default();
+    #[tokio::test]
+    async fn cancel_returns_partial_reply_and_closes_connection() {
+        let (base_url, server) = stalling_server(SSE_PARTIAL).await;
+        let model = test_model(base_url, "LIMERENCE_TEST_CANCEL_KEY");
+        let client = LlmClient::new()
+            .with_retry(RetryPolicy::none())
+            .with_timeouts(short_timeouts(0, 0));
+
+        let cancel = CancellationToken::new();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let messages = [Message::user("hi")];
+        let stream = client.stream(&model, &messages, &[], tx, &cancel);
+        let canceller = async {
+            while let Some(event) = rx.recv().await {
+                if matches!(event, StreamEvent::ToolCallDelta { .. }) {
+                    cancel.cancel();
+                }
+            }
+        };
+        let (msg, ()) = tokio::join!(stream, canceller);
+
+        let msg = msg.expect("cancel is not an error");
+        assert_eq!(msg.content_text(), "你好");
+        assert!(
+            matches!(&msg, Message::Assistant { tool_calls, .. } if tool_calls.is_empty()),
+            "truncated tool calls must be dropped: {msg:?}"
+        );
+        assert!(server.await.expect("server task"));
+    }
 
-        models.sort_by(|a, b| a.0.cmp(&b.0));
-        Ok(models)
+    #[tokio::test]
+    async fn scripted_transport_retries_and_streams_offline() {
+        let transport = Arc::new(
+            crate::ScriptedTransport::new()
+                .fail("connection reset")
+                .sse([
+                    r#"{"choices":[{"delta":{"content":"离线"}}]}"#,
+                    r#"{"choices":[{"delta":{"content":"回放"}}]}"#,
+                    "[DONE]",
+                ]),
+        );
+        let model = test_model(
+            "https://api.invalid/v1".to_string(),
+            "LIMERENCE_TEST_SCRIPTED_KEY",
+        );
+        let client = LlmClient::new()
+            .with_retry(fast_retry(1))
+            .with_transport(transport.clone());
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let msg = client
+            .stream(
+                &model,
+                &[Message::user("hi")],
+                &[],
+                tx,
+                &CancellationToken::new(),
+            )
+            .await
+            .expect("scripted stream");
+        assert_eq!(msg.content_text(), "离线回放");
+        assert!(std::iter::from_fn(|| rx.try_recv().ok()).any(|e| matches!(
+            e,
+            StreamEvent::Retry { reason, .. } if reason.contains("connection reset")
+        )));
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].url, "https://api.invalid/v1/chat/completions");
+        assert_eq!(transport.remaining(), 0);
     }
-}
 
-impl Default for LlmClient {
-    fn default() -> Self {
-        Self::new()
+    #[tokio::test]
+    async fn timeouts_set_after_a_transport_keep_it() {
+        let transport = Arc::new(
+            crate::ScriptedTransport::new()
+                .sse([r#"{"choices":[{"delta":{"content":"好"}}]}"#, "[DONE]"]),
+        );
+        let model = test_model(
+            "https://api.invalid/v1".to_string(),
+            "LIMERENCE_TEST_TIMEOUT_ORDER_KEY",
+        );
+        let client = LlmClient::new()
+            .with_transport(transport.clone())
+            .with_timeouts(short_timeouts(0, 0));
+
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let msg = client
+            .stream(
+                &model,
+                &[Message::user("hi")],
+                &[],
+                tx,
+                &CancellationToken::new(),
+            )
+            .await
+            .expect("scripted reply");
+        assert_eq!(msg.content_text(), "好");
+        assert_eq!(transport.requests().len(), 1);
     }
 }
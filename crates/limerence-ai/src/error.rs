@@ -0,0 +1,323 @@
+use std::fmt;
+
+/// What went wrong, as far as the caller needs to react to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiErrorKind {
+    /// Invalid or missing credentials, or no permission for the model.
+    Auth,
+    /// Rate limited or out of quota/credits.
+    RateLimited,
+    /// The prompt does not fit the model's context window.
+    ContextLengthExceeded,
+    /// The request or response was blocked by a safety filter.
+    ContentFiltered,
+    /// The model id is unknown to the endpoint.
+    ModelNotFound,
+    /// 5xx or overloaded.
+    Server,
+    /// Any other rejected request (malformed parameters, etc.).
+    InvalidRequest,
+    /// Could not be classified.
+    Unknown,
+}
+
+impl ApiErrorKind {
+    /// Whether another model in a fallback chain could plausibly succeed.
+    /// Context overflow and content filtering are properties of the prompt itself.
+    pub fn should_fallback(self) -> bool {
+        !matches!(self, Self::ContextLengthExceeded | Self::ContentFiltered)
+    }
+}
+
+/// A provider error: HTTP status, request id and the parsed error body.
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    pub kind: ApiErrorKind,
+    /// HTTP status, or `None` for errors reported inside a stream.
+    pub status: Option<u16>,
+    pub request_id: Option<String>,
+    /// Human-readable message extracted from the body.
+    pub message: String,
+    /// The provider's error object, if the body was JSON.
+    pub body: Option<serde_json::Value>,
+}
+
+/// Headers providers use to identify a request for support tickets.
+const REQUEST_ID_HEADERS: &[&str] = &["x-request-id", "request-id", "x-goog-request-id"];
+
+impl ApiError {
+    /// Build from a non-2xx HTTP response.
+    pub fn from_response(status: u16, headers: &reqwest::header::HeaderMap, body: &str) -> Self {
+        let header_request_id = REQUEST_ID_HEADERS
+            .iter()
+            .find_map(|name| headers.get(*name)?.to_str().ok())
+            .map(str::to_string);
+        let mut err = Self::from_body_text(Some(status), body);
+        if header_request_id.is_some() {
+            err.request_id = header_request_id;
+        }
+        err
+    }
+
+    /// Build from an error payload (a full body or an in-stream error object).
+    pub fn from_body_text(status: Option<u16>, body: &str) -> Self {
+        match serde_json::from_str::<serde_json::Value>(body) {
+            Ok(json) => Self::from_json(status, json),
+            Err(_) => Self {
+                kind: classify(status, "", "", body),
+                status,
+                request_id: None,
+                message: body.trim().to_string(),
+                body: None,
+            },
+        }
+    }
+
+    /// Build from a JSON error. Accepts `{"error": {...}}` wrappers (OpenAI, Gemini,
+    /// Anthropic) or the inner error object itself.
+    pub fn from_json(status: Option<u16>, json: serde_json::Value) -> Self {
+        let inner = json.get("error").cloned().unwrap_or_else(|| json.clone());
+
+        let message = inner["message"]
+            .as_str()
+            .or_else(|| inner.as_str())
+            .unwrap_or_default()
+            .to_string();
+        // OpenAI: type + code; Anthropic: type; Gemini: status (+ numeric code).
+        let error_type = inner["type"]
+            .as_str()
+            .or(inner["status"].as_str())
+            .unwrap_or_default();
+        let code = inner["code"].as_str().unwrap_or_default();
+        let status = status.or_else(|| inner["code"].as_u64().and_then(|c| u16::try_from(c).ok()));
+        let request_id = json["request_id"].as_str().map(str::to_string);
+
+        Self {
+            kind: classify(status, error_type, code, &message),
+            status,
+            request_id,
+            message: if message.is_empty() {
+                inner.to_string()
+            } else {
+                message
+            },
+            body: Some(inner),
+        }
+    }
+
+    pub fn new(kind: ApiErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            status: None,
+            request_id: None,
+            message: message.into(),
+            body: None,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(status) = self.status {
+            write!(f, "{status}: ")?;
+        }
+        write!(f, "{}", self.message)?;
+        if let Some(id) = &self.request_id {
+            write!(f, " (request id: {id})")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+fn classify(status: Option<u16>, error_type: &str, code: &str, message: &str) -> ApiErrorKind {
+    // The provider's own type/code is the most reliable signal.
+    if let Some(kind) = [code, error_type].into_iter().find_map(kind_for_identifier) {
+        return kind;
+    }
+    // These statuses are unambiguous; words in a free-form message must not override them.
+    match status {
+        Some(401 | 403) => return ApiErrorKind::Auth,
+        Some(429) => return ApiErrorKind::RateLimited,
+        Some(s) if s >= 500 => return ApiErrorKind::Server,
+        _ => {}
+    }
+    // Providers report some errors only in the message (a 400 may be a context overflow),
+    // so look for their exact wording.
+    let message = message.to_lowercase();
+    let says = |phrases: &[&str]| phrases.iter().any(|p| message.contains(p));
+    if says(&[
+        "maximum context length",
+        "prompt is too long",
+        "exceeds the context window",
+        "input token count",
+    ]) {
+        return ApiErrorKind::ContextLengthExceeded;
+    }
+    if says(&[
+        "rejected as a result of our safety system",
+        "content management policy",
+    ]) {
+        return ApiErrorKind::ContentFiltered;
+    }
+    if says(&["unknown model", "is not found for api version"]) {
+        return ApiErrorKind::ModelNotFound;
+    }
+    if says(&["credit balance is too low", "exceeded your current quota"]) {
+        return ApiErrorKind::RateLimited;
+    }
+    if says(&["api key not valid", "incorrect api key provided"]) {
+        return ApiErrorKind::Auth;
+    }
+
+    match status {
+        Some(404) => ApiErrorKind::ModelNotFound,
+        Some(413) => ApiErrorKind::ContextLengthExceeded,
+        Some(400..=499) => ApiErrorKind::InvalidRequest,
+        _ => ApiErrorKind::Unknown,
+    }
+}
+
+/// Map an OpenAI `code`/`type`, Anthropic `type` or Gemini `status` to a kind.
+fn kind_for_identifier(id: &str) -> Option<ApiErrorKind> {
+    Some(match id.to_ascii_lowercase().as_str() {
+        "context_length_exceeded" | "request_too_large" => ApiErrorKind::ContextLengthExceeded,
+        "content_filter" | "content_policy_violation" => ApiErrorKind::ContentFiltered,
+        "model_not_found" | "not_found_error" => ApiErrorKind::ModelNotFound,
+        "insufficient_quota"
+        | "rate_limit_exceeded"
+        | "rate_limit_error"
+        | "resource_exhausted" => ApiErrorKind::RateLimited,
+        "authentication_error"
+        | "invalid_api_key"
+        | "permission_error"
+        | "unauthenticated"
+        | "permission_denied" => ApiErrorKind::Auth,
+        "overloaded_error" | "server_error" | "api_error" | "internal" | "unavailable" => {
+            ApiErrorKind::Server
+        }
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_openai_errors() {
+        let err = ApiError::from_body_text(
+            Some(400),
+            r#"{"error":{"message":"This model's maximum context length is 8192 tokens","type":"invalid_request_error","code":"context_length_exceeded"}}"#,
+        );
+        assert_eq!(err.kind, ApiErrorKind::ContextLengthExceeded);
+        assert_eq!(err.status, Some(400));
+        assert!(err.body.is_some());
+
+        let quota = ApiError::from_body_text(
+            Some(429),
+            r#"{"error":{"message":"You exceeded your current quota","type":"insufficient_quota","code":"insufficient_quota"}}"#,
+        );
+        assert_eq!(quota.kind, ApiErrorKind::RateLimited);
+    }
+
+    #[test]
+    fn classifies_anthropic_errors_and_reads_request_id() {
+        let err = ApiError::from_body_text(
+            Some(401),
+            r#"{"type":"error","error":{"type":"authentication_error","message":"invalid x-api-key"},"request_id":"req_123"}"#,
+        );
+        assert_eq!(err.kind, ApiErrorKind::Auth);
+        assert_eq!(err.request_id.as_deref(), Some("req_123"));
+        assert_eq!(err.message, "invalid x-api-key");
+
+        let overloaded = ApiError::from_body_text(
+            Some(529),
+            r#"{"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}"#,
+        );
+        assert_eq!(overloaded.kind, ApiErrorKind::Server);
+    }
+
+    #[test]
+    fn classifies_gemini_errors_from_in_band_code() {
+        let err = ApiError::from_body_text(
+            None,
+            r#"{"error":{"code":429,"message":"Resource has been exhausted","status":"RESOURCE_EXHAUSTED"}}"#,
+        );
+        assert_eq!(err.kind, ApiErrorKind::RateLimited);
+        assert_eq!(err.status, Some(429));
+    }
+
+    #[test]
+    fn falls_back_to_status_for_plain_text_bodies() {
+        assert_eq!(
+            ApiError::from_body_text(Some(404), "Not Found").kind,
+            ApiErrorKind::ModelNotFound
+        );
+        assert_eq!(
+            ApiError::from_body_text(Some(502), "Bad Gateway").kind,
+            ApiErrorKind::Server
+        );
+        assert_eq!(
+            ApiError::from_body_text(Some(418), "teapot").kind,
+            ApiErrorKind::InvalidRequest
+        );
+    }
+
+    #[test]
+    fn message_wording_does_not_override_the_status() {
+        let cases = [
+            (401, "Your account is blocked"),
+            (403, "Access blocked for this project"),
+            (500, "Internal safety service unavailable"),
+            (503, "Upstream request blocked"),
+            (429, "Too many tokens per minute"),
+        ];
+        for (status, message) in cases {
+            let body = format!(r#"{{"error":{{"message":"{message}"}}}}"#);
+            let kind = ApiError::from_body_text(Some(status), &body).kind;
+            assert!(kind.should_fallback(), "{status} {message}: {kind:?}");
+        }
+        assert_eq!(
+            ApiError::from_body_text(Some(401), "quota blocked").kind,
+            ApiErrorKind::Auth
+        );
+        assert_eq!(
+            ApiError::from_body_text(Some(503), "quota blocked").kind,
+            ApiErrorKind::Server
+        );
+        assert_eq!(
+            ApiError::from_body_text(Some(400), "this quota request was blocked").kind,
+            ApiErrorKind::InvalidRequest
+        );
+    }
+
+    #[test]
+    fn reads_exact_provider_phrases_on_bad_requests() {
+        let credits = ApiError::from_body_text(
+            Some(400),
+            r#"{"type":"error","error":{"type":"invalid_request_error","message":"Your credit balance is too low to access the Anthropic API."}}"#,
+        );
+        assert_eq!(credits.kind, ApiErrorKind::RateLimited);
+
+        let key = ApiError::from_body_text(
+            Some(400),
+            r#"{"error":{"code":400,"message":"API key not valid. Please pass a valid API key.","status":"INVALID_ARGUMENT"}}"#,
+        );
+        assert_eq!(key.kind, ApiErrorKind::Auth);
+
+        let overflow = ApiError::from_body_text(
+            Some(400),
+            r#"{"type":"error","error":{"type":"invalid_request_error","message":"prompt is too long: 210000 tokens > 200000 maximum"}}"#,
+        );
+        assert_eq!(overflow.kind, ApiErrorKind::ContextLengthExceeded);
+    }
+
+    #[test]
+    fn display_includes_status_and_request_id() {
+        let mut err = ApiError::from_body_text(Some(500), "oops");
+        err.request_id = Some("abc".to_string());
+        assert_eq!(err.to_string(), "500: oops (request id: abc)");
+    }
+}
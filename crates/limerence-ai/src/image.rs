@@ -0,0 +1,128 @@
+//! Image attachments: media type detection and inlining local files.
+
+use base64::Engine;
+use std::path::Path;
+
+use crate::client::LlmError;
+use crate::types::*;
+
+/// Detect an image's media type from its magic bytes.
+pub fn sniff_media_type(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\x89PNG") {
+        Some("image/png")
+    } else if bytes.starts_with(b"\xFF\xD8\xFF") {
+        Some("image/jpeg")
+    } else if bytes.starts_with(b"GIF8") {
+        Some("image/gif")
+    } else if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WEBP") {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+/// Guess a media type from a file name or URL, for when the bytes are not at hand.
+pub fn media_type_from_extension(name: &str) -> Option<&'static str> {
+    let name = name.split(['?', '#']).next().unwrap_or(name);
+    let ext = Path::new(name).extension()?.to_str()?.to_ascii_lowercase();
+    match ext.as_str() {
+        "png" => Some("image/png"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "gif" => Some("image/gif"),
+        "webp" => Some("image/webp"),
+        _ => None,
+    }
+}
+
+/// Build an inline image from raw bytes.
+pub fn base64_source(bytes: &[u8], media_type: impl Into<String>) -> ImageSource {
+    ImageSource::Base64 {
+        media_type: media_type.into(),
+        data: base64::engine::general_purpose::STANDARD.encode(bytes),
+    }
+}
+
+/// Read `ImageSource::Path` images into inline base64, leaving everything else as is.
+pub async fn inline_local_images(messages: &[Message]) -> Result<Vec<Message>, LlmError> {
+    let mut out = Vec::with_capacity(messages.len());
+    for msg in messages {
+        let Message::User { content } = msg else {
+            out.push(msg.clone());
+            continue;
+        };
+        let mut parts = Vec::with_capacity(content.len());
+        for part in content {
+            parts.push(match part {
+                ContentPart::Image {
+                    source: ImageSource::Path { path },
+                } => ContentPart::image(read_image(path).await?),
+                other => other.clone(),
+            });
+        }
+        out.push(Message::user_parts(parts));
+    }
+    Ok(out)
+}
+
+async fn read_image(path: &Path) -> Result<ImageSource, LlmError> {
+    let bytes = tokio::fs::read(path)
+        .await
+        .map_err(|e| LlmError::Image(format!("{}: {e}", path.display())))?;
+    let media_type = sniff_media_type(&bytes)
+        .or_else(|| media_type_from_extension(&path.to_string_lossy()))
+        .ok_or_else(|| LlmError::Image(format!("{}: unsupported image format", path.display())))?;
+    Ok(base64_source(&bytes, media_type))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PNG_HEADER: &[u8] = &[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+    #[test]
+    fn sniffs_common_formats() {
+        assert_eq!(sniff_media_type(PNG_HEADER), Some("image/png"));
+        assert_eq!(
+            sniff_media_type(b"RIFF\0\0\0\0WEBPVP8 "),
+            Some("image/webp")
+        );
+        assert_eq!(sniff_media_type(b"hello"), None);
+        assert_eq!(
+            media_type_from_extension("https://x.test/cat.JPG?size=large"),
+            Some("image/jpeg")
+        );
+    }
+
+    #[tokio::test]
+    async fn inlines_path_images() {
+        let path = std::env::temp_dir().join(format!("limerence-image-{}.bin", std::process::id()));
+        std::fs::write(&path, PNG_HEADER).expect("write image");
+
+        let messages = vec![Message::user_parts(vec![
+            ContentPart::text("看看这个"),
+            ContentPart::image(ImageSource::Path { path: path.clone() }),
+        ])];
+        let inlined = inline_local_images(&messages).await.expect("inline");
+        let _ = std::fs::remove_file(&path);
+
+        match inlined[0].images().next() {
+            Some(ImageSource::Base64 { media_type, data }) => {
+                assert_eq!(media_type, "image/png");
+                assert_eq!(data, "iVBORw0KGgo=");
+            }
+            other => panic!("unexpected image: {other:?}"),
+        }
+        assert_eq!(inlined[0].content_text(), "看看这个");
+
+        let missing = vec![Message::user_parts(vec![ContentPart::image(
+            ImageSource::Path {
+                path: path.with_extension("missing"),
+            },
+        )])];
+        assert!(matches!(
+            inline_local_images(&missing).await,
+            Err(LlmError::Image(_))
+        ));
+    }
+}
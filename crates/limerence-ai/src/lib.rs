@@ -1,6 +1,21 @@
 pub mod client;
+pub mod error;
+pub mod image;
+pub mod provider;
+pub mod retry;
 pub mod stream;
+pub mod think;
+pub mod timeout;
+pub mod transport;
 pub mod types;
 
 pub use client::{LlmClient, LlmError};
+pub use error::{ApiError, ApiErrorKind};
+pub use provider::{LlmProvider, provider_for};
+pub use retry::RetryPolicy;
+pub use timeout::Timeouts;
+pub use tokio_util::sync::CancellationToken;
+pub use transport::{
+    HttpTransport, RecordingTransport, ScriptedTransport, Transport, TransportResponse,
+};
 pub use types::*;
@@ -0,0 +1,475 @@
+use std::collections::HashMap;
+
+use super::{
+    LlmProvider, ProviderRequest, StreamDecoder, endpoint, parse_arguments, unresolved_image_text,
+};
+use crate::client::LlmError;
+use crate::error::{ApiError, ApiErrorKind};
+use crate::types::*;
+
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+const DEFAULT_MAX_TOKENS: u32 = 4096;
+
+/// Native Anthropic Messages API backend.
+pub struct AnthropicProvider;
+
+impl LlmProvider for AnthropicProvider {
+    fn chat_request(
+        &self,
+        model: &Model,
+        api_key: &str,
+        messages: &[Message],
+        tools: &[ToolDef],
+        stream: bool,
+    ) -> ProviderRequest {
+        let (system, anthropic_messages) = messages_to_anthropic(messages);
+
+        let sampling = &model.sampling;
+        let mut body = serde_json::json!({
+            "model": model.id,
+            "messages": anthropic_messages,
+            "max_tokens": sampling.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            "stream": stream,
+        });
+        // Anthropic's temperature range is 0..=1, not OpenAI's 0..=2. Penalties,
+        // seed and response_format have no equivalent and are dropped.
+        if let Some(temperature) = sampling.temperature {
+            body["temperature"] = serde_json::json!(temperature.clamp(0.0, 1.0));
+        }
+        if let Some(top_p) = sampling.top_p {
+            body["top_p"] = serde_json::json!(top_p);
+        }
+        if !sampling.stop.is_empty() {
+            body["stop_sequences"] = serde_json::json!(sampling.stop);
+        }
+        if !system.is_empty() {
+            body["system"] = serde_json::Value::String(system);
+        }
+        if !tools.is_empty() {
+            body["tools"] = tools
+                .iter()
+                .map(|t| {
+                    serde_json::json!({
+                        "name": t.name,
+                        "description": t.description,
+                        "input_schema": t.parameters,
+                    })
+                })
+                .collect();
+            if let Some(choice) = sampling.tool_choice {
+                let kind = match choice {
+                    ToolChoice::Auto => "auto",
+                    ToolChoice::None => "none",
+                };
+                body["tool_choice"] = serde_json::json!({ "type": kind });
+            }
+        }
+
+        ProviderRequest::post(endpoint(&model.base_url, "messages"), body)
+            .header("x-api-key", api_key)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+    }
+
+    fn stream_decoder(&self) -> Box<dyn StreamDecoder> {
+        Box::new(AnthropicStreamDecoder::default())
+    }
+
+    fn parse_completion(&self, body: &serde_json::Value) -> Result<Message, LlmError> {
+        if body["type"] == "error" {
+            return Err(LlmError::Api(ApiError::from_json(None, body.clone())));
+        }
+
+        let mut content = String::new();
+        let mut reasoning = String::new();
+        let mut tool_calls = Vec::new();
+
+        for block in body["content"].as_array().into_iter().flatten() {
+            match block["type"].as_str() {
+                Some("text") => content.push_str(block["text"].as_str().unwrap_or_default()),
+                Some("thinking") => {
+                    reasoning.push_str(block["thinking"].as_str().unwrap_or_default())
+                }
+                Some("tool_use") => tool_calls.push(ToolCall {
+                    id: block["id"].as_str().unwrap_or_default().to_string(),
+                    function: FunctionCall {
+                        name: block["name"].as_str().unwrap_or_default().to_string(),
+                        arguments: block["input"].to_string(),
+                    },
+                }),
+                _ => {}
+            }
+        }
+
+        let msg = if tool_calls.is_empty() {
+            Message::assistant(content)
+        } else {
+            Message::assistant_with_tools(content, tool_calls)
+        };
+        Ok(msg.with_reasoning(reasoning))
+    }
+
+    fn models_request(&self, model: &Model, api_key: &str) -> ProviderRequest {
+        ProviderRequest::get(endpoint(&model.base_url, "models"))
+            .header("x-api-key", api_key)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+    }
+
+    fn parse_models(&self, body: &serde_json::Value) -> Vec<(String, String)> {
+        body["data"]
+            .as_array()
+            .map(|arr| {
+                arr.iter()
+                    .filter_map(|m| Some((m["id"].as_str()?.to_string(), "anthropic".to_string())))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// Tracks which content block index maps to which tool call slot, and the
+/// prompt usage from `message_start` until output usage arrives in `message_delta`.
+#[derive(Default)]
+struct AnthropicStreamDecoder {
+    tool_slots: HashMap<u64, usize>,
+    usage: Usage,
+}
+
+impl StreamDecoder for AnthropicStreamDecoder {
+    fn decode(&mut self, data: &str) -> Vec<StreamEvent> {
+        let Ok(json) = serde_json::from_str::<serde_json::Value>(data) else {
+            return vec![];
+        };
+
+        match json["type"].as_str() {
+            Some("message_start") => {
+                let usage = &json["message"]["usage"];
+                let cached = usage["cache_read_input_tokens"].as_u64().unwrap_or(0);
+                // Anthropic's input_tokens excludes cache reads and writes.
+                self.usage.prompt_tokens = usage["input_tokens"].as_u64().unwrap_or(0)
+                    + cached
+                    + usage["cache_creation_input_tokens"].as_u64().unwrap_or(0);
+                self.usage.cached_tokens = cached;
+                vec![]
+            }
+            Some("content_block_start") => {
+                let block = &json["content_block"];
+                if block["type"] != "tool_use" {
+                    return vec![];
+                }
+                let block_index = json["index"].as_u64().unwrap_or(0);
+                let index = self.tool_slots.len();
+                self.tool_slots.insert(block_index, index);
+                vec![StreamEvent::ToolCallStart {
+                    index,
+                    id: block["id"].as_str().unwrap_or_default().to_string(),
+                    name: block["name"].as_str().unwrap_or_default().to_string(),
+                }]
+            }
+            Some("content_block_delta") => {
+                let delta = &json["delta"];
+                match delta["type"].as_str() {
+                    Some("text_delta") => match delta["text"].as_str() {
+                        Some(text) if !text.is_empty() => {
+                            vec![StreamEvent::TextDelta(text.to_string())]
+                        }
+                        _ => vec![],
+                    },
+                    Some("thinking_delta") => match delta["thinking"].as_str() {
+                        Some(text) if !text.is_empty() => {
+                            vec![StreamEvent::ReasoningDelta(text.to_string())]
+                        }
+                        _ => vec![],
+                    },
+                    Some("input_json_delta") => {
+                        let block_index = json["index"].as_u64().unwrap_or(0);
+                        match (
+                            self.tool_slots.get(&block_index),
+                            delta["partial_json"].as_str(),
+                        ) {
+                            (Some(&index), Some(args)) if !args.is_empty() => {
+                                vec![StreamEvent::ToolCallDelta {
+                                    index,
+                                    arguments: args.to_string(),
+                                }]
+                            }
+                            _ => vec![],
+                        }
+                    }
+                    _ => vec![],
+                }
+            }
+            Some("message_delta") => {
+                let mut events = Vec::new();
+                if let Some(output) = json["usage"]["output_tokens"].as_u64() {
+                    self.usage.completion_tokens = output;
+                    events.push(StreamEvent::Usage(self.usage));
+                }
+                if json["delta"]["stop_reason"] == "refusal" {
+                    events.push(StreamEvent::Error(ApiError::new(
+                        ApiErrorKind::ContentFiltered,
+                        "response refused by safety filter",
+                    )));
+                }
+                events
+            }
+            Some("message_stop") => vec![StreamEvent::Done],
+            Some("error") => vec![StreamEvent::Error(ApiError::from_json(None, json))],
+            _ => vec![],
+        }
+    }
+}
+
+/// Split out system messages and convert the rest to Anthropic content blocks.
+/// Consecutive tool results are merged into a single user turn, as the API requires.
+fn messages_to_anthropic(messages: &[Message]) -> (String, Vec<serde_json::Value>) {
+    let mut system_parts = Vec::new();
+    let mut out: Vec<serde_json::Value> = Vec::new();
+
+    for msg in messages {
+        match msg {
+            Message::System { content } => system_parts.push(content.clone()),
+            Message::User { content } => out.push(serde_json::json!({
+                "role": "user",
+                "content": content.iter().map(part_to_anthropic).collect::<Vec<_>>(),
+            })),
+            Message::Assistant {
+                content,
+                tool_calls,
+                ..
+            } => {
+                let mut blocks = Vec::new();
+                if !content.is_empty() {
+                    blocks.push(serde_json::json!({"type": "text", "text": content}));
+                }
+                for tc in tool_calls {
+                    blocks.push(serde_json::json!({
+                        "type": "tool_use",
+                        "id": tc.id,
+                        "name": tc.function.name,
+                        "input": parse_arguments(&tc.function.arguments),
+                    }));
+                }
+                if blocks.is_empty() {
+                    continue;
+                }
+                out.push(serde_json::json!({"role": "assistant", "content": blocks}));
+            }
+            Message::ToolResult {
+                tool_call_id,
+                content,
+            } => {
+                let block = serde_json::json!({
+                    "type": "tool_result",
+                    "tool_use_id": tool_call_id,
+                    "content": content,
+                });
+                let merge = out.last().is_some_and(|last| {
+                    last["role"] == "user"
+                        && last["content"]
+                            .as_array()
+                            .and_then(|c| c.first())
+                            .is_some_and(|b| b["type"] == "tool_result")
+                });
+                if merge
+                    && let Some(blocks) = out.last_mut().and_then(|m| m["content"].as_array_mut())
+                {
+                    blocks.push(block);
+                } else {
+                    out.push(serde_json::json!({"role": "user", "content": [block]}));
+                }
+            }
+        }
+    }
+
+    (system_parts.join("\n\n"), out)
+}
+
+fn part_to_anthropic(part: &ContentPart) -> serde_json::Value {
+    match part {
+        ContentPart::Text { text } => serde_json::json!({"type": "text", "text": text}),
+        ContentPart::Image { source } => match source {
+            ImageSource::Base64 { media_type, data } => serde_json::json!({
+                "type": "image",
+                "source": {"type": "base64", "media_type": media_type, "data": data},
+            }),
+            ImageSource::Url { url } => serde_json::json!({
+                "type": "image",
+                "source": {"type": "url", "url": url},
+            }),
+            other => serde_json::json!({"type": "text", "text": unresolved_image_text(other)}),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model() -> Model {
+        Model {
+            id: "claude-sonnet-4-5".to_string(),
+            base_url: "https://api.anthropic.com/v1".to_string(),
+            api_key_env: "ANTHROPIC_API_KEY".to_string(),
+            provider: ProviderKind::Anthropic,
+            sampling: SamplingParams::default(),
+            stream_usage: None,
+        }
+    }
+
+    #[test]
+    fn chat_request_maps_sampling_params() {
+        let body = AnthropicProvider
+            .chat_request(&model(), "key", &[Message::user("你好")], &[], true)
+            .body
+            .expect("post body");
+        assert_eq!(body["max_tokens"], DEFAULT_MAX_TOKENS);
+        assert!(body.get("temperature").is_none());
+
+        let mut model = model();
+        model.sampling = SamplingParams {
+            temperature: Some(1.3),
+            max_tokens: Some(1024),
+            stop: vec!["</reply>".to_string()],
+            presence_penalty: Some(0.5),
+            ..SamplingParams::default()
+        };
+        let body = AnthropicProvider
+            .chat_request(&model, "key", &[Message::user("你好")], &[], true)
+            .body
+            .expect("post body");
+        assert_eq!(body["max_tokens"], 1024);
+        assert_eq!(body["temperature"], 1.0);
+        assert_eq!(body["stop_sequences"][0], "</reply>");
+        assert!(body.get("presence_penalty").is_none());
+    }
+
+    #[test]
+    fn chat_request_maps_tool_choice() {
+        let mut model = model();
+        model.sampling.tool_choice = Some(ToolChoice::None);
+        let tools = [ToolDef {
+            name: "note_read".to_string(),
+            description: "读取笔记".to_string(),
+            parameters: serde_json::json!({"type": "object", "properties": {}}),
+        }];
+        let body = AnthropicProvider
+            .chat_request(&model, "key", &[Message::user("你好")], &tools, true)
+            .body
+            .expect("post body");
+        assert_eq!(body["tool_choice"]["type"], "none");
+        assert_eq!(body["tools"][0]["name"], "note_read");
+    }
+
+    #[test]
+    fn chat_request_lifts_system_and_merges_tool_results() {
+        let messages = vec![
+            Message::system("你是苏晚。"),
+            Message::user("还记得我吗？"),
+            Message::assistant_with_tools(
+                "",
+                vec![
+                    ToolCall {
+                        id: "toolu_1".to_string(),
+                        function: FunctionCall {
+                            name: "memory_search".to_string(),
+                            arguments: "{\"query\":\"名字\"}".to_string(),
+                        },
+                    },
+                    ToolCall {
+                        id: "toolu_2".to_string(),
+                        function: FunctionCall {
+                            name: "note_read".to_string(),
+                            arguments: String::new(),
+                        },
+                    },
+                ],
+            ),
+            Message::tool_result("toolu_1", "小林"),
+            Message::tool_result("toolu_2", "暂无笔记。"),
+        ];
+
+        let req = AnthropicProvider.chat_request(&model(), "key", &messages, &[], true);
+        assert_eq!(req.url, "https://api.anthropic.com/v1/messages");
+        assert!(
+            req.headers
+                .contains(&("x-api-key".to_string(), "key".to_string()))
+        );
+
+        let body = req.body.expect("post body");
+        assert_eq!(body["system"], "你是苏晚。");
+        let msgs = body["messages"].as_array().expect("messages");
+        assert_eq!(msgs.len(), 3);
+        assert_eq!(msgs[1]["content"][0]["input"]["query"], "名字");
+        assert_eq!(msgs[1]["content"][1]["input"], serde_json::json!({}));
+        assert_eq!(msgs[2]["role"], "user");
+        assert_eq!(msgs[2]["content"].as_array().map(|c| c.len()), Some(2));
+    }
+
+    #[test]
+    fn stream_decoder_maps_blocks_to_tool_slots() {
+        let mut decoder = AnthropicProvider.stream_decoder();
+        let lines = [
+            r#"{"type":"message_start","message":{"usage":{"input_tokens":10,"cache_read_input_tokens":90,"output_tokens":1}}}"#,
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"让我想想"}}"#,
+            r#"{"type":"content_block_start","index":1,"content_block":{"type":"tool_use","id":"toolu_1","name":"memory_search","input":{}}}"#,
+            r#"{"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"{\"query\":"}}"#,
+            r#"{"type":"message_delta","delta":{"stop_reason":"tool_use"},"usage":{"output_tokens":42}}"#,
+            r#"{"type":"message_stop"}"#,
+        ];
+        let events: Vec<StreamEvent> = lines.iter().flat_map(|l| decoder.decode(l)).collect();
+
+        assert!(matches!(&events[0], StreamEvent::TextDelta(t) if t == "让我想想"));
+        assert!(
+            matches!(&events[1], StreamEvent::ToolCallStart { index: 0, name, .. } if name == "memory_search")
+        );
+        assert!(matches!(
+            &events[2],
+            StreamEvent::ToolCallDelta { index: 0, .. }
+        ));
+        match &events[3] {
+            StreamEvent::Usage(usage) => {
+                assert_eq!(usage.prompt_tokens, 100);
+                assert_eq!(usage.cached_tokens, 90);
+                assert_eq!(usage.completion_tokens, 42);
+            }
+            other => panic!("unexpected event: {other:?}"),
+        }
+        assert!(matches!(events[4], StreamEvent::Done));
+    }
+
+    #[test]
+    fn thinking_blocks_become_reasoning() {
+        let mut decoder = AnthropicProvider.stream_decoder();
+        let events = decoder.decode(
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"thinking_delta","thinking":"她在问天气"}}"#,
+        );
+        assert!(matches!(&events[..], [StreamEvent::ReasoningDelta(t)] if t == "她在问天气"));
+
+        let msg = AnthropicProvider
+            .parse_completion(&serde_json::json!({
+                "content": [
+                    {"type": "thinking", "thinking": "先想想", "signature": "sig"},
+                    {"type": "text", "text": "晴天"}
+                ]
+            }))
+            .expect("message");
+        assert_eq!(msg.content_text(), "晴天");
+        assert_eq!(msg.reasoning(), Some("先想想"));
+    }
+
+    #[test]
+    fn user_images_become_image_blocks() {
+        let (_, messages) = messages_to_anthropic(&[Message::user_parts(vec![
+            ContentPart::image(ImageSource::Base64 {
+                media_type: "image/jpeg".to_string(),
+                data: "/9j/".to_string(),
+            }),
+            ContentPart::text("好看吗？"),
+        ])]);
+        let blocks = &messages[0]["content"];
+        assert_eq!(blocks[0]["type"], "image");
+        assert_eq!(blocks[0]["source"]["media_type"], "image/jpeg");
+        assert_eq!(blocks[1]["text"], "好看吗？");
+    }
+}
@@ -0,0 +1,499 @@
+use std::collections::HashMap;
+
+use super::{
+    LlmProvider, ProviderRequest, StreamDecoder, endpoint, parse_arguments, unresolved_image_text,
+};
+use crate::client::LlmError;
+use crate::error::{ApiError, ApiErrorKind};
+use crate::image::media_type_from_extension;
+use crate::types::*;
+
+/// JSON Schema keywords the Gemini function-declaration schema rejects.
+const UNSUPPORTED_SCHEMA_KEYS: &[&str] = &["default", "additionalProperties", "$schema"];
+
+/// Native Google Gemini `generateContent` backend.
+pub struct GeminiProvider;
+
+impl LlmProvider for GeminiProvider {
+    fn chat_request(
+        &self,
+        model: &Model,
+        api_key: &str,
+        messages: &[Message],
+        tools: &[ToolDef],
+        stream: bool,
+    ) -> ProviderRequest {
+        let (system, contents) = messages_to_gemini(messages);
+
+        let mut body = serde_json::json!({ "contents": contents });
+        if !system.is_empty() {
+            body["systemInstruction"] = serde_json::json!({ "parts": [{ "text": system }] });
+        }
+        if !tools.is_empty() {
+            let declarations: Vec<serde_json::Value> = tools
+                .iter()
+                .map(|t| {
+                    serde_json::json!({
+                        "name": t.name,
+                        "description": t.description,
+                        "parameters": sanitize_schema(&t.parameters),
+                    })
+                })
+                .collect();
+            body["tools"] = serde_json::json!([{ "functionDeclarations": declarations }]);
+            if let Some(choice) = model.sampling.tool_choice {
+                let mode = match choice {
+                    ToolChoice::Auto => "AUTO",
+                    ToolChoice::None => "NONE",
+                };
+                body["toolConfig"] =
+                    serde_json::json!({ "functionCallingConfig": { "mode": mode } });
+            }
+        }
+        let generation_config = generation_config(&model.sampling);
+        if !generation_config.is_empty() {
+            body["generationConfig"] = generation_config.into();
+        }
+
+        let path = if stream {
+            format!("models/{}:streamGenerateContent?alt=sse", model.id)
+        } else {
+            format!("models/{}:generateContent", model.id)
+        };
+
+        ProviderRequest::post(endpoint(&model.base_url, &path), body)
+            .header("x-goog-api-key", api_key)
+    }
+
+    fn stream_decoder(&self) -> Box<dyn StreamDecoder> {
+        Box::new(GeminiStreamDecoder::default())
+    }
+
+    fn parse_completion(&self, body: &serde_json::Value) -> Result<Message, LlmError> {
+        if body.get("error").is_some() {
+            return Err(LlmError::Api(ApiError::from_json(None, body.clone())));
+        }
+        if let Some(err) = blocked_error(body) {
+            return Err(LlmError::Api(err));
+        }
+
+        let mut content = String::new();
+        let mut reasoning = String::new();
+        let mut tool_calls = Vec::new();
+
+        for part in candidate_parts(body) {
+            if let Some(text) = part["text"].as_str() {
+                // Thought summaries (includeThoughts) arrive as text parts flagged `thought`.
+                if part["thought"] == true {
+                    reasoning.push_str(text);
+                } else {
+                    content.push_str(text);
+                }
+            } else if let Some(call) = part.get("functionCall") {
+                tool_calls.push(function_call_to_tool_call(call, tool_calls.len()));
+            }
+        }
+
+        let msg = if tool_calls.is_empty() {
+            Message::assistant(content)
+        } else {
+            Message::assistant_with_tools(content, tool_calls)
+        };
+        Ok(msg.with_reasoning(reasoning))
+    }
+
+    fn models_request(&self, model: &Model, api_key: &str) -> ProviderRequest {
+        ProviderRequest::get(endpoint(&model.base_url, "models")).header("x-goog-api-key", api_key)
+    }
+
+    fn parse_models(&self, body: &serde_json::Value) -> Vec<(String, String)> {
+        body["models"]
+            .as_array()
+            .map(|arr| {
+                arr.iter()
+                    .filter_map(|m| {
+                        let name = m["name"].as_str()?;
+                        let id = name.strip_prefix("models/").unwrap_or(name);
+                        Some((id.to_string(), "google".to_string()))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// Gemini streams whole function calls, so each one becomes a start + single delta.
+#[derive(Default)]
+struct GeminiStreamDecoder {
+    tool_count: usize,
+}
+
+impl StreamDecoder for GeminiStreamDecoder {
+    fn decode(&mut self, data: &str) -> Vec<StreamEvent> {
+        let Ok(json) = serde_json::from_str::<serde_json::Value>(data) else {
+            return vec![];
+        };
+
+        if json.get("error").is_some() {
+            return vec![StreamEvent::Error(ApiError::from_json(None, json))];
+        }
+        if let Some(err) = blocked_error(&json) {
+            return vec![StreamEvent::Error(err)];
+        }
+
+        let mut events = Vec::new();
+        for part in candidate_parts(&json) {
+            if let Some(text) = part["text"].as_str() {
+                if text.is_empty() {
+                    continue;
+                }
+                events.push(if part["thought"] == true {
+                    StreamEvent::ReasoningDelta(text.to_string())
+                } else {
+                    StreamEvent::TextDelta(text.to_string())
+                });
+            } else if let Some(call) = part.get("functionCall") {
+                let index = self.tool_count;
+                self.tool_count += 1;
+                let tc = function_call_to_tool_call(call, index);
+                events.push(StreamEvent::ToolCallStart {
+                    index,
+                    id: tc.id,
+                    name: tc.function.name,
+                });
+                events.push(StreamEvent::ToolCallDelta {
+                    index,
+                    arguments: tc.function.arguments,
+                });
+            }
+        }
+
+        if let Some(meta) = json.get("usageMetadata") {
+            events.push(StreamEvent::Usage(Usage {
+                prompt_tokens: meta["promptTokenCount"].as_u64().unwrap_or(0),
+                completion_tokens: meta["candidatesTokenCount"].as_u64().unwrap_or(0)
+                    + meta["thoughtsTokenCount"].as_u64().unwrap_or(0),
+                cached_tokens: meta["cachedContentTokenCount"].as_u64().unwrap_or(0),
+            }));
+        }
+
+        if json["candidates"][0]["finishReason"].is_string() {
+            events.push(StreamEvent::Done);
+        }
+
+        events
+    }
+}
+
+/// Gemini reports safety blocks as a finish/block reason rather than an error object.
+fn blocked_error(json: &serde_json::Value) -> Option<ApiError> {
+    let reason =
+        json["promptFeedback"]["blockReason"].as_str().or_else(|| {
+            match json["candidates"][0]["finishReason"].as_str() {
+                Some(r @ ("SAFETY" | "PROHIBITED_CONTENT" | "BLOCKLIST" | "SPII")) => Some(r),
+                _ => None,
+            }
+        })?;
+    Some(ApiError::new(
+        ApiErrorKind::ContentFiltered,
+        format!("blocked by safety filter: {reason}"),
+    ))
+}
+
+fn candidate_parts(json: &serde_json::Value) -> impl Iterator<Item = &serde_json::Value> {
+    json["candidates"][0]["content"]["parts"]
+        .as_array()
+        .into_iter()
+        .flatten()
+}
+
+fn function_call_to_tool_call(call: &serde_json::Value, index: usize) -> ToolCall {
+    let name = call["name"].as_str().unwrap_or_default().to_string();
+    // Older Gemini models do not return call ids; synthesize a stable one.
+    let id = call["id"]
+        .as_str()
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("call_{index}_{name}"));
+    let args = match call.get("args") {
+        Some(v) if v.is_object() => v.to_string(),
+        _ => "{}".to_string(),
+    };
+    ToolCall {
+        id,
+        function: FunctionCall {
+            name,
+            arguments: args,
+        },
+    }
+}
+
+/// Convert messages to Gemini `contents`. Function responses must name the
+/// function they answer, so tool call ids are resolved back to names.
+fn messages_to_gemini(messages: &[Message]) -> (String, Vec<serde_json::Value>) {
+    let mut system_parts = Vec::new();
+    let mut call_names: HashMap<&str, &str> = HashMap::new();
+    let mut out: Vec<serde_json::Value> = Vec::new();
+
+    for msg in messages {
+        match msg {
+            Message::System { content } => system_parts.push(content.clone()),
+            Message::User { content } => out.push(serde_json::json!({
+                "role": "user",
+                "parts": content.iter().map(part_to_gemini).collect::<Vec<_>>(),
+            })),
+            Message::Assistant {
+                content,
+                tool_calls,
+                ..
+            } => {
+                let mut parts = Vec::new();
+                if !content.is_empty() {
+                    parts.push(serde_json::json!({ "text": content }));
+                }
+                for tc in tool_calls {
+                    call_names.insert(&tc.id, &tc.function.name);
+                    parts.push(serde_json::json!({
+                        "functionCall": {
+                            "name": tc.function.name,
+                            "args": parse_arguments(&tc.function.arguments),
+                        }
+                    }));
+                }
+                if parts.is_empty() {
+                    continue;
+                }
+                out.push(serde_json::json!({ "role": "model", "parts": parts }));
+            }
+            Message::ToolResult {
+                tool_call_id,
+                content,
+            } => {
+                let name = call_names.get(tool_call_id.as_str()).copied().unwrap_or("");
+                let part = serde_json::json!({
+                    "functionResponse": {
+                        "name": name,
+                        "response": { "content": content },
+                    }
+                });
+                let merge = out.last().is_some_and(|last| {
+                    last["role"] == "user" && last["parts"][0].get("functionResponse").is_some()
+                });
+                if merge && let Some(parts) = out.last_mut().and_then(|m| m["parts"].as_array_mut())
+                {
+                    parts.push(part);
+                } else {
+                    out.push(serde_json::json!({ "role": "user", "parts": [part] }));
+                }
+            }
+        }
+    }
+
+    (system_parts.join("\n\n"), out)
+}
+
+fn sanitize_schema(schema: &serde_json::Value) -> serde_json::Value {
+    match schema {
+        serde_json::Value::Object(map) => map
+            .iter()
+            .filter(|(k, _)| !UNSUPPORTED_SCHEMA_KEYS.contains(&k.as_str()))
+            .map(|(k, v)| (k.clone(), sanitize_schema(v)))
+            .collect::<serde_json::Map<_, _>>()
+            .into(),
+        serde_json::Value::Array(items) => items.iter().map(sanitize_schema).collect(),
+        other => other.clone(),
+    }
+}
+
+fn part_to_gemini(part: &ContentPart) -> serde_json::Value {
+    match part {
+        ContentPart::Text { text } => serde_json::json!({ "text": text }),
+        ContentPart::Image { source } => match source {
+            ImageSource::Base64 { media_type, data } => serde_json::json!({
+                "inlineData": { "mimeType": media_type, "data": data },
+            }),
+            ImageSource::Url { url } => serde_json::json!({
+                "fileData": {
+                    "mimeType": media_type_from_extension(url).unwrap_or("image/jpeg"),
+                    "fileUri": url,
+                },
+            }),
+            other => serde_json::json!({ "text": unresolved_image_text(other) }),
+        },
+    }
+}
+
+/// Map sampling params onto Gemini's `generationConfig`.
+fn generation_config(sampling: &SamplingParams) -> serde_json::Map<String, serde_json::Value> {
+    let mut config = serde_json::Map::new();
+    let mut set = |key: &str, value: Option<serde_json::Value>| {
+        if let Some(value) = value {
+            config.insert(key.to_string(), value);
+        }
+    };
+    set("temperature", sampling.temperature.map(Into::into));
+    set("topP", sampling.top_p.map(Into::into));
+    set("maxOutputTokens", sampling.max_tokens.map(Into::into));
+    set("presencePenalty", sampling.presence_penalty.map(Into::into));
+    set(
+        "frequencyPenalty",
+        sampling.frequency_penalty.map(Into::into),
+    );
+    set("seed", sampling.seed.map(Into::into));
+    set(
+        "stopSequences",
+        (!sampling.stop.is_empty()).then(|| sampling.stop.clone().into()),
+    );
+
+    // OpenAI's json_object / json_schema response formats become a JSON MIME type.
+    if let Some(format) = &sampling.response_format {
+        match format["type"].as_str() {
+            Some("json_object") => set("responseMimeType", Some("application/json".into())),
+            Some("json_schema") => {
+                set("responseMimeType", Some("application/json".into()));
+                let schema = &format["json_schema"]["schema"];
+                if !schema.is_null() {
+                    set("responseSchema", Some(sanitize_schema(schema)));
+                }
+            }
+            _ => {}
+        }
+    }
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model() -> Model {
+        Model {
+            id: "gemini-2.5-flash".to_string(),
+            base_url: "https://generativelanguage.googleapis.com/v1beta".to_string(),
+            api_key_env: "GEMINI_API_KEY".to_string(),
+            provider: ProviderKind::Gemini,
+            sampling: SamplingParams::default(),
+            stream_usage: None,
+        }
+    }
+
+    #[test]
+    fn chat_request_sends_generation_config() {
+        let body = GeminiProvider
+            .chat_request(&model(), "key", &[Message::user("你好")], &[], false)
+            .body
+            .expect("post body");
+        assert!(body.get("generationConfig").is_none());
+
+        let mut model = model();
+        model.sampling = SamplingParams {
+            temperature: Some(0.75),
+            top_p: Some(0.9),
+            max_tokens: Some(2048),
+            stop: vec!["END".to_string()],
+            response_format: Some(serde_json::json!({ "type": "json_object" })),
+            ..SamplingParams::default()
+        };
+        let body = GeminiProvider
+            .chat_request(&model, "key", &[Message::user("你好")], &[], false)
+            .body
+            .expect("post body");
+        let config = &body["generationConfig"];
+        assert_eq!(config["temperature"], 0.75);
+        assert_eq!(config["maxOutputTokens"], 2048);
+        assert_eq!(config["stopSequences"][0], "END");
+        assert_eq!(config["responseMimeType"], "application/json");
+        assert!(config.get("seed").is_none());
+    }
+
+    #[test]
+    fn chat_request_resolves_function_response_names() {
+        let messages = vec![
+            Message::system("你是苏晚。"),
+            Message::user("查一下天气"),
+            Message::assistant_with_tools(
+                "",
+                vec![ToolCall {
+                    id: "call_0_web_search".to_string(),
+                    function: FunctionCall {
+                        name: "web_search".to_string(),
+                        arguments: "{\"query\":\"天气\"}".to_string(),
+                    },
+                }],
+            ),
+            Message::tool_result("call_0_web_search", "晴"),
+        ];
+        let tools = vec![ToolDef {
+            name: "memory_search".to_string(),
+            description: "搜索".to_string(),
+            parameters: serde_json::json!({
+                "type": "object",
+                "properties": {"limit": {"type": "integer", "default": 5}}
+            }),
+        }];
+
+        let req = GeminiProvider.chat_request(&model(), "key", &messages, &tools, true);
+        assert!(
+            req.url
+                .ends_with("models/gemini-2.5-flash:streamGenerateContent?alt=sse")
+        );
+
+        let body = req.body.expect("post body");
+        assert_eq!(body["systemInstruction"]["parts"][0]["text"], "你是苏晚。");
+        assert_eq!(body["contents"][1]["role"], "model");
+        assert_eq!(
+            body["contents"][2]["parts"][0]["functionResponse"]["name"],
+            "web_search"
+        );
+        let params = &body["tools"][0]["functionDeclarations"][0]["parameters"];
+        assert!(params["properties"]["limit"].get("default").is_none());
+        assert!(body.get("toolConfig").is_none());
+
+        let mut model = model();
+        model.sampling.tool_choice = Some(ToolChoice::None);
+        let body = GeminiProvider
+            .chat_request(&model, "key", &messages, &tools, true)
+            .body
+            .expect("post body");
+        assert_eq!(body["toolConfig"]["functionCallingConfig"]["mode"], "NONE");
+    }
+
+    #[test]
+    fn stream_decoder_emits_text_and_whole_function_calls() {
+        let mut decoder = GeminiProvider.stream_decoder();
+        let events = decoder.decode(
+            r#"{"candidates":[{"content":{"role":"model","parts":[{"text":"好的"},{"functionCall":{"name":"note_read","args":{}}}]},"finishReason":"STOP"}],"usageMetadata":{"promptTokenCount":50,"candidatesTokenCount":7}}"#,
+        );
+
+        assert!(matches!(&events[0], StreamEvent::TextDelta(t) if t == "好的"));
+        assert!(
+            matches!(&events[1], StreamEvent::ToolCallStart { index: 0, name, .. } if name == "note_read")
+        );
+        assert!(
+            matches!(&events[2], StreamEvent::ToolCallDelta { arguments, .. } if arguments == "{}")
+        );
+        assert!(matches!(
+            events[3],
+            StreamEvent::Usage(Usage {
+                prompt_tokens: 50,
+                completion_tokens: 7,
+                ..
+            })
+        ));
+        assert!(matches!(events[4], StreamEvent::Done));
+    }
+
+    #[test]
+    fn user_images_become_inline_data() {
+        let (_, contents) = messages_to_gemini(&[Message::user_parts(vec![
+            ContentPart::text("猜猜这是哪里"),
+            ContentPart::image(ImageSource::Base64 {
+                media_type: "image/webp".to_string(),
+                data: "UklG".to_string(),
+            }),
+        ])]);
+        let parts = &contents[0]["parts"];
+        assert_eq!(parts[0]["text"], "猜猜这是哪里");
+        assert_eq!(parts[1]["inlineData"]["mimeType"], "image/webp");
+        assert_eq!(parts[1]["inlineData"]["data"], "UklG");
+    }
+}
@@ -0,0 +1,104 @@
+//! Provider backends. Each backend translates our provider-neutral `Message`/`ToolDef`
+//! types into its wire format and decodes its responses back into `StreamEvent`s and
+//! `Message`s, so `LlmClient` can stay protocol-agnostic.
+
+pub mod anthropic;
+pub mod gemini;
+pub mod openai;
+
+use crate::client::LlmError;
+use crate::types::{ImageSource, Message, Model, ProviderKind, StreamEvent, ToolDef};
+
+/// An HTTP request described by a provider, executed by `LlmClient`.
+#[derive(Debug, Clone)]
+pub struct ProviderRequest {
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    /// JSON body. `None` means a GET request.
+    pub body: Option<serde_json::Value>,
+}
+
+impl ProviderRequest {
+    pub fn get(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            headers: Vec::new(),
+            body: None,
+        }
+    }
+
+    pub fn post(url: impl Into<String>, body: serde_json::Value) -> Self {
+        Self {
+            url: url.into(),
+            headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+            body: Some(body),
+        }
+    }
+
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+}
+
+/// Incremental decoder for one streaming response.
+/// Fed the payload of each SSE `data:` field, in order.
+pub trait StreamDecoder: Send {
+    fn decode(&mut self, data: &str) -> Vec<StreamEvent>;
+}
+
+/// A chat backend speaking one wire protocol.
+pub trait LlmProvider: Send + Sync {
+    /// Build a chat request. `stream` selects the SSE variant of the endpoint.
+    fn chat_request(
+        &self,
+        model: &Model,
+        api_key: &str,
+        messages: &[Message],
+        tools: &[ToolDef],
+        stream: bool,
+    ) -> ProviderRequest;
+
+    /// Create a fresh decoder for a streaming response.
+    fn stream_decoder(&self) -> Box<dyn StreamDecoder>;
+
+    /// Parse a non-streaming response body into the assistant message.
+    fn parse_completion(&self, body: &serde_json::Value) -> Result<Message, LlmError>;
+
+    /// Build a request listing the models available at this endpoint.
+    fn models_request(&self, model: &Model, api_key: &str) -> ProviderRequest;
+
+    /// Parse a model-list response into (model_id, owned_by) tuples.
+    fn parse_models(&self, body: &serde_json::Value) -> Vec<(String, String)>;
+}
+
+/// Look up the backend implementing the given wire protocol.
+pub fn provider_for(kind: ProviderKind) -> &'static dyn LlmProvider {
+    match kind {
+        ProviderKind::OpenAi => &openai::OpenAiProvider,
+        ProviderKind::Anthropic => &anthropic::AnthropicProvider,
+        ProviderKind::Gemini => &gemini::GeminiProvider,
+    }
+}
+
+pub(crate) fn endpoint(base_url: &str, path: &str) -> String {
+    format!("{}/{}", base_url.trim_end_matches('/'), path)
+}
+
+/// Parse tool call arguments into a JSON object, tolerating empty or malformed input.
+pub(crate) fn parse_arguments(arguments: &str) -> serde_json::Value {
+    match serde_json::from_str::<serde_json::Value>(arguments) {
+        Ok(v) if v.is_object() => v,
+        _ => serde_json::json!({}),
+    }
+}
+
+/// Text sent in place of an image that was never loaded (a path or blob reference
+/// that reached a provider unresolved), so the model at least knows one was attached.
+pub(crate) fn unresolved_image_text(source: &ImageSource) -> String {
+    match source {
+        ImageSource::Path { path } => format!("[image not loaded: {}]", path.display()),
+        ImageSource::Blob { sha256, .. } => format!("[image not loaded: blob {sha256}]"),
+        ImageSource::Base64 { .. } | ImageSource::Url { .. } => "[image]".to_string(),
+    }
+}
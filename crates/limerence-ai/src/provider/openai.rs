@@ -0,0 +1,525 @@
+use serde::Serialize;
+
+use super::{LlmProvider, ProviderRequest, StreamDecoder, endpoint, unresolved_image_text};
+use crate::client::LlmError;
+use crate::error::{ApiError, ApiErrorKind};
+use crate::types::*;
+
+/// OpenAI-compatible `/chat/completions` backend.
+pub struct OpenAiProvider;
+
+#[derive(Debug, Serialize)]
+struct ChatRequest {
+    model: String,
+    messages: Vec<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tools: Option<Vec<serde_json::Value>>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<serde_json::Value>,
+    /// Field names already match the OpenAI request body.
+    #[serde(flatten)]
+    sampling: SamplingParams,
+}
+
+impl LlmProvider for OpenAiProvider {
+    fn chat_request(
+        &self,
+        model: &Model,
+        api_key: &str,
+        messages: &[Message],
+        tools: &[ToolDef],
+        stream: bool,
+    ) -> ProviderRequest {
+        let mut sampling = model.sampling.clone();
+        if tools.is_empty() {
+            // The API rejects `tool_choice` without `tools`.
+            sampling.tool_choice = None;
+        }
+        let body = ChatRequest {
+            model: model.id.clone(),
+            messages: messages.iter().map(message_to_openai).collect(),
+            tools: if tools.is_empty() {
+                None
+            } else {
+                Some(tools.iter().map(tool_to_openai).collect())
+            },
+            stream,
+            stream_options: (stream && model.stream_usage())
+                .then(|| serde_json::json!({ "include_usage": true })),
+            sampling,
+        };
+
+        ProviderRequest::post(
+            endpoint(&model.base_url, "chat/completions"),
+            serde_json::to_value(body).unwrap_or_default(),
+        )
+        .header("Authorization", format!("Bearer {api_key}"))
+    }
+
+    fn stream_decoder(&self) -> Box<dyn StreamDecoder> {
+        Box::new(OpenAiStreamDecoder)
+    }
+
+    fn parse_completion(&self, body: &serde_json::Value) -> Result<Message, LlmError> {
+        if body.get("error").is_some() {
+            return Err(LlmError::Api(ApiError::from_json(None, body.clone())));
+        }
+
+        let choice = &body["choices"][0]["message"];
+        let content = choice["content"].as_str().unwrap_or_default().to_string();
+        let reasoning = reasoning_field(choice).unwrap_or_default().to_string();
+
+        let tool_calls: Vec<ToolCall> = choice
+            .get("tool_calls")
+            .and_then(|t| t.as_array())
+            .map(|tcs| {
+                tcs.iter()
+                    .filter_map(|tc| {
+                        Some(ToolCall {
+                            id: tc.get("id")?.as_str()?.to_string(),
+                            function: FunctionCall {
+                                name: tc.get("function")?.get("name")?.as_str()?.to_string(),
+                                arguments: tc
+                                    .get("function")?
+                                    .get("arguments")?
+                                    .as_str()?
+                                    .to_string(),
+                            },
+                        })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let msg = if tool_calls.is_empty() {
+            Message::assistant(content)
+        } else {
+            Message::assistant_with_tools(content, tool_calls)
+        };
+        Ok(msg.with_reasoning(reasoning))
+    }
+
+    fn models_request(&self, model: &Model, api_key: &str) -> ProviderRequest {
+        ProviderRequest::get(endpoint(&model.base_url, "models"))
+            .header("Authorization", format!("Bearer {api_key}"))
+    }
+
+    fn parse_models(&self, body: &serde_json::Value) -> Vec<(String, String)> {
+        body["data"]
+            .as_array()
+            .map(|arr| {
+                arr.iter()
+                    .filter_map(|m| {
+                        let id = m["id"].as_str()?.to_string();
+                        let owned_by = m["owned_by"].as_str().unwrap_or("").to_string();
+                        Some((id, owned_by))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+struct OpenAiStreamDecoder;
+
+impl StreamDecoder for OpenAiStreamDecoder {
+    fn decode(&mut self, data: &str) -> Vec<StreamEvent> {
+        parse_chunk(data)
+    }
+}
+
+/// Parse the JSON payload of one OpenAI streaming chunk into every event it carries.
+pub(crate) fn parse_chunk(data: &str) -> Vec<StreamEvent> {
+    if data == "[DONE]" {
+        return vec![StreamEvent::Done];
+    }
+
+    let Ok(json) = serde_json::from_str::<serde_json::Value>(data) else {
+        return vec![];
+    };
+
+    // Check for error
+    if json.get("error").is_some() {
+        return vec![StreamEvent::Error(ApiError::from_json(None, json))];
+    }
+
+    let mut events = Vec::new();
+    let choice = &json["choices"][0];
+    let delta = &choice["delta"];
+
+    if let Some(reasoning) = reasoning_field(delta)
+        && !reasoning.is_empty()
+    {
+        events.push(StreamEvent::ReasoningDelta(reasoning.to_string()));
+    }
+
+    // Text content delta
+    if let Some(content) = delta["content"].as_str()
+        && !content.is_empty()
+    {
+        events.push(StreamEvent::TextDelta(content.to_string()));
+    }
+
+    // Tool call deltas; one chunk may start several calls, and a start may
+    // already carry the first piece of the arguments.
+    for tc in delta["tool_calls"].as_array().into_iter().flatten() {
+        let index = tc["index"].as_u64().unwrap_or(0) as usize;
+        let func = &tc["function"];
+
+        if let (Some(id), Some(name)) = (tc["id"].as_str(), func["name"].as_str()) {
+            events.push(StreamEvent::ToolCallStart {
+                index,
+                id: id.to_string(),
+                name: name.to_string(),
+            });
+        }
+
+        if let Some(args) = func["arguments"].as_str()
+            && !args.is_empty()
+        {
+            events.push(StreamEvent::ToolCallDelta {
+                index,
+                arguments: args.to_string(),
+            });
+        }
+    }
+
+    // With include_usage, the final chunk has empty choices and a usage object.
+    if let Some(usage) = json.get("usage").filter(|u| u.is_object()) {
+        events.push(StreamEvent::Usage(parse_usage(usage)));
+    }
+
+    // finish_reason = "stop" or "tool_calls"
+    match choice["finish_reason"].as_str() {
+        Some("stop" | "tool_calls") => events.push(StreamEvent::Done),
+        Some("content_filter") => events.push(StreamEvent::Error(ApiError::new(
+            ApiErrorKind::ContentFiltered,
+            "response blocked by content filter",
+        ))),
+        _ => {}
+    }
+
+    events
+}
+
+/// DeepSeek and most compatible servers use `reasoning_content`; OpenRouter uses `reasoning`.
+fn reasoning_field(message: &serde_json::Value) -> Option<&str> {
+    message["reasoning_content"]
+        .as_str()
+        .or_else(|| message["reasoning"].as_str())
+}
+
+fn parse_usage(usage: &serde_json::Value) -> Usage {
+    Usage {
+        prompt_tokens: usage["prompt_tokens"].as_u64().unwrap_or(0),
+        completion_tokens: usage["completion_tokens"].as_u64().unwrap_or(0),
+        // OpenAI reports prompt_tokens_details.cached_tokens; DeepSeek prompt_cache_hit_tokens.
+        cached_tokens: usage["prompt_tokens_details"]["cached_tokens"]
+            .as_u64()
+            .or_else(|| usage["prompt_cache_hit_tokens"].as_u64())
+            .unwrap_or(0),
+    }
+}
+
+/// Serialize a Message to OpenAI format
+pub(crate) fn message_to_openai(msg: &Message) -> serde_json::Value {
+    match msg {
+        Message::System { content } => serde_json::json!({
+            "role": "system",
+            "content": content,
+        }),
+        Message::User { content } => serde_json::json!({
+            "role": "user",
+            "content": user_content_to_openai(content),
+        }),
+        Message::Assistant {
+            content,
+            tool_calls,
+            ..
+        } => {
+            let mut obj = serde_json::json!({
+                "role": "assistant",
+                "content": content,
+            });
+            if !tool_calls.is_empty() {
+                let tc: Vec<serde_json::Value> = tool_calls
+                    .iter()
+                    .map(|tc| {
+                        serde_json::json!({
+                            "id": tc.id,
+                            "type": "function",
+                            "function": {
+                                "name": tc.function.name,
+                                "arguments": tc.function.arguments,
+                            }
+                        })
+                    })
+                    .collect();
+                obj["tool_calls"] = serde_json::Value::Array(tc);
+            }
+            obj
+        }
+        Message::ToolResult {
+            tool_call_id,
+            content,
+        } => serde_json::json!({
+            "role": "tool",
+            "tool_call_id": tool_call_id,
+            "content": content,
+        }),
+    }
+}
+
+/// Text-only messages stay a plain string; anything with images becomes a part list.
+fn user_content_to_openai(parts: &[ContentPart]) -> serde_json::Value {
+    if let [ContentPart::Text { text }] = parts {
+        return serde_json::json!(text);
+    }
+    parts
+        .iter()
+        .map(|part| match part {
+            ContentPart::Text { text } => serde_json::json!({ "type": "text", "text": text }),
+            ContentPart::Image { source } => match source {
+                ImageSource::Base64 { media_type, data } => serde_json::json!({
+                    "type": "image_url",
+                    "image_url": { "url": format!("data:{media_type};base64,{data}") },
+                }),
+                ImageSource::Url { url } => serde_json::json!({
+                    "type": "image_url",
+                    "image_url": { "url": url },
+                }),
+                other => {
+                    serde_json::json!({ "type": "text", "text": unresolved_image_text(other) })
+                }
+            },
+        })
+        .collect()
+}
+
+pub(crate) fn tool_to_openai(tool: &ToolDef) -> serde_json::Value {
+    serde_json::json!({
+        "type": "function",
+        "function": {
+            "name": tool.name,
+            "description": tool.description,
+            "parameters": tool.parameters,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model() -> Model {
+        Model {
+            id: "deepseek-chat".to_string(),
+            base_url: "https://api.deepseek.com/v1/".to_string(),
+            api_key_env: "DEEPSEEK_API_KEY".to_string(),
+            provider: ProviderKind::OpenAi,
+            sampling: SamplingParams::default(),
+            stream_usage: None,
+        }
+    }
+
+    #[test]
+    fn chat_request_uses_bearer_auth_and_chat_completions() {
+        let req =
+            OpenAiProvider.chat_request(&model(), "sk-test", &[Message::user("你好")], &[], true);
+
+        assert_eq!(req.url, "https://api.deepseek.com/v1/chat/completions");
+        assert!(
+            req.headers
+                .contains(&("Authorization".to_string(), "Bearer sk-test".to_string()))
+        );
+        let body = req.body.expect("post body");
+        assert_eq!(body["stream"], true);
+        assert_eq!(body["messages"][0]["content"], "你好");
+        assert!(body.get("tools").is_none());
+        assert!(body.get("stream_options").is_none());
+        assert!(body.get("temperature").is_none());
+        assert!(body.get("stop").is_none());
+    }
+
+    #[test]
+    fn stream_usage_is_requested_from_openai_or_when_enabled() {
+        let request = |model: &Model, stream| {
+            OpenAiProvider
+                .chat_request(model, "sk", &[Message::user("hi")], &[], stream)
+                .body
+                .expect("post body")
+        };
+
+        let mut openai = model();
+        openai.base_url = "https://api.openai.com/v1".to_string();
+        assert_eq!(
+            request(&openai, true)["stream_options"]["include_usage"],
+            true
+        );
+        assert!(request(&openai, false).get("stream_options").is_none());
+        openai.stream_usage = Some(false);
+        assert!(request(&openai, true).get("stream_options").is_none());
+
+        let mut compatible = model();
+        compatible.stream_usage = Some(true);
+        assert_eq!(
+            request(&compatible, true)["stream_options"]["include_usage"],
+            true
+        );
+    }
+
+    #[test]
+    fn chat_request_sends_sampling_params() {
+        let mut model = model();
+        model.sampling = SamplingParams {
+            temperature: Some(0.5),
+            max_tokens: Some(800),
+            frequency_penalty: Some(0.25),
+            stop: vec!["\n用户：".to_string()],
+            seed: Some(42),
+            response_format: Some(serde_json::json!({ "type": "json_object" })),
+            ..SamplingParams::default()
+        };
+        let body = OpenAiProvider
+            .chat_request(&model, "sk-test", &[Message::user("你好")], &[], false)
+            .body
+            .expect("post body");
+
+        assert_eq!(body["temperature"], 0.5);
+        assert_eq!(body["max_tokens"], 800);
+        assert_eq!(body["frequency_penalty"], 0.25);
+        assert_eq!(body["stop"][0], "\n用户：");
+        assert_eq!(body["seed"], 42);
+        assert_eq!(body["response_format"]["type"], "json_object");
+        assert!(body.get("top_p").is_none());
+    }
+
+    #[test]
+    fn tool_choice_is_sent_only_with_tools() {
+        let mut model = model();
+        model.sampling.tool_choice = Some(ToolChoice::None);
+        let messages = [Message::user("你好")];
+        let tools = [ToolDef {
+            name: "note_read".to_string(),
+            description: "读取笔记".to_string(),
+            parameters: serde_json::json!({"type": "object", "properties": {}}),
+        }];
+
+        let body = OpenAiProvider
+            .chat_request(&model, "sk-test", &messages, &tools, true)
+            .body
+            .expect("post body");
+        assert_eq!(body["tool_choice"], "none");
+
+        let body = OpenAiProvider
+            .chat_request(&model, "sk-test", &messages, &[], true)
+            .body
+            .expect("post body");
+        assert!(body.get("tool_choice").is_none());
+    }
+
+    #[test]
+    fn parse_chunk_reads_final_usage_chunk() {
+        let events = parse_chunk(
+            r#"{"choices":[],"usage":{"prompt_tokens":120,"completion_tokens":30,"prompt_tokens_details":{"cached_tokens":100}}}"#,
+        );
+        match events.as_slice() {
+            [StreamEvent::Usage(usage)] => {
+                assert_eq!(usage.prompt_tokens, 120);
+                assert_eq!(usage.completion_tokens, 30);
+                assert_eq!(usage.cached_tokens, 100);
+            }
+            other => panic!("unexpected event: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn parse_chunk_reads_reasoning_content() {
+        let events = parse_chunk(
+            r#"{"choices":[{"index":0,"delta":{"role":"assistant","content":null,"reasoning_content":"用户想"}}]}"#,
+        );
+        assert!(matches!(&events[..], [StreamEvent::ReasoningDelta(text)] if text == "用户想"));
+    }
+
+    #[test]
+    fn parse_chunk_emits_every_tool_call_in_a_delta() {
+        let events = parse_chunk(
+            r#"{"choices":[{"index":0,"delta":{"tool_calls":[
+                {"index":0,"id":"call_a","type":"function","function":{"name":"memory_search","arguments":"{\"query\":\"猫\"}"}},
+                {"index":1,"id":"call_b","type":"function","function":{"name":"note_read","arguments":""}}
+            ]},"finish_reason":"tool_calls"}]}"#,
+        );
+        let summary: Vec<String> = events
+            .iter()
+            .map(|e| match e {
+                StreamEvent::ToolCallStart { index, name, .. } => format!("start {index} {name}"),
+                StreamEvent::ToolCallDelta { index, arguments } => {
+                    format!("args {index} {arguments}")
+                }
+                StreamEvent::Done => "done".to_string(),
+                other => format!("{other:?}"),
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                "start 0 memory_search",
+                "args 0 {\"query\":\"猫\"}",
+                "start 1 note_read",
+                "done",
+            ]
+        );
+    }
+
+    #[test]
+    fn reasoning_is_not_sent_back() {
+        let msg = Message::assistant("你好").with_reasoning("先打招呼");
+        let json = message_to_openai(&msg);
+        assert_eq!(json["content"], "你好");
+        assert!(json.get("reasoning_content").is_none());
+        assert!(!json.to_string().contains("先打招呼"));
+    }
+
+    #[test]
+    fn user_images_become_image_url_parts() {
+        let text_only = message_to_openai(&Message::user("你好"));
+        assert_eq!(text_only["content"], "你好");
+
+        let msg = Message::user_parts(vec![
+            ContentPart::text("这是我家的猫"),
+            ContentPart::image(ImageSource::Base64 {
+                media_type: "image/png".to_string(),
+                data: "AAAA".to_string(),
+            }),
+            ContentPart::image(ImageSource::Url {
+                url: "https://example.com/cat.jpg".to_string(),
+            }),
+        ]);
+        let json = message_to_openai(&msg);
+        let parts = json["content"].as_array().expect("content parts");
+        assert_eq!(parts[0]["text"], "这是我家的猫");
+        assert_eq!(parts[1]["image_url"]["url"], "data:image/png;base64,AAAA");
+        assert_eq!(parts[2]["image_url"]["url"], "https://example.com/cat.jpg");
+    }
+
+    #[test]
+    fn parse_completion_extracts_tool_calls() {
+        let body = serde_json::json!({
+            "choices": [{"message": {
+                "content": null,
+                "tool_calls": [{"id": "call_1", "type": "function",
+                    "function": {"name": "memory_search", "arguments": "{\"query\":\"咖啡\"}"}}]
+            }}]
+        });
+
+        let msg = OpenAiProvider.parse_completion(&body).expect("message");
+        match msg {
+            Message::Assistant { tool_calls, .. } => {
+                assert_eq!(tool_calls.len(), 1);
+                assert_eq!(tool_calls[0].function.name, "memory_search");
+            }
+            other => panic!("unexpected message: {other:?}"),
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
+
+/// Retry policy for transient API failures (429, 5xx, dropped connections).
+/// Only the request itself is retried; a stream that fails midway is not replayed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt. 0 disables retrying.
+    pub max_retries: u32,
+    /// Backoff before the first retry; doubled on each subsequent attempt.
+    pub initial_backoff_ms: u64,
+    /// Upper bound for the computed backoff (before jitter).
+    pub max_backoff_ms: u64,
+    /// Fraction of the backoff randomized away, in `0.0..=1.0`.
+    pub jitter: f64,
+    /// Honor `Retry-After` response headers instead of the computed backoff.
+    /// A server asking for more than `max_backoff_ms` is not retried.
+    pub respect_retry_after: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 30_000,
+            jitter: 0.2,
+            respect_retry_after: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that never retries.
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            ..Self::default()
+        }
+    }
+
+    /// Exponential backoff with jitter for the given retry (1-based).
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let exp = retry.saturating_sub(1).min(31);
+        let base = self
+            .initial_backoff_ms
+            .saturating_mul(1u64 << exp)
+            .min(self.max_backoff_ms);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let scale = 1.0 - jitter * fastrand::f64();
+        Duration::from_millis((base as f64 * scale) as u64)
+    }
+
+    /// Delay before the given retry, preferring the server's `Retry-After` if
+    /// allowed. `None` when that is longer than `max_backoff_ms`: waiting it
+    /// out would stall the turn, so the request is better given up.
+    pub fn delay_for(&self, retry: u32, retry_after: Option<Duration>) -> Option<Duration> {
+        match retry_after {
+            Some(d) if self.respect_retry_after => {
+                (d <= Duration::from_millis(self.max_backoff_ms)).then_some(d)
+            }
+            _ => Some(self.backoff(retry)),
+        }
+    }
+}
+
+/// Status codes worth retrying: rate limits, timeouts and server errors.
+pub fn is_retryable_status(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        || status == reqwest::StatusCode::REQUEST_TIMEOUT
+        || status.is_server_error()
+}
+
+/// Transport errors worth retrying: connect failures, resets and timeouts.
+pub fn is_retryable_error(err: &reqwest::Error) -> bool {
+    err.is_connect() || err.is_timeout() || err.is_request()
+}
+
+/// Parse a `Retry-After` header value (delta-seconds or HTTP-date).
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    if let Ok(secs) = value.parse::<f64>() {
+        // Negative, NaN or too large to be a `Duration`: ignored.
+        return Duration::try_from_secs_f64(secs).ok();
+    }
+    let at = httpdate::parse_http_date(value).ok()?;
+    Some(at.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_and_caps_without_jitter() {
+        let policy = RetryPolicy {
+            initial_backoff_ms: 100,
+            max_backoff_ms: 350,
+            jitter: 0.0,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(350));
+    }
+
+    #[test]
+    fn backoff_jitter_stays_within_bounds() {
+        let policy = RetryPolicy {
+            initial_backoff_ms: 1000,
+            jitter: 0.5,
+            ..RetryPolicy::default()
+        };
+        for _ in 0..100 {
+            let d = policy.backoff(1);
+            assert!(d >= Duration::from_millis(500) && d <= Duration::from_millis(1000));
+        }
+    }
+
+    #[test]
+    fn retry_after_accepts_seconds_and_http_dates() {
+        assert_eq!(parse_retry_after("7"), Some(Duration::from_secs(7)));
+        assert_eq!(parse_retry_after("1.5"), Some(Duration::from_millis(1500)));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon"), None);
+        assert_eq!(parse_retry_after("1e20"), None);
+        assert_eq!(parse_retry_after("18446744073709551616"), None);
+        assert_eq!(parse_retry_after("-1"), None);
+        assert_eq!(parse_retry_after("NaN"), None);
+    }
+
+    #[test]
+    fn retry_after_overrides_backoff_only_when_respected() {
+        let mut policy = RetryPolicy {
+            jitter: 0.0,
+            ..RetryPolicy::default()
+        };
+        let hint = Some(Duration::from_secs(3));
+        assert_eq!(policy.delay_for(1, hint), Some(Duration::from_secs(3)));
+
+        policy.respect_retry_after = false;
+        assert_eq!(policy.delay_for(1, hint), Some(Duration::from_millis(1000)));
+    }
+
+    #[test]
+    fn retry_after_past_the_cap_gives_up() {
+        let mut policy = RetryPolicy {
+            max_backoff_ms: 30_000,
+            jitter: 0.0,
+            ..RetryPolicy::default()
+        };
+        let hour = Some(Duration::from_secs(3600));
+        assert_eq!(policy.delay_for(1, hour), None);
+        assert_eq!(
+            policy.delay_for(1, Some(Duration::from_secs(30))),
+            Some(Duration::from_secs(30))
+        );
+
+        policy.respect_retry_after = false;
+        assert_eq!(policy.delay_for(1, hour), Some(Duration::from_millis(1000)));
+    }
+}
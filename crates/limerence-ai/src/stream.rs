@@ -1,103 +1,274 @@
-use crate::types::StreamEvent;
-use futures::Stream;
-use std::pin::Pin;
-use std::task::{Context, Poll};
-
-/// Parses SSE lines from an OpenAI-compatible streaming response into StreamEvents.
-pub struct SseStream {
-    lines: Vec<String>,
-    pos: usize,
+//! Incremental Server-Sent Events decoder.
+//!
+//! Implements the event-stream parsing rules of the HTML spec: `event`, `data`,
+//! `id` and `retry` fields, multi-line `data`, `:` comments and CRLF/LF/CR line
+//! endings. Input is raw bytes in arbitrary chunks; lines are only decoded once
+//! complete, so multi-byte UTF-8 characters split across chunks survive intact.
+
+/// One dispatched event.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SseEvent {
+    /// The `event` field; `None` means the default `message` type.
+    pub event: Option<String>,
+    /// All `data` lines of the event, joined by `\n`.
+    pub data: String,
+    /// Last event id seen on the stream (persists across events, per spec).
+    pub id: Option<String>,
+    /// Reconnection time requested by the server, in milliseconds.
+    pub retry: Option<u64>,
 }
 
-impl SseStream {
-    pub fn new(lines: Vec<String>) -> Self {
-        Self { lines, pos: 0 }
-    }
+#[derive(Debug, Default)]
+pub struct SseDecoder {
+    buf: Vec<u8>,
+    /// The previous chunk ended in `\r`; a leading `\n` in the next one belongs to it.
+    after_cr: bool,
+    started: bool,
+    event: Option<String>,
+    data: String,
+    has_data: bool,
+    last_id: Option<String>,
+    retry: Option<u64>,
 }
 
-impl Stream for SseStream {
-    type Item = StreamEvent;
+impl SseDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed the next chunk of the body; returns the events it completed.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        if chunk.is_empty() {
+            return Vec::new();
+        }
+        let mut chunk = chunk;
+        if self.after_cr && chunk.first() == Some(&b'\n') {
+            chunk = &chunk[1..];
+        }
+        self.after_cr = false;
+        self.buf.extend_from_slice(chunk);
+
+        let mut events = Vec::new();
+        let mut start = 0;
+        let mut i = 0;
+        while i < self.buf.len() {
+            match self.buf[i] {
+                b'\n' => {
+                    self.line_at(start, i, &mut events);
+                    start = i + 1;
+                }
+                b'\r' => {
+                    self.line_at(start, i, &mut events);
+                    if i + 1 == self.buf.len() {
+                        self.after_cr = true;
+                    } else if self.buf[i + 1] == b'\n' {
+                        i += 1;
+                    }
+                    start = i + 1;
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+        self.buf.drain(..start);
+        events
+    }
+
+    /// End of stream. An unterminated final line or event is still delivered,
+    /// since some servers close the connection without the trailing blank line.
+    pub fn finish(&mut self) -> Option<SseEvent> {
+        if !self.buf.is_empty() {
+            let line = std::mem::take(&mut self.buf);
+            self.process_line(&line, &mut Vec::new());
+        }
+        self.dispatch()
+    }
+
+    fn line_at(&mut self, start: usize, end: usize, events: &mut Vec<SseEvent>) {
+        let line = self.buf[start..end].to_vec();
+        self.process_line(&line, events);
+    }
+
+    fn process_line(&mut self, line: &[u8], events: &mut Vec<SseEvent>) {
+        let mut line = String::from_utf8_lossy(line);
+        if !self.started {
+            self.started = true;
+            if let Some(rest) = line.strip_prefix('\u{feff}') {
+                line = rest.to_string().into();
+            }
+        }
 
-    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        while self.pos < self.lines.len() {
-            let pos = self.pos;
-            self.pos += 1;
-            let line = &self.lines[pos];
+        if line.is_empty() {
+            events.extend(self.dispatch());
+            return;
+        }
+        if line.starts_with(':') {
+            return;
+        }
 
-            if let Some(event) = parse_sse_line(line) {
-                return Poll::Ready(Some(event));
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line.as_ref(), ""),
+        };
+        match field {
+            "event" => self.event = Some(value.to_string()),
+            "data" => {
+                self.data.push_str(value);
+                self.data.push('\n');
+                self.has_data = true;
+            }
+            "id" if !value.contains('\0') => self.last_id = Some(value.to_string()),
+            "retry" if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) => {
+                self.retry = value.parse().ok();
             }
+            _ => {}
         }
-        Poll::Ready(None)
+    }
+
+    fn dispatch(&mut self) -> Option<SseEvent> {
+        let event = self.event.take();
+        if !self.has_data {
+            self.data.clear();
+            return None;
+        }
+        self.has_data = false;
+        let mut data = std::mem::take(&mut self.data);
+        data.pop();
+        Some(SseEvent {
+            event,
+            data,
+            id: self.last_id.clone(),
+            retry: self.retry,
+        })
     }
 }
 
-/// Parse a single SSE data line into a StreamEvent.
-pub fn parse_sse_line(line: &str) -> Option<StreamEvent> {
-    let data = line.strip_prefix("data: ")?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn decode_all(chunks: &[&[u8]]) -> Vec<SseEvent> {
+        let mut decoder = SseDecoder::new();
+        let mut events: Vec<SseEvent> = chunks.iter().flat_map(|c| decoder.push(c)).collect();
+        events.extend(decoder.finish());
+        events
+    }
 
-    if data == "[DONE]" {
-        return Some(StreamEvent::Done);
+    #[test]
+    fn parses_fields_comments_and_multiline_data() {
+        let body = b"\xEF\xBB\xBF: keep-alive\n\
+            event: message_start\n\
+            id: 7\n\
+            retry: 3000\n\
+            data: {\"a\":\n\
+            data:1}\n\
+            \n\
+            data\n\
+            \n\
+            event: ignored-without-data\n\
+            \n";
+        let events = decode_all(&[body]);
+        assert_eq!(
+            events,
+            vec![
+                SseEvent {
+                    event: Some("message_start".to_string()),
+                    data: "{\"a\":\n1}".to_string(),
+                    id: Some("7".to_string()),
+                    retry: Some(3000),
+                },
+                SseEvent {
+                    event: None,
+                    data: String::new(),
+                    id: Some("7".to_string()),
+                    retry: Some(3000),
+                },
+            ]
+        );
     }
 
-    let json: serde_json::Value = serde_json::from_str(data).ok()?;
+    #[test]
+    fn keeps_utf8_split_across_chunks() {
+        let body = "data: 你好，世界\n\n".as_bytes();
+        // Split inside the three-byte encoding of 你.
+        let events = decode_all(&[&body[..7], &body[7..]]);
+        assert_eq!(events[0].data, "你好，世界");
+    }
 
-    // Check for error
-    if let Some(err) = json.get("error") {
-        return Some(StreamEvent::Error(err.to_string()));
+    #[test]
+    fn crlf_split_between_chunks_is_one_line_ending() {
+        let events = decode_all(&[b"data: a\r", b"\n\r", b"\ndata: b\r\r"]);
+        let data: Vec<_> = events.iter().map(|e| e.data.as_str()).collect();
+        assert_eq!(data, vec!["a", "b"]);
     }
 
-    let choice = json.get("choices")?.get(0)?;
-    let delta = choice.get("delta")?;
+    #[test]
+    fn delivers_unterminated_final_event() {
+        let events = decode_all(&[b"data: [DONE]"]);
+        assert_eq!(events[0].data, "[DONE]");
+    }
 
-    // Text content delta
-    if let Some(content) = delta.get("content").and_then(|c| c.as_str()) {
-        if !content.is_empty() {
-            return Some(StreamEvent::TextDelta(content.to_string()));
-        }
+    fn data_line() -> impl Strategy<Value = String> {
+        // No line breaks inside a line; mixes ASCII, CJK, emoji and JSON punctuation.
+        proptest::collection::vec(
+            prop_oneof![
+                Just("a"),
+                Just(" "),
+                Just(":"),
+                Just("{\"k\":\"v\"}"),
+                Just("你"),
+                Just("好"),
+                Just("。"),
+                Just("😊"),
+            ],
+            0..12,
+        )
+        .prop_map(|parts| parts.concat())
     }
 
-    // Tool call deltas
-    if let Some(tool_calls) = delta.get("tool_calls").and_then(|t| t.as_array()) {
-        for tc in tool_calls {
-            let index = tc.get("index").and_then(|i| i.as_u64()).unwrap_or(0) as usize;
-
-            // Tool call start: has id and function.name
-            if let (Some(id), Some(func)) = (
-                tc.get("id").and_then(|i| i.as_str()),
-                tc.get("function"),
-            ) {
-                if let Some(name) = func.get("name").and_then(|n| n.as_str()) {
-                    return Some(StreamEvent::ToolCallStart {
-                        index,
-                        id: id.to_string(),
-                        name: name.to_string(),
-                    });
+    proptest! {
+        #[test]
+        fn random_chunking_yields_the_same_events(
+            events in proptest::collection::vec(
+                (proptest::option::of("[a-z_]{1,12}"), proptest::collection::vec(data_line(), 1..4)),
+                1..6,
+            ),
+            newline in prop_oneof![Just("\n"), Just("\r\n"), Just("\r")],
+            cuts in proptest::collection::vec(any::<proptest::sample::Index>(), 0..8),
+        ) {
+            let mut body = String::new();
+            let mut expected = Vec::new();
+            for (name, lines) in &events {
+                body.push_str(": comment");
+                body.push_str(newline);
+                if let Some(name) = name {
+                    body.push_str(&format!("event: {name}{newline}"));
+                }
+                for line in lines {
+                    body.push_str(&format!("data: {line}{newline}"));
                 }
+                body.push_str(newline);
+                expected.push(SseEvent {
+                    event: name.clone(),
+                    data: lines.join("\n"),
+                    id: None,
+                    retry: None,
+                });
             }
 
-            // Tool call delta: has function.arguments chunk
-            if let Some(args) = tc
-                .get("function")
-                .and_then(|f| f.get("arguments"))
-                .and_then(|a| a.as_str())
-            {
-                if !args.is_empty() {
-                    return Some(StreamEvent::ToolCallDelta {
-                        index,
-                        arguments: args.to_string(),
-                    });
-                }
+            let bytes = body.as_bytes();
+            let mut positions: Vec<usize> = cuts.iter().map(|c| c.index(bytes.len() + 1)).collect();
+            positions.sort_unstable();
+            let mut chunks = Vec::new();
+            let mut prev = 0;
+            for p in positions {
+                chunks.push(&bytes[prev..p]);
+                prev = p;
             }
-        }
-    }
+            chunks.push(&bytes[prev..]);
 
-    // finish_reason = "stop" or "tool_calls"
-    if let Some(reason) = choice.get("finish_reason").and_then(|r| r.as_str()) {
-        if reason == "stop" || reason == "tool_calls" {
-            return Some(StreamEvent::Done);
+            prop_assert_eq!(decode_all(&chunks), expected);
         }
     }
-
-    None
 }
@@ -0,0 +1,197 @@
+//! Splits `<think>`-style reasoning out of streamed text.
+//!
+//! Port of pi-web's `think-tag-parser.ts`: models such as DeepSeek and QwQ
+//! inline their reasoning as `<think>…</think>` (also `<thinking>`, `<thought>`,
+//! `<antthinking>`), and some mark the answer with `<final>`. Tags may be split
+//! across stream chunks, so a trailing partial tag is held back until the next push.
+
+const THINK_TAGS: &[&str] = &["think", "thinking", "thought", "antthinking"];
+const FINAL_TAG: &str = "final";
+/// A held-back `<` longer than this cannot be the start of a tag we know.
+const MAX_PARTIAL_TAG: usize = 30;
+
+/// Visible text and reasoning produced by one push.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ThinkSplit {
+    pub content: String,
+    pub reasoning: String,
+}
+
+impl ThinkSplit {
+    pub fn is_empty(&self) -> bool {
+        self.content.is_empty() && self.reasoning.is_empty()
+    }
+}
+
+/// Stateful think-tag tracker for one streamed message.
+#[derive(Debug, Default)]
+pub struct ThinkTagParser {
+    in_think: bool,
+    partial: String,
+}
+
+impl ThinkTagParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the stream is currently inside a think block.
+    pub fn is_thinking(&self) -> bool {
+        self.in_think
+    }
+
+    pub fn push(&mut self, delta: &str) -> ThinkSplit {
+        let text = std::mem::take(&mut self.partial) + delta;
+        let mut out = ThinkSplit::default();
+        let mut rest = text.as_str();
+
+        while let Some(lt) = rest.find('<') {
+            self.route(&rest[..lt], &mut out);
+            let candidate = &rest[lt..];
+            match candidate.find('>') {
+                Some(gt) => match classify_tag(&candidate[1..gt]) {
+                    Some(tag) => {
+                        self.apply(tag);
+                        rest = &candidate[gt + 1..];
+                    }
+                    None => {
+                        self.route("<", &mut out);
+                        rest = &candidate[1..];
+                    }
+                },
+                None if could_be_tag(candidate) => {
+                    self.partial = candidate.to_string();
+                    return out;
+                }
+                None => {
+                    self.route("<", &mut out);
+                    rest = &candidate[1..];
+                }
+            }
+        }
+        self.route(rest, &mut out);
+        out
+    }
+
+    /// Release a held-back partial tag as plain text.
+    pub fn flush(&mut self) -> ThinkSplit {
+        let mut out = ThinkSplit::default();
+        let partial = std::mem::take(&mut self.partial);
+        self.route(&partial, &mut out);
+        out
+    }
+
+    fn route(&self, text: &str, out: &mut ThinkSplit) {
+        if self.in_think {
+            out.reasoning.push_str(text);
+        } else {
+            out.content.push_str(text);
+        }
+    }
+
+    fn apply(&mut self, tag: Tag) {
+        match tag {
+            Tag::ThinkOpen => self.in_think = true,
+            Tag::ThinkClose | Tag::FinalOpen => self.in_think = false,
+            Tag::FinalClose => {}
+        }
+    }
+}
+
+/// Split a complete message into visible text and reasoning, both trimmed.
+pub fn split_think_tags(text: &str) -> ThinkSplit {
+    let mut parser = ThinkTagParser::new();
+    let mut split = parser.push(text);
+    let rest = parser.flush();
+    split.content.push_str(&rest.content);
+    split.reasoning.push_str(&rest.reasoning);
+    ThinkSplit {
+        content: split.content.trim().to_string(),
+        reasoning: split.reasoning.trim().to_string(),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Tag {
+    ThinkOpen,
+    ThinkClose,
+    FinalOpen,
+    FinalClose,
+}
+
+/// Recognize the inside of `<…>`; whitespace around the name is allowed.
+fn classify_tag(inner: &str) -> Option<Tag> {
+    let inner = inner.trim();
+    let (closing, name) = match inner.strip_prefix('/') {
+        Some(name) => (true, name.trim()),
+        None => (false, inner),
+    };
+    let name = name.to_ascii_lowercase();
+    if THINK_TAGS.contains(&name.as_str()) {
+        Some(if closing {
+            Tag::ThinkClose
+        } else {
+            Tag::ThinkOpen
+        })
+    } else if name == FINAL_TAG {
+        Some(if closing {
+            Tag::FinalClose
+        } else {
+            Tag::FinalOpen
+        })
+    } else {
+        None
+    }
+}
+
+/// Whether an unterminated `<…` could still grow into a known tag.
+fn could_be_tag(candidate: &str) -> bool {
+    candidate.len() <= MAX_PARTIAL_TAG
+        && candidate[1..]
+            .chars()
+            .all(|c| c.is_ascii_alphabetic() || c == '/' || c.is_whitespace())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_all(chunks: &[&str]) -> ThinkSplit {
+        let mut parser = ThinkTagParser::new();
+        let mut total = ThinkSplit::default();
+        for chunk in chunks {
+            let split = parser.push(chunk);
+            total.content.push_str(&split.content);
+            total.reasoning.push_str(&split.reasoning);
+        }
+        let rest = parser.flush();
+        total.content.push_str(&rest.content);
+        total.reasoning.push_str(&rest.reasoning);
+        total
+    }
+
+    #[test]
+    fn separates_reasoning_split_across_chunks() {
+        let split = push_all(&["<thi", "nk>用户在打招呼", "，应该</th", "ink>\n\n你好呀"]);
+        assert_eq!(split.reasoning, "用户在打招呼，应该");
+        assert_eq!(split.content, "\n\n你好呀");
+    }
+
+    #[test]
+    fn accepts_tag_variants_and_final_marker() {
+        let split = split_think_tags("< Thinking >想一想</ thinking ><final>答案</final>");
+        assert_eq!(split.reasoning, "想一想");
+        assert_eq!(split.content, "答案");
+
+        let split = split_think_tags("<thought>只有思考<final>结论");
+        assert_eq!(split.reasoning, "只有思考");
+        assert_eq!(split.content, "结论");
+    }
+
+    #[test]
+    fn leaves_other_angle_brackets_alone() {
+        let split = push_all(&["1 < 2 且 <b>加粗</b>", " a <"]);
+        assert_eq!(split.content, "1 < 2 且 <b>加粗</b> a <");
+        assert!(split.reasoning.is_empty());
+    }
+}
@@ -0,0 +1,43 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Network timeouts for LLM requests. A value of 0 disables that timeout.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Timeouts {
+    /// Establishing the TCP/TLS connection.
+    pub connect_ms: u64,
+    /// Waiting for the response headers, and then for the first streamed
+    /// output (text, reasoning or a tool call). Keep-alive comments don't count.
+    pub first_token_ms: u64,
+    /// Silence allowed between chunks once output has started.
+    pub idle_ms: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect_ms: 10_000,
+            first_token_ms: 120_000,
+            idle_ms: 60_000,
+        }
+    }
+}
+
+impl Timeouts {
+    pub fn connect(&self) -> Option<Duration> {
+        non_zero(self.connect_ms)
+    }
+
+    pub fn first_token(&self) -> Option<Duration> {
+        non_zero(self.first_token_ms)
+    }
+
+    pub fn idle(&self) -> Option<Duration> {
+        non_zero(self.idle_ms)
+    }
+}
+
+fn non_zero(ms: u64) -> Option<Duration> {
+    (ms > 0).then(|| Duration::from_millis(ms))
+}
@@ -0,0 +1,447 @@
+//! The HTTP layer under `LlmClient`.
+//!
+//! `HttpTransport` is the real thing. `ScriptedTransport` replays canned
+//! responses in order, so the client and the agent loop can be tested offline,
+//! and `RecordingTransport` wraps another transport to save every exchange as
+//! a JSONL fixture that `ScriptedTransport::from_fixture` can replay later.
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::stream::{self, BoxStream};
+use futures::{Stream, StreamExt};
+use reqwest::StatusCode;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use crate::client::LlmError;
+use crate::provider::ProviderRequest;
+use crate::timeout::Timeouts;
+
+/// Response body, delivered in chunks as they arrive.
+pub type BodyStream = BoxStream<'static, Result<Bytes, LlmError>>;
+
+pub struct TransportResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: BodyStream,
+}
+
+impl TransportResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|v| v.to_str().ok())
+    }
+
+    /// Read the whole body.
+    pub async fn bytes(mut self) -> Result<Vec<u8>, LlmError> {
+        let mut out = Vec::new();
+        while let Some(chunk) = self.body.next().await {
+            out.extend_from_slice(&chunk?);
+        }
+        Ok(out)
+    }
+
+    pub async fn text(self) -> Result<String, LlmError> {
+        Ok(String::from_utf8_lossy(&self.bytes().await?).into_owned())
+    }
+
+    pub async fn json(self) -> Result<serde_json::Value, LlmError> {
+        Ok(serde_json::from_slice(&self.bytes().await?)?)
+    }
+}
+
+/// Executes provider requests. Errors returned here are transport failures;
+/// non-2xx statuses are returned as responses.
+pub trait Transport: Send + Sync {
+    fn send<'a>(
+        &'a self,
+        request: &'a ProviderRequest,
+    ) -> BoxFuture<'a, Result<TransportResponse, LlmError>>;
+}
+
+/// The real transport, backed by reqwest.
+pub struct HttpTransport {
+    http: reqwest::Client,
+}
+
+impl HttpTransport {
+    pub fn new(timeouts: &Timeouts) -> Self {
+        let mut builder = reqwest::Client::builder();
+        if let Some(connect) = timeouts.connect() {
+            builder = builder.connect_timeout(connect);
+        }
+        Self {
+            http: builder.build().unwrap_or_default(),
+        }
+    }
+}
+
+impl Transport for HttpTransport {
+    fn send<'a>(
+        &'a self,
+        request: &'a ProviderRequest,
+    ) -> BoxFuture<'a, Result<TransportResponse, LlmError>> {
+        Box::pin(async move {
+            let mut builder = match &request.body {
+                Some(body) => self.http.post(&request.url).json(body),
+                None => self.http.get(&request.url),
+            };
+            for (name, value) in &request.headers {
+                builder = builder.header(name, value);
+            }
+            let resp = builder.send().await?;
+            Ok(TransportResponse {
+                status: resp.status(),
+                headers: resp.headers().clone(),
+                body: resp
+                    .bytes_stream()
+                    .map(|c| c.map_err(LlmError::from))
+                    .boxed(),
+            })
+        })
+    }
+}
+
+/// One request/response pair of a fixture file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Exchange {
+    pub request: RecordedRequest,
+    pub response: RecordedResponse,
+}
+
+/// A request as seen by the transport. Headers are never kept: they carry API keys.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedRequest {
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<serde_json::Value>,
+}
+
+impl From<&ProviderRequest> for RecordedRequest {
+    fn from(request: &ProviderRequest) -> Self {
+        Self {
+            url: request.url.clone(),
+            body: request.body.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedResponse {
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    pub body: String,
+}
+
+/// Response headers worth keeping in fixtures; the rest is noise or identifying.
+const RECORDED_HEADERS: &[&str] = &["content-type", "retry-after"];
+
+impl RecordedResponse {
+    /// A `200` event stream with one `data:` event per item.
+    pub fn sse<S: AsRef<str>>(events: impl IntoIterator<Item = S>) -> Self {
+        let body = events
+            .into_iter()
+            .map(|data| format!("data: {}\n\n", data.as_ref()))
+            .collect();
+        Self {
+            status: 200,
+            headers: BTreeMap::from([(
+                "content-type".to_string(),
+                "text/event-stream".to_string(),
+            )]),
+            body,
+        }
+    }
+
+    pub fn json(status: u16, body: &serde_json::Value) -> Self {
+        Self {
+            status,
+            headers: BTreeMap::from([("content-type".to_string(), "application/json".to_string())]),
+            body: body.to_string(),
+        }
+    }
+
+    /// Replay as a live response, one chunk per SSE event so streaming code
+    /// sees the same boundaries a server would produce.
+    fn into_transport(self) -> TransportResponse {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                headers.insert(name, value);
+            }
+        }
+        let chunks: Vec<Result<Bytes, LlmError>> = self
+            .body
+            .split_inclusive("\n\n")
+            .map(|chunk| Ok(Bytes::from(chunk.to_string())))
+            .collect();
+        TransportResponse {
+            status: StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+            headers,
+            body: stream::iter(chunks).boxed(),
+        }
+    }
+}
+
+/// Replays scripted responses in order and records the requests it receives.
+#[derive(Default)]
+pub struct ScriptedTransport {
+    state: Mutex<ScriptState>,
+}
+
+#[derive(Default)]
+struct ScriptState {
+    /// `Err` entries fail the request as if the connection dropped.
+    replies: VecDeque<Result<RecordedResponse, String>>,
+    requests: Vec<RecordedRequest>,
+}
+
+impl ScriptedTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load the responses of a fixture written by `RecordingTransport`.
+    pub fn from_fixture(path: &Path) -> std::io::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let mut transport = Self::new();
+        for line in content.lines().filter(|l| !l.trim().is_empty()) {
+            let exchange: Exchange = serde_json::from_str(line)?;
+            transport = transport.respond(exchange.response);
+        }
+        Ok(transport)
+    }
+
+    pub fn respond(self, response: RecordedResponse) -> Self {
+        self.lock().replies.push_back(Ok(response));
+        self
+    }
+
+    /// Queue a `200` event stream (see `RecordedResponse::sse`).
+    pub fn sse<S: AsRef<str>>(self, events: impl IntoIterator<Item = S>) -> Self {
+        self.respond(RecordedResponse::sse(events))
+    }
+
+    pub fn json(self, status: u16, body: serde_json::Value) -> Self {
+        self.respond(RecordedResponse::json(status, &body))
+    }
+
+    /// Queue a transport failure (retried like a dropped connection).
+    pub fn fail(self, message: impl Into<String>) -> Self {
+        self.lock().replies.push_back(Err(message.into()));
+        self
+    }
+
+    /// Requests received so far, in order.
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.lock().requests.clone()
+    }
+
+    /// Scripted replies not consumed yet.
+    pub fn remaining(&self) -> usize {
+        self.lock().replies.len()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ScriptState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Transport for ScriptedTransport {
+    fn send<'a>(
+        &'a self,
+        request: &'a ProviderRequest,
+    ) -> BoxFuture<'a, Result<TransportResponse, LlmError>> {
+        let mut state = self.lock();
+        state.requests.push(request.into());
+        let reply = match state.replies.pop_front() {
+            Some(Ok(response)) => Ok(response.into_transport()),
+            Some(Err(message)) => Err(LlmError::Transport(message)),
+            None => Err(LlmError::Transport(format!(
+                "no scripted response left for {}",
+                request.url
+            ))),
+        };
+        Box::pin(async move { reply })
+    }
+}
+
+/// Passes requests to `inner` and appends each exchange to a JSONL fixture
+/// once its body has been read (or dropped, e.g. on cancel).
+pub struct RecordingTransport {
+    inner: Arc<dyn Transport>,
+    path: PathBuf,
+}
+
+impl RecordingTransport {
+    pub fn new(inner: Arc<dyn Transport>, path: impl Into<PathBuf>) -> Self {
+        Self {
+            inner,
+            path: path.into(),
+        }
+    }
+}
+
+impl Transport for RecordingTransport {
+    fn send<'a>(
+        &'a self,
+        request: &'a ProviderRequest,
+    ) -> BoxFuture<'a, Result<TransportResponse, LlmError>> {
+        Box::pin(async move {
+            let resp = self.inner.send(request).await?;
+            let headers = RECORDED_HEADERS
+                .iter()
+                .filter_map(|&name| Some((name.to_string(), resp.header(name)?.to_string())))
+                .collect();
+            let body = RecordingBody {
+                inner: resp.body,
+                buf: Vec::new(),
+                pending: Some(PendingExchange {
+                    path: self.path.clone(),
+                    request: request.into(),
+                    status: resp.status.as_u16(),
+                    headers,
+                }),
+            };
+            Ok(TransportResponse {
+                status: resp.status,
+                headers: resp.headers,
+                body: body.boxed(),
+            })
+        })
+    }
+}
+
+struct PendingExchange {
+    path: PathBuf,
+    request: RecordedRequest,
+    status: u16,
+    headers: BTreeMap<String, String>,
+}
+
+/// Tees the body into a buffer and writes the exchange when dropped.
+struct RecordingBody {
+    inner: BodyStream,
+    buf: Vec<u8>,
+    pending: Option<PendingExchange>,
+}
+
+impl Stream for RecordingBody {
+    type Item = Result<Bytes, LlmError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = self.inner.poll_next_unpin(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            self.buf.extend_from_slice(chunk);
+        }
+        poll
+    }
+}
+
+impl Drop for RecordingBody {
+    fn drop(&mut self) {
+        let Some(pending) = self.pending.take() else {
+            return;
+        };
+        let exchange = Exchange {
+            request: pending.request,
+            response: RecordedResponse {
+                status: pending.status,
+                headers: pending.headers,
+                body: String::from_utf8_lossy(&self.buf).into_owned(),
+            },
+        };
+        let Ok(line) = serde_json::to_string(&exchange) else {
+            return;
+        };
+        if let Some(parent) = pending.path.parent() {
+            let _ = std::fs::create_dir_all(parent);
+        }
+        if let Ok(mut file) = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&pending.path)
+        {
+            let _ = writeln!(file, "{line}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn scripted_replies_in_order_and_records_requests() {
+        let transport = ScriptedTransport::new()
+            .sse(["{\"a\":1}", "[DONE]"])
+            .fail("connection reset")
+            .json(429, json!({"error": "slow down"}));
+
+        let request = ProviderRequest::post("https://x.test/v1/chat", json!({"n": 1}));
+        let resp = transport.send(&request).await.expect("first reply");
+        assert_eq!(resp.header("content-type"), Some("text/event-stream"));
+        let chunks: Vec<_> = resp.body.map(|c| c.expect("chunk")).collect().await;
+        assert_eq!(chunks, vec!["data: {\"a\":1}\n\n", "data: [DONE]\n\n"]);
+
+        assert!(matches!(
+            transport.send(&request).await,
+            Err(LlmError::Transport(_))
+        ));
+        let resp = transport.send(&request).await.expect("third reply");
+        assert_eq!(resp.status, StatusCode::TOO_MANY_REQUESTS);
+        assert!(transport.send(&request).await.is_err());
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 4);
+        assert_eq!(requests[0].body, Some(json!({"n": 1})));
+        assert_eq!(transport.remaining(), 0);
+    }
+
+    #[tokio::test]
+    async fn recorded_fixture_replays_the_same_exchange() {
+        let path =
+            std::env::temp_dir().join(format!("limerence-fixture-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let live = Arc::new(ScriptedTransport::new().sse(["你好", "[DONE]"]));
+        let recorder = RecordingTransport::new(live, &path);
+
+        let request = ProviderRequest::post("https://x.test/v1/chat", json!({"n": 1}))
+            .header("Authorization", "Bearer sk-secret");
+        let body = recorder
+            .send(&request)
+            .await
+            .expect("recorded send")
+            .text()
+            .await
+            .expect("body");
+
+        let fixture = std::fs::read_to_string(&path).expect("fixture written");
+        assert!(
+            !fixture.contains("sk-secret"),
+            "API keys must not be recorded"
+        );
+
+        let replay = ScriptedTransport::from_fixture(&path).expect("load fixture");
+        let _ = std::fs::remove_file(&path);
+        let replayed = replay
+            .send(&request)
+            .await
+            .expect("replayed send")
+            .text()
+            .await
+            .expect("body");
+        assert_eq!(replayed, body);
+        assert_eq!(replayed, "data: 你好\n\ndata: [DONE]\n\n");
+    }
+}
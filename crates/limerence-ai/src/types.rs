@@ -1,4 +1,6 @@
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::path::PathBuf;
 
 // --- Model ---
 
@@ -7,12 +9,108 @@ pub struct Model {
     pub id: String,
     pub base_url: String,
     pub api_key_env: String,
+    #[serde(default)]
+    pub provider: ProviderKind,
+    #[serde(default)]
+    pub sampling: SamplingParams,
+    /// Ask an OpenAI-compatible endpoint to report usage at the end of a stream
+    /// (`stream_options.include_usage`). Unset means only for api.openai.com.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream_usage: Option<bool>,
+}
+
+/// Wire protocol spoken by a model endpoint.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+    /// OpenAI-compatible `/chat/completions` (OpenAI, DeepSeek, OpenRouter, ...).
+    #[default]
+    #[serde(alias = "openai-compatible")]
+    OpenAi,
+    /// Anthropic Messages API.
+    Anthropic,
+    /// Google Gemini `generateContent` API.
+    Gemini,
 }
 
 impl Model {
     pub fn api_key(&self) -> Option<String> {
         std::env::var(&self.api_key_env).ok()
     }
+
+    /// Whether to send `stream_options.include_usage`. Some compatible servers reject
+    /// the field, so it is only on by default for OpenAI itself.
+    pub fn stream_usage(&self) -> bool {
+        self.stream_usage.unwrap_or_else(|| {
+            reqwest::Url::parse(&self.base_url)
+                .is_ok_and(|url| url.host_str() == Some("api.openai.com"))
+        })
+    }
+}
+
+/// Sampler settings sent with each request. Unset fields use the provider's default;
+/// providers drop the ones their API does not support.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SamplingParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    /// OpenAI-style `response_format`, e.g. `{"type": "json_object"}`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<serde_json::Value>,
+    /// Whether the model may call the offered tools. Ignored when no tools are sent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<ToolChoice>,
+}
+
+/// Tool use policy for one request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolChoice {
+    /// The model decides (the API default).
+    Auto,
+    /// Tools stay declared, since the history may reference them, but must not be called.
+    None,
+}
+
+impl SamplingParams {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// `self` with every field that is set in `overrides` replaced.
+    pub fn merged(&self, overrides: &SamplingParams) -> Self {
+        Self {
+            temperature: overrides.temperature.or(self.temperature),
+            top_p: overrides.top_p.or(self.top_p),
+            max_tokens: overrides.max_tokens.or(self.max_tokens),
+            presence_penalty: overrides.presence_penalty.or(self.presence_penalty),
+            frequency_penalty: overrides.frequency_penalty.or(self.frequency_penalty),
+            stop: if overrides.stop.is_empty() {
+                self.stop.clone()
+            } else {
+                overrides.stop.clone()
+            },
+            seed: overrides.seed.or(self.seed),
+            response_format: overrides
+                .response_format
+                .clone()
+                .or_else(|| self.response_format.clone()),
+            tool_choice: overrides.tool_choice.or(self.tool_choice),
+        }
+    }
 }
 
 // --- Messages ---
@@ -24,10 +122,15 @@ pub enum Message {
         content: String,
     },
     User {
-        content: String,
+        /// Stored as a plain string when the message is text only.
+        #[serde(with = "user_content")]
+        content: Vec<ContentPart>,
     },
     Assistant {
         content: String,
+        /// The model's reasoning, kept in the session but never sent back to the model.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reasoning: Option<String>,
         #[serde(default, skip_serializing_if = "Vec::is_empty")]
         tool_calls: Vec<ToolCall>,
     },
@@ -47,13 +150,18 @@ impl Message {
 
     pub fn user(content: impl Into<String>) -> Self {
         Self::User {
-            content: content.into(),
+            content: vec![ContentPart::text(content)],
         }
     }
 
+    pub fn user_parts(content: Vec<ContentPart>) -> Self {
+        Self::User { content }
+    }
+
     pub fn assistant(content: impl Into<String>) -> Self {
         Self::Assistant {
             content: content.into(),
+            reasoning: None,
             tool_calls: vec![],
         }
     }
@@ -61,10 +169,28 @@ impl Message {
     pub fn assistant_with_tools(content: impl Into<String>, tool_calls: Vec<ToolCall>) -> Self {
         Self::Assistant {
             content: content.into(),
+            reasoning: None,
             tool_calls,
         }
     }
 
+    /// Attach reasoning to an assistant message; empty reasoning is dropped.
+    pub fn with_reasoning(mut self, text: impl Into<String>) -> Self {
+        if let Self::Assistant { reasoning, .. } = &mut self {
+            let text = text.into();
+            *reasoning = (!text.is_empty()).then_some(text);
+        }
+        self
+    }
+
+    /// Reasoning of an assistant message, if any.
+    pub fn reasoning(&self) -> Option<&str> {
+        match self {
+            Self::Assistant { reasoning, .. } => reasoning.as_deref(),
+            _ => None,
+        }
+    }
+
     pub fn tool_result(tool_call_id: impl Into<String>, content: impl Into<String>) -> Self {
         Self::ToolResult {
             tool_call_id: tool_call_id.into(),
@@ -72,15 +198,37 @@ impl Message {
         }
     }
 
-    pub fn content_text(&self) -> &str {
+    /// Text of the message; for user messages, the text parts joined by newlines.
+    pub fn content_text(&self) -> Cow<'_, str> {
         match self {
             Self::System { content }
-            | Self::User { content }
-            | Self::ToolResult { content, .. } => content,
-            Self::Assistant { content, .. } => content,
+            | Self::ToolResult { content, .. }
+            | Self::Assistant { content, .. } => Cow::Borrowed(content),
+            Self::User { content } => match content.as_slice() {
+                [ContentPart::Text { text }] => Cow::Borrowed(text),
+                parts => Cow::Owned(
+                    parts
+                        .iter()
+                        .filter_map(ContentPart::as_text)
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                ),
+            },
         }
     }
 
+    /// Images attached to a user message.
+    pub fn images(&self) -> impl Iterator<Item = &ImageSource> {
+        let parts = match self {
+            Self::User { content } => content.as_slice(),
+            _ => &[],
+        };
+        parts.iter().filter_map(|p| match p {
+            ContentPart::Image { source } => Some(source),
+            ContentPart::Text { .. } => None,
+        })
+    }
+
     pub fn role_str(&self) -> &'static str {
         match self {
             Self::System { .. } => "system",
@@ -91,6 +239,81 @@ impl Message {
     }
 }
 
+/// One piece of a user message.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    Image { source: ImageSource },
+}
+
+impl ContentPart {
+    pub fn text(text: impl Into<String>) -> Self {
+        Self::Text { text: text.into() }
+    }
+
+    pub fn image(source: ImageSource) -> Self {
+        Self::Image { source }
+    }
+
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            Self::Text { text } => Some(text),
+            Self::Image { .. } => None,
+        }
+    }
+}
+
+/// Where an image's bytes come from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ImageSource {
+    /// A local file; the client reads and inlines it when building the request.
+    Path {
+        path: PathBuf,
+    },
+    Base64 {
+        media_type: String,
+        data: String,
+    },
+    /// A public URL the provider fetches itself.
+    Url {
+        url: String,
+    },
+    /// A content-addressed blob (sha256 hex) in the application's blob store.
+    /// Must be resolved to a path or inline data before sending.
+    Blob {
+        sha256: String,
+        media_type: String,
+    },
+}
+
+mod user_content {
+    use super::ContentPart;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        Text(String),
+        Parts(Vec<ContentPart>),
+    }
+
+    pub fn serialize<S: Serializer>(parts: &[ContentPart], s: S) -> Result<S::Ok, S::Error> {
+        match parts {
+            [ContentPart::Text { text }] => text.serialize(s),
+            parts => parts.serialize(s),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<ContentPart>, D::Error> {
+        Ok(match Repr::deserialize(d)? {
+            Repr::Text(text) => vec![ContentPart::Text { text }],
+            Repr::Parts(parts) => parts,
+        })
+    }
+}
+
 // --- Tool Definitions ---
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -112,74 +335,30 @@ pub struct FunctionCall {
     pub arguments: String,
 }
 
-// --- OpenAI API request/response types ---
+// --- Usage ---
 
-#[derive(Debug, Serialize)]
-pub(crate) struct ChatRequest {
-    pub model: String,
-    pub messages: Vec<serde_json::Value>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub tools: Option<Vec<serde_json::Value>>,
-    pub stream: bool,
-}
-
-/// Serialize a Message to OpenAI format
-pub(crate) fn message_to_openai(msg: &Message) -> serde_json::Value {
-    match msg {
-        Message::System { content } => serde_json::json!({
-            "role": "system",
-            "content": content,
-        }),
-        Message::User { content } => serde_json::json!({
-            "role": "user",
-            "content": content,
-        }),
-        Message::Assistant {
-            content,
-            tool_calls,
-        } => {
-            let mut obj = serde_json::json!({
-                "role": "assistant",
-                "content": content,
-            });
-            if !tool_calls.is_empty() {
-                let tc: Vec<serde_json::Value> = tool_calls
-                    .iter()
-                    .map(|tc| {
-                        serde_json::json!({
-                            "id": tc.id,
-                            "type": "function",
-                            "function": {
-                                "name": tc.function.name,
-                                "arguments": tc.function.arguments,
-                            }
-                        })
-                    })
-                    .collect();
-                obj["tool_calls"] = serde_json::Value::Array(tc);
-            }
-            obj
-        }
-        Message::ToolResult {
-            tool_call_id,
-            content,
-        } => serde_json::json!({
-            "role": "tool",
-            "tool_call_id": tool_call_id,
-            "content": content,
-        }),
-    }
-}
-
-pub(crate) fn tool_to_openai(tool: &ToolDef) -> serde_json::Value {
-    serde_json::json!({
-        "type": "function",
-        "function": {
-            "name": tool.name,
-            "description": tool.description,
-            "parameters": tool.parameters,
-        }
-    })
+/// Token counts reported by the provider for one completion.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// Prompt tokens served from the provider's prompt cache (subset of `prompt_tokens`).
+    #[serde(default)]
+    pub cached_tokens: u64,
+}
+
+impl Usage {
+    pub fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+impl std::ops::AddAssign for Usage {
+    fn add_assign(&mut self, rhs: Self) {
+        self.prompt_tokens += rhs.prompt_tokens;
+        self.completion_tokens += rhs.completion_tokens;
+        self.cached_tokens += rhs.cached_tokens;
+    }
 }
 
 // --- Stream Events ---
@@ -187,6 +366,8 @@ pub(crate) fn tool_to_openai(tool: &ToolDef) -> serde_json::Value {
 #[derive(Debug, Clone)]
 pub enum StreamEvent {
     TextDelta(String),
+    /// Reasoning text, from a native reasoning field or split out of `<think>` tags.
+    ReasoningDelta(String),
     ToolCallStart {
         index: usize,
         id: String,
@@ -196,6 +377,16 @@ pub enum StreamEvent {
         index: usize,
         arguments: String,
     },
+    /// Token usage for the whole completion. Providers may report it more than
+    /// once; the last report is authoritative.
+    Usage(Usage),
+    /// A transient failure; the request is retried after `delay`.
+    Retry {
+        attempt: u32,
+        max_retries: u32,
+        delay: std::time::Duration,
+        reason: String,
+    },
     Done,
-    Error(String),
+    Error(crate::error::ApiError),
 }
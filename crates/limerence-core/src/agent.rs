@@ -1,161 +1,444 @@
-use chrono::Utc;
-use limerence_ai::{LlmClient, Message, Model, StreamEvent, ToolDef};
+use chrono::{Local, Utc};
+use futures::StreamExt;
+use futures::stream::FuturesOrdered;
+use limerence_ai::{
+    ApiError, ApiErrorKind, CancellationToken, ContentPart, LlmClient, Message, Model,
+    SamplingParams, StreamEvent, ToolCall, ToolChoice, ToolDef, Transport, Usage,
+};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::mpsc;
 
-use crate::character::CharacterCard;
-use crate::config::{Config, SearchConfig};
+use crate::blob::BlobStore;
+use crate::character::{CharacterCard, TOOL_INSTRUCTIONS};
+use crate::compaction::{self, CompactionConfig};
+use crate::config::{Config, PresetConfig, SearchConfig};
+use crate::context::{self, ContextLimits, ContextOverflow, Fitted, Prompt, TokenCounter};
+use crate::export::USER_NAME;
+use crate::fallback::{FallbackAttempt, ModelChain, ModelSwitch, all_failed_message};
+use crate::lorebook::{self, Activation, CharacterBook, LorePosition};
+use crate::macros::{self, MacroContext, Variables};
 use crate::memory::{MemoryEntry, MemoryIndex};
-use crate::session::Session;
+use crate::preset::{Preset, PromptParts};
+use crate::regex_script::{self, RegexPlacement, RegexScript, RegexScripts, RegexTarget};
+use crate::session::{Session, SessionEntry, SessionEvent};
 use crate::tool;
+use crate::usage::{ModelPricing, UsageTotals, session_totals};
 
 /// Events sent from the agent to the TUI.
 #[derive(Debug, Clone)]
 pub enum AgentEvent {
     /// Streaming text delta from LLM
     TextDelta(String),
+    /// Streaming reasoning delta (not part of the reply)
+    ReasoningDelta(String),
     /// A tool call is starting
     ToolCallStart { name: String },
     /// Tool call result
     ToolCallResult { name: String, result: String },
+    /// The turn used its tool rounds; the model must now answer without tools
+    ToolRoundLimit { rounds: u32 },
+    /// The model only repeated calls it already made this turn; it must now answer without tools
+    ToolLoopDetected { name: String },
+    /// Request failed transiently; retrying after `delay_secs`
+    Retry {
+        attempt: u32,
+        max_retries: u32,
+        delay_secs: f64,
+        reason: String,
+    },
+    /// Older messages were summarized; the summary replaces them in prompts
+    Compacted { replaced: usize },
+    /// The prompt was cut to fit the model's context window
+    ContextTrimmed { dropped: usize, elided: usize },
+    /// Token usage of one completion (a turn with tool calls has several)
+    Usage { usage: Usage, cost: Option<f64> },
+    /// Switched to another model in the fallback chain
+    ModelSwitch {
+        from: String,
+        to: String,
+        reason: String,
+    },
     /// LLM turn complete (no more tool calls)
     Done,
+    /// Turn stopped by the cancellation token; the partial reply, if any, was saved
+    Cancelled,
     /// Error occurred
-    Error(String),
+    Error(ApiError),
 }
 
 pub struct Agent {
     client: LlmClient,
-    model: Model,
+    models: ModelChain,
     character: CharacterCard,
     session: Session,
     memory: MemoryIndex,
+    blobs: BlobStore,
     tools: Vec<ToolDef>,
     search_config: SearchConfig,
-    base_system_prompt: String,
+    tool_timeout: Duration,
+    max_tool_rounds: u32,
+    pricing: HashMap<String, ModelPricing>,
+    context: HashMap<String, ContextLimits>,
+    compaction: CompactionConfig,
+    /// Sampler overrides from the active preset and character card, applied
+    /// on top of each model's configured sampling.
+    preset_sampling: SamplingParams,
+    card_sampling: SamplingParams,
+    /// Presets chosen per character in the config.
+    presets: PresetConfig,
+    /// The SillyTavern preset prompts are assembled with, if any.
+    preset: Option<Preset>,
+    /// Global regex scripts from `regex_dir()`.
+    global_regex: Vec<RegexScript>,
+    /// Global, character and preset scripts, in that order.
+    regex: RegexScripts,
+    /// World-info books from `worlds_dir()`, scanned next to the card's own.
+    worlds: Vec<CharacterBook>,
+    /// Titles of the lorebook entries in the latest prompt.
+    last_lore: Vec<String>,
 }
 
 const MEMORY_INJECTION_MAX_CHARS: usize = 3000;
 
 impl Agent {
     pub fn new(config: &Config, character: CharacterCard) -> Self {
-        let model = config.to_model();
-        let session = Session::new(&character.data.name, &model.id);
+        let models = ModelChain::new(
+            config.model_chain(),
+            std::time::Duration::from_secs(config.fallback.cooldown_secs),
+        );
+        let session = Session::new(&character.data.name, &models.primary().id);
         let mut memory = MemoryIndex::new();
         memory.load_from_disk();
 
-        let base_system_prompt = character.build_system_prompt();
+        let card_sampling = character.sampling_overrides();
+        let preset = configured_preset(&config.preset, &character);
         let tools = tool::all_tool_defs();
 
-        Self {
-            client: LlmClient::new(),
-            model,
+        let mut client = LlmClient::new()
+            .with_retry(config.retry.clone())
+            .with_timeouts(config.timeouts.clone());
+        // `LIMERENCE_RECORD=<file>` saves every API exchange as a replayable fixture.
+        if let Ok(path) = std::env::var("LIMERENCE_RECORD")
+            && !path.trim().is_empty()
+        {
+            client = client.recording_to(path.trim());
+        }
+
+        let mut agent = Self {
+            client,
+            models,
             character,
             session,
             memory,
+            blobs: BlobStore::open(),
             tools,
             search_config: config.search.clone(),
-            base_system_prompt,
-        }
+            tool_timeout: Duration::from_millis(config.tools.timeout_ms),
+            max_tool_rounds: config.tools.max_rounds,
+            pricing: config.model_pricing(),
+            context: config.context_limits(),
+            compaction: config.compaction.clone(),
+            preset_sampling: preset
+                .as_ref()
+                .map(|p| p.sampling.clone())
+                .unwrap_or_default(),
+            card_sampling,
+            presets: config.preset.clone(),
+            preset,
+            global_regex: regex_script::load_global(),
+            regex: RegexScripts::default(),
+            worlds: lorebook::load_worlds(),
+            last_lore: Vec::new(),
+        };
+        agent.load_regex_scripts();
+        agent
+    }
+
+    /// Talk to the API through `transport`, e.g. a `ScriptedTransport` in tests.
+    pub fn with_transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.client = self.client.with_transport(transport);
+        self
     }
 
     pub fn character_name(&self) -> &str {
         &self.character.data.name
     }
 
+    /// Id of the model currently in use (a fallback while the primary cools down).
     pub fn model_id(&self) -> &str {
-        &self.model.id
+        &self.models.active().id
     }
 
     pub fn session_id(&self) -> &str {
         &self.session.header.id
     }
 
-    pub fn first_message(&self) -> Option<&str> {
+    /// The card's greeting with its macros expanded. It is expanded once per
+    /// session and kept there, along with any variables it set.
+    pub fn first_message(&mut self) -> Option<String> {
+        if let Some(greeting) = self.session.greeting() {
+            return Some(greeting.to_string());
+        }
         let msg = &self.character.data.first_mes;
-        if msg.is_empty() { None } else { Some(msg) }
+        if msg.is_empty() {
+            return None;
+        }
+        let history = self.session.active_messages();
+        let ctx = self.macro_context(&history);
+        let mut variables = self.session.variables();
+        let greeting = macros::expand(msg, &ctx, &mut variables);
+        self.session.set_variables(variables);
+        self.session.set_greeting(greeting.clone());
+        Some(greeting)
     }
 
     pub fn memory_count(&self) -> usize {
         self.memory.entry_count()
     }
 
+    /// Apply a preset's sampler settings; the character card still takes precedence.
+    pub fn set_preset_sampling(&mut self, sampling: SamplingParams) {
+        self.preset_sampling = sampling;
+    }
+
+    pub fn preset(&self) -> Option<&Preset> {
+        self.preset.as_ref()
+    }
+
+    /// Assemble prompts with `preset`, or the built-in prompt with `None`.
+    pub fn set_preset(&mut self, preset: Option<Preset>) {
+        self.preset_sampling = preset
+            .as_ref()
+            .map(|p| p.sampling.clone())
+            .unwrap_or_default();
+        self.preset = preset;
+        self.load_regex_scripts();
+    }
+
+    /// The regex scripts in effect, for display-only rewriting.
+    pub fn regex_scripts(&self) -> &RegexScripts {
+        &self.regex
+    }
+
+    fn load_regex_scripts(&mut self) {
+        let scripts = self
+            .global_regex
+            .iter()
+            .cloned()
+            .chain(regex_script::scripts_in(&self.character.data.extensions))
+            .chain(self.preset.iter().flat_map(|p| p.regex_scripts.clone()));
+        self.regex = RegexScripts::new(scripts);
+    }
+
+    /// Tokens and cost spent in the current session.
+    pub fn session_usage(&self) -> UsageTotals {
+        session_totals(&self.session)
+    }
+
     /// Process a user message through the agent loop.
     /// Returns a channel that receives AgentEvents for the TUI to render.
+    /// Cancelling `cancel` stops the turn and keeps whatever was already streamed.
     pub async fn process_message(
         &mut self,
         user_input: String,
         event_tx: mpsc::UnboundedSender<AgentEvent>,
+        cancel: &CancellationToken,
+    ) {
+        self.process_user_message(vec![ContentPart::text(user_input)], event_tx, cancel)
+            .await;
+    }
+
+    /// Like `process_message`, for input with attachments (see `BlobStore::import_image`).
+    pub async fn process_user_message(
+        &mut self,
+        content: Vec<ContentPart>,
+        event_tx: mpsc::UnboundedSender<AgentEvent>,
+        cancel: &CancellationToken,
+    ) {
+        self.append_user_message(content, RegexTarget::Stored);
+        self.run_turn(event_tx, cancel).await;
+    }
+
+    /// Answer the latest user message again. The new reply becomes a sibling
+    /// of the old one (a swipe); if no reply is produced, the old one stays selected.
+    pub async fn regenerate(
+        &mut self,
+        event_tx: mpsc::UnboundedSender<AgentEvent>,
+        cancel: &CancellationToken,
+    ) {
+        let Some(user_id) = self.last_user_entry().map(|e| e.id.clone()) else {
+            let _ = event_tx.send(AgentEvent::Error(ApiError::new(
+                ApiErrorKind::InvalidRequest,
+                "No user message to answer again",
+            )));
+            return;
+        };
+        let previous = self.session.leaf().map(str::to_string);
+        self.session.checkout(Some(&user_id));
+        self.run_turn(event_tx, cancel).await;
+        if self.session.leaf() == Some(user_id.as_str()) {
+            self.session.checkout(previous.as_deref());
+        }
+    }
+
+    /// Replace the user message `entry_id` with `content` on a new branch
+    /// forked from its parent, and answer it. The old branch is kept.
+    pub async fn edit_user_message(
+        &mut self,
+        entry_id: &str,
+        content: Vec<ContentPart>,
+        event_tx: mpsc::UnboundedSender<AgentEvent>,
+        cancel: &CancellationToken,
     ) {
-        // Add user message
-        let user_msg = Message::user(&user_input);
+        let parent = match self.session.entry(entry_id) {
+            Some(
+                entry @ SessionEntry {
+                    message: Message::User { .. },
+                    ..
+                },
+            ) => entry.parent_id.clone(),
+            _ => {
+                let _ = event_tx.send(AgentEvent::Error(ApiError::new(
+                    ApiErrorKind::InvalidRequest,
+                    format!("No user message with id {entry_id}"),
+                )));
+                return;
+            }
+        };
+        self.session.checkout(parent.as_deref());
+        self.append_user_message(content, RegexTarget::Edited);
+        self.run_turn(event_tx, cancel).await;
+    }
+
+    /// Move to the previous (`step < 0`) or next reply to the latest user
+    /// message. Returns the new position, or `None` if there is none that way.
+    pub fn swipe(&mut self, step: isize) -> Option<(usize, usize)> {
+        let user_id = self.last_user_entry()?.id.clone();
+        let (index, total) = self.reply_position()?;
+        let target = index.checked_add_signed(step).filter(|&i| i < total)?;
+        let reply_id = self.session.children(Some(&user_id))[target].id.clone();
+        let leaf = self.session.latest_leaf_under(&reply_id).to_string();
+        self.session.checkout(Some(&leaf));
+        Some((target, total))
+    }
+
+    /// Which of the replies to the latest user message is selected, and how many there are.
+    pub fn reply_position(&self) -> Option<(usize, usize)> {
+        let branch = self.session.branch();
+        let user = branch
+            .iter()
+            .rposition(|e| matches!(e.message, Message::User { .. }))?;
+        let selected = branch.get(user + 1)?;
+        let replies = self.session.children(Some(&branch[user].id));
+        let index = replies.iter().position(|e| e.id == selected.id)?;
+        Some((index, replies.len()))
+    }
+
+    /// Select the branch ending at entry `leaf`.
+    pub fn checkout(&mut self, leaf: &str) {
+        self.session.checkout(Some(leaf));
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    fn last_user_entry(&self) -> Option<&SessionEntry> {
+        self.session
+            .branch()
+            .into_iter()
+            .rfind(|e| matches!(e.message, Message::User { .. }))
+    }
+
+    fn append_user_message(&mut self, content: Vec<ContentPart>, target: RegexTarget) {
+        let user_msg = self
+            .regex
+            .apply_message(Message::user_parts(content), target);
         self.session.append(user_msg.clone());
 
         // Index user message in memory
-        self.memory.add(MemoryEntry {
-            session_id: self.session.header.id.clone(),
-            timestamp: Utc::now(),
-            role: "user".to_string(),
-            content: user_input,
-        });
+        let user_text = user_msg.content_text();
+        if !user_text.is_empty() {
+            self.memory.add(MemoryEntry {
+                session_id: self.session.header.id.clone(),
+                timestamp: Utc::now(),
+                role: "user".to_string(),
+                content: user_text.into_owned(),
+            });
+        }
+    }
+
+    /// Answer the current branch's latest user message, running tools until
+    /// the model replies without calling any.
+    async fn run_turn(
+        &mut self,
+        event_tx: mpsc::UnboundedSender<AgentEvent>,
+        cancel: &CancellationToken,
+    ) {
+        self.compact_if_needed(&event_tx, cancel).await;
+
+        // Guards against tool loops: a cap on rounds per turn, and calls identical
+        // to an earlier one this turn are answered without running them again.
+        let mut rounds = 0;
+        let mut seen_calls = HashSet::new();
+        let mut force_answer = false;
 
         // Agent loop: keep going until LLM responds without tool calls
         loop {
-            // Build message list
-            let runtime_system_prompt =
-                compose_system_prompt(&self.base_system_prompt, self.memory.memory_root());
-            let mut messages = vec![Message::system(runtime_system_prompt)];
-            messages.extend(self.session.messages());
-
-            // Stream LLM response
-            let (stream_tx, mut stream_rx) = mpsc::unbounded_channel::<StreamEvent>();
-
-            let client = self.client.clone();
-            let model = self.model.clone();
-            let tools = self.tools.clone();
-
-            let llm_handle =
-                tokio::spawn(
-                    async move { client.stream(&model, &messages, &tools, stream_tx).await },
-                );
-
-            // Forward stream events to TUI
-            let mut full_text = String::new();
-            while let Some(event) = stream_rx.recv().await {
-                match &event {
-                    StreamEvent::TextDelta(text) => {
-                        full_text.push_str(text);
-                        let _ = event_tx.send(AgentEvent::TextDelta(text.clone()));
-                    }
-                    StreamEvent::Error(e) => {
-                        let _ = event_tx.send(AgentEvent::Error(e.clone()));
-                    }
-                    _ => {}
-                }
+            if cancel.is_cancelled() {
+                let _ = event_tx.send(AgentEvent::Cancelled);
+                break;
             }
 
-            // Get the final assembled message
-            let assistant_msg = match llm_handle.await {
-                Ok(Ok(msg)) => msg,
-                Ok(Err(e)) => {
-                    let _ = event_tx.send(AgentEvent::Error(e.to_string()));
-                    break;
-                }
+            // Build message list
+            let lore = self.activate_lore();
+            self.last_lore = lore.entries.iter().map(|e| e.title.clone()).collect();
+            let mut variables = self.session.variables();
+            let mut prompt = self.prompt_messages(&lore, &mut variables);
+            prompt.messages = prompt
+                .messages
+                .into_iter()
+                .map(|m| self.blobs.resolve(m))
+                .collect();
+            self.session.set_variables(variables);
+
+            let (mut assistant_msg, full_text, interrupted) = match self
+                .stream_with_fallback(prompt, &event_tx, cancel, !force_answer)
+                .await
+            {
+                Ok(result) => result,
                 Err(e) => {
-                    let _ = event_tx.send(AgentEvent::Error(format!("Task join error: {e}")));
+                    let _ = event_tx.send(AgentEvent::Error(e));
                     break;
                 }
             };
+            if force_answer || interrupted.is_some() {
+                // Some servers ignore `tool_choice`; calls past a guard are never run,
+                // nor calls whose arguments an error may have cut short.
+                assistant_msg = without_tool_calls(assistant_msg);
+            }
+
+            // A cancelled reply without text has nothing worth keeping.
+            let cancelled = cancel.is_cancelled();
+            if cancelled && full_text.is_empty() {
+                let _ = event_tx.send(AgentEvent::Cancelled);
+                break;
+            }
 
-            // Save assistant message
+            // Save assistant message, as rewritten by the regex scripts
+            let assistant_msg = self.regex.apply_message(assistant_msg, RegexTarget::Stored);
             self.session.append(assistant_msg.clone());
 
             // Index assistant message in memory
-            if !full_text.is_empty() {
+            let stored_text = assistant_msg.content_text();
+            if !stored_text.is_empty() {
                 self.memory.add(MemoryEntry {
                     session_id: self.session.header.id.clone(),
                     timestamp: Utc::now(),
                     role: "assistant".to_string(),
-                    content: full_text,
+                    content: stored_text.into_owned(),
                 });
             }
 
@@ -165,48 +448,593 @@ impl Agent {
                 _ => vec![],
             };
 
+            if cancelled {
+                let _ = event_tx.send(AgentEvent::Cancelled);
+                break;
+            }
+            // The partial reply is kept, but the turn ends with the error.
+            if let Some(e) = interrupted {
+                let _ = event_tx.send(AgentEvent::Error(e));
+                break;
+            }
             if tool_calls.is_empty() {
                 let _ = event_tx.send(AgentEvent::Done);
                 break;
             }
 
-            // Execute tool calls sequentially
-            for tc in &tool_calls {
-                let name = &tc.function.name;
-                let _ = event_tx.send(AgentEvent::ToolCallStart { name: name.clone() });
-
-                let result = tool::execute_tool(
-                    name,
-                    &tc.function.arguments,
-                    &self.memory,
-                    &self.search_config,
-                );
+            rounds += 1;
+            let repeated: Vec<bool> = tool_calls
+                .iter()
+                .map(|tc| !seen_calls.insert(call_key(tc)))
+                .collect();
 
+            // Run the calls concurrently; results are reported and saved in call order.
+            for tc in &tool_calls {
+                let _ = event_tx.send(AgentEvent::ToolCallStart {
+                    name: tc.function.name.clone(),
+                });
+            }
+            let memory = &self.memory;
+            let search_config = &self.search_config;
+            let timeout = self.tool_timeout;
+            let mut pending: FuturesOrdered<_> = tool_calls
+                .iter()
+                .zip(&repeated)
+                .map(|(tc, &repeated)| async move {
+                    if repeated {
+                        return REPEATED_CALL_RESULT.to_string();
+                    }
+                    tool::execute_tool_with_timeout(
+                        &tc.function.name,
+                        &tc.function.arguments,
+                        memory,
+                        search_config,
+                        timeout,
+                    )
+                    .await
+                })
+                .collect();
+            let mut finished = 0;
+            while finished < tool_calls.len() {
+                let result = tokio::select! {
+                    biased;
+                    _ = cancel.cancelled() => break,
+                    next = pending.next() => match next {
+                        Some(result) => result,
+                        None => break,
+                    },
+                };
+                let tc = &tool_calls[finished];
                 let _ = event_tx.send(AgentEvent::ToolCallResult {
-                    name: name.clone(),
+                    name: tc.function.name.clone(),
                     result: result.clone(),
                 });
+                self.session.append(Message::tool_result(&tc.id, &result));
+                finished += 1;
+            }
+            drop(pending);
+            // Every call needs a result, or the next request would be rejected.
+            for tc in &tool_calls[finished..] {
+                self.session
+                    .append(Message::tool_result(&tc.id, "工具调用已取消。"));
+            }
 
-                // Add tool result to session
-                let tool_msg = Message::tool_result(&tc.id, &result);
-                self.session.append(tool_msg);
+            if repeated.iter().all(|&r| r) {
+                let _ = event_tx.send(AgentEvent::ToolLoopDetected {
+                    name: tool_calls[0].function.name.clone(),
+                });
+                force_answer = true;
+            } else if self.max_tool_rounds > 0 && rounds >= self.max_tool_rounds {
+                let _ = event_tx.send(AgentEvent::ToolRoundLimit { rounds });
+                force_answer = true;
             }
 
             // Continue loop — LLM will see tool results and generate next response
         }
     }
 
+    /// Stream one completion, moving down the model chain on failure.
+    /// With `allow_tools` off, tools stay declared but the model may not call them.
+    /// A model that already streamed text is not retried elsewhere, to avoid
+    /// showing the user two half-replies, and errors caused by the prompt itself
+    /// (context overflow, content filter) are returned without falling back.
+    /// An error that cut a reply short comes back with the partial reply.
+    async fn stream_with_fallback(
+        &mut self,
+        prompt: Prompt,
+        event_tx: &mpsc::UnboundedSender<AgentEvent>,
+        cancel: &CancellationToken,
+        allow_tools: bool,
+    ) -> Result<(Message, String, Option<ApiError>), ApiError> {
+        let now = std::time::Instant::now();
+        if let Some(switch) = self.models.restore_primary_if_due(now) {
+            self.record_model_switch(switch, "cooldown elapsed".to_string(), event_tx);
+        }
+
+        let mut attempts = Vec::new();
+        let mut last_kind = None;
+        for index in self.models.candidates() {
+            let model = self.models.model(index).clone();
+            // Each model gets the history cut to its own window; one too small
+            // for the latest turn is skipped.
+            let messages = match self.fit_prompt(&model, prompt.clone()) {
+                Ok(fitted) => {
+                    if fitted.dropped > 0 || fitted.elided > 0 {
+                        let _ = event_tx.send(AgentEvent::ContextTrimmed {
+                            dropped: fitted.dropped,
+                            elided: fitted.elided,
+                        });
+                    }
+                    fitted.messages
+                }
+                Err(overflow) => {
+                    let error =
+                        ApiError::new(ApiErrorKind::ContextLengthExceeded, overflow.to_string());
+                    if self.models.len() == 1 {
+                        return Err(error);
+                    }
+                    last_kind = Some(error.kind);
+                    attempts.push(FallbackAttempt {
+                        model: model.id.clone(),
+                        error: error.to_string(),
+                    });
+                    continue;
+                }
+            };
+            match self
+                .stream_once(&model, messages, event_tx, cancel, allow_tools)
+                .await
+            {
+                Ok((msg, text, usage, interrupted)) => {
+                    if let Some(usage) = usage {
+                        self.record_usage(&model.id, usage, event_tx);
+                    }
+                    if let Some(switch) = self.models.mark_success(index, now) {
+                        let reason = attempts
+                            .last()
+                            .map(|a: &FallbackAttempt| format!("{} failed: {}", a.model, a.error))
+                            .unwrap_or_default();
+                        self.record_model_switch(switch, reason, event_tx);
+                    }
+                    return Ok((msg, text, interrupted));
+                }
+                Err((error, streamed)) => {
+                    if streamed
+                        || cancel.is_cancelled()
+                        || self.models.len() == 1
+                        || !error.kind.should_fallback()
+                    {
+                        return Err(error);
+                    }
+                    last_kind = Some(error.kind);
+                    attempts.push(FallbackAttempt {
+                        model: model.id.clone(),
+                        error: error.to_string(),
+                    });
+                }
+            }
+        }
+
+        Err(ApiError::new(
+            last_kind.unwrap_or(ApiErrorKind::Unknown),
+            all_failed_message(&attempts),
+        ))
+    }
+
+    /// Stream one completion from `model`, forwarding events to the TUI.
+    /// On failure, also reports whether any text or reasoning had already been streamed.
+    async fn stream_once(
+        &self,
+        model: &Model,
+        messages: Vec<Message>,
+        event_tx: &mpsc::UnboundedSender<AgentEvent>,
+        cancel: &CancellationToken,
+        allow_tools: bool,
+    ) -> Result<(Message, String, Option<Usage>, Option<ApiError>), (ApiError, bool)> {
+        let (stream_tx, mut stream_rx) = mpsc::unbounded_channel::<StreamEvent>();
+
+        let client = self.client.clone();
+        let mut model = model.clone();
+        model.sampling = self.sampling_for(&model);
+        if !allow_tools {
+            model.sampling.tool_choice = Some(ToolChoice::None);
+        }
+        let tools = self.tools.clone();
+        let cancel = cancel.clone();
+
+        let llm_handle = tokio::spawn(async move {
+            client
+                .stream(&model, &messages, &tools, stream_tx, &cancel)
+                .await
+        });
+
+        // Forward stream events to TUI
+        let mut full_text = String::new();
+        let mut reasoned = false;
+        let mut stream_error = None;
+        let mut usage = None;
+        while let Some(event) = stream_rx.recv().await {
+            match event {
+                StreamEvent::TextDelta(text) => {
+                    full_text.push_str(&text);
+                    let _ = event_tx.send(AgentEvent::TextDelta(text));
+                }
+                StreamEvent::ReasoningDelta(text) => {
+                    reasoned = true;
+                    let _ = event_tx.send(AgentEvent::ReasoningDelta(text));
+                }
+                StreamEvent::Error(e) => {
+                    stream_error = Some(e);
+                }
+                StreamEvent::Usage(u) => {
+                    usage = Some(u);
+                }
+                StreamEvent::Retry {
+                    attempt,
+                    max_retries,
+                    delay,
+                    reason,
+                } => {
+                    let _ = event_tx.send(AgentEvent::Retry {
+                        attempt,
+                        max_retries,
+                        delay_secs: delay.as_secs_f64(),
+                        reason,
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        let streamed = !full_text.is_empty() || reasoned;
+        match llm_handle.await {
+            Ok(Ok(msg)) => match stream_error {
+                // An in-band error with no output means the model never answered.
+                Some(e) if !streamed && msg_is_empty(&msg) => Err((e, false)),
+                interrupted => Ok((msg, full_text, usage, interrupted)),
+            },
+            Ok(Err(e)) => Err((e.into_api_error(), streamed)),
+            Err(e) => Err((
+                ApiError::new(ApiErrorKind::Unknown, format!("Task join error: {e}")),
+                streamed,
+            )),
+        }
+    }
+
+    /// The model's sampling with preset and card overrides applied.
+    fn sampling_for(&self, model: &Model) -> SamplingParams {
+        model
+            .sampling
+            .merged(&self.preset_sampling)
+            .merged(&self.card_sampling)
+    }
+
+    /// Lorebook entries the current branch triggers, from the card's book
+    /// and the world-info files.
+    fn activate_lore(&self) -> Activation {
+        let books: Vec<&CharacterBook> = self
+            .character
+            .data
+            .character_book
+            .iter()
+            .chain(&self.worlds)
+            .collect();
+        if books.is_empty() {
+            return Activation::default();
+        }
+        let counter = TokenCounter::for_model(&self.models.active().id);
+        let mut lore = lorebook::activate(&books, &self.session.active_messages(), &counter);
+        for entry in &mut lore.entries {
+            entry.content = self.regex.apply(
+                &entry.content,
+                RegexPlacement::WorldInfo,
+                RegexTarget::Prompt { depth: None },
+            );
+        }
+        lore
+    }
+
+    /// The current branch with prompt-only regex scripts applied.
+    fn history_for_prompt(&self) -> Vec<Message> {
+        self.regex.apply_prompt(self.session.active_messages())
+    }
+
+    /// What `{{char}}`, `{{user}}` and the other macros refer to.
+    fn macro_context<'a>(&'a self, history: &'a [Message]) -> MacroContext<'a> {
+        MacroContext {
+            char: &self.character.data.name,
+            user: USER_NAME,
+            history,
+            model: &self.models.active().id,
+            now: Local::now(),
+        }
+    }
+
+    /// The request for the current branch: the system prompt with memory,
+    /// lore and the compaction summary, the history with at-depth lore, and
+    /// the card's post-history instructions. Macros in the card, preset and
+    /// lore text are expanded, updating `variables`; the history is sent as
+    /// it was written.
+    fn prompt_messages(&self, lore: &Activation, variables: &mut Variables) -> Prompt {
+        let history = self.session.active_messages();
+        let ctx = self.macro_context(&history);
+        let mut expand = |text: &str| macros::expand(text, &ctx, variables);
+        let mut lore = lore.clone();
+        for entry in &mut lore.entries {
+            // Entries before and after the card are expanded with the text around them.
+            if matches!(entry.position, LorePosition::AtDepth { .. }) {
+                entry.content = expand(&entry.content);
+            }
+        }
+        let lore = &lore;
+        if let Some(preset) = &self.preset {
+            let mut instructions =
+                compose_system_prompt(TOOL_INSTRUCTIONS, self.memory.memory_root());
+            if let Some((summary, _)) = self.session.compaction() {
+                instructions.push_str("\n\n");
+                instructions.push_str(&compaction::summary_injection(summary));
+            }
+            return preset.assemble(PromptParts {
+                card: &self.character.data,
+                lore,
+                history: lore.inject(self.history_for_prompt()),
+                instructions,
+                expand: &mut expand,
+            });
+        }
+        let prompt = self.character.build_system_prompt_with_lore(lore);
+        let mut system = compose_system_prompt(&expand(&prompt.main), self.memory.memory_root());
+        if let Some((summary, _)) = self.session.compaction() {
+            system.push_str("\n\n");
+            system.push_str(&compaction::summary_injection(summary));
+        }
+        let mut messages = vec![Message::system(system)];
+        messages.extend(lore.inject(self.history_for_prompt()));
+        let history = 1..messages.len();
+        if let Some(post_history) = prompt.post_history {
+            messages.push(Message::system(expand(&post_history)));
+        }
+        Prompt { messages, history }
+    }
+
+    /// Tokens `model` leaves for the messages of a chat request, next to the
+    /// tool declarations and the reserved reply, and how to count them.
+    fn message_budget(&self, model: &Model) -> (usize, TokenCounter) {
+        let limits = self.context.get(&model.id).copied().unwrap_or_default();
+        let budget = limits.budget(&model.id, self.sampling_for(model).max_tokens);
+        let counter = TokenCounter::for_model(&model.id);
+        let limit = budget
+            .prompt_limit()
+            .saturating_sub(counter.tools(&self.tools));
+        (limit, counter)
+    }
+
+    /// Cut the history in `prompt` to what fits `model`'s context window.
+    fn fit_prompt(&self, model: &Model, prompt: Prompt) -> Result<Fitted, ContextOverflow> {
+        let (limit, counter) = self.message_budget(model);
+        context::fit_prompt(prompt, limit, &counter)
+    }
+
+    /// Summarize older turns once the history fills most of the active
+    /// model's budget. On failure the history is left as it is; truncation
+    /// still keeps each prompt inside the window.
+    async fn compact_if_needed(
+        &mut self,
+        event_tx: &mpsc::UnboundedSender<AgentEvent>,
+        cancel: &CancellationToken,
+    ) {
+        if !self.compaction.enabled {
+            return;
+        }
+        let model = self.models.active().clone();
+        let history = self.session.active_messages();
+        let (limit, counter) = self.message_budget(&model);
+        // Only a size estimate: variables set while building it are dropped.
+        let prompt = self.prompt_messages(&self.activate_lore(), &mut self.session.variables());
+        // Compaction can only shrink the history; the rest of the prompt is fixed.
+        let chat = counter.messages(&prompt.messages[prompt.history.clone()]);
+        let fixed = counter.messages(&prompt.messages) - chat;
+        let room = limit.saturating_sub(fixed);
+        if (chat as f64) < room as f64 * self.compaction.threshold {
+            return;
+        }
+        let Some(cut) = compaction::cut_index(&history, self.compaction.keep_recent) else {
+            return;
+        };
+        if counter.messages(&history[..cut]) < self.compaction.min_saved_tokens as usize {
+            return;
+        }
+
+        let previous = self
+            .session
+            .compaction()
+            .map(|(summary, _)| summary.to_string());
+        let transcript = compaction::transcript(previous.as_deref(), &history[..cut]);
+        let Some(summary) = self.summarize(&model, transcript, event_tx, cancel).await else {
+            return;
+        };
+
+        let start = self.session.compaction().map_or(0, |(_, index)| index);
+        let first_kept = self.session.branch()[start + cut].id.clone();
+        self.session.record(SessionEvent::Compaction {
+            timestamp: Utc::now(),
+            first_kept,
+            replaced: start + cut,
+            summary: summary.clone(),
+        });
+        if self.compaction.write_memory {
+            let note = format!(
+                "\n## {} 对话摘要（{}）\n\n{summary}\n",
+                Utc::now().format("%Y-%m-%d"),
+                self.character.data.name
+            );
+            let _ = self
+                .memory
+                .write_memory_file("memory/MEMORY.md", &note, true);
+        }
+        let _ = event_tx.send(AgentEvent::Compacted { replaced: cut });
+    }
+
+    /// One silent completion summarizing `transcript`; `None` if it fails,
+    /// is cancelled or comes back empty.
+    async fn summarize(
+        &mut self,
+        model: &Model,
+        transcript: String,
+        event_tx: &mpsc::UnboundedSender<AgentEvent>,
+        cancel: &CancellationToken,
+    ) -> Option<String> {
+        let mut model = model.clone();
+        model.sampling.max_tokens = Some(self.compaction.summary_max_tokens);
+        let messages = vec![
+            Message::system(compaction::SUMMARY_SYSTEM_PROMPT),
+            Message::user(transcript),
+        ];
+        let limits = self.context.get(&model.id).copied().unwrap_or_default();
+        let limit = limits
+            .budget(&model.id, model.sampling.max_tokens)
+            .prompt_limit();
+        if TokenCounter::for_model(&model.id).messages(&messages) > limit {
+            return None;
+        }
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let reply = self.client.stream(&model, &messages, &[], tx, cancel).await;
+        let mut usage = None;
+        let mut failed = false;
+        while let Ok(event) = rx.try_recv() {
+            match event {
+                StreamEvent::Usage(u) => usage = Some(u),
+                StreamEvent::Error(_) => failed = true,
+                _ => {}
+            }
+        }
+        if let Some(usage) = usage {
+            self.record_usage(&model.id, usage, event_tx);
+        }
+        let summary = reply.ok()?.content_text().trim().to_string();
+        (!failed && !cancel.is_cancelled() && !summary.is_empty()).then_some(summary)
+    }
+
+    fn record_usage(
+        &mut self,
+        model: &str,
+        usage: Usage,
+        event_tx: &mpsc::UnboundedSender<AgentEvent>,
+    ) {
+        let cost = self.pricing.get(model).map(|p| p.cost(&usage));
+        self.session.record(SessionEvent::Usage {
+            timestamp: Utc::now(),
+            model: model.to_string(),
+            usage,
+            cost,
+        });
+        let _ = event_tx.send(AgentEvent::Usage { usage, cost });
+    }
+
+    fn record_model_switch(
+        &mut self,
+        switch: ModelSwitch,
+        reason: String,
+        event_tx: &mpsc::UnboundedSender<AgentEvent>,
+    ) {
+        self.session.record(SessionEvent::ModelSwitch {
+            timestamp: Utc::now(),
+            from: switch.from.clone(),
+            to: switch.to.clone(),
+            reason: reason.clone(),
+        });
+        let _ = event_tx.send(AgentEvent::ModelSwitch {
+            from: switch.from,
+            to: switch.to,
+            reason,
+        });
+    }
+
     /// Start a new session, keeping the same character and config.
     pub fn new_session(&mut self) {
-        self.session = Session::new(&self.character.data.name, &self.model.id);
+        self.session = Session::new(&self.character.data.name, &self.models.primary().id);
     }
 
     /// Switch to a different character.
     pub fn switch_character(&mut self, character: CharacterCard) {
-        self.base_system_prompt = character.build_system_prompt();
-        self.character = character;
+        self.set_character(character);
         self.new_session();
     }
+
+    /// Continue a saved session with `character`.
+    pub fn resume_session(&mut self, session: Session, character: CharacterCard) {
+        self.set_character(character);
+        self.session = session;
+    }
+
+    /// Delete saved session `id` and its conversation history from memory.
+    pub fn delete_session(&mut self, id: &str) -> std::io::Result<()> {
+        Session::delete(id)?;
+        self.memory.forget_session(id);
+        Ok(())
+    }
+
+    pub fn character(&self) -> &CharacterCard {
+        &self.character
+    }
+
+    /// Titles of the lorebook entries sent with the latest request.
+    pub fn last_lore(&self) -> &[String] {
+        &self.last_lore
+    }
+
+    fn set_character(&mut self, character: CharacterCard) {
+        if character.data.name != self.character.data.name {
+            let preset = configured_preset(&self.presets, &character);
+            self.set_preset(preset);
+        }
+        self.card_sampling = character.sampling_overrides();
+        self.character = character;
+        self.load_regex_scripts();
+    }
+}
+
+/// The preset the config names for `character`; one that fails to load is
+/// left out and the built-in prompt used.
+fn configured_preset(presets: &PresetConfig, character: &CharacterCard) -> Option<Preset> {
+    Preset::find(presets.for_character(&character.data.name)?).ok()
+}
+
+/// Result given to a call identical to one already made this turn.
+const REPEATED_CALL_RESULT: &str = "本轮已用相同参数调用过该工具，未重复执行，请使用之前的结果。";
+
+/// Identity of a tool call for repeat detection; arguments are compared as JSON
+/// so key order and whitespace do not matter.
+fn call_key(tc: &ToolCall) -> (String, String) {
+    let args = serde_json::from_str::<serde_json::Value>(&tc.function.arguments)
+        .map(|v| v.to_string())
+        .unwrap_or_else(|_| tc.function.arguments.clone());
+    (tc.function.name.clone(), args)
+}
+
+fn without_tool_calls(msg: Message) -> Message {
+    match msg {
+        Message::Assistant {
+            content, reasoning, ..
+        } => Message::Assistant {
+            content,
+            reasoning,
+            tool_calls: Vec::new(),
+        },
+        other => other,
+    }
+}
+
+fn msg_is_empty(msg: &Message) -> bool {
+    match msg {
+        Message::Assistant {
+            content,
+            tool_calls,
+            ..
+        } => content.is_empty() && tool_calls.is_empty(),
+        _ => false,
+    }
 }
 
 fn compose_system_prompt(base_system_prompt: &str, memory_root: &std::path::Path) -> String {
@@ -311,4 +1139,735 @@ mod tests {
         assert!(composed.contains("用户的记忆档案"));
         assert!(composed.contains("喜欢夜跑"));
     }
+
+    /// Run one user turn against scripted API responses in a fresh data dir.
+    fn run_scripted_turn(
+        config: Config,
+        transport: Arc<limerence_ai::ScriptedTransport>,
+        input: &str,
+    ) -> (Agent, Vec<AgentEvent>, TempMemoryRoot) {
+        run_scripted_turn_after(config, transport, &[], input)
+    }
+
+    /// Like `run_scripted_turn`, with `history` already in the session.
+    fn run_scripted_turn_after(
+        config: Config,
+        transport: Arc<limerence_ai::ScriptedTransport>,
+        history: &[Message],
+        input: &str,
+    ) -> (Agent, Vec<AgentEvent>, TempMemoryRoot) {
+        run_scripted_turn_as(
+            CharacterCard::default_character(),
+            config,
+            transport,
+            history,
+            input,
+        )
+    }
+
+    /// Like `run_scripted_turn_after`, talking to `character`.
+    fn run_scripted_turn_as(
+        character: CharacterCard,
+        mut config: Config,
+        transport: Arc<limerence_ai::ScriptedTransport>,
+        history: &[Message],
+        input: &str,
+    ) -> (Agent, Vec<AgentEvent>, TempMemoryRoot) {
+        let _guard = crate::config::env_lock().lock().expect("env lock poisoned");
+        let home = TempMemoryRoot::new();
+        // SAFETY: guarded by the crate-wide env lock.
+        unsafe {
+            std::env::set_var("LIMERENCE_HOME", &home.root);
+            std::env::set_var("LIMERENCE_TEST_AGENT_KEY", "sk-test");
+        }
+
+        config.model.api_key_env = "LIMERENCE_TEST_AGENT_KEY".to_string();
+        config.retry = limerence_ai::RetryPolicy::none();
+        let mut agent = Agent::new(&config, character).with_transport(transport);
+        for msg in history {
+            agent.session.append(msg.clone());
+        }
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("runtime");
+        runtime.block_on(agent.process_message(input.to_string(), tx, &CancellationToken::new()));
+        // SAFETY: guarded by the crate-wide env lock.
+        unsafe {
+            std::env::remove_var("LIMERENCE_HOME");
+        }
+
+        let events = std::iter::from_fn(|| rx.try_recv().ok()).collect();
+        (agent, events, home)
+    }
+
+    fn reply(text: &str) -> [String; 2] {
+        [
+            serde_json::json!({"choices": [{"delta": {"content": text}, "finish_reason": "stop"}]})
+                .to_string(),
+            "[DONE]".to_string(),
+        ]
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("runtime")
+            .block_on(future)
+    }
+
+    fn texts(agent: &Agent) -> Vec<String> {
+        agent
+            .session()
+            .messages()
+            .iter()
+            .map(|m| m.content_text().into_owned())
+            .collect()
+    }
+
+    fn tool_call_chunk(index: usize, name: &str, arguments: serde_json::Value) -> String {
+        serde_json::json!({"choices": [{"delta": {"tool_calls": [{
+            "index": index,
+            "id": format!("call_{index}"),
+            "type": "function",
+            "function": {"name": name, "arguments": arguments.to_string()},
+        }]}}]})
+        .to_string()
+    }
+
+    fn load_session(home: &TempMemoryRoot, agent: &Agent) -> Session {
+        let path = home
+            .root
+            .join("sessions")
+            .join(format!("{}.jsonl", agent.session_id()));
+        Session::load(&path).expect("session persisted")
+    }
+
+    #[test]
+    fn scripted_turn_runs_tools_and_persists_the_session() {
+        let transport = Arc::new(
+            limerence_ai::ScriptedTransport::new()
+                .sse([
+                    tool_call_chunk(0, "memory_search", serde_json::json!({"query": "咖啡"})),
+                    r#"{"choices":[{"delta":{},"finish_reason":"tool_calls"}]}"#.to_string(),
+                    "[DONE]".to_string(),
+                ])
+                .sse([
+                    r#"{"choices":[{"delta":{"content":"记得，你喜欢咖啡。"},"finish_reason":"stop"}]}"#,
+                    "[DONE]",
+                ]),
+        );
+        let (agent, events, home) =
+            run_scripted_turn(Config::default(), transport.clone(), "我喜欢咖啡");
+
+        assert!(
+            events.iter().any(
+                |e| matches!(e, AgentEvent::ToolCallStart { name } if name == "memory_search")
+            )
+        );
+        assert!(matches!(events.last(), Some(AgentEvent::Done)));
+
+        // The second request carries the tool result back to the model.
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 2);
+        let sent = requests[1].body.as_ref().expect("chat body");
+        let last = sent["messages"].as_array().and_then(|m| m.last());
+        assert_eq!(last.map(|m| &m["role"]), Some(&serde_json::json!("tool")));
+
+        let session = load_session(&home, &agent);
+        let roles: Vec<_> = session.messages().iter().map(|m| m.role_str()).collect();
+        assert_eq!(roles, vec!["user", "assistant", "tool", "assistant"]);
+        assert_eq!(session.messages()[3].content_text(), "记得，你喜欢咖啡。");
+    }
+
+    #[test]
+    fn post_history_instructions_follow_the_chat_history() {
+        let mut character = CharacterCard::default_character();
+        character.data.post_history_instructions =
+            "{{original}}保持角色，不要替用户说话。".to_string();
+        let transport = Arc::new(limerence_ai::ScriptedTransport::new().sse(reply("好的。")));
+        let (_agent, _events, _home) = run_scripted_turn_as(
+            character,
+            Config::default(),
+            transport.clone(),
+            &[Message::user("早"), Message::assistant("早上好。")],
+            "今天做什么？",
+        );
+
+        let requests = transport.requests();
+        let sent = requests[0].body.as_ref().expect("chat body");
+        let messages = sent["messages"].as_array().expect("messages");
+        let roles: Vec<_> = messages
+            .iter()
+            .map(|m| m["role"].as_str().unwrap())
+            .collect();
+        assert_eq!(roles, vec!["system", "user", "assistant", "user", "system"]);
+        assert_eq!(messages[4]["content"], "保持角色，不要替用户说话。");
+        assert!(
+            !messages[0]["content"]
+                .as_str()
+                .unwrap()
+                .contains("不要替用户说话")
+        );
+    }
+
+    #[test]
+    fn triggered_lore_is_placed_in_the_prompt() {
+        let mut character = CharacterCard::default_character();
+        character.data.character_book = Some(
+            serde_json::from_value(serde_json::json!({"entries": [
+                {"comment": "团子", "keys": ["团子"], "content": "团子是用户的橘猫。"},
+                {"comment": "提醒", "keys": ["团子"], "content": "记得问候团子。",
+                 "extensions": {"position": 4, "depth": 0, "role": 0}},
+                {"comment": "王都", "keys": ["王都"], "content": "王都在北方。"},
+            ]}))
+            .unwrap(),
+        );
+        let transport = Arc::new(limerence_ai::ScriptedTransport::new().sse(reply("喵。")));
+        let (agent, _events, _home) = run_scripted_turn_as(
+            character,
+            Config::default(),
+            transport.clone(),
+            &[],
+            "团子今天很乖",
+        );
+
+        let requests = transport.requests();
+        let sent = requests[0].body.as_ref().expect("chat body");
+        let messages = sent["messages"].as_array().expect("messages");
+        let system = messages[0]["content"].as_str().unwrap();
+        assert!(system.contains("团子是用户的橘猫。"));
+        assert!(!system.contains("王都在北方。"));
+        assert_eq!(messages.last().unwrap()["content"], "记得问候团子。");
+        assert_eq!(agent.last_lore(), ["团子", "提醒"]);
+    }
+
+    #[test]
+    fn configured_preset_orders_the_request_and_sets_sampling() {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../../预设example/预设/久久预设 FOR Claude.json");
+        let mut config = Config::default();
+        config.preset.characters.insert(
+            CharacterCard::default_character().data.name,
+            path.to_string_lossy().into_owned(),
+        );
+        let transport = Arc::new(limerence_ai::ScriptedTransport::new().sse(reply("好的。")));
+        let (agent, _events, _home) = run_scripted_turn_as(
+            CharacterCard::default_character(),
+            config,
+            transport.clone(),
+            &[],
+            "你好",
+        );
+
+        assert_eq!(
+            agent.preset().map(|p| p.name.as_str()),
+            Some("久久预设 FOR Claude")
+        );
+        let requests = transport.requests();
+        let sent = requests[0].body.as_ref().expect("chat body");
+        let messages = sent["messages"].as_array().expect("messages");
+        assert_eq!(messages[0]["role"], "user");
+        assert!(
+            messages[0]["content"]
+                .as_str()
+                .unwrap()
+                .contains("你不再是大语言模型")
+        );
+        let tools = messages
+            .iter()
+            .position(|m| {
+                m["content"]
+                    .as_str()
+                    .is_some_and(|c| c.contains("memory_search"))
+            })
+            .expect("tool instructions");
+        assert_eq!(messages[tools + 1]["content"], "你好");
+    }
+
+    #[test]
+    fn card_regex_scripts_rewrite_stored_replies_and_prompts() {
+        let mut character = CharacterCard::default_character();
+        character.data.extensions = serde_json::json!({"regex_scripts": [
+            {"scriptName": "去掉状态栏", "findRegex": "/<status>[\\s\\S]*?<\\/status>\\n?/g",
+             "replaceString": "", "placement": [2]},
+            {"scriptName": "发送时改写", "findRegex": "团子", "replaceString": "[猫]{{match}}",
+             "placement": [1], "promptOnly": true},
+        ]});
+        let transport = Arc::new(
+            limerence_ai::ScriptedTransport::new().sse(reply("<status>心情：好</status>\n喵。")),
+        );
+        let (agent, _events, _home) = run_scripted_turn_as(
+            character,
+            Config::default(),
+            transport.clone(),
+            &[],
+            "团子来了",
+        );
+        let stored: Vec<_> = agent
+            .session()
+            .messages()
+            .iter()
+            .map(|m| m.content_text().into_owned())
+            .collect();
+        assert_eq!(stored, vec!["团子来了", "喵。"]);
+
+        let requests = transport.requests();
+        let sent = requests[0].body.as_ref().expect("chat body");
+        let messages = sent["messages"].as_array().expect("messages");
+        assert_eq!(messages[1]["content"], "[猫]团子来了");
+    }
+
+    #[test]
+    fn card_macros_are_expanded_and_variables_saved_with_the_session() {
+        let mut character = CharacterCard::default_character();
+        character.data.name = "苏晚".to_string();
+        character.data.description = "{{char}}认识{{user}}。{{setvar::见面::第一次}}".to_string();
+        character.data.post_history_instructions = "不要替{{user}}说话。".to_string();
+        character.data.first_mes = "你好，{{user}}。".to_string();
+        let transport = Arc::new(limerence_ai::ScriptedTransport::new().sse(reply("嗯。")));
+        let (mut agent, _events, _home) = run_scripted_turn_as(
+            character,
+            Config::default(),
+            transport.clone(),
+            &[],
+            "{{char}}在吗",
+        );
+
+        let requests = transport.requests();
+        let sent = requests[0].body.as_ref().expect("chat body");
+        let messages = sent["messages"].as_array().expect("messages");
+        let system = messages[0]["content"].as_str().expect("system prompt");
+        assert!(system.contains("苏晚认识用户。"), "{system}");
+        assert!(!system.contains("{{"), "{system}");
+        // What the user typed is sent as written.
+        assert_eq!(messages[1]["content"], "{{char}}在吗");
+        assert_eq!(messages.last().unwrap()["content"], "不要替用户说话。");
+        assert_eq!(agent.first_message().as_deref(), Some("你好，用户。"));
+
+        let loaded = Session::load(&agent.session().path().to_path_buf()).expect("saved session");
+        assert_eq!(loaded.variables()["见面"], "第一次");
+    }
+
+    #[test]
+    fn greeting_is_expanded_once_and_its_variables_are_kept() {
+        let mut character = CharacterCard::default_character();
+        character.data.first_mes =
+            "{{setvar::天气::{{random::晴::雨}}}}今天{{getvar::天气}}，第{{incvar::见面}}次见。"
+                .to_string();
+        let transport = Arc::new(limerence_ai::ScriptedTransport::new().sse(reply("嗯。")));
+        let (mut agent, _events, _home) =
+            run_scripted_turn_as(character, Config::default(), transport, &[], "你好");
+
+        let greeting = agent.first_message().expect("greeting");
+        assert!(greeting.ends_with("，第1次见。"), "{greeting}");
+        for _ in 0..10 {
+            assert_eq!(agent.first_message().as_deref(), Some(greeting.as_str()));
+        }
+        let loaded = Session::load(&agent.session().path().to_path_buf()).expect("saved session");
+        assert_eq!(loaded.greeting(), Some(greeting.as_str()));
+        assert_eq!(loaded.variables()["见面"], "1");
+        assert!(greeting.contains(&loaded.variables()["天气"]));
+    }
+
+    #[test]
+    fn tool_calls_run_concurrently_and_keep_call_order() {
+        let mut config = Config::default();
+        config.tools.timeout_ms = 500;
+        config.search = SearchConfig {
+            engine: "searxng".to_string(),
+            searxng_url: Some(crate::tool::tests::stalled_searxng()),
+        };
+        let transport = Arc::new(
+            limerence_ai::ScriptedTransport::new()
+                .sse([
+                    tool_call_chunk(0, "web_search", serde_json::json!({"query": "天气"})),
+                    tool_call_chunk(1, "web_search", serde_json::json!({"query": "气温"})),
+                    tool_call_chunk(2, "memory_get", serde_json::json!({})),
+                    "[DONE]".to_string(),
+                ])
+                .sse([r#"{"choices":[{"delta":{"content":"好的"}}]}"#, "[DONE]"]),
+        );
+
+        let started = std::time::Instant::now();
+        let (agent, events, home) = run_scripted_turn(config, transport, "查一下天气");
+        let elapsed = started.elapsed();
+        assert!(
+            elapsed < Duration::from_millis(950),
+            "two stuck searches should time out together, took {elapsed:?}"
+        );
+
+        let results: Vec<_> = events
+            .iter()
+            .filter_map(|e| match e {
+                AgentEvent::ToolCallResult { name, result } => Some((name.as_str(), result)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].0, "web_search");
+        assert!(results[0].1.contains("超时"));
+        assert!(results[1].1.contains("超时"));
+        assert_eq!(results[2].0, "memory_get");
+
+        let session = load_session(&home, &agent);
+        let ids: Vec<_> = session
+            .messages()
+            .iter()
+            .filter_map(|m| match m {
+                Message::ToolResult { tool_call_id, .. } => Some(tool_call_id.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(ids, vec!["call_0", "call_1", "call_2"]);
+    }
+
+    #[test]
+    fn repeated_tool_calls_force_a_final_answer() {
+        let query = serde_json::json!({"query": "生日"});
+        let search_round = |args: &serde_json::Value| {
+            [
+                tool_call_chunk(0, "memory_search", args.clone()),
+                "[DONE]".to_string(),
+            ]
+        };
+        let transport = Arc::new(
+            limerence_ai::ScriptedTransport::new()
+                .sse(search_round(&query))
+                // The model asks for exactly the same thing again.
+                .sse(search_round(&query))
+                .sse([
+                    r#"{"choices":[{"delta":{"content":"我不记得了。"}}]}"#,
+                    "[DONE]",
+                ]),
+        );
+        let (agent, events, home) =
+            run_scripted_turn(Config::default(), transport.clone(), "我生日是哪天？");
+
+        assert!(events.iter().any(
+            |e| matches!(e, AgentEvent::ToolLoopDetected { name } if name == "memory_search")
+        ));
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 3);
+        assert!(
+            requests[1]
+                .body
+                .as_ref()
+                .expect("body")
+                .get("tool_choice")
+                .is_none()
+        );
+        assert_eq!(
+            requests[2].body.as_ref().expect("body")["tool_choice"],
+            "none"
+        );
+
+        let results: Vec<_> = load_session(&home, &agent)
+            .messages()
+            .into_iter()
+            .filter(|m| matches!(m, Message::ToolResult { .. }))
+            .map(|m| m.content_text().into_owned())
+            .collect();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[1], REPEATED_CALL_RESULT);
+    }
+
+    #[test]
+    fn round_limit_forces_an_answer_and_drops_further_calls() {
+        let mut config = Config::default();
+        config.tools.max_rounds = 1;
+        let transport = Arc::new(
+            limerence_ai::ScriptedTransport::new()
+                .sse([
+                    tool_call_chunk(0, "memory_get", serde_json::json!({})),
+                    "[DONE]".to_string(),
+                ])
+                // A server that ignores tool_choice and calls a tool anyway.
+                .sse([
+                    tool_call_chunk(0, "memory_search", serde_json::json!({"query": "猫"})),
+                    r#"{"choices":[{"delta":{"content":"先说到这里。"}}]}"#.to_string(),
+                    "[DONE]".to_string(),
+                ]),
+        );
+        let (agent, events, home) = run_scripted_turn(config, transport.clone(), "讲讲我的猫");
+
+        assert!(
+            events
+                .iter()
+                .any(|e| matches!(e, AgentEvent::ToolRoundLimit { rounds: 1 }))
+        );
+        assert!(matches!(events.last(), Some(AgentEvent::Done)));
+        assert_eq!(transport.requests().len(), 2);
+
+        let messages = load_session(&home, &agent).messages();
+        match messages.last() {
+            Some(Message::Assistant {
+                content,
+                tool_calls,
+                ..
+            }) => {
+                assert_eq!(content, "先说到这里。");
+                assert!(tool_calls.is_empty());
+            }
+            other => panic!("unexpected last message: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn prompt_too_big_for_the_primary_window_goes_to_a_larger_fallback() {
+        let mut config = Config::default();
+        config.model.max_context = Some(4000);
+        let mut fallback = config.model.clone();
+        fallback.id = "gpt-4o-mini".to_string();
+        fallback.base_url = "https://fallback.test/v1".to_string();
+        fallback.api_key_env = "LIMERENCE_TEST_AGENT_KEY".to_string();
+        fallback.max_context = None;
+        config.fallback.models.push(fallback);
+        let transport = Arc::new(limerence_ai::ScriptedTransport::new().sse([
+            r#"{"choices":[{"delta":{"content":"读完了。"},"finish_reason":"stop"}]}"#,
+            "[DONE]",
+        ]));
+        let (_agent, events, _home) =
+            run_scripted_turn(config, transport.clone(), &"很长的信。".repeat(2000));
+
+        assert!(events.iter().any(
+            |e| matches!(e, AgentEvent::ModelSwitch { to, reason, .. } if to == "gpt-4o-mini" && reason.contains("context window"))
+        ));
+        assert!(matches!(events.last(), Some(AgentEvent::Done)));
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].url.starts_with("https://fallback.test/v1"));
+    }
+
+    #[test]
+    fn stream_error_after_partial_output_keeps_the_text_and_ends_the_turn() {
+        let transport = Arc::new(limerence_ai::ScriptedTransport::new().sse([
+            r#"{"choices":[{"delta":{"content":"我查一下"}}]}"#.to_string(),
+            tool_call_chunk(0, "memory_get", serde_json::json!({})),
+            r#"{"error":{"message":"upstream reset","type":"server_error"}}"#.to_string(),
+        ]));
+        let (agent, events, _home) =
+            run_scripted_turn(Config::default(), transport.clone(), "你好");
+
+        assert!(matches!(events.last(), Some(AgentEvent::Error(_))));
+        let errors = events
+            .iter()
+            .filter(|e| matches!(e, AgentEvent::Error(_)))
+            .count();
+        assert_eq!(errors, 1);
+        assert!(
+            !events
+                .iter()
+                .any(|e| matches!(e, AgentEvent::ToolCallStart { .. }))
+        );
+        assert_eq!(transport.requests().len(), 1);
+        match agent.session().messages().last() {
+            Some(Message::Assistant {
+                content,
+                tool_calls,
+                ..
+            }) => {
+                assert_eq!(content, "我查一下");
+                assert!(tool_calls.is_empty());
+            }
+            other => panic!("unexpected last message: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn prompt_too_big_for_every_window_is_not_sent() {
+        let mut config = Config::default();
+        config.model.max_context = Some(4000);
+        let transport = Arc::new(limerence_ai::ScriptedTransport::new());
+        let (_agent, events, _home) =
+            run_scripted_turn(config, transport.clone(), &"很长的信。".repeat(2000));
+
+        match events.last() {
+            Some(AgentEvent::Error(e)) => {
+                assert_eq!(e.kind, ApiErrorKind::ContextLengthExceeded)
+            }
+            other => panic!("unexpected last event: {other:?}"),
+        }
+        assert!(transport.requests().is_empty());
+    }
+
+    #[test]
+    fn compaction_ignores_the_fixed_prompt_and_skips_small_cuts() {
+        let history: Vec<Message> = (0..6)
+            .flat_map(|n| {
+                [
+                    Message::user(format!("第 {n} 件事")),
+                    Message::assistant(format!("记下第 {n} 件事了。")),
+                ]
+            })
+            .collect();
+        let compacted = |character: CharacterCard, config: Config| {
+            let transport = Arc::new(limerence_ai::ScriptedTransport::new().sse(reply("好。")));
+            let (_agent, events, _home) =
+                run_scripted_turn_as(character, config, transport.clone(), &history, "还记得吗？");
+            assert!(matches!(events.last(), Some(AgentEvent::Done)));
+            events
+                .iter()
+                .any(|e| matches!(e, AgentEvent::Compacted { .. }))
+                || transport.requests().len() > 1
+        };
+
+        // A long card fills the prompt, but the history is still short.
+        let mut character = CharacterCard::default_character();
+        character.data.description = "很长的设定。".repeat(1000);
+        let mut config = Config::default();
+        config.model.max_context = Some(20_000);
+        config.compaction.threshold = 0.1;
+        config.compaction.keep_recent = 2;
+        config.compaction.min_saved_tokens = 0;
+        assert!(!compacted(character, config));
+
+        // Over the threshold, but the cut would save next to nothing.
+        let mut config = Config::default();
+        config.compaction.threshold = 0.0;
+        config.compaction.keep_recent = 2;
+        assert!(!compacted(CharacterCard::default_character(), config));
+    }
+
+    #[test]
+    fn long_history_is_compacted_into_a_summary_that_replaces_it() {
+        let mut config = Config::default();
+        config.compaction.threshold = 0.0;
+        config.compaction.keep_recent = 2;
+        config.compaction.min_saved_tokens = 0;
+        config.compaction.write_memory = true;
+        let history: Vec<Message> = (0..6)
+            .flat_map(|n| {
+                [
+                    Message::user(format!("第 {n} 件事")),
+                    Message::assistant(format!("记下第 {n} 件事了。")),
+                ]
+            })
+            .collect();
+        let transport = Arc::new(
+            limerence_ai::ScriptedTransport::new()
+                .sse([
+                    r#"{"choices":[{"delta":{"content":"用户讲了六件事。"},"finish_reason":"stop"}]}"#,
+                    "[DONE]",
+                ])
+                .sse([
+                    r#"{"choices":[{"delta":{"content":"都记得。"},"finish_reason":"stop"}]}"#,
+                    "[DONE]",
+                ]),
+        );
+        let (agent, events, home) =
+            run_scripted_turn_after(config, transport.clone(), &history, "还记得吗？");
+
+        assert!(
+            events
+                .iter()
+                .any(|e| matches!(e, AgentEvent::Compacted { replaced: 10 }))
+        );
+        assert!(matches!(events.last(), Some(AgentEvent::Done)));
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 2);
+        let summarize = requests[0].body.as_ref().expect("summary body");
+        assert!(summarize.get("tools").is_none());
+        assert!(
+            summarize["messages"][1]["content"]
+                .as_str()
+                .is_some_and(|t| t.contains("用户: 第 0 件事\n助手: 记下第 0 件事了。"))
+        );
+
+        // The reply request sees the summary instead of the first five turns.
+        let chat = requests[1].body.as_ref().expect("chat body");
+        let sent = chat["messages"].as_array().expect("messages");
+        let texts: Vec<_> = sent
+            .iter()
+            .map(|m| m["content"].as_str().unwrap_or_default())
+            .collect();
+        assert!(texts[0].contains("[较早对话的摘要]\n用户讲了六件事。"));
+        assert_eq!(
+            &texts[1..],
+            ["第 5 件事", "记下第 5 件事了。", "还记得吗？"]
+        );
+
+        // Nothing is deleted from the session file.
+        let session = load_session(&home, &agent);
+        assert_eq!(session.messages().len(), 14);
+        assert_eq!(session.compaction(), Some(("用户讲了六件事。", 10)));
+        let memory = std::fs::read_to_string(home.root.join("memory").join("MEMORY.md"))
+            .expect("summary written to memory");
+        assert!(memory.contains("用户讲了六件事。"));
+    }
+
+    #[test]
+    fn regenerated_replies_are_swipes_of_the_same_message() {
+        let transport = Arc::new(
+            limerence_ai::ScriptedTransport::new()
+                .sse(reply("第一个笑话"))
+                .sse(reply("第二个笑话")),
+        );
+        let (mut agent, _, home) =
+            run_scripted_turn(Config::default(), transport.clone(), "讲个笑话");
+        let (tx, _rx) = mpsc::unbounded_channel();
+        block_on(agent.regenerate(tx, &CancellationToken::new()));
+
+        // The second request is the same prompt, without the first reply.
+        let requests = transport.requests();
+        let sent = requests[1].body.as_ref().expect("chat body")["messages"]
+            .as_array()
+            .expect("messages")
+            .clone();
+        assert_eq!(sent.last().unwrap()["content"], "讲个笑话");
+
+        assert_eq!(texts(&agent), vec!["讲个笑话", "第二个笑话"]);
+        assert_eq!(agent.reply_position(), Some((1, 2)));
+        assert_eq!(agent.swipe(-1), Some((0, 2)));
+        assert_eq!(texts(&agent), vec!["讲个笑话", "第一个笑话"]);
+        assert_eq!(agent.swipe(-1), None);
+
+        // The swipe choice is persisted.
+        let session = load_session(&home, &agent);
+        assert_eq!(session.messages()[1].content_text(), "第一个笑话");
+    }
+
+    #[test]
+    fn failed_regeneration_keeps_the_old_reply_selected() {
+        let transport = Arc::new(
+            limerence_ai::ScriptedTransport::new()
+                .sse(reply("原来的回答"))
+                .fail("connection reset"),
+        );
+        let (mut agent, _, _home) = run_scripted_turn(Config::default(), transport, "你好");
+        let (tx, _rx) = mpsc::unbounded_channel();
+        block_on(agent.regenerate(tx, &CancellationToken::new()));
+
+        assert_eq!(texts(&agent), vec!["你好", "原来的回答"]);
+        assert_eq!(agent.reply_position(), Some((0, 1)));
+    }
+
+    #[test]
+    fn editing_a_user_message_forks_a_new_branch() {
+        let transport = Arc::new(
+            limerence_ai::ScriptedTransport::new()
+                .sse(reply("你好，小明。"))
+                .sse(reply("你好，小红。")),
+        );
+        let (mut agent, _, _home) = run_scripted_turn(Config::default(), transport, "我叫小明");
+        let original = agent.session().branch()[0].id.clone();
+        let (tx, _rx) = mpsc::unbounded_channel();
+        block_on(agent.edit_user_message(
+            &original,
+            vec![ContentPart::text("我叫小红")],
+            tx,
+            &CancellationToken::new(),
+        ));
+
+        assert_eq!(texts(&agent), vec!["我叫小红", "你好，小红。"]);
+        assert_eq!(agent.session().leaves().len(), 2);
+
+        let old_leaf = agent.session().latest_leaf_under(&original).to_string();
+        agent.checkout(&old_leaf);
+        assert_eq!(texts(&agent), vec!["我叫小明", "你好，小明。"]);
+    }
 }
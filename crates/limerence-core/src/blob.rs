@@ -0,0 +1,122 @@
+use limerence_ai::image::sniff_media_type;
+use limerence_ai::{ContentPart, ImageSource, Message};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+use crate::config::blobs_dir;
+
+/// Content-addressed store for attachments, one file per sha256 under `blobs/`.
+/// Sessions reference images by hash, so the same photo is stored once.
+pub struct BlobStore {
+    dir: PathBuf,
+}
+
+impl BlobStore {
+    pub fn open() -> Self {
+        Self::at(blobs_dir())
+    }
+
+    pub fn at(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Store `bytes` and return their sha256 (hex). Existing blobs are not rewritten.
+    pub fn put(&self, bytes: &[u8]) -> std::io::Result<String> {
+        let hash = format!("{:x}", Sha256::digest(bytes));
+        let path = self.path(&hash);
+        if !path.exists() {
+            std::fs::create_dir_all(&self.dir)?;
+            std::fs::write(&path, bytes)?;
+        }
+        Ok(hash)
+    }
+
+    pub fn path(&self, sha256: &str) -> PathBuf {
+        self.dir.join(sha256)
+    }
+
+    /// Copy an image file into the store and return a part referencing it.
+    pub fn import_image(&self, path: &Path) -> Result<ContentPart, String> {
+        let bytes = std::fs::read(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        let media_type = sniff_media_type(&bytes)
+            .ok_or_else(|| format!("{}: unsupported image format", path.display()))?;
+        let sha256 = self
+            .put(&bytes)
+            .map_err(|e| format!("{}: {e}", path.display()))?;
+        Ok(ContentPart::image(ImageSource::Blob {
+            sha256,
+            media_type: media_type.to_string(),
+        }))
+    }
+
+    /// Point blob references at their files so the client can inline them.
+    pub fn resolve(&self, msg: Message) -> Message {
+        match msg {
+            Message::User { content } => Message::user_parts(
+                content
+                    .into_iter()
+                    .map(|part| match part {
+                        ContentPart::Image {
+                            source: ImageSource::Blob { sha256, .. },
+                        } => ContentPart::image(ImageSource::Path {
+                            path: self.path(&sha256),
+                        }),
+                        other => other,
+                    })
+                    .collect(),
+            ),
+            other => other,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn images_are_stored_once_and_resolved_to_paths() {
+        let root = std::env::temp_dir().join(format!("limerence-blobs-{}", uuid::Uuid::new_v4()));
+        let store = BlobStore::at(root.join("blobs"));
+        let photo = root.join("photo.png");
+        std::fs::create_dir_all(&root).expect("temp dir");
+        std::fs::write(&photo, b"\x89PNG\r\n\x1a\nfake").expect("write photo");
+
+        let part = store.import_image(&photo).expect("import");
+        let again = store.import_image(&photo).expect("import again");
+        assert_eq!(part, again);
+
+        let ContentPart::Image {
+            source: ImageSource::Blob { sha256, media_type },
+        } = &part
+        else {
+            panic!("expected blob reference, got {part:?}");
+        };
+        assert_eq!(media_type, "image/png");
+        assert_eq!(sha256.len(), 64);
+
+        // The session line only carries the hash, never the bytes.
+        let line = serde_json::to_string(&Message::user_parts(vec![
+            ContentPart::text("看"),
+            part.clone(),
+        ]))
+        .expect("serialize");
+        assert!(line.contains(sha256.as_str()));
+        assert!(!line.contains("fake"));
+        // Text-only lines keep the old plain-string form.
+        let old: Message =
+            serde_json::from_str(r#"{"role":"user","content":"你好"}"#).expect("old line");
+        assert_eq!(old.content_text(), "你好");
+
+        let resolved = store.resolve(Message::user_parts(vec![part.clone()]));
+        assert_eq!(
+            resolved.images().next(),
+            Some(&ImageSource::Path {
+                path: store.path(sha256)
+            })
+        );
+
+        assert!(store.import_image(&root.join("missing.png")).is_err());
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
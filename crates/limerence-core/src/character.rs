@@ -1,6 +1,15 @@
+use limerence_ai::SamplingParams;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 
-/// SillyTavern V2 compatible character card.
+use crate::character_png;
+use crate::config::characters_dir;
+use crate::lorebook::{Activation, CharacterBook};
+
+/// SillyTavern character card, V2 or V3.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CharacterCard {
     #[serde(default = "default_spec")]
@@ -8,6 +17,9 @@ pub struct CharacterCard {
     #[serde(default = "default_spec_version")]
     pub spec_version: String,
     pub data: CharacterData,
+    /// Fields outside the spec, kept so cards round-trip intact.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 fn default_spec() -> String {
@@ -17,40 +29,384 @@ fn default_spec_version() -> String {
     "2.0".to_string()
 }
 
+/// Card fields. A value of the wrong type is not an error: it stays in
+/// `extra` under its own key, is written back unchanged and shows up in
+/// `CharacterCard::validate`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "Map<String, Value>", into = "Map<String, Value>")]
 pub struct CharacterData {
     pub name: String,
-    #[serde(default)]
     pub description: String,
-    #[serde(default)]
     pub personality: String,
-    #[serde(default)]
     pub scenario: String,
-    #[serde(default)]
     pub first_mes: String,
-    #[serde(default)]
-    pub system_prompt: String,
-    #[serde(default)]
     pub mes_example: String,
-    #[serde(default)]
-    pub extensions: serde_json::Value,
+    // V2
+    pub creator_notes: String,
+    pub system_prompt: String,
+    /// Sent after the chat history rather than in the system prompt.
+    pub post_history_instructions: String,
+    pub alternate_greetings: Vec<String>,
+    pub character_book: Option<CharacterBook>,
+    pub tags: Vec<String>,
+    pub creator: String,
+    pub character_version: String,
+    pub extensions: Value,
+    // V3; `None` when the card does not set them.
+    pub nickname: Option<String>,
+    pub creator_notes_multilingual: Option<BTreeMap<String, String>>,
+    pub source: Option<Vec<String>>,
+    pub group_only_greetings: Option<Vec<String>>,
+    pub creation_date: Option<i64>,
+    pub modification_date: Option<i64>,
+    pub assets: Option<Vec<CardAsset>>,
+    /// Unknown fields, and known ones whose value did not parse.
+    pub extra: Map<String, Value>,
+}
+
+/// A V3 asset: an icon, background or other file the card refers to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CardAsset {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub uri: String,
+    pub name: String,
+    pub ext: String,
+}
+
+impl CharacterData {
+    const FIELDS: &[(&str, &str)] = &[
+        ("name", "string"),
+        ("description", "string"),
+        ("personality", "string"),
+        ("scenario", "string"),
+        ("first_mes", "string"),
+        ("mes_example", "string"),
+        ("creator_notes", "string"),
+        ("system_prompt", "string"),
+        ("post_history_instructions", "string"),
+        ("alternate_greetings", "array of strings"),
+        ("character_book", "character book object"),
+        ("tags", "array of strings"),
+        ("creator", "string"),
+        ("character_version", "string"),
+        ("extensions", "object"),
+        ("nickname", "string"),
+        ("creator_notes_multilingual", "object of strings"),
+        ("source", "array of strings"),
+        ("group_only_greetings", "array of strings"),
+        ("creation_date", "integer"),
+        ("modification_date", "integer"),
+        ("assets", "array of assets"),
+    ];
+}
+
+impl TryFrom<Map<String, Value>> for CharacterData {
+    type Error = String;
+
+    fn try_from(map: Map<String, Value>) -> Result<Self, Self::Error> {
+        let mut f = Fields(map);
+        let name = f
+            .take("name")
+            .ok_or("character card needs a string `name`")?;
+        Ok(Self {
+            name,
+            description: f.take("description").unwrap_or_default(),
+            personality: f.take("personality").unwrap_or_default(),
+            scenario: f.take("scenario").unwrap_or_default(),
+            first_mes: f.take("first_mes").unwrap_or_default(),
+            mes_example: f.take("mes_example").unwrap_or_default(),
+            creator_notes: f.take("creator_notes").unwrap_or_default(),
+            system_prompt: f.take("system_prompt").unwrap_or_default(),
+            post_history_instructions: f.take("post_history_instructions").unwrap_or_default(),
+            alternate_greetings: f.take("alternate_greetings").unwrap_or_default(),
+            character_book: f.take("character_book"),
+            tags: f.take("tags").unwrap_or_default(),
+            creator: f.take("creator").unwrap_or_default(),
+            character_version: f.take("character_version").unwrap_or_default(),
+            extensions: f.take_object("extensions"),
+            nickname: f.take("nickname"),
+            creator_notes_multilingual: f.take("creator_notes_multilingual"),
+            source: f.take("source"),
+            group_only_greetings: f.take("group_only_greetings"),
+            creation_date: f.take("creation_date"),
+            modification_date: f.take("modification_date"),
+            assets: f.take("assets"),
+            extra: f.0,
+        })
+    }
+}
+
+impl From<CharacterData> for Map<String, Value> {
+    fn from(d: CharacterData) -> Self {
+        let mut f = Fields(d.extra);
+        f.put("name", d.name);
+        f.put("description", d.description);
+        f.put("personality", d.personality);
+        f.put("scenario", d.scenario);
+        f.put("first_mes", d.first_mes);
+        f.put("mes_example", d.mes_example);
+        f.put("creator_notes", d.creator_notes);
+        f.put("system_prompt", d.system_prompt);
+        f.put("post_history_instructions", d.post_history_instructions);
+        f.put("alternate_greetings", d.alternate_greetings);
+        f.put("character_book", d.character_book);
+        f.put("tags", d.tags);
+        f.put("creator", d.creator);
+        f.put("character_version", d.character_version);
+        f.put("extensions", d.extensions);
+        f.put("nickname", d.nickname);
+        f.put("creator_notes_multilingual", d.creator_notes_multilingual);
+        f.put("source", d.source);
+        f.put("group_only_greetings", d.group_only_greetings);
+        f.put("creation_date", d.creation_date);
+        f.put("modification_date", d.modification_date);
+        f.put("assets", d.assets);
+        f.0
+    }
+}
+
+/// A JSON object read field by field. Values that do not parse are left in
+/// place, so what remains is the struct's `extra`.
+pub(crate) struct Fields(pub(crate) Map<String, Value>);
+
+impl Fields {
+    pub(crate) fn take<T: DeserializeOwned>(&mut self, key: &str) -> Option<T> {
+        let value = self.0.remove(key)?;
+        match T::deserialize(&value) {
+            Ok(parsed) => Some(parsed),
+            Err(_) => {
+                self.0.insert(key.to_string(), value);
+                None
+            }
+        }
+    }
+
+    /// An object field; `Null` when missing or not an object.
+    pub(crate) fn take_object(&mut self, key: &str) -> Value {
+        match self.0.get(key) {
+            Some(Value::Object(_)) => self.0.remove(key).unwrap_or_default(),
+            _ => Value::Null,
+        }
+    }
+
+    /// Write a field back. `None` is left out, and a malformed original
+    /// still in the map wins.
+    pub(crate) fn put(&mut self, key: &str, value: impl Serialize) {
+        if self.0.contains_key(key) {
+            return;
+        }
+        match serde_json::to_value(value) {
+            Ok(Value::Null) | Err(_) => {}
+            Ok(value) => {
+                self.0.insert(key.to_string(), value);
+            }
+        }
+    }
+}
+
+/// Something `CharacterCard::validate` found wrong with a card.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CardIssue {
+    /// Where, e.g. `data.character_book.entries[3].comment`.
+    pub path: String,
+    pub problem: CardProblem,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CardProblem {
+    /// Not part of the V2 or V3 spec.
+    Unknown,
+    /// A spec field holding the wrong type of value.
+    Malformed { expected: &'static str },
+}
+
+impl std::fmt::Display for CardIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.problem {
+            CardProblem::Unknown => write!(f, "{}: unknown field", self.path),
+            CardProblem::Malformed { expected } => {
+                write!(f, "{}: malformed, expected {expected}", self.path)
+            }
+        }
+    }
+}
+
+/// Report the leftovers in `extra`: spec fields that did not parse, and
+/// fields the spec does not have.
+pub(crate) fn extra_issues(
+    path: &str,
+    extra: &Map<String, Value>,
+    fields: &[(&str, &'static str)],
+    out: &mut Vec<CardIssue>,
+) {
+    for key in extra.keys() {
+        let problem = match fields.iter().find(|(name, _)| name == key) {
+            Some((_, expected)) => CardProblem::Malformed { expected },
+            None => CardProblem::Unknown,
+        };
+        out.push(CardIssue {
+            path: format!("{path}.{key}"),
+            problem,
+        });
+    }
+}
+
+/// Top-level fields SillyTavern writes next to `data` for V1 readers.
+const V1_FIELDS: &[&str] = &[
+    "name",
+    "description",
+    "personality",
+    "scenario",
+    "first_mes",
+    "mes_example",
+    "creatorcomment",
+    "avatar",
+    "chat",
+    "talkativeness",
+    "fav",
+    "tags",
+    "create_date",
+];
+
+/// How the model should use Limerence's tools; part of every system prompt.
+pub const TOOL_INSTRUCTIONS: &str = "你可以使用以下工具来增强对话体验：\n\
+     - memory_search：搜索历史对话和持久记忆文件\n\
+     - memory_write：写入持久记忆文件（memory/PROFILE.md、memory/MEMORY.md、memory/YYYY-MM-DD.md）\n\
+     - memory_get：读取记忆文件的指定行范围\n\
+     - web_search：搜索互联网获取实时信息\n\
+     - note_write：写入持久化笔记，记录用户的重要信息\n\
+     - note_read：读取之前写的笔记\n\
+     - file_read：读取工作区文件\n\
+     - file_write：在工作区创建或写入文件（memory/ 目录请使用 memory_write）\n\
+     \n\
+     回忆之前的事情时：先用 memory_search 搜索，再用 memory_get 获取完整内容。\n\
+     发现用户的重要新信息时，优先用 memory_write 写入持久记忆。\n\
+     用 note_write 记录短期笔记（偏好、经历、情绪状态等）。";
+
+/// The character's prompt text, split around the chat history.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SystemPrompt {
+    /// The system prompt, sent before the history.
+    pub main: String,
+    /// `post_history_instructions`, sent after the history.
+    pub post_history: Option<String>,
 }
 
 impl CharacterCard {
-    pub fn load(path: &std::path::Path) -> Result<Self, Box<dyn std::error::Error>> {
-        let content = std::fs::read_to_string(path)?;
-        let card: Self = serde_json::from_str(&content)?;
+    /// Load a card from JSON or from a PNG with an embedded card.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let bytes = std::fs::read(path)?;
+        if character_png::is_png(&bytes) {
+            return Ok(Self::from_png(&bytes)?);
+        }
+        let card: Self = serde_json::from_slice(&bytes)?;
         Ok(card)
     }
 
+    pub fn from_png(png: &[u8]) -> Result<Self, character_png::CardPngError> {
+        Ok(serde_json::from_value(character_png::read_card(png)?)?)
+    }
+
+    /// `image` with this card embedded; a placeholder image when `None`.
+    pub fn to_png(&self, image: Option<&[u8]>) -> Result<Vec<u8>, character_png::CardPngError> {
+        let placeholder;
+        let image = match image {
+            Some(image) => image,
+            None => {
+                placeholder = character_png::placeholder(&self.data.name);
+                &placeholder
+            }
+        };
+        character_png::write_card(image, &serde_json::to_value(self)?)
+    }
+
+    /// Write this card as a PNG over its avatar, if it has one.
+    pub fn save_png(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let avatar = self.avatar().and_then(|p| std::fs::read(p).ok());
+        std::fs::write(path, self.to_png(avatar.as_deref())?)?;
+        Ok(())
+    }
+
+    /// Copy the card at `path` into `characters_dir()` as `<name>.json`; a PNG
+    /// card also leaves its image there as `<name>.png`, the avatar.
+    pub fn install(path: &Path) -> Result<(Self, PathBuf), Box<dyn std::error::Error>> {
+        let bytes = std::fs::read(path)?;
+        let card = if character_png::is_png(&bytes) {
+            Self::from_png(&bytes)?
+        } else {
+            serde_json::from_slice(&bytes)?
+        };
+        let target = characters_dir().join(format!("{}.json", file_stem(&card.data.name)));
+        std::fs::write(&target, serde_json::to_string_pretty(&card)?)?;
+        if character_png::is_png(&bytes) {
+            std::fs::write(target.with_extension("png"), &bytes)?;
+        }
+        Ok((card, target))
+    }
+
+    /// The avatar image kept next to the installed card, if any.
+    pub fn avatar(&self) -> Option<PathBuf> {
+        let path = characters_dir().join(format!("{}.png", file_stem(&self.data.name)));
+        path.exists().then_some(path)
+    }
+
     /// Load the default character from config/default_character.json (embedded).
     pub fn default_character() -> Self {
         let json = include_str!("../../limerence-tui/../../config/default_character.json");
         serde_json::from_str(json).expect("default character card should be valid JSON")
     }
 
-    /// Build the system prompt from character card fields.
-    pub fn build_system_prompt(&self) -> String {
+    /// The card named `name`: one in `~/.limerence/characters/` (JSON or PNG),
+    /// or the default character.
+    pub fn find_by_name(name: &str) -> Option<Self> {
+        std::fs::read_dir(crate::config::characters_dir())
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|e| e == "json" || e == "png"))
+            .filter_map(|path| Self::load(&path).ok())
+            .chain(std::iter::once(Self::default_character()))
+            .find(|card| card.data.name == name)
+    }
+
+    /// Sampler overrides from `extensions.limerence.sampling`, if the card sets any.
+    pub fn sampling_overrides(&self) -> SamplingParams {
+        self.data
+            .extensions
+            .pointer("/limerence/sampling")
+            .and_then(|v| serde_json::from_value(v.clone()).ok())
+            .unwrap_or_default()
+    }
+
+    /// Spec fields holding the wrong type and fields the spec does not have,
+    /// at every level of the card. The card still loads either way.
+    pub fn validate(&self) -> Vec<CardIssue> {
+        let mut issues = Vec::new();
+        for key in self.extra.keys() {
+            if !V1_FIELDS.contains(&key.as_str()) {
+                issues.push(CardIssue {
+                    path: key.clone(),
+                    problem: CardProblem::Unknown,
+                });
+            }
+        }
+        extra_issues("data", &self.data.extra, CharacterData::FIELDS, &mut issues);
+        if let Some(book) = &self.data.character_book {
+            book.validate("data.character_book", &mut issues);
+        }
+        issues
+    }
+
+    /// Build the prompt from character card fields.
+    pub fn build_system_prompt(&self) -> SystemPrompt {
+        self.build_system_prompt_with_lore(&Activation::default())
+    }
+
+    /// Build the prompt with triggered lorebook entries in their slots:
+    /// before the description and after the scenario, as SillyTavern does.
+    pub fn build_system_prompt_with_lore(&self, lore: &Activation) -> SystemPrompt {
         let d = &self.data;
         let mut parts = Vec::new();
 
@@ -60,6 +416,9 @@ impl CharacterCard {
 
         parts.push(format!("你的名字是{}。", d.name));
 
+        if let Some(before) = lore.before_char() {
+            parts.push(before);
+        }
         if !d.description.is_empty() {
             parts.push(format!("角色描述：{}", d.description));
         }
@@ -69,28 +428,140 @@ impl CharacterCard {
         if !d.scenario.is_empty() {
             parts.push(format!("场景设定：{}", d.scenario));
         }
+        if let Some(after) = lore.after_char() {
+            parts.push(after);
+        }
         if !d.mes_example.is_empty() {
             parts.push(format!("对话示例：\n{}", d.mes_example));
         }
 
         // Append tool usage instructions
-        parts.push(
-            "你可以使用以下工具来增强对话体验：\n\
-             - memory_search：搜索历史对话和持久记忆文件\n\
-             - memory_write：写入持久记忆文件（memory/PROFILE.md、memory/MEMORY.md、memory/YYYY-MM-DD.md）\n\
-             - memory_get：读取记忆文件的指定行范围\n\
-             - web_search：搜索互联网获取实时信息\n\
-             - note_write：写入持久化笔记，记录用户的重要信息\n\
-             - note_read：读取之前写的笔记\n\
-             - file_read：读取工作区文件\n\
-             - file_write：在工作区创建或写入文件（memory/ 目录请使用 memory_write）\n\
-             \n\
-             回忆之前的事情时：先用 memory_search 搜索，再用 memory_get 获取完整内容。\n\
-             发现用户的重要新信息时，优先用 memory_write 写入持久记忆。\n\
-             用 note_write 记录短期笔记（偏好、经历、情绪状态等）。"
-                .to_string(),
+        parts.push(TOOL_INSTRUCTIONS.to_string());
+
+        // With no global instructions to stand in for, `{{original}}` is empty.
+        let post_history = d.post_history_instructions.replace("{{original}}", "");
+        SystemPrompt {
+            main: parts.join("\n\n"),
+            post_history: (!post_history.trim().is_empty())
+                .then(|| post_history.trim().to_string()),
+        }
+    }
+}
+
+/// `name` made safe to use as a file name.
+fn file_stem(name: &str) -> String {
+    let stem: String = name
+        .trim()
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    if stem.is_empty() || stem.starts_with('.') {
+        format!("_{stem}")
+    } else {
+        stem
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_json() -> Value {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../角色卡示例/json");
+        let path = std::fs::read_dir(dir)
+            .expect("sample dir")
+            .flatten()
+            .next()
+            .expect("a sample card")
+            .path();
+        serde_json::from_slice(&std::fs::read(path).expect("read sample")).expect("sample JSON")
+    }
+
+    #[test]
+    fn sample_card_round_trips_losslessly() {
+        let original = sample_json();
+        let card: CharacterCard = serde_json::from_value(original.clone()).expect("card");
+        let d = &card.data;
+        assert_eq!(
+            d.alternate_greetings.len(),
+            original["data"]["alternate_greetings"]
+                .as_array()
+                .unwrap()
+                .len()
         );
+        let book = d.character_book.as_ref().expect("character book");
+        assert_eq!(
+            book.entries.len(),
+            original["data"]["character_book"]["entries"]
+                .as_array()
+                .unwrap()
+                .len()
+        );
+        assert!(d.group_only_greetings.is_some());
+
+        assert_eq!(serde_json::to_value(&card).expect("serialize"), original);
+    }
+
+    #[test]
+    fn validate_reports_unknown_and_malformed_fields_without_failing_the_load() {
+        let card: CharacterCard = serde_json::from_value(serde_json::json!({
+            "spec": "chara_card_v3",
+            "spec_version": "3.0",
+            "avatar": "none",
+            "mystery": 1,
+            "data": {
+                "name": "苏晚",
+                "tags": "温柔",
+                "moods": [],
+                "character_book": {
+                    "scan_depth": -1,
+                    "entries": [
+                        {"keys": ["猫"], "content": "团子是一只橘猫", "comment": ["备注"]},
+                    ],
+                },
+            },
+        }))
+        .expect("card with bad fields still loads");
+        assert!(card.data.tags.is_empty());
+        assert_eq!(
+            card.data.character_book.as_ref().unwrap().entries[0].content,
+            "团子是一只橘猫"
+        );
+
+        let mut issues: Vec<String> = card.validate().iter().map(ToString::to_string).collect();
+        issues.sort();
+        assert_eq!(
+            issues,
+            vec![
+                "data.character_book.entries[0].comment: malformed, expected string",
+                "data.character_book.scan_depth: malformed, expected non-negative integer",
+                "data.moods: unknown field",
+                "data.tags: malformed, expected array of strings",
+                "mystery: unknown field",
+            ]
+        );
+
+        // Malformed values are written back as they were.
+        let json = serde_json::to_value(&card).expect("serialize");
+        assert_eq!(json["data"]["tags"], "温柔");
+        assert_eq!(
+            json["data"]["character_book"]["entries"][0]["comment"],
+            serde_json::json!(["备注"])
+        );
+    }
+
+    #[test]
+    fn post_history_instructions_are_kept_out_of_the_main_prompt() {
+        let mut card = CharacterCard::default_character();
+        assert_eq!(card.build_system_prompt().post_history, None);
 
-        parts.join("\n\n")
+        card.data.post_history_instructions = "{{original}}\n用中文回答。".to_string();
+        let prompt = card.build_system_prompt();
+        assert_eq!(prompt.post_history.as_deref(), Some("用中文回答。"));
+        assert!(!prompt.main.contains("用中文回答"));
     }
 }
@@ -0,0 +1,310 @@
+//! SillyTavern character cards embedded in PNG images: the card JSON, base64
+//! encoded, in a `chara` (V2) or `ccv3` (V3) tEXt chunk.
+
+use base64::Engine;
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use serde_json::Value;
+
+const PNG_SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+
+/// Cards in the wild come with and without base64 padding.
+const LENIENT_BASE64: GeneralPurpose = GeneralPurpose::new(
+    &base64::alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+#[derive(Debug, thiserror::Error)]
+pub enum CardPngError {
+    #[error("not a PNG image")]
+    NotPng,
+    #[error("no character card in the PNG")]
+    NoCard,
+    #[error("invalid card JSON: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+pub fn is_png(bytes: &[u8]) -> bool {
+    bytes.starts_with(PNG_SIGNATURE)
+}
+
+struct Chunk<'a> {
+    kind: &'a [u8],
+    data: &'a [u8],
+}
+
+/// Chunks up to and including IEND; a truncated tail is ignored.
+fn chunks(png: &[u8]) -> Vec<Chunk<'_>> {
+    let mut out = Vec::new();
+    let mut offset = PNG_SIGNATURE.len();
+    while offset + 12 <= png.len() {
+        let length = u32::from_be_bytes(png[offset..offset + 4].try_into().unwrap()) as usize;
+        let Some(end) = (offset + 12)
+            .checked_add(length)
+            .filter(|&end| end <= png.len())
+        else {
+            break;
+        };
+        let chunk = Chunk {
+            kind: &png[offset + 4..offset + 8],
+            data: &png[offset + 8..offset + 8 + length],
+        };
+        let is_end = chunk.kind == b"IEND";
+        out.push(chunk);
+        offset = end;
+        if is_end {
+            break;
+        }
+    }
+    out
+}
+
+/// Keyword and text of a tEXt chunk.
+fn text_chunk<'a>(chunk: &Chunk<'a>) -> Option<(&'a [u8], &'a [u8])> {
+    if chunk.kind != b"tEXt" {
+        return None;
+    }
+    let nul = chunk.data.iter().position(|&b| b == 0)?;
+    Some((&chunk.data[..nul], &chunk.data[nul + 1..]))
+}
+
+fn is_card_keyword(keyword: &[u8]) -> bool {
+    keyword.eq_ignore_ascii_case(b"chara") || keyword.eq_ignore_ascii_case(b"ccv3")
+}
+
+fn decode_card(text: &[u8]) -> Option<Value> {
+    let text: Vec<u8> = text
+        .iter()
+        .filter(|b| !b.is_ascii_whitespace())
+        .map(|&b| match b {
+            b'-' => b'+',
+            b'_' => b'/',
+            b => b,
+        })
+        .collect();
+    let json = LENIENT_BASE64.decode(text).ok()?;
+    serde_json::from_slice(&json).ok()
+}
+
+/// The card JSON stored in `png`. A `ccv3` chunk wins over `chara`.
+pub fn read_card(png: &[u8]) -> Result<Value, CardPngError> {
+    if !is_png(png) {
+        return Err(CardPngError::NotPng);
+    }
+    let mut v2 = None;
+    let mut v3 = None;
+    for chunk in chunks(png) {
+        let Some((keyword, text)) = text_chunk(&chunk) else {
+            continue;
+        };
+        if keyword.eq_ignore_ascii_case(b"ccv3") {
+            v3 = v3.or_else(|| decode_card(text));
+        } else if keyword.eq_ignore_ascii_case(b"chara") {
+            v2 = v2.or_else(|| decode_card(text));
+        }
+    }
+    v3.or(v2).ok_or(CardPngError::NoCard)
+}
+
+/// `png` with `card` stored in a `chara` tEXt chunk, replacing any card it
+/// already carried.
+pub fn write_card(png: &[u8], card: &Value) -> Result<Vec<u8>, CardPngError> {
+    if !is_png(png) {
+        return Err(CardPngError::NotPng);
+    }
+    let json = serde_json::to_vec(card)?;
+    let mut text = b"chara\0".to_vec();
+    text.extend_from_slice(
+        base64::engine::general_purpose::STANDARD
+            .encode(json)
+            .as_bytes(),
+    );
+
+    let mut out = PNG_SIGNATURE.to_vec();
+    let mut written = false;
+    for chunk in chunks(png) {
+        if text_chunk(&chunk).is_some_and(|(keyword, _)| is_card_keyword(keyword)) {
+            continue;
+        }
+        if chunk.kind == b"IEND" {
+            push_chunk(&mut out, b"tEXt", &text);
+            written = true;
+        }
+        push_chunk(&mut out, chunk.kind, chunk.data);
+    }
+    if !written {
+        push_chunk(&mut out, b"tEXt", &text);
+        push_chunk(&mut out, b"IEND", &[]);
+    }
+    Ok(out)
+}
+
+fn push_chunk(out: &mut Vec<u8>, kind: &[u8], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &b in bytes {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// A 256×256 PNG in a color derived from `name`, for cards without an avatar.
+pub fn placeholder(name: &str) -> Vec<u8> {
+    const SIZE: u32 = 256;
+    let hash = name
+        .chars()
+        .fold(0u32, |h, c| h.wrapping_mul(31).wrapping_add(c as u32));
+    let [r, g, b] = hsl_to_rgb((hash % 360) as f64, 0.45, 0.55);
+
+    // Each scanline: filter type 0 (none), then the pixels.
+    let mut row = vec![0];
+    for _ in 0..SIZE {
+        row.extend_from_slice(&[r, g, b]);
+    }
+    let pixels = row.repeat(SIZE as usize);
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&SIZE.to_be_bytes());
+    header.extend_from_slice(&SIZE.to_be_bytes());
+    header.extend_from_slice(&[8, 2, 0, 0, 0]); // 8-bit RGB
+
+    let mut out = PNG_SIGNATURE.to_vec();
+    push_chunk(&mut out, b"IHDR", &header);
+    push_chunk(&mut out, b"IDAT", &zlib_stored(&pixels));
+    push_chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+/// `data` as a zlib stream of uncompressed deflate blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = data.chunks(0xffff).collect();
+    for (i, block) in blocks.iter().enumerate() {
+        out.push(u8::from(i + 1 == blocks.len()));
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    out.extend_from_slice(&((b << 16) | a).to_be_bytes());
+    out
+}
+
+fn hsl_to_rgb(h: f64, s: f64, l: f64) -> [u8; 3] {
+    let c = (1.0 - (2.0 * l - 1.0).abs()) * s;
+    let x = c * (1.0 - ((h / 60.0) % 2.0 - 1.0).abs());
+    let m = l - c / 2.0;
+    let (r, g, b) = match (h / 60.0) as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    [r, g, b].map(|v| ((v + m) * 255.0).round() as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn reads_the_sample_cards() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("../../角色卡示例/图片");
+        for entry in std::fs::read_dir(dir).expect("sample dir").flatten() {
+            let png = std::fs::read(entry.path()).expect("read sample");
+            let card = read_card(&png).expect("card in sample");
+            assert_eq!(card["spec"], "chara_card_v3");
+            assert!(card["data"]["name"].is_string());
+        }
+        assert!(matches!(read_card(b"GIF89a"), Err(CardPngError::NotPng)));
+        assert!(matches!(
+            read_card(&placeholder("苏晚")),
+            Err(CardPngError::NoCard)
+        ));
+    }
+
+    #[test]
+    fn written_cards_replace_old_ones_and_read_back() {
+        let old = write_card(&placeholder("旧"), &json!({"data": {"name": "旧"}})).expect("write");
+        let card =
+            json!({"spec": "chara_card_v2", "data": {"name": "苏晚", "description": "心理咨询师"}});
+        let png = write_card(&old, &card).expect("rewrite");
+
+        assert_eq!(read_card(&png).expect("read"), card);
+        let cards = chunks(&png)
+            .iter()
+            .filter(|c| text_chunk(c).is_some_and(|(k, _)| is_card_keyword(k)))
+            .count();
+        assert_eq!(cards, 1);
+        assert_eq!(chunks(&png).last().map(|c| c.kind), Some(&b"IEND"[..]));
+    }
+
+    #[test]
+    fn ccv3_wins_over_chara() {
+        let mut png =
+            write_card(&placeholder("苏晚"), &json!({"data": {"name": "v2"}})).expect("write");
+        let v3 = format!(
+            "ccv3\0{}",
+            base64::engine::general_purpose::STANDARD.encode(r#"{"data":{"name":"v3"}}"#)
+        );
+        let iend = png.len() - 12;
+        let mut tail = png.split_off(iend);
+        push_chunk(&mut png, b"tEXt", v3.as_bytes());
+        png.append(&mut tail);
+        assert_eq!(read_card(&png).expect("read")["data"]["name"], "v3");
+    }
+
+    #[test]
+    fn cards_round_trip_with_fields_this_crate_ignores() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("../../角色卡示例/图片");
+        let sample = std::fs::read_dir(dir)
+            .expect("sample dir")
+            .flatten()
+            .next()
+            .expect("a sample card");
+        let png = std::fs::read(sample.path()).expect("read sample");
+        let card = crate::CharacterCard::from_png(&png).expect("card");
+        assert!(card.data.character_book.is_some());
+
+        let again = crate::CharacterCard::from_png(&card.to_png(Some(&png)).expect("to png"))
+            .expect("card again");
+        assert_eq!(
+            serde_json::to_value(&again).unwrap(),
+            serde_json::to_value(&card).unwrap()
+        );
+        let fresh = card.to_png(None).expect("placeholder card");
+        assert_eq!(
+            crate::CharacterCard::from_png(&fresh).unwrap().data.name,
+            card.data.name
+        );
+    }
+
+    #[test]
+    fn crc_and_adler_match_known_values() {
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+        let zlib = zlib_stored(b"Wikipedia");
+        assert_eq!(&zlib[zlib.len() - 4..], &0x11e6_0398u32.to_be_bytes());
+    }
+}
@@ -0,0 +1,154 @@
+//! Conversation compaction: older turns are summarized by the model and the
+//! summary stands in for them in later prompts. The turns themselves stay in
+//! the session file.
+
+use limerence_ai::Message;
+use serde::{Deserialize, Serialize};
+
+/// Longest stretch of a single message quoted in the transcript to summarize.
+const TRANSCRIPT_MESSAGE_CHARS: usize = 500;
+
+pub const SUMMARY_SYSTEM_PROMPT: &str = "你负责为一段角色扮演对话写摘要，供之后的对话参考。\
+请用简洁的中文要点概括：用户的偏好和个人信息、已发生的关键事件和决定、人物关系与情感变化、\
+尚未结束的话题。只根据给出的内容写，不要编造，不要续写对话。";
+
+/// When and how much history to compact.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompactionConfig {
+    pub enabled: bool,
+    /// Fraction of the prompt budget the history may fill before compacting,
+    /// counting only what the system prompt, preset and lore leave over.
+    pub threshold: f64,
+    /// Messages kept verbatim after the cut (rounded up to a whole turn).
+    pub keep_recent: usize,
+    /// Upper bound on the summary's length, in tokens.
+    pub summary_max_tokens: u32,
+    /// Also append each summary to `memory/MEMORY.md`.
+    pub write_memory: bool,
+    /// Compaction is skipped when the messages it would replace come to
+    /// fewer tokens than this.
+    pub min_saved_tokens: u32,
+}
+
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            threshold: 0.8,
+            keep_recent: 10,
+            summary_max_tokens: 1024,
+            write_memory: false,
+            min_saved_tokens: 2048,
+        }
+    }
+}
+
+/// Index of the first message to keep: the latest user message that leaves at
+/// least `keep_recent` messages, so no turn (and no tool call) is split.
+/// `None` when there is nothing before it to compact.
+pub fn cut_index(messages: &[Message], keep_recent: usize) -> Option<usize> {
+    // The newest message is always kept.
+    let latest = messages.len().checked_sub(keep_recent.max(1))?;
+    (1..=latest)
+        .rev()
+        .find(|&i| matches!(messages[i], Message::User { .. }))
+}
+
+/// The text handed to the summarizer: the previous summary, if any, followed
+/// by the user and assistant lines of the compacted messages. Tool traffic is
+/// left out; what the assistant made of it is in its replies.
+pub fn transcript(previous_summary: Option<&str>, messages: &[Message]) -> String {
+    let mut parts = Vec::new();
+    if let Some(summary) = previous_summary {
+        parts.push(format!("[之前的摘要]\n{summary}\n\n[之后的对话]"));
+    }
+    for msg in messages {
+        let label = match msg {
+            Message::User { .. } => "用户",
+            Message::Assistant { .. } => "助手",
+            _ => continue,
+        };
+        let text = msg.content_text();
+        let text = text.trim();
+        if text.is_empty() {
+            continue;
+        }
+        if text.chars().count() > TRANSCRIPT_MESSAGE_CHARS {
+            let head: String = text.chars().take(TRANSCRIPT_MESSAGE_CHARS).collect();
+            parts.push(format!("{label}: {head}..."));
+        } else {
+            parts.push(format!("{label}: {text}"));
+        }
+    }
+    parts.join("\n")
+}
+
+/// How a summary is shown to the model inside the system prompt.
+pub fn summary_injection(summary: &str) -> String {
+    format!("[较早对话的摘要]\n{summary}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use limerence_ai::{FunctionCall, ToolCall};
+
+    fn turn(n: usize) -> Vec<Message> {
+        vec![
+            Message::user(format!("问题 {n}")),
+            Message::assistant(format!("回答 {n}")),
+        ]
+    }
+
+    #[test]
+    fn cut_lands_on_a_user_message_and_keeps_at_least_keep_recent() {
+        let mut messages: Vec<Message> = (0..5).flat_map(turn).collect();
+        assert_eq!(cut_index(&messages, 4), Some(6));
+        assert_eq!(cut_index(&messages, 3), Some(6));
+        // The newest message is kept even with `keep_recent = 0`.
+        assert_eq!(cut_index(&messages, 0), Some(8));
+
+        // A tool exchange at the cut point moves the cut back to its user message.
+        messages.splice(
+            7..7,
+            [
+                Message::assistant_with_tools(
+                    "",
+                    vec![ToolCall {
+                        id: "c1".to_string(),
+                        function: FunctionCall {
+                            name: "memory_get".to_string(),
+                            arguments: "{}".to_string(),
+                        },
+                    }],
+                ),
+                Message::tool_result("c1", "无"),
+            ],
+        );
+        assert_eq!(cut_index(&messages, 5), Some(6));
+        assert!(matches!(messages[6], Message::User { .. }));
+    }
+
+    #[test]
+    fn nothing_to_cut_when_history_is_short() {
+        let messages: Vec<Message> = (0..2).flat_map(turn).collect();
+        assert_eq!(cut_index(&messages, 4), None);
+        assert_eq!(cut_index(&messages, 10), None);
+    }
+
+    #[test]
+    fn transcript_carries_the_previous_summary_and_skips_tool_results() {
+        let messages = vec![
+            Message::user("我养了一只猫"),
+            Message::tool_result("c1", "不该出现"),
+            Message::assistant("它叫什么名字？"),
+            Message::user("长".repeat(600)),
+        ];
+        let text = transcript(Some("用户住在杭州。"), &messages);
+        assert!(text.starts_with("[之前的摘要]\n用户住在杭州。"));
+        assert!(text.contains("用户: 我养了一只猫\n助手: 它叫什么名字？"));
+        assert!(!text.contains("不该出现"));
+        assert!(text.ends_with(&format!("{}...", "长".repeat(500))));
+    }
+}
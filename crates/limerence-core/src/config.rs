@@ -1,11 +1,29 @@
+use limerence_ai::{ProviderKind, RetryPolicy, SamplingParams, Timeouts};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::PathBuf;
 
+use crate::compaction::CompactionConfig;
+use crate::context::ContextLimits;
+use crate::usage::ModelPricing;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub model: ModelConfig,
     #[serde(default)]
     pub search: SearchConfig,
+    #[serde(default)]
+    pub tools: ToolConfig,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    #[serde(default)]
+    pub fallback: FallbackConfig,
+    #[serde(default)]
+    pub compaction: CompactionConfig,
+    #[serde(default)]
+    pub preset: PresetConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -13,6 +31,47 @@ pub struct ModelConfig {
     pub id: String,
     pub base_url: String,
     pub api_key_env: String,
+    /// Wire protocol: `openai` (default), `anthropic` or `gemini`.
+    #[serde(default)]
+    pub provider: ProviderKind,
+    /// Per-million-token prices used for cost accounting.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pricing: Option<ModelPricing>,
+    /// Default sampler settings; presets and character cards may override them.
+    #[serde(default, skip_serializing_if = "SamplingParams::is_empty")]
+    pub sampling: SamplingParams,
+    /// Context window in tokens; defaults to the known size for `id`, else 128k.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_context: Option<u32>,
+    /// Tokens kept free for the reply; defaults to `sampling.max_tokens`, else 4096.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reserved_output: Option<u32>,
+    /// Request token usage in streamed replies; defaults to on only for api.openai.com.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream_usage: Option<bool>,
+}
+
+/// Models tried in order when the primary model fails.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FallbackConfig {
+    /// Seconds to stay on a fallback before trying the primary again.
+    #[serde(default = "default_fallback_cooldown")]
+    pub cooldown_secs: u64,
+    #[serde(default)]
+    pub models: Vec<ModelConfig>,
+}
+
+fn default_fallback_cooldown() -> u64 {
+    300
+}
+
+impl Default for FallbackConfig {
+    fn default() -> Self {
+        Self {
+            cooldown_secs: default_fallback_cooldown(),
+            models: Vec::new(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -35,6 +94,50 @@ impl Default for SearchConfig {
     }
 }
 
+/// Which SillyTavern preset (a name in `presets_dir()` or a path) each
+/// character is prompted with.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PresetConfig {
+    /// For characters without their own entry; none means the built-in prompt.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default: Option<String>,
+    /// Character name to preset.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub characters: HashMap<String, String>,
+}
+
+impl PresetConfig {
+    pub fn for_character(&self, name: &str) -> Option<&str> {
+        self.characters
+            .get(name)
+            .or(self.default.as_ref())
+            .map(String::as_str)
+            .filter(|p| !p.trim().is_empty())
+    }
+}
+
+/// Tool execution settings.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ToolConfig {
+    /// A tool still running after this long is abandoned with an error result.
+    /// 0 means no limit.
+    pub timeout_ms: u64,
+    /// Tool rounds allowed per user turn before the model must answer without
+    /// tools. 0 means no limit.
+    pub max_rounds: u32,
+}
+
+impl Default for ToolConfig {
+    fn default() -> Self {
+        Self {
+            timeout_ms: 30_000,
+            max_rounds: 8,
+        }
+    }
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self {
@@ -42,8 +145,20 @@ impl Default for Config {
                 id: "deepseek-chat".to_string(),
                 base_url: "https://api.deepseek.com/v1".to_string(),
                 api_key_env: "DEEPSEEK_API_KEY".to_string(),
+                provider: ProviderKind::OpenAi,
+                pricing: None,
+                sampling: SamplingParams::default(),
+                max_context: None,
+                reserved_output: None,
+                stream_usage: None,
             },
             search: SearchConfig::default(),
+            tools: ToolConfig::default(),
+            retry: RetryPolicy::default(),
+            timeouts: Timeouts::default(),
+            fallback: FallbackConfig::default(),
+            compaction: CompactionConfig::default(),
+            preset: PresetConfig::default(),
         }
     }
 }
@@ -63,11 +178,50 @@ impl Config {
         }
     }
 
+    pub fn to_model(&self) -> limerence_ai::Model {
+        self.model.to_model()
+    }
+
+    /// Pricing for every model in the chain that has it, keyed by model id.
+    pub fn model_pricing(&self) -> HashMap<String, ModelPricing> {
+        std::iter::once(&self.model)
+            .chain(&self.fallback.models)
+            .filter_map(|m| Some((m.id.clone(), m.pricing?)))
+            .collect()
+    }
+
+    /// Context limits of every model in the chain, keyed by model id.
+    pub fn context_limits(&self) -> HashMap<String, ContextLimits> {
+        std::iter::once(&self.model)
+            .chain(&self.fallback.models)
+            .map(|m| {
+                let limits = ContextLimits {
+                    max_context: m.max_context,
+                    reserved_output: m.reserved_output,
+                };
+                (m.id.clone(), limits)
+            })
+            .collect()
+    }
+
+    /// The primary model followed by the configured fallbacks.
+    pub fn model_chain(&self) -> Vec<limerence_ai::Model> {
+        std::iter::once(&self.model)
+            .chain(&self.fallback.models)
+            .map(ModelConfig::to_model)
+            .collect()
+    }
+}
+
+impl ModelConfig {
     pub fn to_model(&self) -> limerence_ai::Model {
         limerence_ai::Model {
-            id: self.model.id.clone(),
-            base_url: self.model.base_url.clone(),
-            api_key_env: self.model.api_key_env.clone(),
+            id: self.id.clone(),
+            base_url: self.base_url.clone(),
+            api_key_env: self.api_key_env.clone(),
+            provider: self.provider,
+            sampling: self.sampling.clone(),
+            stream_usage: self.stream_usage,
         }
     }
 }
@@ -110,22 +264,55 @@ pub fn workspace_dir() -> PathBuf {
     d
 }
 
+pub fn blobs_dir() -> PathBuf {
+    let d = data_dir().join("blobs");
+    let _ = std::fs::create_dir_all(&d);
+    d
+}
+
+pub fn exports_dir() -> PathBuf {
+    let d = data_dir().join("exports");
+    let _ = std::fs::create_dir_all(&d);
+    d
+}
+
+/// SillyTavern chat-completion presets.
+pub fn presets_dir() -> PathBuf {
+    let d = data_dir().join("presets");
+    let _ = std::fs::create_dir_all(&d);
+    d
+}
+
+/// Global SillyTavern regex scripts.
+pub fn regex_dir() -> PathBuf {
+    let d = data_dir().join("regex");
+    let _ = std::fs::create_dir_all(&d);
+    d
+}
+
+/// Standalone world-info (lorebook) files, active for every character.
+pub fn worlds_dir() -> PathBuf {
+    let d = data_dir().join("worlds");
+    let _ = std::fs::create_dir_all(&d);
+    d
+}
+
 pub fn characters_dir() -> PathBuf {
     let d = data_dir().join("characters");
     let _ = std::fs::create_dir_all(&d);
     d
 }
 
+/// Serializes tests that change process-wide environment variables such as `LIMERENCE_HOME`.
 #[cfg(test)]
-mod tests {
-    use super::data_dir;
-    use std::sync::{Mutex, OnceLock};
-
-    static ENV_LOCK: OnceLock<Mutex<()>> = OnceLock::new();
+pub(crate) fn env_lock() -> &'static std::sync::Mutex<()> {
+    static ENV_LOCK: std::sync::OnceLock<std::sync::Mutex<()>> = std::sync::OnceLock::new();
+    ENV_LOCK.get_or_init(|| std::sync::Mutex::new(()))
+}
 
-    fn env_lock() -> &'static Mutex<()> {
-        ENV_LOCK.get_or_init(|| Mutex::new(()))
-    }
+#[cfg(test)]
+mod tests {
+    use super::{data_dir, env_lock};
 
     #[test]
     fn data_dir_uses_limerence_home_when_set() {
@@ -0,0 +1,517 @@
+//! Context window budgeting: token counting and history truncation.
+//!
+//! Counts use tiktoken's BPE tables. OpenAI models get their own encoding;
+//! other families (Claude, Gemini, DeepSeek, Qwen) have tokenizers of their
+//! own, so their counts use `cl100k_base` padded by a safety margin.
+
+use limerence_ai::{ContentPart, Message, ToolDef};
+use std::ops::Range;
+use tiktoken_rs::CoreBPE;
+
+/// Window assumed for models missing from the known table.
+pub const DEFAULT_CONTEXT_WINDOW: u32 = 128_000;
+/// Output reserve when neither the config nor `max_tokens` sets one.
+pub const DEFAULT_RESERVED_OUTPUT: u32 = 4096;
+
+/// Framing tokens added per message (role, separators).
+const MESSAGE_OVERHEAD: usize = 4;
+/// Flat estimate for one image; providers bill a large image at roughly this much.
+const IMAGE_TOKENS: usize = 1600;
+/// Percent added to counts for models whose tokenizer is not tiktoken's.
+const FOREIGN_TOKENIZER_MARGIN: usize = 15;
+/// Characters kept from the start of a message whose content is elided.
+const ELIDE_KEEP_CHARS: usize = 200;
+
+const KNOWN_CONTEXT_WINDOWS: &[(&str, u32)] = &[
+    // Anthropic
+    ("claude-opus-4", 200_000),
+    ("claude-opus-4-5", 200_000),
+    ("claude-sonnet-4", 200_000),
+    ("claude-sonnet-4-5", 200_000),
+    ("claude-haiku-4-5", 200_000),
+    ("claude-3-5-haiku", 200_000),
+    // OpenAI
+    ("gpt-4o", 128_000),
+    ("gpt-4o-mini", 128_000),
+    ("o3", 200_000),
+    ("o3-mini", 200_000),
+    ("o4-mini", 200_000),
+    // Google
+    ("gemini-2.5-pro", 1_000_000),
+    ("gemini-2.5-flash", 1_000_000),
+    ("gemini-3-flash-preview", 1_000_000),
+    // DeepSeek
+    ("deepseek-chat", 128_000),
+    ("deepseek-reasoner", 128_000),
+    // Qwen
+    ("qwen-max", 128_000),
+    ("qwen-plus", 128_000),
+    ("qwen-turbo", 128_000),
+];
+
+/// Context window of a known model id. Like `TokenCounter::for_model`, a
+/// provider prefix (`anthropic/…`) is ignored and the longest known id the
+/// model id starts with wins, so dated ids such as `gpt-4o-2024-08-06` match.
+pub fn known_context_window(model_id: &str) -> Option<u32> {
+    let id = model_id.rsplit('/').next().unwrap_or(model_id);
+    KNOWN_CONTEXT_WINDOWS
+        .iter()
+        .filter(|(known, _)| {
+            id.strip_prefix(known)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(['-', ':', '@']))
+        })
+        .max_by_key(|(known, _)| known.len())
+        .map(|&(_, window)| window)
+}
+
+/// Configured context limits of one model; unset fields are derived.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ContextLimits {
+    pub max_context: Option<u32>,
+    pub reserved_output: Option<u32>,
+}
+
+impl ContextLimits {
+    /// Budget for `model_id`, reserving `max_tokens` for the reply unless a
+    /// reserve is configured.
+    pub fn budget(&self, model_id: &str, max_tokens: Option<u32>) -> ContextBudget {
+        ContextBudget {
+            max_context: self
+                .max_context
+                .or_else(|| known_context_window(model_id))
+                .unwrap_or(DEFAULT_CONTEXT_WINDOW),
+            reserved_output: self
+                .reserved_output
+                .or(max_tokens)
+                .unwrap_or(DEFAULT_RESERVED_OUTPUT),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContextBudget {
+    pub max_context: u32,
+    pub reserved_output: u32,
+}
+
+impl ContextBudget {
+    /// Tokens available to the prompt.
+    pub fn prompt_limit(&self) -> usize {
+        self.max_context.saturating_sub(self.reserved_output) as usize
+    }
+}
+
+/// Counts prompt tokens for one model.
+#[derive(Clone, Copy)]
+pub struct TokenCounter {
+    bpe: &'static CoreBPE,
+    margin: usize,
+}
+
+impl TokenCounter {
+    pub fn for_model(model_id: &str) -> Self {
+        let id = model_id.rsplit('/').next().unwrap_or(model_id);
+        if id.starts_with("gpt-4o")
+            || id.starts_with("gpt-4.1")
+            || id.starts_with("gpt-5")
+            || id.starts_with("o1")
+            || id.starts_with("o3")
+            || id.starts_with("o4")
+        {
+            Self {
+                bpe: tiktoken_rs::o200k_base_singleton(),
+                margin: 0,
+            }
+        } else if id.starts_with("gpt-") {
+            Self {
+                bpe: tiktoken_rs::cl100k_base_singleton(),
+                margin: 0,
+            }
+        } else {
+            Self {
+                bpe: tiktoken_rs::cl100k_base_singleton(),
+                margin: FOREIGN_TOKENIZER_MARGIN,
+            }
+        }
+    }
+
+    pub fn text(&self, text: &str) -> usize {
+        let tokens = self.bpe.encode_ordinary(text).len();
+        tokens + (tokens * self.margin).div_ceil(100)
+    }
+
+    /// Tokens of one message as sent; reasoning is never sent and not counted.
+    pub fn message(&self, msg: &Message) -> usize {
+        let body = match msg {
+            Message::System { content } => self.text(content),
+            Message::User { content } => content
+                .iter()
+                .map(|part| match part {
+                    ContentPart::Text { text } => self.text(text),
+                    ContentPart::Image { .. } => IMAGE_TOKENS,
+                })
+                .sum(),
+            Message::Assistant {
+                content,
+                tool_calls,
+                ..
+            } => {
+                self.text(content)
+                    + tool_calls
+                        .iter()
+                        .map(|tc| {
+                            self.text(&tc.function.name)
+                                + self.text(&tc.function.arguments)
+                                + MESSAGE_OVERHEAD
+                        })
+                        .sum::<usize>()
+            }
+            Message::ToolResult { content, .. } => self.text(content),
+        };
+        body + MESSAGE_OVERHEAD
+    }
+
+    pub fn messages(&self, messages: &[Message]) -> usize {
+        messages.iter().map(|m| self.message(m)).sum()
+    }
+
+    /// Tokens taken by the tool declarations sent with each request.
+    pub fn tools(&self, tools: &[ToolDef]) -> usize {
+        if tools.is_empty() {
+            return 0;
+        }
+        self.text(&serde_json::to_string(tools).unwrap_or_default())
+    }
+}
+
+/// A prompt cut down to fit its budget.
+#[derive(Debug)]
+pub struct Fitted {
+    pub messages: Vec<Message>,
+    /// Messages left out of the prompt.
+    pub dropped: usize,
+    /// Messages whose content was shortened.
+    pub elided: usize,
+    pub tokens: usize,
+}
+
+/// The prompt does not fit even after dropping and eliding everything allowed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("Prompt needs {needed} tokens but only {limit} fit in the context window")]
+pub struct ContextOverflow {
+    pub needed: usize,
+    pub limit: usize,
+}
+
+/// A request's messages and where the chat history sits among them.
+#[derive(Debug, Clone)]
+pub struct Prompt {
+    pub messages: Vec<Message>,
+    /// Only these messages are dropped or elided to fit a context window.
+    pub history: Range<usize>,
+}
+
+/// Fit `messages` (leading system prompts, then history) into `limit` tokens.
+pub fn fit_messages(
+    messages: Vec<Message>,
+    limit: usize,
+    counter: &TokenCounter,
+) -> Result<Fitted, ContextOverflow> {
+    let history_start = messages
+        .iter()
+        .position(|m| !matches!(m, Message::System { .. }))
+        .unwrap_or(messages.len());
+    let history = history_start..messages.len();
+    fit_prompt(Prompt { messages, history }, limit, counter)
+}
+
+/// Fit `prompt` into `limit` tokens, trimming only its history.
+///
+/// Everything outside the history, the first history message and the latest
+/// user turn are always kept. Older turns are dropped oldest first, each turn
+/// being a user message with everything up to the next one, so a tool call
+/// never loses its result. If that is not enough, long messages in the
+/// history outside the latest user message are elided.
+pub fn fit_prompt(
+    prompt: Prompt,
+    limit: usize,
+    counter: &TokenCounter,
+) -> Result<Fitted, ContextOverflow> {
+    let Prompt { messages, history } = prompt;
+    let costs: Vec<usize> = messages.iter().map(|m| counter.message(m)).collect();
+    let total: usize = costs.iter().sum();
+    if total <= limit {
+        return Ok(Fitted {
+            messages,
+            dropped: 0,
+            elided: 0,
+            tokens: total,
+        });
+    }
+
+    let history_end = history.end.min(messages.len());
+    let history_start = history.start.min(history_end);
+    // A first message that opens a tool exchange is not pinned on its own.
+    let pinned = match messages[history_start..history_end].first() {
+        Some(Message::Assistant { tool_calls, .. }) if !tool_calls.is_empty() => history_start,
+        Some(_) => history_start + 1,
+        None => history_start,
+    };
+    let turn_starts: Vec<usize> = (pinned..history_end)
+        .filter(|&i| i == pinned || matches!(messages[i], Message::User { .. }))
+        .collect();
+
+    // Drop whole turns, oldest first, never the latest one.
+    let mut tokens = total;
+    let mut cut = pinned;
+    for pair in turn_starts.windows(2) {
+        if tokens <= limit {
+            break;
+        }
+        tokens -= costs[pair[0]..pair[1]].iter().sum::<usize>();
+        cut = pair[1];
+    }
+
+    let mut kept: Vec<Message> = Vec::with_capacity(messages.len() - (cut - pinned));
+    let mut iter = messages.into_iter();
+    kept.extend(iter.by_ref().take(pinned));
+    kept.extend(iter.skip(cut - pinned));
+    let dropped = cut - pinned;
+    let history_end = history_end - dropped;
+
+    let last_user = kept[history_start..history_end]
+        .iter()
+        .rposition(|m| matches!(m, Message::User { .. }))
+        .map(|i| history_start + i);
+    let mut elided = 0;
+    for (i, message) in kept[..history_end]
+        .iter_mut()
+        .enumerate()
+        .skip(history_start)
+    {
+        if tokens <= limit {
+            break;
+        }
+        if Some(i) == last_user {
+            continue;
+        }
+        let before = counter.message(message);
+        if elide(message) {
+            tokens = tokens - before + counter.message(message);
+            elided += 1;
+        }
+    }
+
+    if tokens > limit {
+        return Err(ContextOverflow {
+            needed: tokens,
+            limit,
+        });
+    }
+    Ok(Fitted {
+        messages: kept,
+        dropped,
+        elided,
+        tokens,
+    })
+}
+
+/// Shorten a long assistant reply or tool result to its opening. Returns
+/// whether anything was cut.
+fn elide(msg: &mut Message) -> bool {
+    let content = match msg {
+        Message::Assistant { content, .. } | Message::ToolResult { content, .. } => content,
+        _ => return false,
+    };
+    let total = content.chars().count();
+    if total <= ELIDE_KEEP_CHARS {
+        return false;
+    }
+    let head: String = content.chars().take(ELIDE_KEEP_CHARS).collect();
+    *content = format!("{head}…（已省略 {} 字）", total - ELIDE_KEEP_CHARS);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use limerence_ai::{FunctionCall, ToolCall};
+
+    fn counter() -> TokenCounter {
+        TokenCounter::for_model("gpt-4o")
+    }
+
+    fn call(id: &str) -> ToolCall {
+        ToolCall {
+            id: id.to_string(),
+            function: FunctionCall {
+                name: "memory_search".to_string(),
+                arguments: r#"{"query":"咖啡"}"#.to_string(),
+            },
+        }
+    }
+
+    /// System prompt, greeting, then `turns` user turns of which the second
+    /// makes a tool call.
+    fn conversation(turns: usize) -> Vec<Message> {
+        let mut messages = vec![
+            Message::system("你是苏晚。"),
+            Message::assistant("你好，今天想聊些什么？"),
+        ];
+        for turn in 0..turns {
+            messages.push(Message::user(format!("第 {turn} 轮：{}", "话".repeat(50))));
+            if turn == 1 {
+                messages.push(Message::assistant_with_tools("", vec![call("c1")]));
+                messages.push(Message::tool_result("c1", "用户喜欢咖啡。"));
+            }
+            messages.push(Message::assistant(format!(
+                "回答 {turn}：{}",
+                "嗯".repeat(50)
+            )));
+        }
+        messages
+    }
+
+    fn user_texts(messages: &[Message]) -> Vec<String> {
+        messages
+            .iter()
+            .filter(|m| matches!(m, Message::User { .. }))
+            .map(|m| m.content_text().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn known_windows_match_dated_and_prefixed_ids() {
+        assert_eq!(
+            known_context_window("claude-sonnet-4-5-20250929"),
+            Some(200_000)
+        );
+        assert_eq!(
+            known_context_window("anthropic/claude-sonnet-4-5"),
+            Some(200_000)
+        );
+        assert_eq!(known_context_window("gpt-4o-2024-08-06"), Some(128_000));
+        assert_eq!(
+            known_context_window("claude-3-5-haiku-20241022"),
+            Some(200_000)
+        );
+        assert_eq!(
+            known_context_window("google/gemini-2.5-flash"),
+            Some(1_000_000)
+        );
+        assert_eq!(known_context_window("o3-pro"), Some(200_000));
+        assert_eq!(known_context_window("gpt-4o1"), None);
+        assert_eq!(known_context_window("my-local-model"), None);
+    }
+
+    #[test]
+    fn budget_prefers_config_then_known_window_then_default() {
+        let limits = ContextLimits::default();
+        assert_eq!(
+            limits.budget("claude-sonnet-4-5", None),
+            ContextBudget {
+                max_context: 200_000,
+                reserved_output: DEFAULT_RESERVED_OUTPUT,
+            }
+        );
+        assert_eq!(
+            limits.budget("my-local-model", Some(1000)).prompt_limit(),
+            (DEFAULT_CONTEXT_WINDOW - 1000) as usize
+        );
+        let limits = ContextLimits {
+            max_context: Some(8192),
+            reserved_output: Some(1024),
+        };
+        assert_eq!(limits.budget("gpt-4o", Some(4096)).prompt_limit(), 7168);
+    }
+
+    #[test]
+    fn counts_tool_calls_and_pads_foreign_tokenizers() {
+        let plain = Message::assistant("");
+        let with_call = Message::assistant_with_tools("", vec![call("c1")]);
+        assert!(counter().message(&with_call) > counter().message(&plain) + 5);
+
+        let text = "今天天气很好，我们去散步吧。".repeat(20);
+        assert!(TokenCounter::for_model("deepseek-chat").text(&text) > counter().text(&text));
+    }
+
+    #[test]
+    fn prompt_under_the_limit_is_unchanged() {
+        let messages = conversation(3);
+        let fitted = fit_messages(messages.clone(), 100_000, &counter()).unwrap();
+        assert_eq!(fitted.messages.len(), messages.len());
+        assert_eq!(fitted.dropped, 0);
+        assert_eq!(fitted.tokens, counter().messages(&messages));
+    }
+
+    #[test]
+    fn drops_oldest_turns_and_keeps_system_first_message_and_latest_turn() {
+        let messages = conversation(6);
+        let counter = counter();
+        // Room for the pinned messages plus the last two turns.
+        let limit =
+            counter.messages(&messages[..2]) + counter.messages(&messages[messages.len() - 4..]);
+        let fitted = fit_messages(messages, limit, &counter).unwrap();
+
+        assert!(fitted.tokens <= limit);
+        assert_eq!(fitted.elided, 0);
+        assert_eq!(fitted.messages[0].content_text(), "你是苏晚。");
+        assert_eq!(fitted.messages[1].content_text(), "你好，今天想聊些什么？");
+        let users = user_texts(&fitted.messages);
+        assert_eq!(users.len(), 2);
+        assert!(users[0].starts_with("第 4 轮"));
+        assert!(users[1].starts_with("第 5 轮"));
+        assert_eq!(fitted.dropped, 10);
+    }
+
+    #[test]
+    fn tool_calls_and_results_are_dropped_together() {
+        let messages = conversation(4);
+        let counter = counter();
+        for limit in (counter.messages(&messages[..2])..counter.messages(&messages)).step_by(7) {
+            let Ok(fitted) = fit_messages(messages.clone(), limit, &counter) else {
+                continue;
+            };
+            let calls = fitted
+                .messages
+                .iter()
+                .filter(|m| matches!(m, Message::Assistant { tool_calls, .. } if !tool_calls.is_empty()))
+                .count();
+            let results = fitted
+                .messages
+                .iter()
+                .filter(|m| matches!(m, Message::ToolResult { .. }))
+                .count();
+            assert_eq!(calls, results, "limit {limit}");
+            assert!(fitted.tokens <= limit);
+        }
+    }
+
+    #[test]
+    fn elides_long_tool_results_in_the_latest_turn() {
+        let mut messages = conversation(1);
+        messages.push(Message::user("查一下"));
+        messages.push(Message::assistant_with_tools("", vec![call("c9")]));
+        messages.push(Message::tool_result("c9", "结果".repeat(2000)));
+        let counter = counter();
+        let limit = counter.messages(&messages[..2]) + 300;
+
+        let fitted = fit_messages(messages, limit, &counter).unwrap();
+        assert!(fitted.tokens <= limit);
+        assert_eq!(fitted.elided, 1);
+        assert_eq!(user_texts(&fitted.messages), vec!["查一下"]);
+        let result = fitted.messages.last().unwrap().content_text();
+        assert!(result.ends_with("（已省略 3800 字）"));
+    }
+
+    #[test]
+    fn reports_overflow_when_the_latest_message_alone_is_too_big() {
+        let messages = vec![
+            Message::system("你是苏晚。"),
+            Message::user("长".repeat(5000)),
+        ];
+        let err = fit_messages(messages, 500, &counter()).unwrap_err();
+        assert_eq!(err.limit, 500);
+        assert!(err.needed > 500);
+    }
+}
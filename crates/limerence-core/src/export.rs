@@ -0,0 +1,574 @@
+use chrono::{DateTime, Local, Utc};
+use limerence_ai::image::base64_source;
+use limerence_ai::{ContentPart, ImageSource, Message};
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::blob::BlobStore;
+use crate::config::exports_dir;
+use crate::session::{Session, SessionEntry};
+
+/// Name given to the user in exports.
+pub const USER_NAME: &str = "用户";
+
+/// What a session can be exported to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Markdown,
+    /// A single HTML file with styles and images inlined.
+    Html,
+    /// SillyTavern's chat JSONL, importable into ST.
+    SillyTavern,
+}
+
+impl ExportFormat {
+    /// The format a file name's extension implies.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "md" | "markdown" => Some(Self::Markdown),
+            "html" | "htm" => Some(Self::Html),
+            "jsonl" => Some(Self::SillyTavern),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Markdown => "md",
+            Self::Html => "html",
+            Self::SillyTavern => "jsonl",
+        }
+    }
+
+    /// Render the current branch of `session`.
+    pub fn render(self, session: &Session) -> String {
+        match self {
+            Self::Markdown => markdown(session),
+            Self::Html => html(session),
+            Self::SillyTavern => sillytavern(session),
+        }
+    }
+}
+
+impl std::str::FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "md" | "markdown" => Ok(Self::Markdown),
+            "html" | "htm" => Ok(Self::Html),
+            "st" | "sillytavern" | "jsonl" => Ok(Self::SillyTavern),
+            _ => Err(format!("unknown export format: {s} (md, html or st)")),
+        }
+    }
+}
+
+/// Write `session` as `format` to `path`, or to the exports directory when
+/// `path` is `None`. Returns where the file went.
+pub fn export(
+    session: &Session,
+    format: ExportFormat,
+    path: Option<&Path>,
+) -> std::io::Result<PathBuf> {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => exports_dir().join(format!(
+            "{}-{}.{}",
+            session
+                .header
+                .timestamp
+                .with_timezone(&Local)
+                .format("%Y%m%d-%H%M"),
+            &session.header.id[..8.min(session.header.id.len())],
+            format.extension()
+        )),
+    };
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&path, format.render(session))?;
+    Ok(path)
+}
+
+/// One block of a rendered conversation.
+enum Block<'a> {
+    User {
+        timestamp: DateTime<Utc>,
+        content: &'a [ContentPart],
+    },
+    Reasoning(&'a str),
+    Reply {
+        timestamp: DateTime<Utc>,
+        text: &'a str,
+    },
+    ToolCall {
+        name: &'a str,
+        arguments: &'a str,
+    },
+    ToolResult {
+        name: &'a str,
+        result: &'a str,
+    },
+}
+
+/// The current branch as display blocks; tool results carry the name of the
+/// call they answer.
+fn blocks(session: &Session) -> Vec<Block<'_>> {
+    let mut tool_names = HashMap::new();
+    let mut out = Vec::new();
+    for entry in session.branch() {
+        match &entry.message {
+            Message::System { .. } => {}
+            Message::User { content } => out.push(Block::User {
+                timestamp: entry.timestamp,
+                content,
+            }),
+            Message::Assistant {
+                content,
+                reasoning,
+                tool_calls,
+            } => {
+                if let Some(reasoning) = reasoning.as_deref().filter(|r| !r.is_empty()) {
+                    out.push(Block::Reasoning(reasoning));
+                }
+                if !content.is_empty() {
+                    out.push(Block::Reply {
+                        timestamp: entry.timestamp,
+                        text: content,
+                    });
+                }
+                for tc in tool_calls {
+                    tool_names.insert(tc.id.as_str(), tc.function.name.as_str());
+                    out.push(Block::ToolCall {
+                        name: &tc.function.name,
+                        arguments: &tc.function.arguments,
+                    });
+                }
+            }
+            Message::ToolResult {
+                tool_call_id,
+                content,
+            } => out.push(Block::ToolResult {
+                name: tool_names.get(tool_call_id.as_str()).copied().unwrap_or(""),
+                result: content,
+            }),
+        }
+    }
+    out
+}
+
+fn local_time(timestamp: DateTime<Utc>) -> impl std::fmt::Display {
+    timestamp.with_timezone(&Local).format("%Y-%m-%d %H:%M")
+}
+
+/// Where an image can be found from the exported file.
+fn image_link(source: &ImageSource) -> String {
+    match source {
+        ImageSource::Path { path } => path.display().to_string(),
+        ImageSource::Url { url } => url.clone(),
+        ImageSource::Base64 { media_type, data } => format!("data:{media_type};base64,{data}"),
+        ImageSource::Blob { sha256, .. } => BlobStore::open().path(sha256).display().to_string(),
+    }
+}
+
+/// An image as a `data:` URI, so HTML exports stay self-contained.
+fn image_data_uri(source: &ImageSource) -> Option<String> {
+    let (path, media_type) = match source {
+        ImageSource::Path { path } => (path.clone(), None),
+        ImageSource::Blob { sha256, media_type } => {
+            (BlobStore::open().path(sha256), Some(media_type.as_str()))
+        }
+        other => return Some(image_link(other)),
+    };
+    let bytes = std::fs::read(&path).ok()?;
+    let media_type = media_type
+        .or_else(|| limerence_ai::image::sniff_media_type(&bytes))
+        .unwrap_or("application/octet-stream");
+    Some(image_link(&base64_source(&bytes, media_type)))
+}
+
+/// A fenced code block that survives backticks inside `text`.
+fn fenced(text: &str, lang: &str) -> String {
+    let mut fence = "```".to_string();
+    while text.contains(&fence) {
+        fence.push('`');
+    }
+    format!("{fence}{lang}\n{text}\n{fence}\n\n")
+}
+
+/// The conversation as Markdown, tool calls and results in code blocks.
+pub fn markdown(session: &Session) -> String {
+    let header = &session.header;
+    let mut out = format!(
+        "# 与{}的会话\n\n- 会话：`{}`\n- 模型：{}\n- 开始于：{}\n\n---\n\n",
+        header.character,
+        header.id,
+        header.model,
+        local_time(header.timestamp)
+    );
+    for block in blocks(session) {
+        match block {
+            Block::User { timestamp, content } => {
+                out.push_str(&format!("### {USER_NAME} · {}\n\n", local_time(timestamp)));
+                for part in content {
+                    match part {
+                        ContentPart::Text { text } => out.push_str(&format!("{text}\n\n")),
+                        ContentPart::Image { source } => {
+                            out.push_str(&format!("![图片](<{}>)\n\n", image_link(source)))
+                        }
+                    }
+                }
+            }
+            Block::Reasoning(text) => {
+                out.push_str("> **思考过程**\n>\n");
+                for line in text.lines() {
+                    out.push_str(&format!("> {line}\n"));
+                }
+                out.push('\n');
+            }
+            Block::Reply { timestamp, text } => {
+                out.push_str(&format!(
+                    "### {} · {}\n\n{text}\n\n",
+                    header.character,
+                    local_time(timestamp)
+                ));
+            }
+            Block::ToolCall { name, arguments } => {
+                out.push_str(&format!("**调用工具** `{name}`\n\n"));
+                out.push_str(&fenced(arguments, "json"));
+            }
+            Block::ToolResult { name, result } => {
+                out.push_str(&format!("**工具结果** `{name}`\n\n"));
+                out.push_str(&fenced(result, ""));
+            }
+        }
+    }
+    out
+}
+
+fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+const HTML_STYLE: &str = "body{max-width:760px;margin:2em auto;padding:0 1em;font-family:system-ui,sans-serif;line-height:1.6;color:#222;background:#fafafa}\
+.meta{color:#777;font-size:.9em}\
+.msg{margin:1em 0;padding:.8em 1em;border-radius:8px;background:#fff;box-shadow:0 1px 2px rgba(0,0,0,.08)}\
+.msg.user{background:#eef5ff}\
+.who{font-weight:bold;margin-bottom:.3em}.who time{font-weight:normal;color:#999;font-size:.85em;margin-left:.5em}\
+.text{white-space:pre-wrap}\
+img{max-width:100%;border-radius:4px}\
+details{margin:.5em 0;color:#555}summary{cursor:pointer}\
+pre{white-space:pre-wrap;background:#f3f3f3;padding:.6em;border-radius:4px;font-size:.9em}";
+
+/// The conversation as a standalone HTML page; images are inlined.
+pub fn html(session: &Session) -> String {
+    let header = &session.header;
+    let character = escape_html(&header.character);
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html lang=\"zh\">\n<head>\n<meta charset=\"utf-8\">\n<title>与{character}的会话</title>\n<style>{HTML_STYLE}</style>\n</head>\n<body>\n<h1>与{character}的会话</h1>\n<p class=\"meta\">会话 {} · 模型 {} · 开始于 {}</p>\n",
+        escape_html(&header.id),
+        escape_html(&header.model),
+        local_time(header.timestamp)
+    );
+    for block in blocks(session) {
+        match block {
+            Block::User { timestamp, content } => {
+                out.push_str(&format!(
+                    "<div class=\"msg user\"><div class=\"who\">{USER_NAME}<time>{}</time></div>\n",
+                    local_time(timestamp)
+                ));
+                for part in content {
+                    match part {
+                        ContentPart::Text { text } => out.push_str(&format!(
+                            "<div class=\"text\">{}</div>\n",
+                            escape_html(text)
+                        )),
+                        ContentPart::Image { source } => match image_data_uri(source) {
+                            Some(src) => out.push_str(&format!(
+                                "<img src=\"{}\" alt=\"图片\">\n",
+                                escape_html(&src)
+                            )),
+                            None => out.push_str("<div class=\"text\">[图片]</div>\n"),
+                        },
+                    }
+                }
+                out.push_str("</div>\n");
+            }
+            Block::Reasoning(text) => out.push_str(&format!(
+                "<details><summary>思考过程</summary><div class=\"text\">{}</div></details>\n",
+                escape_html(text)
+            )),
+            Block::Reply { timestamp, text } => out.push_str(&format!(
+                "<div class=\"msg\"><div class=\"who\">{character}<time>{}</time></div>\n<div class=\"text\">{}</div></div>\n",
+                local_time(timestamp),
+                escape_html(text)
+            )),
+            Block::ToolCall { name, arguments } => out.push_str(&format!(
+                "<details><summary>调用工具 <code>{}</code></summary><pre>{}</pre></details>\n",
+                escape_html(name),
+                escape_html(arguments)
+            )),
+            Block::ToolResult { name, result } => out.push_str(&format!(
+                "<details><summary>工具结果 <code>{}</code></summary><pre>{}</pre></details>\n",
+                escape_html(name),
+                escape_html(result)
+            )),
+        }
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+/// SillyTavern's `send_date`, e.g. `October 17, 2026 4:52am`.
+fn st_send_date(timestamp: DateTime<Utc>) -> String {
+    timestamp
+        .with_timezone(&Local)
+        .format("%B %-d, %Y %-I:%M%P")
+        .to_string()
+}
+
+/// Text of a user message for ST, with images as placeholders.
+fn st_user_text(content: &[ContentPart]) -> String {
+    content
+        .iter()
+        .map(|part| part.as_text().unwrap_or("[图片]"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The reply text of a run of non-user entries: its assistant texts, joined.
+/// Tool calls and results have no place in an ST chat and are left out.
+fn st_reply_text<'a>(run: impl IntoIterator<Item = &'a SessionEntry>) -> String {
+    run.into_iter()
+        .filter_map(|e| match &e.message {
+            Message::Assistant { content, .. } if !content.is_empty() => Some(content.as_str()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// The run of non-user entries starting at `start`, following the newest
+/// child at every fork.
+fn latest_run<'a>(session: &'a Session, start: &'a SessionEntry) -> Vec<&'a SessionEntry> {
+    let mut run = vec![start];
+    while let Some(next) = session
+        .children(Some(&run[run.len() - 1].id))
+        .into_iter()
+        .rfind(|e| !matches!(e.message, Message::User { .. }))
+    {
+        run.push(next);
+    }
+    run
+}
+
+/// The conversation in SillyTavern's chat JSONL: a metadata line, then one
+/// line per message. Each character reply carries its regenerated
+/// alternatives as swipes.
+pub fn sillytavern(session: &Session) -> String {
+    let header = &session.header;
+    let metadata = json!({
+        "user_name": USER_NAME,
+        "character_name": header.character,
+        "create_date": header
+            .timestamp
+            .with_timezone(&Local)
+            .format("%Y-%-m-%-d @%Hh %Mm %Ss %3fms")
+            .to_string(),
+        "chat_metadata": {},
+    });
+    let mut lines = vec![metadata.to_string()];
+
+    let branch = session.branch();
+    let mut i = 0;
+    while i < branch.len() {
+        let entry = branch[i];
+        if let Message::User { content } = &entry.message {
+            lines.push(
+                json!({
+                    "name": USER_NAME,
+                    "is_user": true,
+                    "is_system": false,
+                    "send_date": st_send_date(entry.timestamp),
+                    "mes": st_user_text(content),
+                    "extra": {},
+                })
+                .to_string(),
+            );
+            i += 1;
+            continue;
+        }
+
+        let end = branch[i..]
+            .iter()
+            .position(|e| matches!(e.message, Message::User { .. }))
+            .map_or(branch.len(), |n| i + n);
+        let run = &branch[i..end];
+        i = end;
+        let mes = st_reply_text(run.iter().copied());
+        if mes.is_empty() {
+            continue;
+        }
+
+        // Swipes are the replies that start beside this one.
+        let mut swipes = Vec::new();
+        let mut swipe_id = 0;
+        for alternative in session.children(entry.parent_id.as_deref()) {
+            if alternative.id == entry.id {
+                swipe_id = swipes.len();
+                swipes.push(mes.clone());
+            } else if !matches!(alternative.message, Message::User { .. }) {
+                let text = st_reply_text(latest_run(session, alternative));
+                if !text.is_empty() {
+                    swipes.push(text);
+                }
+            }
+        }
+
+        let last = run[run.len() - 1];
+        lines.push(
+            json!({
+                "name": header.character,
+                "is_user": false,
+                "is_system": false,
+                "send_date": st_send_date(last.timestamp),
+                "mes": mes,
+                "swipes": swipes,
+                "swipe_id": swipe_id,
+                "extra": {},
+            })
+            .to_string(),
+        );
+    }
+
+    let mut out = lines.join("\n");
+    out.push('\n');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use limerence_ai::{FunctionCall, ToolCall};
+    use uuid::Uuid;
+
+    /// A session whose file lives in a fresh temp data dir.
+    fn temp_session() -> Session {
+        let _guard = crate::config::env_lock().lock().expect("env lock poisoned");
+        let home = std::env::temp_dir().join(format!("limerence-export-{}", Uuid::new_v4()));
+        // SAFETY: guarded by the crate-wide env lock.
+        unsafe {
+            std::env::set_var("LIMERENCE_HOME", &home);
+        }
+        let session = Session::new("苏晚", "deepseek-chat");
+        // SAFETY: guarded by the crate-wide env lock.
+        unsafe {
+            std::env::remove_var("LIMERENCE_HOME");
+        }
+        session
+    }
+
+    fn cleanup(session: &Session) {
+        let _ = std::fs::remove_dir_all(session.path().parent().unwrap().parent().unwrap());
+    }
+
+    fn tool_call() -> ToolCall {
+        ToolCall {
+            id: "call_1".to_string(),
+            function: FunctionCall {
+                name: "web_search".to_string(),
+                arguments: r#"{"query":"今天天气"}"#.to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn markdown_and_html_include_tool_calls_and_results() {
+        let mut session = temp_session();
+        session.append(Message::user("今天天气怎么样？"));
+        session.append(Message::assistant_with_tools("", vec![tool_call()]));
+        session.append(Message::tool_result("call_1", "晴，<25°C>"));
+        session.append(Message::assistant("今天是晴天，```不冷```。").with_reasoning("查一下"));
+
+        let md = markdown(&session);
+        assert!(md.starts_with("# 与苏晚的会话"));
+        assert!(md.contains("### 用户 · "));
+        assert!(md.contains("**调用工具** `web_search`\n\n```json\n{\"query\":\"今天天气\"}\n```"));
+        assert!(md.contains("**工具结果** `web_search`\n\n```\n晴，<25°C>\n```"));
+        assert!(md.contains("> **思考过程**\n>\n> 查一下"));
+        assert!(md.contains("今天是晴天，```不冷```。"));
+
+        let page = html(&session);
+        assert!(page.starts_with("<!DOCTYPE html>"));
+        assert!(page.contains("<style>"));
+        assert!(page.contains("<code>web_search</code>"));
+        assert!(page.contains("晴，&lt;25°C&gt;"));
+        assert!(!page.contains("<25°C>"));
+        cleanup(&session);
+    }
+
+    #[test]
+    fn sillytavern_lines_carry_swipes_of_the_selected_reply() {
+        let mut session = temp_session();
+        session.append(Message::user("讲个笑话"));
+        let user = session.leaf().unwrap().to_string();
+        session.append(Message::assistant("第一个笑话"));
+        session.checkout(Some(&user));
+        session.append(Message::assistant_with_tools("", vec![tool_call()]));
+        session.append(Message::tool_result("call_1", "结果"));
+        session.append(Message::assistant("第二个笑话"));
+        session.checkout(Some(&user));
+        session.append(Message::assistant("第三个笑话"));
+        // Back to the second version, the one that used a tool.
+        let second = session
+            .latest_leaf_under(&session.children(Some(&user))[1].id)
+            .to_string();
+        session.checkout(Some(&second));
+
+        let lines: Vec<serde_json::Value> = sillytavern(&session)
+            .lines()
+            .map(|l| serde_json::from_str(l).expect("json line"))
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["character_name"], "苏晚");
+        assert_eq!(lines[0]["user_name"], USER_NAME);
+
+        assert_eq!(lines[1]["is_user"], true);
+        assert_eq!(lines[1]["mes"], "讲个笑话");
+        assert!(lines[1]["send_date"].is_string());
+
+        assert_eq!(lines[2]["is_user"], false);
+        assert_eq!(lines[2]["name"], "苏晚");
+        assert_eq!(lines[2]["mes"], "第二个笑话");
+        assert_eq!(
+            lines[2]["swipes"],
+            json!(["第一个笑话", "第二个笑话", "第三个笑话"])
+        );
+        assert_eq!(lines[2]["swipe_id"], 1);
+        cleanup(&session);
+    }
+
+    #[test]
+    fn formats_are_parsed_from_names_and_extensions() {
+        assert_eq!("st".parse(), Ok(ExportFormat::SillyTavern));
+        assert_eq!("Markdown".parse(), Ok(ExportFormat::Markdown));
+        assert!("pdf".parse::<ExportFormat>().is_err());
+        assert_eq!(
+            ExportFormat::from_path(Path::new("chat.HTML")),
+            Some(ExportFormat::Html)
+        );
+        assert_eq!(ExportFormat::from_path(Path::new("chat")), None);
+    }
+}
@@ -0,0 +1,187 @@
+use limerence_ai::Model;
+use std::time::{Duration, Instant};
+
+/// A failed attempt on one model in the chain.
+#[derive(Debug, Clone)]
+pub struct FallbackAttempt {
+    pub model: String,
+    pub error: String,
+}
+
+/// Ordered model chain: the primary model followed by its fallbacks.
+///
+/// Mirrors pi-web's `runWithModelFallback`: candidates are tried in order until
+/// one succeeds. Unlike the web version the chain is sticky — after falling back
+/// it keeps using the fallback until `cooldown` has passed, then retries the primary.
+pub struct ModelChain {
+    models: Vec<Model>,
+    active: usize,
+    switched_at: Option<Instant>,
+    cooldown: Duration,
+}
+
+/// A change of the active model.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelSwitch {
+    pub from: String,
+    pub to: String,
+}
+
+impl ModelChain {
+    pub fn new(models: Vec<Model>, cooldown: Duration) -> Self {
+        assert!(!models.is_empty(), "model chain needs a primary model");
+        Self {
+            models,
+            active: 0,
+            switched_at: None,
+            cooldown,
+        }
+    }
+
+    pub fn active(&self) -> &Model {
+        &self.models[self.active]
+    }
+
+    pub fn primary(&self) -> &Model {
+        &self.models[0]
+    }
+
+    pub fn len(&self) -> usize {
+        self.models.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.models.is_empty()
+    }
+
+    pub fn model(&self, index: usize) -> &Model {
+        &self.models[index]
+    }
+
+    /// Go back to the primary model once the cooldown has elapsed.
+    pub fn restore_primary_if_due(&mut self, now: Instant) -> Option<ModelSwitch> {
+        let switched_at = self.switched_at?;
+        if self.active == 0 || now.duration_since(switched_at) < self.cooldown {
+            return None;
+        }
+        Some(self.switch_to(0, now))
+    }
+
+    /// Candidate indices in try order: the active model, the ones after it,
+    /// then any earlier ones (so a cooled-down primary is still tried last).
+    pub fn candidates(&self) -> Vec<usize> {
+        (self.active..self.models.len())
+            .chain(0..self.active)
+            .collect()
+    }
+
+    /// Record that `index` succeeded; returns the switch if it was not the active model.
+    pub fn mark_success(&mut self, index: usize, now: Instant) -> Option<ModelSwitch> {
+        if index == self.active {
+            return None;
+        }
+        Some(self.switch_to(index, now))
+    }
+
+    fn switch_to(&mut self, index: usize, now: Instant) -> ModelSwitch {
+        let switch = ModelSwitch {
+            from: self.models[self.active].id.clone(),
+            to: self.models[index].id.clone(),
+        };
+        self.active = index;
+        self.switched_at = if index == 0 { None } else { Some(now) };
+        switch
+    }
+}
+
+/// Summarize every failed attempt, matching pi-web's aggregated fallback error.
+pub fn all_failed_message(attempts: &[FallbackAttempt]) -> String {
+    let summary = attempts
+        .iter()
+        .map(|a| format!("{}: {}", a.model, a.error))
+        .collect::<Vec<_>>()
+        .join(" | ");
+    format!("All models failed ({}): {summary}", attempts.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model(id: &str) -> Model {
+        Model {
+            id: id.to_string(),
+            base_url: "http://localhost".to_string(),
+            api_key_env: "KEY".to_string(),
+            provider: Default::default(),
+            sampling: Default::default(),
+            stream_usage: None,
+        }
+    }
+
+    fn chain() -> ModelChain {
+        ModelChain::new(
+            vec![model("primary"), model("fb1"), model("fb2")],
+            Duration::from_secs(60),
+        )
+    }
+
+    #[test]
+    fn candidates_start_at_active_and_wrap() {
+        let mut chain = chain();
+        assert_eq!(chain.candidates(), vec![0, 1, 2]);
+
+        let now = Instant::now();
+        chain.mark_success(2, now);
+        assert_eq!(chain.candidates(), vec![2, 0, 1]);
+        assert_eq!(chain.active().id, "fb2");
+    }
+
+    #[test]
+    fn success_on_active_is_not_a_switch() {
+        let mut chain = chain();
+        assert_eq!(chain.mark_success(0, Instant::now()), None);
+    }
+
+    #[test]
+    fn primary_is_restored_after_cooldown() {
+        let mut chain = chain();
+        let t0 = Instant::now();
+        let switch = chain.mark_success(1, t0).expect("switch to fallback");
+        assert_eq!(switch.from, "primary");
+        assert_eq!(switch.to, "fb1");
+
+        assert_eq!(
+            chain.restore_primary_if_due(t0 + Duration::from_secs(30)),
+            None
+        );
+
+        let back = chain
+            .restore_primary_if_due(t0 + Duration::from_secs(61))
+            .expect("switch back");
+        assert_eq!(back.to, "primary");
+        assert_eq!(chain.active().id, "primary");
+        assert_eq!(
+            chain.restore_primary_if_due(t0 + Duration::from_secs(999)),
+            None
+        );
+    }
+
+    #[test]
+    fn all_failed_message_lists_every_attempt() {
+        let msg = all_failed_message(&[
+            FallbackAttempt {
+                model: "primary".to_string(),
+                error: "429".to_string(),
+            },
+            FallbackAttempt {
+                model: "fb1".to_string(),
+                error: "missing key".to_string(),
+            },
+        ]);
+        assert_eq!(
+            msg,
+            "All models failed (2): primary: 429 | fb1: missing key"
+        );
+    }
+}
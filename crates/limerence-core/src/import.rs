@@ -0,0 +1,284 @@
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone, Utc};
+use limerence_ai::Message;
+use serde::Deserialize;
+use serde_json::Value;
+use std::path::Path;
+
+use crate::memory::{MemoryEntry, MemoryIndex};
+use crate::session::Session;
+
+/// Model recorded in the header of imported sessions.
+pub const IMPORTED_MODEL: &str = "sillytavern";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ImportError {
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("line {line}: {source}")]
+    Json {
+        line: usize,
+        source: serde_json::Error,
+    },
+    #[error("no messages in the chat file")]
+    Empty,
+}
+
+/// One line of an ST chat file after the metadata line.
+#[derive(Debug, Deserialize)]
+struct StMessage {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    is_user: bool,
+    #[serde(default)]
+    is_system: bool,
+    #[serde(default)]
+    send_date: Value,
+    #[serde(default)]
+    mes: String,
+    #[serde(default)]
+    swipes: Vec<String>,
+    #[serde(default)]
+    swipe_id: Option<usize>,
+    #[serde(default)]
+    swipe_info: Vec<StSwipeInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StSwipeInfo {
+    #[serde(default)]
+    send_date: Value,
+}
+
+/// Read an ST chat file into a new saved session and index its messages in
+/// `memory`, so `memory_search` finds them at once.
+pub fn import_sillytavern(path: &Path, memory: &mut MemoryIndex) -> Result<Session, ImportError> {
+    let content = std::fs::read_to_string(path)?;
+    let session = session_from_sillytavern(&content)?;
+    backfill_memory(&session, memory);
+    Ok(session)
+}
+
+/// Build a session from ST chat JSONL. Each reply's swipes become sibling
+/// entries, with the selected swipe's branch checked out. System and
+/// narrator lines are skipped.
+pub fn session_from_sillytavern(content: &str) -> Result<Session, ImportError> {
+    let mut metadata = Value::Null;
+    let mut messages = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let value: Value = serde_json::from_str(line).map_err(|source| ImportError::Json {
+            line: i + 1,
+            source,
+        })?;
+        // The first line holds chat metadata rather than a message.
+        if value.get("mes").is_none() {
+            if messages.is_empty() {
+                metadata = value;
+            }
+            continue;
+        }
+        let message: StMessage =
+            serde_json::from_value(value).map_err(|source| ImportError::Json {
+                line: i + 1,
+                source,
+            })?;
+        if !message.is_system {
+            messages.push(message);
+        }
+    }
+    if messages.is_empty() {
+        return Err(ImportError::Empty);
+    }
+
+    let character = metadata
+        .get("character_name")
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .or_else(|| messages.iter().find(|m| !m.is_user).map(|m| m.name.clone()))
+        .unwrap_or_default();
+    let mut last = metadata
+        .get("create_date")
+        .and_then(parse_st_date)
+        .or_else(|| parse_st_date(&messages[0].send_date))
+        .unwrap_or_else(Utc::now);
+
+    let mut session = Session::new(&character, IMPORTED_MODEL);
+    // The header is written with the first entry, so it can still change.
+    session.header.timestamp = last;
+
+    for message in messages {
+        let timestamp = parse_st_date(&message.send_date).unwrap_or(last);
+        last = timestamp;
+        if message.is_user {
+            session.append_at(Message::user(message.mes), timestamp);
+            continue;
+        }
+
+        if message.swipes.len() < 2 {
+            session.append_at(Message::assistant(message.mes), timestamp);
+            continue;
+        }
+        let parent = session.leaf().map(str::to_string);
+        let selected = message.swipe_id.unwrap_or(0).min(message.swipes.len() - 1);
+        let mut selected_id = None;
+        let mut mes = message.mes;
+        for (i, swipe) in message.swipes.into_iter().enumerate() {
+            session.checkout(parent.as_deref());
+            // `mes` is what the user last saw; prefer it for the selected swipe.
+            let (text, swipe_time) = if i == selected {
+                (std::mem::take(&mut mes), timestamp)
+            } else {
+                let swipe_time = message
+                    .swipe_info
+                    .get(i)
+                    .and_then(|info| parse_st_date(&info.send_date))
+                    .unwrap_or(timestamp);
+                (swipe, swipe_time)
+            };
+            session.append_at(Message::assistant(text), swipe_time);
+            if i == selected {
+                selected_id = session.leaf().map(str::to_string);
+            }
+        }
+        session.checkout(selected_id.as_deref());
+    }
+    Ok(session)
+}
+
+/// Add every message of `session`, on all branches, to `memory`.
+pub fn backfill_memory(session: &Session, memory: &mut MemoryIndex) -> usize {
+    let mut added = 0;
+    for entry in &session.entries {
+        let content = entry.message.content_text();
+        if content.is_empty() {
+            continue;
+        }
+        memory.add(MemoryEntry {
+            session_id: session.header.id.clone(),
+            timestamp: entry.timestamp,
+            role: entry.message.role_str().to_string(),
+            content: content.into_owned(),
+        });
+        added += 1;
+    }
+    added
+}
+
+/// ST has written dates in several formats over the years: epoch
+/// milliseconds, ISO 8601, `October 17, 2026 4:52pm` and
+/// `2026-10-17 @16h 52m 03s 120ms`. The last two are local time.
+fn parse_st_date(value: &Value) -> Option<DateTime<Utc>> {
+    if let Some(ms) = value.as_i64() {
+        return DateTime::from_timestamp_millis(ms);
+    }
+    let text = value.as_str()?.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+        return Some(time.with_timezone(&Utc));
+    }
+    [
+        "%B %d, %Y %I:%M%p",
+        "%B %d, %Y %I:%M %p",
+        "%Y-%m-%d @%Hh %Mm %Ss %3fms",
+        "%Y-%m-%d @%Hh %Mm %Ss",
+        "%Y-%m-%d@%Hh%Mm%Ss",
+    ]
+    .iter()
+    .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+    .and_then(|naive| Local.from_local_datetime(&naive).earliest())
+    .map(|time| time.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    const CHAT: &str = r#"{"user_name":"You","character_name":"苏晚","create_date":"2024-3-1 @20h 15m 00s 000ms","chat_metadata":{}}
+{"name":"苏晚","is_user":false,"is_system":false,"send_date":"March 1, 2024 8:15pm","mes":"你来啦。","extra":{}}
+{"name":"You","is_user":true,"is_system":false,"send_date":"March 1, 2024 8:16pm","mes":"我养了一只猫，叫团子","extra":{}}
+{"name":"苏晚","is_user":false,"send_date":"2024-03-01T12:17:00.000Z","mes":"团子好可爱！","swipes":["好名字。","团子好可爱！","猫猫！"],"swipe_id":1,"swipe_info":[{"send_date":"March 1, 2024 8:17pm"}],"extra":{}}
+{"name":"System","is_user":false,"is_system":true,"send_date":"March 1, 2024 8:18pm","mes":"[场景切换]"}
+{"name":"You","is_user":true,"send_date":1709295540000,"mes":"它很黏人"}
+"#;
+
+    #[test]
+    fn imports_swipes_as_siblings_with_the_selected_branch_and_fills_memory() {
+        let _guard = crate::config::env_lock().lock().expect("env lock poisoned");
+        let home = std::env::temp_dir().join(format!("limerence-import-{}", Uuid::new_v4()));
+        // SAFETY: guarded by the crate-wide env lock.
+        unsafe {
+            std::env::set_var("LIMERENCE_HOME", &home);
+        }
+        let path = home.join("chat.jsonl");
+        std::fs::create_dir_all(&home).expect("temp dir");
+        std::fs::write(&path, CHAT).expect("write chat");
+
+        let mut memory = MemoryIndex::with_memory_root(home.join("memory"));
+        std::fs::create_dir_all(memory.memory_root()).expect("memory dir");
+        let session = import_sillytavern(&path, &mut memory).expect("import");
+
+        assert_eq!(session.header.character, "苏晚");
+        assert_eq!(
+            session.header.timestamp,
+            Local
+                .with_ymd_and_hms(2024, 3, 1, 20, 15, 0)
+                .unwrap()
+                .with_timezone(&Utc)
+        );
+        let texts: Vec<_> = session
+            .messages()
+            .iter()
+            .map(|m| m.content_text().into_owned())
+            .collect();
+        assert_eq!(
+            texts,
+            vec![
+                "你来啦。",
+                "我养了一只猫，叫团子",
+                "团子好可爱！",
+                "它很黏人"
+            ]
+        );
+
+        let branch = session.branch();
+        let swipes = session.children(branch[2].parent_id.as_deref());
+        let swipe_texts: Vec<_> = swipes.iter().map(|e| e.message.content_text()).collect();
+        assert_eq!(swipe_texts, vec!["好名字。", "团子好可爱！", "猫猫！"]);
+        assert_eq!(
+            branch[2].timestamp,
+            "2024-03-01T12:17:00Z".parse::<DateTime<Utc>>().unwrap()
+        );
+        assert_eq!(branch[3].timestamp.timestamp_millis(), 1709295540000);
+
+        // The saved file reopens on the same branch.
+        let loaded = Session::load(&session.path().to_path_buf()).expect("saved session");
+        assert_eq!(loaded.messages().len(), 4);
+        assert_eq!(loaded.leaf(), session.leaf());
+
+        // Every imported message, swipes included, is searchable.
+        assert_eq!(memory.entry_count(), 6);
+        let hits = memory.search("团子", 5);
+        assert!(hits.iter().any(|h| h.content == "团子好可爱！"));
+
+        // SAFETY: guarded by the crate-wide env lock.
+        unsafe {
+            std::env::remove_var("LIMERENCE_HOME");
+        }
+        let _ = std::fs::remove_dir_all(home);
+    }
+
+    #[test]
+    fn rejects_files_without_messages() {
+        assert!(matches!(
+            session_from_sillytavern(r#"{"user_name":"You","character_name":"苏晚"}"#),
+            Err(ImportError::Empty)
+        ));
+        assert!(matches!(
+            session_from_sillytavern("not json"),
+            Err(ImportError::Json { line: 1, .. })
+        ));
+    }
+}
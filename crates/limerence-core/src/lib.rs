@@ -1,11 +1,23 @@
 pub mod agent;
+pub mod blob;
 pub mod character;
+pub mod character_png;
+pub mod compaction;
 pub mod config;
+pub mod context;
+pub mod export;
+pub mod fallback;
 pub mod file_os;
+pub mod import;
+pub mod lorebook;
+pub mod macros;
 pub mod memory;
 pub mod notes;
+pub mod preset;
+pub mod regex_script;
 pub mod session;
 pub mod tool;
+pub mod usage;
 
 pub use agent::{Agent, AgentEvent};
 pub use character::CharacterCard;
@@ -0,0 +1,835 @@
+use limerence_ai::Message;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value, json};
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::character::{CardIssue, Fields, extra_issues};
+use crate::config::worlds_dir;
+use crate::context::TokenCounter;
+use crate::regex_script::{build_js_regex, split_js_regex};
+
+/// Messages scanned for keys when neither the book nor the entry says
+/// (SillyTavern's default).
+pub const DEFAULT_SCAN_DEPTH: usize = 2;
+/// Tokens of lore one book may add to a prompt when it sets no budget.
+pub const DEFAULT_TOKEN_BUDGET: usize = 2048;
+/// Rounds of recursive scanning after the first.
+const MAX_RECURSION: usize = 5;
+
+/// A card's lorebook (`character_book`), in the V2/V3 schema. Like
+/// `CharacterData`, values of the wrong type stay in `extra` untouched.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(from = "Map<String, Value>", into = "Map<String, Value>")]
+pub struct CharacterBook {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub scan_depth: Option<u32>,
+    pub token_budget: Option<u32>,
+    pub recursive_scanning: Option<bool>,
+    pub extensions: Value,
+    pub entries: Vec<BookEntry>,
+    pub extra: Map<String, Value>,
+}
+
+/// One lorebook entry.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "Map<String, Value>", into = "Map<String, Value>")]
+pub struct BookEntry {
+    pub keys: Vec<String>,
+    pub content: String,
+    pub extensions: Value,
+    pub enabled: bool,
+    pub insertion_order: i64,
+    pub case_sensitive: Option<bool>,
+    pub name: Option<String>,
+    pub priority: Option<i64>,
+    /// A number in V2; V3 also allows a string.
+    pub id: Option<Value>,
+    pub comment: Option<String>,
+    pub selective: Option<bool>,
+    pub secondary_keys: Option<Vec<String>>,
+    pub constant: Option<bool>,
+    /// `before_char` or `after_char`.
+    pub position: Option<String>,
+    /// V3: keys are regular expressions.
+    pub use_regex: Option<bool>,
+    pub extra: Map<String, Value>,
+}
+
+impl CharacterBook {
+    const FIELDS: &[(&str, &str)] = &[
+        ("name", "string"),
+        ("description", "string"),
+        ("scan_depth", "non-negative integer"),
+        ("token_budget", "non-negative integer"),
+        ("recursive_scanning", "boolean"),
+        ("extensions", "object"),
+        ("entries", "array of entry objects"),
+    ];
+
+    pub(crate) fn validate(&self, path: &str, out: &mut Vec<CardIssue>) {
+        extra_issues(path, &self.extra, Self::FIELDS, out);
+        for (i, entry) in self.entries.iter().enumerate() {
+            extra_issues(
+                &format!("{path}.entries[{i}]"),
+                &entry.extra,
+                BookEntry::FIELDS,
+                out,
+            );
+        }
+    }
+}
+
+impl BookEntry {
+    const FIELDS: &[(&str, &str)] = &[
+        ("keys", "array of strings"),
+        ("content", "string"),
+        ("extensions", "object"),
+        ("enabled", "boolean"),
+        ("insertion_order", "integer"),
+        ("case_sensitive", "boolean"),
+        ("name", "string"),
+        ("priority", "integer"),
+        ("id", "integer or string"),
+        ("comment", "string"),
+        ("selective", "boolean"),
+        ("secondary_keys", "array of strings"),
+        ("constant", "boolean"),
+        ("position", "string"),
+        ("use_regex", "boolean"),
+    ];
+}
+
+impl From<Map<String, Value>> for CharacterBook {
+    fn from(map: Map<String, Value>) -> Self {
+        let mut f = Fields(map);
+        Self {
+            name: f.take("name"),
+            description: f.take("description"),
+            scan_depth: f.take("scan_depth"),
+            token_budget: f.take("token_budget"),
+            recursive_scanning: f.take("recursive_scanning"),
+            extensions: f.take_object("extensions"),
+            entries: f.take("entries").unwrap_or_default(),
+            extra: f.0,
+        }
+    }
+}
+
+impl From<CharacterBook> for Map<String, Value> {
+    fn from(b: CharacterBook) -> Self {
+        let mut f = Fields(b.extra);
+        f.put("name", b.name);
+        f.put("description", b.description);
+        f.put("scan_depth", b.scan_depth);
+        f.put("token_budget", b.token_budget);
+        f.put("recursive_scanning", b.recursive_scanning);
+        f.put("extensions", b.extensions);
+        f.put("entries", b.entries);
+        f.0
+    }
+}
+
+impl From<Map<String, Value>> for BookEntry {
+    fn from(map: Map<String, Value>) -> Self {
+        let mut f = Fields(map);
+        Self {
+            keys: f.take("keys").unwrap_or_default(),
+            content: f.take("content").unwrap_or_default(),
+            extensions: f.take_object("extensions"),
+            enabled: f.take("enabled").unwrap_or(true),
+            insertion_order: f.take("insertion_order").unwrap_or_default(),
+            case_sensitive: f.take("case_sensitive"),
+            name: f.take("name"),
+            priority: f.take("priority"),
+            id: f.take("id"),
+            comment: f.take("comment"),
+            selective: f.take("selective"),
+            secondary_keys: f.take("secondary_keys"),
+            constant: f.take("constant"),
+            position: f.take("position"),
+            use_regex: f.take("use_regex"),
+            extra: f.0,
+        }
+    }
+}
+
+impl From<BookEntry> for Map<String, Value> {
+    fn from(e: BookEntry) -> Self {
+        let mut f = Fields(e.extra);
+        f.put("keys", e.keys);
+        f.put("content", e.content);
+        f.put("extensions", e.extensions);
+        f.put("enabled", e.enabled);
+        f.put("insertion_order", e.insertion_order);
+        f.put("case_sensitive", e.case_sensitive);
+        f.put("name", e.name);
+        f.put("priority", e.priority);
+        f.put("id", e.id);
+        f.put("comment", e.comment);
+        f.put("selective", e.selective);
+        f.put("secondary_keys", e.secondary_keys);
+        f.put("constant", e.constant);
+        f.put("position", e.position);
+        f.put("use_regex", e.use_regex);
+        f.0
+    }
+}
+
+/// Where an activated entry goes in the prompt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LorePosition {
+    /// In the system prompt, before the character's description.
+    BeforeChar,
+    /// In the system prompt, after the scenario.
+    AfterChar,
+    /// As its own message `depth` messages from the end of the history.
+    AtDepth { depth: usize, role: LoreRole },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoreRole {
+    System,
+    User,
+    Assistant,
+}
+
+/// How secondary keys combine with a primary match (`selectiveLogic`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelectiveLogic {
+    AndAny,
+    NotAll,
+    NotAny,
+    AndAll,
+}
+
+impl BookEntry {
+    /// Name for lists: the entry's name or comment, else its first key.
+    pub fn title(&self) -> String {
+        [self.name.as_deref(), self.comment.as_deref()]
+            .into_iter()
+            .flatten()
+            .find(|t| !t.trim().is_empty())
+            .or(self.keys.first().map(String::as_str))
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    fn extension(&self, key: &str) -> Option<&Value> {
+        self.extensions.get(key).filter(|v| !v.is_null())
+    }
+
+    /// SillyTavern keeps the exact position in `extensions.position`
+    /// (0 before, 1 after, 4 at depth); the spec's `position` is the fallback.
+    pub fn position(&self) -> LorePosition {
+        match self.extension("position").and_then(Value::as_u64) {
+            Some(0) => LorePosition::BeforeChar,
+            Some(4) => LorePosition::AtDepth {
+                depth: self.extension("depth").and_then(Value::as_u64).unwrap_or(4) as usize,
+                role: match self.extension("role").and_then(Value::as_u64) {
+                    Some(1) => LoreRole::User,
+                    Some(2) => LoreRole::Assistant,
+                    _ => LoreRole::System,
+                },
+            },
+            Some(_) => LorePosition::AfterChar,
+            None if self.position.as_deref() == Some("before_char") => LorePosition::BeforeChar,
+            None => LorePosition::AfterChar,
+        }
+    }
+
+    pub fn selective_logic(&self) -> SelectiveLogic {
+        match self.extension("selectiveLogic").and_then(Value::as_u64) {
+            Some(1) => SelectiveLogic::NotAll,
+            Some(2) => SelectiveLogic::NotAny,
+            Some(3) => SelectiveLogic::AndAll,
+            _ => SelectiveLogic::AndAny,
+        }
+    }
+
+    fn flag(&self, key: &str) -> bool {
+        self.extension(key)
+            .and_then(Value::as_bool)
+            .unwrap_or(false)
+    }
+
+    fn case_sensitive(&self) -> bool {
+        self.case_sensitive
+            .unwrap_or_else(|| self.flag("case_sensitive"))
+    }
+
+    fn scan_depth(&self) -> Option<usize> {
+        self.extension("scan_depth")
+            .and_then(Value::as_u64)
+            .map(|d| d as usize)
+    }
+
+    /// A matcher for `key`: a regex when the entry uses regex keys or the key
+    /// is written `/pattern/flags`, else a plain substring.
+    fn matcher(&self, key: &str) -> Option<Regex> {
+        let key = key.trim();
+        if key.is_empty() {
+            return None;
+        }
+        if let Some((pattern, flags)) = split_js_regex(key) {
+            return build_js_regex(pattern, flags);
+        }
+        let pattern = if self.use_regex == Some(true) {
+            key.to_string()
+        } else if self.flag("match_whole_words") {
+            // ASCII word boundaries as in ST's JavaScript regexes; Rust's
+            // Unicode `\W` would count CJK text as part of the word.
+            format!(
+                "(?:^|[^A-Za-z0-9_]){}(?:$|[^A-Za-z0-9_])",
+                regex::escape(key)
+            )
+        } else {
+            regex::escape(key)
+        };
+        RegexBuilder::new(&pattern)
+            .case_insensitive(!self.case_sensitive())
+            .build()
+            .ok()
+    }
+
+    fn matches_any(&self, keys: &[String], text: &str) -> bool {
+        keys.iter()
+            .filter_map(|k| self.matcher(k))
+            .any(|re| re.is_match(text))
+    }
+
+    fn matches_all(&self, keys: &[String], text: &str) -> bool {
+        keys.iter()
+            .filter_map(|k| self.matcher(k))
+            .all(|re| re.is_match(text))
+    }
+
+    /// Whether `text` triggers this entry: a primary key matches and, for a
+    /// selective entry, the secondary keys satisfy its logic.
+    pub fn triggered_by(&self, text: &str) -> bool {
+        if !self.matches_any(&self.keys, text) {
+            return false;
+        }
+        let secondary = self.secondary_keys.as_deref().unwrap_or_default();
+        if self.selective != Some(true) || secondary.is_empty() {
+            return true;
+        }
+        match self.selective_logic() {
+            SelectiveLogic::AndAny => self.matches_any(secondary, text),
+            SelectiveLogic::AndAll => self.matches_all(secondary, text),
+            SelectiveLogic::NotAny => !self.matches_any(secondary, text),
+            SelectiveLogic::NotAll => !self.matches_all(secondary, text),
+        }
+    }
+}
+
+/// An entry triggered for this prompt.
+#[derive(Debug, Clone)]
+pub struct ActivatedEntry {
+    pub title: String,
+    pub content: String,
+    pub position: LorePosition,
+    pub insertion_order: i64,
+}
+
+/// The lore for one prompt, in insertion order.
+#[derive(Debug, Clone, Default)]
+pub struct Activation {
+    pub entries: Vec<ActivatedEntry>,
+}
+
+impl Activation {
+    fn joined(&self, position: LorePosition) -> Option<String> {
+        let parts: Vec<&str> = self
+            .entries
+            .iter()
+            .filter(|e| e.position == position)
+            .map(|e| e.content.as_str())
+            .collect();
+        (!parts.is_empty()).then(|| parts.join("\n"))
+    }
+
+    pub fn before_char(&self) -> Option<String> {
+        self.joined(LorePosition::BeforeChar)
+    }
+
+    pub fn after_char(&self) -> Option<String> {
+        self.joined(LorePosition::AfterChar)
+    }
+
+    /// `history` with the at-depth entries inserted, each `depth` chat
+    /// messages from the end of the original history (0: after the last
+    /// message). See [`depth_index`].
+    pub fn inject(&self, mut history: Vec<Message>) -> Vec<Message> {
+        let mut inserts: Vec<(usize, Message)> = self
+            .entries
+            .iter()
+            .filter_map(|e| match e.position {
+                LorePosition::AtDepth { depth, role } => {
+                    let message = match role {
+                        LoreRole::System => Message::system(e.content.clone()),
+                        LoreRole::User => Message::user(e.content.clone()),
+                        LoreRole::Assistant => Message::assistant(e.content.clone()),
+                    };
+                    Some((depth_index(&history, depth), message))
+                }
+                _ => None,
+            })
+            .collect();
+        // Insert from the back so earlier indices stay valid; entries at the
+        // same index keep their insertion order.
+        inserts.reverse();
+        inserts.sort_by_key(|(at, _)| std::cmp::Reverse(*at));
+        for (at, message) in inserts {
+            history.insert(at, message);
+        }
+        history
+    }
+}
+
+/// Where a message `depth` chat messages from the end goes in `history`.
+/// Like ST, only user and assistant messages count, and the index never
+/// falls inside a tool exchange: a tool result must directly follow the
+/// call that asked for it, so the message goes before that call instead.
+pub(crate) fn depth_index(history: &[Message], depth: usize) -> usize {
+    let mut at = history.len();
+    let mut remaining = depth;
+    while remaining > 0 && at > 0 {
+        at -= 1;
+        if matches!(
+            history[at],
+            Message::User { .. } | Message::Assistant { .. }
+        ) {
+            remaining -= 1;
+        }
+    }
+    while at > 0 && matches!(history.get(at), Some(Message::ToolResult { .. })) {
+        at -= 1;
+    }
+    at
+}
+
+/// Text of the last `depth` user and assistant messages, newest last.
+fn recent_text(history: &[Message], depth: usize) -> String {
+    let chat: Vec<&Message> = history
+        .iter()
+        .filter(|m| matches!(m, Message::User { .. } | Message::Assistant { .. }))
+        .collect();
+    chat[chat.len().saturating_sub(depth)..]
+        .iter()
+        .map(|m| m.content_text())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Scan `history` against `books` and pick the lore for the next prompt.
+///
+/// Constant entries always fire; others fire when their keys match the last
+/// few messages. Books with `recursive_scanning` also scan the content of
+/// what fired, up to a few rounds. Each book then keeps what fits its token
+/// budget, constant entries first and then by priority.
+pub fn activate(
+    books: &[&CharacterBook],
+    history: &[Message],
+    counter: &TokenCounter,
+) -> Activation {
+    let candidates: Vec<(usize, &BookEntry)> = books
+        .iter()
+        .enumerate()
+        .flat_map(|(b, book)| book.entries.iter().map(move |e| (b, e)))
+        .filter(|(_, e)| e.enabled && !e.content.trim().is_empty())
+        .collect();
+
+    let mut texts: HashMap<usize, String> = HashMap::new();
+    let mut fired = vec![false; candidates.len()];
+    let mut recursed = String::new();
+    for round in 0..=MAX_RECURSION {
+        let mut newly = Vec::new();
+        for (i, (b, entry)) in candidates.iter().enumerate() {
+            if fired[i] {
+                continue;
+            }
+            let hit = if entry.constant == Some(true) {
+                true
+            } else {
+                let depth = entry
+                    .scan_depth()
+                    .or(books[*b].scan_depth.map(|d| d as usize))
+                    .unwrap_or(DEFAULT_SCAN_DEPTH);
+                let chat = texts
+                    .entry(depth)
+                    .or_insert_with(|| recent_text(history, depth));
+                entry.triggered_by(chat)
+                    || (round > 0
+                        && !entry.flag("exclude_recursion")
+                        && entry.triggered_by(&recursed))
+            };
+            if hit {
+                fired[i] = true;
+                newly.push(i);
+            }
+        }
+
+        let before = recursed.len();
+        for &i in &newly {
+            let (b, entry) = candidates[i];
+            if books[b].recursive_scanning == Some(true) && !entry.flag("prevent_recursion") {
+                recursed.push('\n');
+                recursed.push_str(&entry.content);
+            }
+        }
+        if recursed.len() == before {
+            break;
+        }
+    }
+
+    // Spend each book's budget: constant entries first, then higher priority.
+    let mut chosen: Vec<&BookEntry> = Vec::new();
+    for (b, book) in books.iter().enumerate() {
+        let mut entries: Vec<&BookEntry> = candidates
+            .iter()
+            .zip(&fired)
+            .filter(|((book, _), fired)| *book == b && **fired)
+            .map(|((_, e), _)| *e)
+            .collect();
+        entries.sort_by_key(|e| {
+            (
+                e.constant != Some(true),
+                std::cmp::Reverse(e.priority.unwrap_or(e.insertion_order)),
+            )
+        });
+        let budget = book
+            .token_budget
+            .map_or(DEFAULT_TOKEN_BUDGET, |t| t as usize);
+        let mut used = 0;
+        for entry in entries {
+            used += counter.text(&entry.content);
+            if used > budget {
+                break;
+            }
+            chosen.push(entry);
+        }
+    }
+
+    chosen.sort_by_key(|e| e.insertion_order);
+    Activation {
+        entries: chosen
+            .into_iter()
+            .map(|e| ActivatedEntry {
+                title: e.title(),
+                content: e.content.clone(),
+                position: e.position(),
+                insertion_order: e.insertion_order,
+            })
+            .collect(),
+    }
+}
+
+/// Read a standalone world-info file: SillyTavern's export (`entries` keyed
+/// by uid) or a bare `character_book` object.
+pub fn load_world(path: &Path) -> Result<CharacterBook, Box<dyn std::error::Error>> {
+    let value: Value = serde_json::from_slice(&std::fs::read(path)?)?;
+    let mut book = match value.get("entries") {
+        Some(Value::Object(entries)) => {
+            let mut book = CharacterBook::from(match value.clone() {
+                Value::Object(mut map) => {
+                    map.remove("entries");
+                    map
+                }
+                _ => Map::new(),
+            });
+            let mut entries: Vec<&Value> = entries.values().collect();
+            entries.sort_by_key(|e| {
+                e.get("displayIndex")
+                    .or(e.get("uid"))
+                    .and_then(Value::as_i64)
+            });
+            book.entries = entries.into_iter().map(entry_from_world_info).collect();
+            book
+        }
+        _ => serde_json::from_value(value)?,
+    };
+    if book.name.is_none() {
+        book.name = path.file_stem().map(|s| s.to_string_lossy().into_owned());
+    }
+    Ok(book)
+}
+
+/// Every world-info file in `worlds_dir()`; files that fail to parse are skipped.
+pub fn load_worlds() -> Vec<CharacterBook> {
+    let mut paths: Vec<_> = std::fs::read_dir(worlds_dir())
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|e| e == "json"))
+        .collect();
+    paths.sort();
+    paths.iter().filter_map(|p| load_world(p).ok()).collect()
+}
+
+/// Map an entry of SillyTavern's world-info format onto the card schema;
+/// ST-only settings go to `extensions` the way ST itself exports them.
+fn entry_from_world_info(st: &Value) -> BookEntry {
+    let get = |key: &str| st.get(key).cloned().unwrap_or(Value::Null);
+    let mut extensions = Map::new();
+    for (from, to) in [
+        ("position", "position"),
+        ("depth", "depth"),
+        ("role", "role"),
+        ("selectiveLogic", "selectiveLogic"),
+        ("excludeRecursion", "exclude_recursion"),
+        ("preventRecursion", "prevent_recursion"),
+        ("scanDepth", "scan_depth"),
+        ("matchWholeWords", "match_whole_words"),
+        ("caseSensitive", "case_sensitive"),
+        ("probability", "probability"),
+        ("useProbability", "useProbability"),
+        ("group", "group"),
+    ] {
+        if let Some(value) = st.get(from).filter(|v| !v.is_null()) {
+            extensions.insert(to.to_string(), value.clone());
+        }
+    }
+    let position = match st.get("position").and_then(Value::as_u64) {
+        Some(0) => "before_char",
+        _ => "after_char",
+    };
+    let entry = json!({
+        "id": get("uid"),
+        "keys": get("key"),
+        "secondary_keys": get("keysecondary"),
+        "comment": get("comment"),
+        "content": get("content"),
+        "constant": get("constant"),
+        "selective": get("selective"),
+        "insertion_order": get("order"),
+        "enabled": !st.get("disable").and_then(Value::as_bool).unwrap_or(false),
+        "position": position,
+        "case_sensitive": get("caseSensitive"),
+        "extensions": extensions,
+    });
+    match entry {
+        Value::Object(mut map) => {
+            map.retain(|_, v| !v.is_null());
+            BookEntry::from(map)
+        }
+        _ => unreachable!("json! object literal"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(value: Value) -> BookEntry {
+        serde_json::from_value(value).expect("entry")
+    }
+
+    fn book(entries: Vec<Value>) -> CharacterBook {
+        serde_json::from_value(json!({ "entries": entries })).expect("book")
+    }
+
+    fn titles(activation: &Activation) -> Vec<&str> {
+        activation
+            .entries
+            .iter()
+            .map(|e| e.title.as_str())
+            .collect()
+    }
+
+    fn counter() -> TokenCounter {
+        TokenCounter::for_model("gpt-4o")
+    }
+
+    #[test]
+    fn keys_regexes_and_selective_logic() {
+        let plain = entry(json!({"keys": ["Tuanzi"], "content": "c"}));
+        assert!(plain.triggered_by("i met tuanzi today"));
+        let exact = entry(json!({"keys": ["Tuanzi"], "content": "c", "case_sensitive": true}));
+        assert!(!exact.triggered_by("tuanzi"));
+
+        let regex = entry(json!({"keys": ["/团子|橘猫/"], "content": "c"}));
+        assert!(regex.triggered_by("家里有只橘猫"));
+        let v3 = entry(json!({"keys": ["cat(s)?\\b"], "content": "c", "use_regex": true}));
+        assert!(v3.triggered_by("two CATS here"));
+        let whole = entry(
+            json!({"keys": ["cat"], "content": "c", "extensions": {"match_whole_words": true}}),
+        );
+        assert!(!whole.triggered_by("concatenate"));
+        assert!(whole.triggered_by("a cat."));
+        let cjk = entry(
+            json!({"keys": ["苏晚"], "content": "c", "extensions": {"match_whole_words": true}}),
+        );
+        assert!(cjk.triggered_by("我见到苏晚了"));
+        assert!(cjk.triggered_by("苏晚"));
+
+        let selective = |logic: u64| {
+            entry(json!({
+                "keys": ["猫"], "secondary_keys": ["团子", "生病"], "selective": true,
+                "content": "c", "extensions": {"selectiveLogic": logic},
+            }))
+        };
+        let text = "团子是一只猫";
+        assert!(selective(0).triggered_by(text));
+        assert!(!selective(3).triggered_by(text));
+        assert!(!selective(2).triggered_by(text));
+        assert!(selective(1).triggered_by(text));
+        assert!(!selective(0).triggered_by("狗"));
+    }
+
+    #[test]
+    fn scan_depth_constants_recursion_and_order() {
+        let history = vec![
+            Message::user("说说王都"),
+            Message::assistant("那里很大。"),
+            Message::user("那里的猫呢？"),
+        ];
+        let mut book = book(vec![
+            json!({"comment": "王都", "keys": ["王都"], "content": "王都是国王的城市。", "insertion_order": 5}),
+            json!({"comment": "猫", "keys": ["猫"], "content": "猫神守护着王都的宫殿。", "insertion_order": 1}),
+            json!({"comment": "宫殿", "keys": ["宫殿"], "content": "宫殿在山上。", "insertion_order": 3}),
+            json!({"comment": "常驻", "keys": [], "constant": true, "content": "这是一个奇幻世界。", "insertion_order": 0}),
+            json!({"comment": "关闭", "keys": ["猫"], "enabled": false, "content": "不会出现"}),
+        ]);
+
+        // The default depth of 2 no longer sees the first message.
+        let activation = activate(&[&book], &history, &counter());
+        assert_eq!(titles(&activation), vec!["常驻", "猫"]);
+
+        book.recursive_scanning = Some(true);
+        let activation = activate(&[&book], &history, &counter());
+        assert_eq!(titles(&activation), vec!["常驻", "猫", "宫殿", "王都"]);
+
+        book.recursive_scanning = None;
+        book.scan_depth = Some(3);
+        let activation = activate(&[&book], &history, &counter());
+        assert_eq!(titles(&activation), vec!["常驻", "猫", "王都"]);
+    }
+
+    #[test]
+    fn token_budget_keeps_constants_and_higher_priority_entries() {
+        let history = vec![Message::user("猫和狗")];
+        let long = "很长的设定。".repeat(40);
+        let mut book = book(vec![
+            json!({"comment": "狗", "keys": ["狗"], "content": long, "priority": 1}),
+            json!({"comment": "猫", "keys": ["猫"], "content": long, "priority": 9}),
+            json!({"comment": "常驻", "constant": true, "content": "世界观"}),
+        ]);
+        let per_entry = counter().text(&long);
+        book.token_budget = Some((per_entry + 10) as u32);
+        let activation = activate(&[&book], &history, &counter());
+        assert_eq!(titles(&activation), vec!["常驻", "猫"]);
+    }
+
+    #[test]
+    fn positions_split_between_prompt_and_depth_injections() {
+        let book = book(vec![
+            json!({"comment": "前", "constant": true, "content": "前置", "position": "before_char"}),
+            json!({"comment": "后", "constant": true, "content": "后置"}),
+            json!({"comment": "深", "constant": true, "content": "深度", "extensions": {"position": 4, "depth": 1, "role": 1}}),
+        ]);
+        let history = vec![Message::user("一"), Message::assistant("二")];
+        let activation = activate(&[&book], &history, &counter());
+        assert_eq!(activation.before_char().as_deref(), Some("前置"));
+        assert_eq!(activation.after_char().as_deref(), Some("后置"));
+
+        let injected = activation.inject(history);
+        let texts: Vec<_> = injected
+            .iter()
+            .map(|m| (m.role_str(), m.content_text().into_owned()))
+            .collect();
+        assert_eq!(
+            texts,
+            vec![
+                ("user", "一".to_string()),
+                ("user", "深度".to_string()),
+                ("assistant", "二".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn depth_skips_tool_results_and_never_splits_a_tool_exchange() {
+        let call = |id: &str| limerence_ai::ToolCall {
+            id: id.to_string(),
+            function: limerence_ai::FunctionCall {
+                name: "memory_search".to_string(),
+                arguments: "{}".to_string(),
+            },
+        };
+        // A tool round in progress: two parallel calls and their results.
+        let history = vec![
+            Message::user("一"),
+            Message::assistant("二"),
+            Message::user("查一下"),
+            Message::assistant_with_tools("", vec![call("a"), call("b")]),
+            Message::tool_result("a", "甲"),
+            Message::tool_result("b", "乙"),
+        ];
+        assert_eq!(depth_index(&history, 0), 6);
+        assert_eq!(depth_index(&history, 1), 3);
+        assert_eq!(depth_index(&history, 2), 2);
+        assert_eq!(depth_index(&history, 4), 0);
+        assert_eq!(depth_index(&history, 9), 0);
+
+        let book = book(vec![
+            json!({"comment": "深", "constant": true, "content": "深度",
+            "extensions": {"position": 4, "depth": 1, "role": 0}}),
+        ]);
+        let activation = activate(&[&book], &history, &counter());
+        let roles: Vec<_> = activation
+            .inject(history)
+            .iter()
+            .map(|m| m.role_str())
+            .collect();
+        assert_eq!(
+            roles,
+            vec![
+                "user",
+                "assistant",
+                "user",
+                "system",
+                "assistant",
+                "tool",
+                "tool"
+            ]
+        );
+    }
+
+    #[test]
+    fn sillytavern_world_files_load_as_books() {
+        let dir = std::env::temp_dir().join(format!("limerence-world-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).expect("temp dir");
+        let path = dir.join("王国.json");
+        std::fs::write(
+            &path,
+            json!({"entries": {
+                "1": {"uid": 1, "key": ["宫殿"], "keysecondary": [], "comment": "宫殿", "content": "宫殿在山上。",
+                      "order": 10, "position": 4, "depth": 2, "role": 0, "disable": false, "selectiveLogic": 0},
+                "0": {"uid": 0, "key": ["王都"], "comment": "王都", "content": "王都很大。", "order": 100,
+                      "position": 0, "disable": true},
+            }})
+            .to_string(),
+        )
+        .expect("write world");
+
+        let world = load_world(&path).expect("world");
+        assert_eq!(world.name.as_deref(), Some("王国"));
+        assert_eq!(world.entries.len(), 2);
+        assert_eq!(world.entries[0].title(), "王都");
+        assert!(!world.entries[0].enabled);
+        assert_eq!(world.entries[0].position(), LorePosition::BeforeChar);
+        assert_eq!(
+            world.entries[1].position(),
+            LorePosition::AtDepth {
+                depth: 2,
+                role: LoreRole::System
+            }
+        );
+        assert_eq!(world.entries[1].insertion_order, 10);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
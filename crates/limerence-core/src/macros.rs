@@ -0,0 +1,318 @@
+//! `{{macro}}` substitution in card fields, preset prompts, lorebook entries
+//! and first messages, with SillyTavern's names and argument syntax
+//! (`{{random::a::b}}` or `{{random:a,b}}`). Macros may nest; unknown ones,
+//! like `{{original}}`, are left as they are.
+
+use chrono::{DateTime, Datelike, Local};
+use limerence_ai::Message;
+use std::collections::BTreeMap;
+
+/// Macros nested deeper than this are left as text, so a card full of
+/// unclosed `{{` cannot exhaust the stack.
+const MAX_NESTING: usize = 32;
+
+/// Per-session variables for `{{getvar}}` and friends.
+pub type Variables = BTreeMap<String, String>;
+
+/// What macros can refer to.
+pub struct MacroContext<'a> {
+    pub char: &'a str,
+    pub user: &'a str,
+    /// The chat so far, for `{{lastMessage}}` and the like.
+    pub history: &'a [Message],
+    pub model: &'a str,
+    pub now: DateTime<Local>,
+}
+
+/// `text` with its macros replaced. `{{setvar}}` and the other variable
+/// macros update `vars`.
+pub fn expand(text: &str, ctx: &MacroContext<'_>, vars: &mut Variables) -> String {
+    if !text.contains("{{") {
+        return text.to_string();
+    }
+    let mut pos = 0;
+    Expander { ctx, vars }.expand(text, &mut pos, 0).0
+}
+
+struct Expander<'a, 'b> {
+    ctx: &'a MacroContext<'b>,
+    vars: &'a mut Variables,
+}
+
+impl Expander<'_, '_> {
+    /// Expand from `pos` to the end, or inside a macro (`depth` above 0) to
+    /// the `}}` closing it. Returns the text and whether that `}}` was found.
+    fn expand(&mut self, text: &str, pos: &mut usize, depth: usize) -> (String, bool) {
+        let mut out = String::new();
+        while let Some(c) = text[*pos..].chars().next() {
+            let rest = &text[*pos..];
+            if rest.starts_with("{{") && depth < MAX_NESTING {
+                *pos += 2;
+                let (inner, closed) = self.expand(text, pos, depth + 1);
+                match closed.then(|| self.evaluate(&inner)).flatten() {
+                    Some(value) => out.push_str(&value),
+                    None => {
+                        out.push_str("{{");
+                        out.push_str(&inner);
+                        if closed {
+                            out.push_str("}}");
+                        }
+                    }
+                }
+            } else if depth > 0 && rest.starts_with("}}") {
+                *pos += 2;
+                return (out, true);
+            } else {
+                out.push(c);
+                *pos += c.len_utf8();
+            }
+        }
+        (out, false)
+    }
+
+    /// The value of one macro, or `None` for one this engine does not know.
+    fn evaluate(&mut self, inner: &str) -> Option<String> {
+        let inner = inner.trim();
+        if inner.starts_with("//") {
+            return Some(String::new());
+        }
+        let (name, args) = split_macro(inner);
+        let ctx = self.ctx;
+        let now = ctx.now;
+        let arg = |i: usize| {
+            args.get(i)
+                .map(|a| a.trim().to_string())
+                .unwrap_or_default()
+        };
+        let value = match name.to_lowercase().as_str() {
+            "char" | "charname" | "charactername" => ctx.char.to_string(),
+            "user" | "username" => ctx.user.to_string(),
+            "model" | "modelid" => ctx.model.to_string(),
+            "date" => now.format("%Y-%m-%d").to_string(),
+            "time" => now.format("%H:%M").to_string(),
+            "datetime" => now.format("%Y-%m-%d %H:%M").to_string(),
+            "year" => now.year().to_string(),
+            "month" => format!("{:02}", now.month()),
+            "day" => format!("{:02}", now.day()),
+            "weekday" => ["日", "一", "二", "三", "四", "五", "六"]
+                [now.weekday().num_days_from_sunday() as usize]
+                .to_string(),
+            "newline" => "\n".to_string(),
+            "noop" => String::new(),
+            "random" => {
+                let choices: Vec<&String> = args.iter().collect();
+                if choices.is_empty() {
+                    return Some(String::new());
+                }
+                choices[fastrand::usize(..choices.len())].clone()
+            }
+            "roll" => roll(&arg(0))?.to_string(),
+            "lastmessage" => last_text(ctx.history, |_| true),
+            "lastusermessage" => last_text(ctx.history, |m| matches!(m, Message::User { .. })),
+            "lastcharmessage" => last_text(ctx.history, |m| matches!(m, Message::Assistant { .. })),
+            "getvar" => self.vars.get(&arg(0)).cloned().unwrap_or_default(),
+            "setvar" => {
+                self.vars
+                    .insert(arg(0), args.get(1).cloned().unwrap_or_default());
+                String::new()
+            }
+            "addvar" => {
+                let value = args.get(1).cloned().unwrap_or_default();
+                let entry = self.vars.entry(arg(0)).or_default();
+                *entry = match (entry.trim().parse::<f64>(), value.trim().parse::<f64>()) {
+                    (Ok(a), Ok(b)) => format_number(a + b),
+                    _ if entry.is_empty() => value,
+                    _ => format!("{entry}{value}"),
+                };
+                String::new()
+            }
+            "incvar" | "decvar" => {
+                let step = if name.eq_ignore_ascii_case("incvar") {
+                    1.0
+                } else {
+                    -1.0
+                };
+                let entry = self.vars.entry(arg(0)).or_default();
+                *entry = format_number(entry.trim().parse::<f64>().unwrap_or(0.0) + step);
+                entry.clone()
+            }
+            _ => return None,
+        };
+        Some(value)
+    }
+}
+
+/// Name and arguments: `name::a::b`, `name:a,b` or `name a`.
+fn split_macro(inner: &str) -> (&str, Vec<String>) {
+    if let Some((name, rest)) = inner.split_once("::") {
+        return (name.trim(), rest.split("::").map(str::to_string).collect());
+    }
+    if let Some((name, rest)) = inner.split_once(':') {
+        let args = if name.trim().eq_ignore_ascii_case("random") {
+            rest.split(',').map(str::to_string).collect()
+        } else {
+            vec![rest.to_string()]
+        };
+        return (name.trim(), args);
+    }
+    match inner.split_once(char::is_whitespace) {
+        Some((name, rest)) => (name, vec![rest.to_string()]),
+        None => (inner, Vec::new()),
+    }
+}
+
+/// Dice notation: `d20`, `2d6+3`, or a bare `20` for one twenty-sided die.
+fn roll(formula: &str) -> Option<i64> {
+    let formula: String = formula.chars().filter(|c| !c.is_whitespace()).collect();
+    let formula = formula.to_lowercase();
+    let (dice, modifier) = match formula.find(['+', '-']) {
+        Some(i) => (&formula[..i], formula[i..].parse::<i64>().ok()?),
+        None => (formula.as_str(), 0),
+    };
+    let (count, sides) = match dice.split_once('d') {
+        Some(("", sides)) => (1, sides.parse::<u64>().ok()?),
+        Some((count, sides)) => (count.parse::<u64>().ok()?, sides.parse::<u64>().ok()?),
+        None => (1, dice.parse::<u64>().ok()?),
+    };
+    if sides == 0 || count > 1000 {
+        return None;
+    }
+    // Huge dice or modifiers from a card give up instead of overflowing.
+    let sides = usize::try_from(sides).ok()?;
+    let mut total: i64 = 0;
+    for _ in 0..count {
+        let face = i64::try_from(fastrand::usize(..sides))
+            .ok()?
+            .checked_add(1)?;
+        total = total.checked_add(face)?;
+    }
+    total.checked_add(modifier)
+}
+
+fn last_text(history: &[Message], pick: impl Fn(&Message) -> bool) -> String {
+    history
+        .iter()
+        .rev()
+        .filter(|m| matches!(m, Message::User { .. } | Message::Assistant { .. }))
+        .find(|m| pick(m))
+        .map(|m| m.content_text().into_owned())
+        .unwrap_or_default()
+}
+
+/// Integers without a trailing `.0`.
+fn format_number(n: f64) -> String {
+    if n.fract() == 0.0 && n.abs() < 1e15 {
+        format!("{}", n as i64)
+    } else {
+        n.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn with_context<T>(history: &[Message], f: impl FnOnce(&MacroContext<'_>) -> T) -> T {
+        let ctx = MacroContext {
+            char: "艾莉",
+            user: "小明",
+            history,
+            model: "deepseek-chat",
+            now: Local.with_ymd_and_hms(2025, 1, 15, 9, 5, 0).unwrap(),
+        };
+        f(&ctx)
+    }
+
+    fn run(text: &str) -> String {
+        with_context(&[], |ctx| expand(text, ctx, &mut Variables::new()))
+    }
+
+    #[test]
+    fn replaces_names_dates_and_leaves_unknown_macros() {
+        assert_eq!(
+            run("{{char}}对{{user}}说：今天是{{date}} {{time}}，星期{{weekday}}"),
+            "艾莉对小明说：今天是2025-01-15 09:05，星期三"
+        );
+        assert_eq!(run("{{CHAR}}{{ user }}"), "艾莉小明");
+        assert_eq!(
+            run("{{original}}、{{unknown}}、{{char"),
+            "{{original}}、{{unknown}}、{{char"
+        );
+        assert_eq!(run("a{{// 注释}}b{{newline}}c"), "ab\nc");
+        assert_eq!(run("no variables here"), "no variables here");
+        assert_eq!(run(""), "");
+    }
+
+    #[test]
+    fn random_and_roll_stay_in_range() {
+        for _ in 0..50 {
+            let pick = run("{{random:红,绿,蓝}}");
+            assert!(["红", "绿", "蓝"].contains(&pick.as_str()), "{pick}");
+            assert!(["甲", "乙"].contains(&run("{{random::甲::乙}}").as_str()));
+            let d20: i64 = run("{{roll:d20}}").parse().unwrap();
+            assert!((1..=20).contains(&d20));
+            let sum: i64 = run("{{roll 2d6+3}}").parse().unwrap();
+            assert!((5..=15).contains(&sum));
+        }
+        assert_eq!(run("{{roll:d0}}"), "{{roll:d0}}");
+        for overflowing in [
+            "{{roll:1000d18446744073709551615}}",
+            "{{roll:d6+9223372036854775807}}",
+        ] {
+            assert_eq!(run(overflowing), overflowing);
+        }
+    }
+
+    #[test]
+    fn deep_nesting_is_capped() {
+        let unclosed = "{{".repeat(100_000);
+        assert_eq!(run(&unclosed), unclosed);
+        let nested = format!("{}char{}", "{{".repeat(3), "}}".repeat(3));
+        assert_eq!(run(&nested), "{{{{艾莉}}}}");
+    }
+
+    #[test]
+    fn variables_persist_across_expansions_and_nest() {
+        let mut vars = Variables::new();
+        with_context(&[], |ctx| {
+            assert_eq!(
+                expand(
+                    "{{setvar::心情::{{random::好}}}}{{getvar::心情}}",
+                    ctx,
+                    &mut vars
+                ),
+                "好"
+            );
+            expand("{{addvar::好感::5}}{{addvar::好感::2}}", ctx, &mut vars);
+            assert_eq!(
+                expand("{{incvar::次数}}{{incvar::次数}}", ctx, &mut vars),
+                "12"
+            );
+            assert_eq!(expand("{{decvar::次数}}", ctx, &mut vars), "1");
+        });
+        assert_eq!(vars["心情"], "好");
+        assert_eq!(vars["好感"], "7");
+        assert_eq!(run("[{{getvar::没有}}]"), "[]");
+    }
+
+    #[test]
+    fn last_messages_come_from_the_history() {
+        let history = [
+            Message::user("第一句"),
+            Message::assistant("回答"),
+            Message::user("第二句"),
+        ];
+        with_context(&history, |ctx| {
+            let mut vars = Variables::new();
+            assert_eq!(
+                expand(
+                    "{{lastMessage}}|{{lastUsermessage}}|{{lastCharMessage}}",
+                    ctx,
+                    &mut vars
+                ),
+                "第二句|第二句|回答"
+            );
+        });
+    }
+}
@@ -47,12 +47,12 @@ impl MemoryIndex {
         if let Ok(read_dir) = std::fs::read_dir(&dir) {
             for entry in read_dir.flatten() {
                 let path = entry.path();
-                if path.extension().is_some_and(|e| e == "jsonl") {
-                    if let Ok(content) = std::fs::read_to_string(&path) {
-                        for line in content.lines() {
-                            if let Ok(mem) = serde_json::from_str::<MemoryEntry>(line) {
-                                self.entries.push(mem);
-                            }
+                if path.extension().is_some_and(|e| e == "jsonl")
+                    && let Ok(content) = std::fs::read_to_string(&path)
+                {
+                    for line in content.lines() {
+                        if let Ok(mem) = serde_json::from_str::<MemoryEntry>(line) {
+                            self.entries.push(mem);
                         }
                     }
                 }
@@ -101,6 +101,13 @@ impl MemoryIndex {
         self.entries.push(entry);
     }
 
+    /// Drop the conversation history of session `id`, on disk and in the index.
+    pub fn forget_session(&mut self, id: &str) {
+        let _ = std::fs::remove_file(self.memory_root.join(format!("{id}.jsonl")));
+        self.entries.retain(|e| e.session_id != id);
+        self.rebuild_index();
+    }
+
     /// Search memories using BM25 scoring.
     pub fn search(&self, query: &str, limit: usize) -> Vec<SearchResult> {
         if self.entries.is_empty() {
@@ -433,7 +440,7 @@ fn collect_markdown_files(root: &Path, dir: &Path, out: &mut Vec<String>) -> Res
             continue;
         }
 
-        if !meta.is_file() || !path.extension().is_some_and(|ext| ext == "md") {
+        if !meta.is_file() || path.extension().is_none_or(|ext| ext != "md") {
             continue;
         }
 
@@ -469,7 +476,7 @@ fn memory_file_write_in_dir(
             .map_err(|e| format!("写入记忆文件失败：{e}"))?;
 
         if file_exists && file_len > 0 {
-            write!(file, "\n").map_err(|e| format!("写入记忆文件失败：{e}"))?;
+            writeln!(file).map_err(|e| format!("写入记忆文件失败：{e}"))?;
         }
         write!(file, "{content}").map_err(|e| format!("写入记忆文件失败：{e}"))?;
         return Ok(format!(
@@ -0,0 +1,584 @@
+//! SillyTavern chat-completion presets: sampler settings, and prompts
+//! assembled in the preset's `prompt_order` around the card and chat history.
+
+use limerence_ai::{Message, SamplingParams};
+use serde::Deserialize;
+use serde_json::Value;
+use std::path::Path;
+
+use crate::character::CharacterData;
+use crate::config::presets_dir;
+use crate::context::Prompt;
+use crate::lorebook::{self, Activation};
+use crate::regex_script::{self, RegexScript};
+
+/// The `prompt_order` list ST uses for chat completion; `100000` is its
+/// pristine default.
+const GLOBAL_ORDER_ID: i64 = 100001;
+
+/// A prompt of the preset, or a marker for content ST fills in.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PresetPrompt {
+    pub identifier: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub role: Option<String>,
+    #[serde(default)]
+    pub content: String,
+    /// Filled from the card, lore or chat (`charDescription`, `chatHistory`, ...).
+    #[serde(default)]
+    pub marker: bool,
+    #[serde(default)]
+    pub enabled: Option<bool>,
+    /// 0: where it stands in the order; 1: inside the chat history.
+    #[serde(default)]
+    pub injection_position: u8,
+    /// Messages from the end of the history, for `injection_position` 1.
+    #[serde(default)]
+    pub injection_depth: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct PromptOrder {
+    #[serde(default)]
+    character_id: i64,
+    #[serde(default)]
+    order: Vec<OrderEntry>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct OrderEntry {
+    identifier: String,
+    #[serde(default = "enabled_by_default")]
+    enabled: bool,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+/// The parts of a SillyTavern chat-completion preset Limerence uses.
+#[derive(Debug, Clone)]
+pub struct Preset {
+    pub name: String,
+    pub prompts: Vec<PresetPrompt>,
+    /// Prompt identifiers in sending order, with their switch.
+    pub order: Vec<(String, bool)>,
+    pub scenario_format: String,
+    pub personality_format: String,
+    /// `{0}` stands for the activated entries.
+    pub wi_format: String,
+    pub new_chat_prompt: String,
+    pub new_example_chat_prompt: String,
+    pub sampling: SamplingParams,
+    /// Regex scripts bound to the preset (`extensions.regex_scripts`).
+    pub regex_scripts: Vec<RegexScript>,
+}
+
+/// What a preset is assembled around.
+pub struct PromptParts<'a> {
+    pub card: &'a CharacterData,
+    pub lore: &'a Activation,
+    /// The chat history, with at-depth lore already in place.
+    pub history: Vec<Message>,
+    /// Limerence's own instructions (tools, memory), sent before the history.
+    pub instructions: String,
+    /// Macro expansion for the preset's and card's text.
+    pub expand: &'a mut dyn FnMut(&str) -> String,
+}
+
+impl Preset {
+    /// Read a preset file; its name is the file stem.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let value: Value = serde_json::from_slice(&std::fs::read(path)?)?;
+        let name = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        Ok(Self::from_value(name, &value)?)
+    }
+
+    /// A preset file path, or the name of one in `presets_dir()`.
+    pub fn find(name: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = Path::new(name);
+        if path.is_file() {
+            return Self::load(path);
+        }
+        Self::load(&presets_dir().join(format!("{name}.json")))
+    }
+
+    /// Names of the presets in `presets_dir()`.
+    pub fn list() -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(presets_dir())
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|e| e == "json"))
+            .filter_map(|path| Some(path.file_stem()?.to_string_lossy().into_owned()))
+            .collect();
+        names.sort();
+        names
+    }
+
+    pub fn from_value(name: String, value: &Value) -> Result<Self, serde_json::Error> {
+        let prompts: Vec<PresetPrompt> = match value.get("prompts") {
+            Some(prompts) => serde_json::from_value(prompts.clone())?,
+            None => Vec::new(),
+        };
+        let orders: Vec<PromptOrder> = match value.get("prompt_order") {
+            Some(orders) => serde_json::from_value(orders.clone())?,
+            None => Vec::new(),
+        };
+        let order = match orders
+            .iter()
+            .find(|o| o.character_id == GLOBAL_ORDER_ID)
+            .or(orders.last())
+        {
+            Some(o) => o
+                .order
+                .iter()
+                .map(|e| (e.identifier.clone(), e.enabled))
+                .collect(),
+            None => prompts
+                .iter()
+                .map(|p| (p.identifier.clone(), p.enabled.unwrap_or(true)))
+                .collect(),
+        };
+        let text = |key: &str, default: &str| {
+            value
+                .get(key)
+                .and_then(Value::as_str)
+                .unwrap_or(default)
+                .to_string()
+        };
+        Ok(Self {
+            name,
+            prompts,
+            order,
+            scenario_format: text("scenario_format", "{{scenario}}"),
+            personality_format: text("personality_format", "{{personality}}"),
+            wi_format: text("wi_format", "{0}"),
+            new_chat_prompt: text("new_chat_prompt", ""),
+            new_example_chat_prompt: text("new_example_chat_prompt", ""),
+            sampling: sampling_from_st_preset(value),
+            regex_scripts: value
+                .get("extensions")
+                .map(regex_script::scripts_in)
+                .unwrap_or_default(),
+        })
+    }
+
+    fn prompt(&self, identifier: &str) -> Option<&PresetPrompt> {
+        self.prompts.iter().find(|p| p.identifier == identifier)
+    }
+
+    /// The enabled prompts in order.
+    fn enabled(&self) -> impl Iterator<Item = &PresetPrompt> {
+        self.order
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .filter_map(|(id, _)| self.prompt(id))
+    }
+
+    /// The request messages in the preset's order. Markers are filled from
+    /// the card and lore; the card's `system_prompt` and
+    /// `post_history_instructions` replace `main` and `jailbreak`, with
+    /// `{{original}}` standing for the preset's text. In-chat prompts go
+    /// into the history at their depth. Without a `jailbreak` slot the
+    /// post-history instructions follow everything else. The prompt's
+    /// history span covers the chat alone, not the preset's prompts around it.
+    pub fn assemble(&self, parts: PromptParts<'_>) -> Prompt {
+        let PromptParts {
+            card,
+            lore,
+            mut history,
+            instructions,
+            expand,
+        } = parts;
+        let mut in_chat: Vec<(usize, Message)> = self
+            .enabled()
+            .filter(|p| p.injection_position == 1 && !p.marker)
+            .filter(|p| !p.content.trim().is_empty())
+            .map(|p| {
+                let at = lorebook::depth_index(&history, p.injection_depth);
+                (at, message(p.role.as_deref(), expand(&p.content)))
+            })
+            .collect();
+        // Insert from the back so earlier indices stay valid.
+        in_chat.reverse();
+        in_chat.sort_by_key(|(at, _)| std::cmp::Reverse(*at));
+        for (at, message) in in_chat {
+            history.insert(at, message);
+        }
+
+        let mut lead = Vec::new();
+        if !instructions.trim().is_empty() {
+            lead.push(Message::system(instructions));
+        }
+        if !self.new_chat_prompt.trim().is_empty() {
+            lead.push(Message::system(expand(&self.new_chat_prompt)));
+        }
+
+        let mut messages = Vec::new();
+        let mut chat = Some((lead, history));
+        let mut span = 0..0;
+        let mut post_history_sent = false;
+        let mut place_chat = |messages: &mut Vec<Message>, span: &mut std::ops::Range<usize>| {
+            if let Some((lead, history)) = chat.take() {
+                messages.extend(lead);
+                let start = messages.len();
+                messages.extend(history);
+                *span = start..messages.len();
+            }
+        };
+
+        for prompt in self.enabled() {
+            if prompt.injection_position == 1 && !prompt.marker {
+                continue;
+            }
+            let content = match prompt.identifier.as_str() {
+                "chatHistory" => {
+                    place_chat(&mut messages, &mut span);
+                    continue;
+                }
+                "main" => with_original(&card.system_prompt, &prompt.content),
+                "jailbreak" => {
+                    post_history_sent = true;
+                    with_original(&card.post_history_instructions, &prompt.content)
+                }
+                "charDescription" => card.description.clone(),
+                "charPersonality" => format_field(
+                    &self.personality_format,
+                    "{{personality}}",
+                    &card.personality,
+                ),
+                "scenario" => format_field(&self.scenario_format, "{{scenario}}", &card.scenario),
+                "worldInfoBefore" => self.world_info(lore.before_char()),
+                "worldInfoAfter" => self.world_info(lore.after_char()),
+                "dialogueExamples" if !card.mes_example.trim().is_empty() => {
+                    format!("{}\n{}", self.new_example_chat_prompt, card.mes_example)
+                        .trim()
+                        .to_string()
+                }
+                // Markers Limerence has nothing for, like `personaDescription`.
+                _ if prompt.marker => continue,
+                _ => prompt.content.clone(),
+            };
+            if !content.trim().is_empty() {
+                let role = if prompt.marker {
+                    None
+                } else {
+                    prompt.role.as_deref()
+                };
+                messages.push(message(role, expand(&content)));
+            }
+        }
+
+        // A preset without a history slot still gets the conversation.
+        place_chat(&mut messages, &mut span);
+        if !post_history_sent {
+            let post_history = card.post_history_instructions.replace("{{original}}", "");
+            if !post_history.trim().is_empty() {
+                messages.push(Message::system(expand(post_history.trim())));
+            }
+        }
+        Prompt {
+            messages,
+            history: span,
+        }
+    }
+
+    fn world_info(&self, lore: Option<String>) -> String {
+        lore.map(|lore| self.wi_format.replace("{0}", &lore))
+            .unwrap_or_default()
+    }
+}
+
+fn message(role: Option<&str>, content: String) -> Message {
+    match role {
+        Some("user") => Message::user(content),
+        Some("assistant") => Message::assistant(content),
+        _ => Message::system(content),
+    }
+}
+
+/// The card's text in place of the preset's, keeping the preset's where
+/// `{{original}}` says so.
+fn with_original(card: &str, preset: &str) -> String {
+    if card.trim().is_empty() {
+        preset.to_string()
+    } else {
+        card.replace("{{original}}", preset)
+    }
+}
+
+/// A `*_format` string with the field filled in; empty when the field is.
+fn format_field(format: &str, placeholder: &str, value: &str) -> String {
+    if value.trim().is_empty() {
+        return String::new();
+    }
+    format.replace(placeholder, value)
+}
+
+/// Sampler settings from a SillyTavern chat-completion preset.
+///
+/// ST stores them as top-level keys next to `prompts`; `openai_max_tokens` is the
+/// response length and a negative `seed` means "random". Missing keys stay unset.
+pub fn sampling_from_st_preset(preset: &serde_json::Value) -> SamplingParams {
+    let float = |key: &str| preset.get(key).and_then(|v| v.as_f64());
+    SamplingParams {
+        temperature: float("temperature"),
+        top_p: float("top_p"),
+        max_tokens: preset
+            .get("openai_max_tokens")
+            .and_then(|v| v.as_u64())
+            .and_then(|v| u32::try_from(v).ok()),
+        presence_penalty: float("presence_penalty"),
+        frequency_penalty: float("frequency_penalty"),
+        seed: preset
+            .get("seed")
+            .and_then(|v| v.as_i64())
+            .filter(|s| *s >= 0),
+        ..SamplingParams::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CharacterCard;
+    use serde_json::json;
+
+    fn sample() -> Preset {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../../预设example/预设/久久预设 FOR Claude.json");
+        Preset::load(&path).expect("sample preset")
+    }
+
+    fn roles_and_texts(messages: &[Message]) -> Vec<(&'static str, String)> {
+        messages
+            .iter()
+            .map(|m| (m.role_str(), m.content_text().into_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn sample_preset_is_assembled_in_prompt_order() {
+        let preset = sample();
+        assert_eq!(preset.name, "久久预设 FOR Claude");
+        let mut card = CharacterCard::default_character().data;
+        card.description = "一位温柔的心理咨询师。".to_string();
+        card.personality = "耐心".to_string();
+        let history = vec![Message::user("你好"), Message::assistant("你好呀。")];
+        let prompt = preset.assemble(PromptParts {
+            card: &card,
+            lore: &Activation::default(),
+            history,
+            instructions: "工具说明".to_string(),
+            expand: &mut |s: &str| s.to_string(),
+        });
+        let texts = roles_and_texts(&prompt.messages);
+
+        assert_eq!(texts[0].0, "user");
+        assert!(texts[0].1.contains("你不再是大语言模型"));
+        assert_eq!(texts[1].0, "assistant");
+        let position = |needle: &str| texts.iter().position(|(_, t)| t.contains(needle));
+        let lore_open = position("<Lore>").expect("lore opener");
+        let description = position("一位温柔的心理咨询师。").expect("description");
+        let lore_close = position("</Lore>").expect("lore closer");
+        assert!(lore_open < description && description < lore_close);
+        // `charPersonality` is switched off in the sample's order.
+        assert_eq!(position("耐心"), None);
+        // So is the one-off full summary prompt.
+        assert_eq!(position("全文大总结"), None);
+
+        let chat = texts
+            .iter()
+            .position(|t| t == &("user", "你好".to_string()))
+            .expect("history");
+        assert_eq!(texts[chat - 1].1, "工具说明");
+        assert!(texts[chat - 2].1.contains("<savedata>"));
+        assert_eq!(texts[chat + 1], ("assistant", "你好呀。".to_string()));
+        assert!(texts[chat + 2].1.contains("</savedata>"));
+        assert_eq!(texts.last().unwrap().0, "assistant");
+        assert_eq!(prompt.history, chat..chat + 2);
+    }
+
+    #[test]
+    fn fitting_a_preset_prompt_only_drops_chat_turns() {
+        let preset = sample();
+        let mut card = CharacterCard::default_character().data;
+        card.description = "一位温柔的心理咨询师。".to_string();
+        let mut history = Vec::new();
+        for i in 0..20 {
+            history.push(Message::user(format!("第 {i} 轮：{}", "聊天".repeat(50))));
+            history.push(Message::assistant("好的。".repeat(20)));
+        }
+        let prompt = preset.assemble(PromptParts {
+            card: &card,
+            lore: &Activation::default(),
+            history,
+            instructions: String::new(),
+            expand: &mut |s: &str| s.to_string(),
+        });
+        let counter = crate::context::TokenCounter::for_model("gpt-4o");
+        let limit = counter.messages(&prompt.messages) - 500;
+        let kept_outside = prompt.messages.len() - prompt.history.len();
+
+        let fitted = crate::context::fit_prompt(prompt, limit, &counter).expect("fits");
+        assert!(fitted.dropped > 0);
+        assert_eq!(fitted.elided, 0);
+        let texts = roles_and_texts(&fitted.messages);
+        assert!(texts[0].1.contains("你不再是大语言模型"));
+        assert!(
+            texts
+                .iter()
+                .any(|(_, t)| t.contains("一位温柔的心理咨询师。"))
+        );
+        assert!(texts.iter().any(|(_, t)| t.contains("<savedata>")));
+        assert_eq!(fitted.messages.len(), kept_outside + 40 - fitted.dropped);
+    }
+
+    #[test]
+    fn card_prompts_replace_main_and_jailbreak_and_in_chat_prompts_go_to_depth() {
+        let preset = Preset::from_value(
+            "测试".to_string(),
+            &json!({
+                "scenario_format": "[场景：{{scenario}}]",
+                "wi_format": "<世界>{0}</世界>",
+                "prompts": [
+                    {"identifier": "main", "content": "主提示", "system_prompt": true},
+                    {"identifier": "worldInfoBefore", "marker": true},
+                    {"identifier": "scenario", "marker": true},
+                    {"identifier": "chatHistory", "marker": true},
+                    {"identifier": "jailbreak", "content": "越狱提示", "system_prompt": true},
+                    {"identifier": "nudge", "role": "user", "content": "（记得保持角色）",
+                     "injection_position": 1, "injection_depth": 1},
+                ],
+                "prompt_order": [
+                    {"character_id": 100000, "order": [{"identifier": "main", "enabled": true}]},
+                    {"character_id": 100001, "order": [
+                        {"identifier": "main", "enabled": true},
+                        {"identifier": "worldInfoBefore", "enabled": true},
+                        {"identifier": "scenario", "enabled": true},
+                        {"identifier": "nudge", "enabled": true},
+                        {"identifier": "chatHistory", "enabled": true},
+                        {"identifier": "jailbreak", "enabled": true},
+                    ]},
+                ],
+            }),
+        )
+        .expect("preset");
+        let mut card = CharacterCard::default_character().data;
+        card.system_prompt = "{{original}}，再加上角色的要求".to_string();
+        card.scenario = "{{char}}在雨夜的咖啡馆".to_string();
+        card.post_history_instructions = "不要替用户说话。".to_string();
+        let lore = crate::lorebook::activate(
+            &[&serde_json::from_value(json!({"entries": [
+                {"constant": true, "content": "这是一个魔法世界。", "position": "before_char"},
+            ]}))
+            .unwrap()],
+            &[],
+            &crate::context::TokenCounter::for_model("gpt-4o"),
+        );
+        let prompt = preset.assemble(PromptParts {
+            card: &card,
+            lore: &lore,
+            history: vec![Message::user("一"), Message::assistant("二")],
+            instructions: String::new(),
+            expand: &mut |s: &str| s.replace("{{char}}", "苏晚"),
+        });
+
+        assert_eq!(
+            roles_and_texts(&prompt.messages),
+            vec![
+                ("system", "主提示，再加上角色的要求".to_string()),
+                ("system", "<世界>这是一个魔法世界。</世界>".to_string()),
+                ("system", "[场景：苏晚在雨夜的咖啡馆]".to_string()),
+                ("user", "一".to_string()),
+                ("user", "（记得保持角色）".to_string()),
+                ("assistant", "二".to_string()),
+                ("system", "不要替用户说话。".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn in_chat_prompts_stay_out_of_tool_exchanges() {
+        let preset = Preset::from_value(
+            "测试".to_string(),
+            &json!({"prompts": [
+                {"identifier": "chatHistory", "marker": true},
+                {"identifier": "nudge", "role": "system", "content": "提醒",
+                 "injection_position": 1, "injection_depth": 1},
+            ]}),
+        )
+        .expect("preset");
+        let call = limerence_ai::ToolCall {
+            id: "a".to_string(),
+            function: limerence_ai::FunctionCall {
+                name: "memory_get".to_string(),
+                arguments: "{}".to_string(),
+            },
+        };
+        let prompt = preset.assemble(PromptParts {
+            card: &CharacterCard::default_character().data,
+            lore: &Activation::default(),
+            history: vec![
+                Message::user("查一下"),
+                Message::assistant_with_tools("", vec![call]),
+                Message::tool_result("a", "无"),
+            ],
+            instructions: String::new(),
+            expand: &mut |s: &str| s.to_string(),
+        });
+        let roles: Vec<_> = prompt.messages.iter().map(|m| m.role_str()).collect();
+        assert_eq!(roles, vec!["user", "system", "assistant", "tool"]);
+    }
+
+    #[test]
+    fn preset_sampling_reads_st_keys() {
+        let preset = serde_json::json!({
+            "temperature": 1.1,
+            "top_p": 0.95,
+            "frequency_penalty": 0,
+            "openai_max_tokens": 1200,
+            "seed": -1,
+            "prompts": [],
+        });
+        let sampling = sampling_from_st_preset(&preset);
+        assert_eq!(sampling.temperature, Some(1.1));
+        assert_eq!(sampling.top_p, Some(0.95));
+        assert_eq!(sampling.frequency_penalty, Some(0.0));
+        assert_eq!(sampling.max_tokens, Some(1200));
+        assert_eq!(sampling.seed, None);
+        assert_eq!(sampling.presence_penalty, None);
+    }
+
+    #[test]
+    fn card_overrides_preset_which_overrides_model() {
+        let model = SamplingParams {
+            temperature: Some(0.7),
+            max_tokens: Some(4000),
+            stop: vec!["END".to_string()],
+            ..SamplingParams::default()
+        };
+        let preset = sampling_from_st_preset(&serde_json::json!({
+            "temperature": 1.0,
+            "top_p": 0.9,
+        }));
+
+        let mut card = CharacterCard::default_character();
+        card.data.extensions = serde_json::json!({
+            "limerence": { "sampling": { "temperature": 0.4, "stop": ["\n小林："] } }
+        });
+
+        let effective = model.merged(&preset).merged(&card.sampling_overrides());
+        assert_eq!(effective.temperature, Some(0.4));
+        assert_eq!(effective.top_p, Some(0.9));
+        assert_eq!(effective.max_tokens, Some(4000));
+        assert_eq!(effective.stop, vec!["\n小林：".to_string()]);
+    }
+}
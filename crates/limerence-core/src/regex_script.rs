@@ -0,0 +1,387 @@
+//! SillyTavern regex scripts: find/replace rules run on user input, replies,
+//! world info and reasoning. Each script changes the stored message, only
+//! the prompt, or only what is displayed.
+
+use limerence_ai::{ContentPart, Message};
+use regex::{Captures, Regex, RegexBuilder};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+use std::path::Path;
+use std::sync::LazyLock;
+
+use crate::config::regex_dir;
+
+/// Text a script may run on (ST's `placement` numbers).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegexPlacement {
+    UserInput = 1,
+    AiOutput = 2,
+    SlashCommand = 3,
+    WorldInfo = 5,
+    Reasoning = 6,
+}
+
+/// Where the text is going.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegexTarget {
+    /// Into the session: scripts with neither `markdownOnly` nor `promptOnly`.
+    Stored,
+    /// Into the session through an edit: like `Stored`, for `runOnEdit` scripts.
+    Edited,
+    /// Into a request; `depth` counts messages from the end (0: the latest).
+    Prompt { depth: Option<usize> },
+    /// Onto the screen.
+    Display { depth: Option<usize> },
+}
+
+/// One script, in ST's export format.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegexScript {
+    #[serde(default)]
+    pub id: String,
+    #[serde(default)]
+    pub script_name: String,
+    /// `/pattern/flags` as in JavaScript, or a bare pattern.
+    pub find_regex: String,
+    /// `$1`, `$<name>` and `{{match}}` stand for the match and its groups.
+    #[serde(default)]
+    pub replace_string: String,
+    /// Removed from the match and groups before they are substituted.
+    #[serde(default)]
+    pub trim_strings: Vec<String>,
+    #[serde(default)]
+    pub placement: Vec<u8>,
+    #[serde(default)]
+    pub disabled: bool,
+    #[serde(default)]
+    pub markdown_only: bool,
+    #[serde(default)]
+    pub prompt_only: bool,
+    #[serde(default)]
+    pub run_on_edit: bool,
+    #[serde(default, deserialize_with = "lenient_depth")]
+    pub min_depth: Option<i64>,
+    #[serde(default, deserialize_with = "lenient_depth")]
+    pub max_depth: Option<i64>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// ST writes depths as numbers, `null` or an empty string.
+fn lenient_depth<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<i64>, D::Error> {
+    let value = Value::deserialize(deserializer)?;
+    Ok(match value {
+        Value::Number(n) => n.as_i64(),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    })
+}
+
+impl RegexScript {
+    /// Whether this script runs on `placement` text headed for `target`.
+    pub fn applies(&self, placement: RegexPlacement, target: RegexTarget) -> bool {
+        if self.disabled || !self.placement.contains(&(placement as u8)) {
+            return false;
+        }
+        let stored = !self.markdown_only && !self.prompt_only;
+        let depth = match target {
+            RegexTarget::Stored => return stored,
+            RegexTarget::Edited => return stored && self.run_on_edit,
+            RegexTarget::Prompt { depth } if self.prompt_only => depth,
+            RegexTarget::Display { depth } if self.markdown_only => depth,
+            _ => return false,
+        };
+        let Some(depth) = depth.map(|d| d as i64) else {
+            return true;
+        };
+        // ST treats bounds below -1 as unset.
+        self.min_depth.is_none_or(|min| min < -1 || depth >= min)
+            && self.max_depth.is_none_or(|max| max < -1 || depth <= max)
+    }
+}
+
+/// The `/pattern/flags` parts of a JavaScript regex literal.
+pub(crate) fn split_js_regex(literal: &str) -> Option<(&str, &str)> {
+    literal
+        .strip_prefix('/')
+        .and_then(|rest| rest.rsplit_once('/'))
+        .filter(|(pattern, flags)| {
+            !pattern.is_empty() && flags.chars().all(|c| "dgimsuvy".contains(c))
+        })
+}
+
+/// `pattern` compiled with the JavaScript `flags` this crate can honor.
+pub(crate) fn build_js_regex(pattern: &str, flags: &str) -> Option<Regex> {
+    RegexBuilder::new(pattern)
+        .case_insensitive(flags.contains('i'))
+        .multi_line(flags.contains('m'))
+        .dot_matches_new_line(flags.contains('s'))
+        .build()
+        .ok()
+}
+
+static GROUP_REF: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\$(\d+)|\$<([^>]+)>").expect("valid pattern"));
+static MATCH_MACRO: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)\{\{match\}\}").expect("valid pattern"));
+
+struct Compiled {
+    script: RegexScript,
+    regex: Regex,
+    global: bool,
+}
+
+impl Compiled {
+    fn new(script: RegexScript) -> Option<Self> {
+        let find = script.find_regex.trim();
+        let (regex, global) = match split_js_regex(find) {
+            Some((pattern, flags)) => (build_js_regex(pattern, flags)?, flags.contains('g')),
+            None => (Regex::new(find).ok()?, false),
+        };
+        Some(Self {
+            script,
+            regex,
+            global,
+        })
+    }
+
+    fn run(&self, text: &str) -> String {
+        let template = MATCH_MACRO.replace_all(&self.script.replace_string, "$$0");
+        let replace = |caps: &Captures| {
+            GROUP_REF
+                .replace_all(&template, |r: &Captures| {
+                    let group = match (r.get(1), r.get(2)) {
+                        (Some(n), _) => n.as_str().parse().ok().and_then(|n| caps.get(n)),
+                        (_, Some(name)) => caps.name(name.as_str()),
+                        _ => None,
+                    };
+                    let mut value = group.map_or("", |g| g.as_str()).to_string();
+                    for trim in &self.script.trim_strings {
+                        if !trim.is_empty() {
+                            value = value.replace(trim, "");
+                        }
+                    }
+                    value
+                })
+                .into_owned()
+        };
+        let limit = if self.global { 0 } else { 1 };
+        self.regex.replacen(text, limit, replace).into_owned()
+    }
+}
+
+/// Scripts ready to run, in the order ST runs them: global, then the
+/// character's, then the preset's. Scripts whose regex does not compile
+/// are dropped.
+#[derive(Default)]
+pub struct RegexScripts {
+    scripts: Vec<Compiled>,
+}
+
+impl RegexScripts {
+    pub fn new(scripts: impl IntoIterator<Item = RegexScript>) -> Self {
+        Self {
+            scripts: scripts.into_iter().filter_map(Compiled::new).collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scripts.is_empty()
+    }
+
+    /// `text` after every script for `placement` and `target`, in order.
+    pub fn apply(&self, text: &str, placement: RegexPlacement, target: RegexTarget) -> String {
+        self.scripts
+            .iter()
+            .filter(|c| c.script.applies(placement, target))
+            .fold(text.to_string(), |text, c| c.run(&text))
+    }
+
+    /// `message` with the scripts for its role run on its text and reasoning.
+    pub fn apply_message(&self, message: Message, target: RegexTarget) -> Message {
+        match message {
+            Message::User { content } => Message::User {
+                content: content
+                    .into_iter()
+                    .map(|part| match part {
+                        ContentPart::Text { text } => {
+                            ContentPart::text(self.apply(&text, RegexPlacement::UserInput, target))
+                        }
+                        other => other,
+                    })
+                    .collect(),
+            },
+            Message::Assistant {
+                content,
+                reasoning,
+                tool_calls,
+            } => Message::Assistant {
+                content: self.apply(&content, RegexPlacement::AiOutput, target),
+                reasoning: reasoning.map(|r| self.apply(&r, RegexPlacement::Reasoning, target)),
+                tool_calls,
+            },
+            other => other,
+        }
+    }
+
+    /// `history` as it is sent: prompt-only scripts run on each user and
+    /// assistant message at its depth.
+    pub fn apply_prompt(&self, history: Vec<Message>) -> Vec<Message> {
+        if self.is_empty() {
+            return history;
+        }
+        let mut depth = history
+            .iter()
+            .filter(|m| matches!(m, Message::User { .. } | Message::Assistant { .. }))
+            .count();
+        history
+            .into_iter()
+            .map(|m| {
+                if !matches!(m, Message::User { .. } | Message::Assistant { .. }) {
+                    return m;
+                }
+                depth -= 1;
+                self.apply_message(m, RegexTarget::Prompt { depth: Some(depth) })
+            })
+            .collect()
+    }
+}
+
+/// Scripts in a card's or preset's `extensions.regex_scripts`.
+pub fn scripts_in(extensions: &Value) -> Vec<RegexScript> {
+    extensions
+        .get("regex_scripts")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|s| serde_json::from_value(s.clone()).ok())
+        .collect()
+}
+
+/// Read a script file: one script, or an array of them.
+pub fn load_scripts(path: &Path) -> Result<Vec<RegexScript>, Box<dyn std::error::Error>> {
+    let value: Value = serde_json::from_slice(&std::fs::read(path)?)?;
+    Ok(match value {
+        Value::Array(_) => serde_json::from_value(value)?,
+        _ => vec![serde_json::from_value(value)?],
+    })
+}
+
+/// The global scripts in `regex_dir()`, by file name; unreadable files are skipped.
+pub fn load_global() -> Vec<RegexScript> {
+    let mut paths: Vec<_> = std::fs::read_dir(regex_dir())
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|e| e == "json"))
+        .collect();
+    paths.sort();
+    paths
+        .iter()
+        .filter_map(|p| load_scripts(p).ok())
+        .flatten()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn sample(name: &str) -> RegexScript {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../../预设example/正则")
+            .join(name);
+        load_scripts(&path).expect("sample script").remove(0)
+    }
+
+    fn script(value: Value) -> RegexScript {
+        serde_json::from_value(value).expect("script")
+    }
+
+    #[test]
+    fn sample_scripts_clean_replies_for_the_prompt_and_display() {
+        let clean = RegexScripts::new([sample("正则三删除多余内容.json")]);
+        let reply = "<think>先想想\n</think>\n你好呀。<disclaimer>免责声明</disclaimer>";
+        for target in [
+            RegexTarget::Prompt { depth: Some(0) },
+            RegexTarget::Display { depth: Some(0) },
+        ] {
+            assert_eq!(
+                clean.apply(reply, RegexPlacement::AiOutput, target),
+                "你好呀。"
+            );
+        }
+        assert_eq!(
+            clean.apply(reply, RegexPlacement::AiOutput, RegexTarget::Stored),
+            reply
+        );
+        assert_eq!(
+            clean.apply(
+                reply,
+                RegexPlacement::UserInput,
+                RegexTarget::Display { depth: None }
+            ),
+            reply
+        );
+
+        // Only replies at depth 5 or more are cut down to their summary.
+        let summary = RegexScripts::new([sample("正则二部分仅保留总结.json")]);
+        let long = "正文很长\n<This_round_events>见面</This_round_events>\n尾注";
+        let history: Vec<Message> = (0..6).map(|_| Message::assistant(long)).collect();
+        let sent = summary.apply_prompt(history);
+        let texts: Vec<_> = sent.iter().map(|m| m.content_text().into_owned()).collect();
+        assert_eq!(
+            texts[0],
+            "<This_round_events>见面</This_round_events>\n尾注"
+        );
+        assert_eq!(texts[1], long);
+        assert_eq!(texts[5], long);
+    }
+
+    #[test]
+    fn replacements_follow_javascript_semantics() {
+        let scripts = RegexScripts::new([
+            script(json!({
+                "findRegex": "/(?<who>小\\w)说：(.+)/",
+                "replaceString": "「$2」——$<who>（{{MATCH}}）",
+                "trimStrings": ["。"],
+                "placement": [1],
+            })),
+            script(json!({"findRegex": "/a/gi", "replaceString": "b", "placement": [1]})),
+            script(json!({"findRegex": "c", "replaceString": "d", "placement": [1]})),
+            script(json!({"findRegex": "/(/", "replaceString": "", "placement": [1]})),
+            script(
+                json!({"findRegex": "x", "replaceString": "y", "placement": [1], "disabled": true}),
+            ),
+        ]);
+        assert_eq!(
+            scripts.apply(
+                "小林说：走吧。",
+                RegexPlacement::UserInput,
+                RegexTarget::Stored
+            ),
+            "「走吧」——小林（小林说：走吧）"
+        );
+        assert_eq!(
+            scripts.apply("Aa cc x", RegexPlacement::UserInput, RegexTarget::Stored),
+            "bb dc x"
+        );
+    }
+
+    #[test]
+    fn edits_run_only_scripts_marked_for_them() {
+        let mut s = script(json!({"findRegex": "猫", "replaceString": "狗", "placement": [1, 2]}));
+        assert!(s.applies(RegexPlacement::AiOutput, RegexTarget::Stored));
+        assert!(!s.applies(RegexPlacement::AiOutput, RegexTarget::Edited));
+        assert!(!s.applies(
+            RegexPlacement::AiOutput,
+            RegexTarget::Prompt { depth: None }
+        ));
+        s.run_on_edit = true;
+        assert!(s.applies(RegexPlacement::UserInput, RegexTarget::Edited));
+        assert!(!s.applies(RegexPlacement::WorldInfo, RegexTarget::Stored));
+    }
+}
@@ -1,10 +1,12 @@
 use chrono::{DateTime, Utc};
-use limerence_ai::Message;
+use limerence_ai::{Message, Usage};
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use uuid::Uuid;
 
 use crate::config::sessions_dir;
+use crate::macros::Variables;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SessionHeader {
@@ -22,11 +24,73 @@ pub struct SessionEntry {
     pub message: Message,
 }
 
+/// Non-message records stored alongside entries in the session JSONL.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SessionEvent {
+    /// The agent switched to another model in the fallback chain.
+    ModelSwitch {
+        timestamp: DateTime<Utc>,
+        from: String,
+        to: String,
+        reason: String,
+    },
+    /// Token usage of one completion, with its cost if the model has pricing.
+    Usage {
+        timestamp: DateTime<Utc>,
+        model: String,
+        usage: Usage,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cost: Option<f64>,
+    },
+    /// The current branch now ends at `leaf` (`None`: before the first entry).
+    /// Appended entries continue from it.
+    Checkout {
+        timestamp: DateTime<Utc>,
+        leaf: Option<String>,
+    },
+    /// Entries before `first_kept` were summarized; prompts use `summary` in
+    /// their place. The entries themselves stay in the file.
+    Compaction {
+        timestamp: DateTime<Utc>,
+        first_kept: String,
+        /// Entries covered by the summary, including earlier compactions.
+        replaced: usize,
+        summary: String,
+    },
+    /// The session's macro variables (`{{setvar}}`) after a change; the
+    /// latest record holds them all.
+    Variables {
+        timestamp: DateTime<Utc>,
+        variables: Variables,
+    },
+    /// The character's greeting as first shown, with its macros expanded.
+    Greeting {
+        timestamp: DateTime<Utc>,
+        text: String,
+    },
+}
+
+/// What the session browser shows for one saved session.
+#[derive(Debug, Clone)]
+pub struct SessionSummary {
+    pub header: SessionHeader,
+    pub path: PathBuf,
+    /// Time of the latest entry.
+    pub updated: DateTime<Utc>,
+    /// First line of the first user message.
+    pub first_line: String,
+    /// Messages on the selected branch.
+    pub message_count: usize,
+}
+
 pub struct Session {
     pub header: SessionHeader,
     pub entries: Vec<SessionEntry>,
+    pub events: Vec<SessionEvent>,
     path: PathBuf,
-    last_entry_id: Option<String>,
+    /// Last entry of the current branch.
+    leaf: Option<String>,
 }
 
 impl Session {
@@ -39,14 +103,15 @@ impl Session {
             model: model.to_string(),
         };
         let path = sessions_dir().join(format!("{id}.jsonl"));
-        let mut session = Self {
+        // The file is written with the first record, so sessions that never
+        // got a message leave nothing behind.
+        Self {
             header,
             entries: Vec::new(),
+            events: Vec::new(),
             path,
-            last_entry_id: None,
-        };
-        session.write_header();
-        session
+            leaf: None,
+        }
     }
 
     pub fn load(path: &PathBuf) -> Option<Self> {
@@ -55,23 +120,32 @@ impl Session {
 
         let header: SessionHeader = serde_json::from_str(lines.next()?).ok()?;
         let mut entries = Vec::new();
-        let mut last_entry_id = None;
+        let mut events = Vec::new();
+        let mut leaf = None;
 
+        // Replaying in file order restores the selected branch: each entry
+        // extends it and each checkout moves it.
         for line in lines {
             if line.trim().is_empty() {
                 continue;
             }
             if let Ok(entry) = serde_json::from_str::<SessionEntry>(line) {
-                last_entry_id = Some(entry.id.clone());
+                leaf = Some(entry.id.clone());
                 entries.push(entry);
+            } else if let Ok(event) = serde_json::from_str::<SessionEvent>(line) {
+                if let SessionEvent::Checkout { leaf: to, .. } = &event {
+                    leaf = to.clone();
+                }
+                events.push(event);
             }
         }
 
         Some(Self {
             header,
             entries,
+            events,
             path: path.clone(),
-            last_entry_id,
+            leaf,
         })
     }
 
@@ -82,33 +156,117 @@ impl Session {
         if let Ok(entries) = std::fs::read_dir(&dir) {
             for entry in entries.flatten() {
                 let path = entry.path();
-                if path.extension().is_some_and(|e| e == "jsonl") {
-                    if let Ok(content) = std::fs::read_to_string(&path) {
-                        if let Some(first_line) = content.lines().next() {
-                            if let Ok(header) = serde_json::from_str::<SessionHeader>(first_line) {
-                                sessions.push((header.id.clone(), header));
-                            }
-                        }
-                    }
+                if path.extension().is_some_and(|e| e == "jsonl")
+                    && let Ok(content) = std::fs::read_to_string(&path)
+                    && let Some(first_line) = content.lines().next()
+                    && let Ok(header) = serde_json::from_str::<SessionHeader>(first_line)
+                {
+                    sessions.push((header.id.clone(), header));
                 }
             }
         }
 
-        sessions.sort_by(|a, b| b.1.timestamp.cmp(&a.1.timestamp));
+        sessions.sort_by_key(|s| std::cmp::Reverse(s.1.timestamp));
         sessions
     }
 
+    /// Saved sessions that have at least one message, most recently updated first.
+    pub fn summaries() -> Vec<SessionSummary> {
+        let mut summaries: Vec<SessionSummary> = std::fs::read_dir(sessions_dir())
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|e| e == "jsonl"))
+            .filter_map(|path| Self::load(&path)?.summary())
+            .collect();
+        summaries.sort_by_key(|s| std::cmp::Reverse(s.updated));
+        summaries
+    }
+
+    fn summary(&self) -> Option<SessionSummary> {
+        let updated = self.entries.iter().map(|e| e.timestamp).max()?;
+        let first_line = self
+            .branch()
+            .into_iter()
+            .find(|e| matches!(e.message, Message::User { .. }))
+            .map(|e| {
+                let text = e.message.content_text();
+                text.lines()
+                    .find(|l| !l.trim().is_empty())
+                    .unwrap_or("")
+                    .trim()
+                    .to_string()
+            })
+            .unwrap_or_default();
+        Some(SessionSummary {
+            header: self.header.clone(),
+            path: self.path.clone(),
+            updated,
+            first_line,
+            message_count: self.branch().len(),
+        })
+    }
+
+    /// Load a saved session by id, unique id prefix, or `last` for the most
+    /// recently updated one.
+    pub fn find(key: &str) -> Option<Self> {
+        let summaries = Self::summaries();
+        let path = if key == "last" {
+            &summaries.first()?.path
+        } else {
+            let mut matches = summaries.iter().filter(|s| s.header.id.starts_with(key));
+            match (matches.next(), matches.next()) {
+                (Some(only), None) => &only.path,
+                _ => return None,
+            }
+        };
+        Self::load(path)
+    }
+
+    /// Remove the saved session `id`.
+    pub fn delete(id: &str) -> std::io::Result<()> {
+        std::fs::remove_file(sessions_dir().join(format!("{id}.jsonl")))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Add `message` to the current branch.
     pub fn append(&mut self, message: Message) {
+        self.append_at(message, Utc::now());
+    }
+
+    /// Add `message` to the current branch as sent at `timestamp`.
+    pub fn append_at(&mut self, message: Message, timestamp: DateTime<Utc>) {
         let entry = SessionEntry {
             id: Uuid::new_v4().to_string(),
-            parent_id: self.last_entry_id.clone(),
-            timestamp: Utc::now(),
+            parent_id: self.leaf.clone(),
+            timestamp,
             message,
         };
-        self.last_entry_id = Some(entry.id.clone());
+        self.leaf = Some(entry.id.clone());
 
-        // Append to file
-        if let Ok(line) = serde_json::to_string(&entry) {
+        self.append_line(&entry);
+        self.entries.push(entry);
+    }
+
+    /// Record a non-message event in the session file.
+    pub fn record(&mut self, event: SessionEvent) {
+        // Until the first entry, events wait to be written with the header,
+        // so a session nobody spoke in leaves no file behind.
+        if !self.entries.is_empty() || self.path.exists() {
+            self.append_line(&event);
+        }
+        self.events.push(event);
+    }
+
+    fn append_line(&self, value: &impl Serialize) {
+        if !self.path.exists() {
+            self.write_header();
+        }
+        if let Ok(line) = serde_json::to_string(value) {
             use std::io::Write;
             if let Ok(mut file) = std::fs::OpenOptions::new()
                 .create(true)
@@ -118,17 +276,290 @@ impl Session {
                 let _ = writeln!(file, "{line}");
             }
         }
+    }
 
-        self.entries.push(entry);
+    /// Last entry of the current branch.
+    pub fn leaf(&self) -> Option<&str> {
+        self.leaf.as_deref()
+    }
+
+    pub fn entry(&self, id: &str) -> Option<&SessionEntry> {
+        self.entries.iter().find(|e| e.id == id)
+    }
+
+    /// Entries of the current branch, from the root to the leaf.
+    pub fn branch(&self) -> Vec<&SessionEntry> {
+        let by_id: HashMap<&str, &SessionEntry> =
+            self.entries.iter().map(|e| (e.id.as_str(), e)).collect();
+        let mut branch = Vec::new();
+        let mut next = self.leaf.as_deref();
+        while let Some(entry) = next.and_then(|id| by_id.get(id)) {
+            branch.push(*entry);
+            next = entry.parent_id.as_deref();
+        }
+        branch.reverse();
+        branch
     }
 
+    /// Messages of the current branch.
     pub fn messages(&self) -> Vec<Message> {
-        self.entries.iter().map(|e| e.message.clone()).collect()
+        self.branch()
+            .into_iter()
+            .map(|e| e.message.clone())
+            .collect()
+    }
+
+    /// Entries whose parent is `parent` (`None`: the roots), oldest first.
+    pub fn children(&self, parent: Option<&str>) -> Vec<&SessionEntry> {
+        self.entries
+            .iter()
+            .filter(|e| e.parent_id.as_deref() == parent)
+            .collect()
     }
 
-    fn write_header(&mut self) {
+    /// Entries nothing continues from; each ends one branch.
+    pub fn leaves(&self) -> Vec<&SessionEntry> {
+        let parents: std::collections::HashSet<&str> = self
+            .entries
+            .iter()
+            .filter_map(|e| e.parent_id.as_deref())
+            .collect();
+        self.entries
+            .iter()
+            .filter(|e| !parents.contains(e.id.as_str()))
+            .collect()
+    }
+
+    /// Select the branch ending at `leaf`, persisting the choice.
+    pub fn checkout(&mut self, leaf: Option<&str>) {
+        if self.leaf.as_deref() == leaf {
+            return;
+        }
+        self.leaf = leaf.map(str::to_string);
+        self.record(SessionEvent::Checkout {
+            timestamp: Utc::now(),
+            leaf: self.leaf.clone(),
+        });
+    }
+
+    /// The end of the most recent branch through `id`, following the newest
+    /// child at every fork.
+    pub fn latest_leaf_under<'a>(&'a self, id: &'a str) -> &'a str {
+        let mut id = id;
+        while let Some(child) = self.children(Some(id)).last() {
+            id = &child.id;
+        }
+        id
+    }
+
+    /// The latest compaction summary on the current branch and the index in
+    /// `branch()` of the first entry after it.
+    pub fn compaction(&self) -> Option<(&str, usize)> {
+        let branch = self.branch();
+        self.events.iter().rev().find_map(|event| match event {
+            SessionEvent::Compaction {
+                first_kept,
+                summary,
+                ..
+            } => {
+                let index = branch.iter().position(|e| &e.id == first_kept)?;
+                Some((summary.as_str(), index))
+            }
+            _ => None,
+        })
+    }
+
+    /// Macro variables set so far in this session.
+    pub fn variables(&self) -> Variables {
+        self.events
+            .iter()
+            .rev()
+            .find_map(|event| match event {
+                SessionEvent::Variables { variables, .. } => Some(variables.clone()),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    /// The greeting shown when the session started, if one was.
+    pub fn greeting(&self) -> Option<&str> {
+        self.events.iter().rev().find_map(|event| match event {
+            SessionEvent::Greeting { text, .. } => Some(text.as_str()),
+            _ => None,
+        })
+    }
+
+    pub fn set_greeting(&mut self, text: String) {
+        self.record(SessionEvent::Greeting {
+            timestamp: Utc::now(),
+            text,
+        });
+    }
+
+    /// Save `variables` if they differ from the stored ones.
+    pub fn set_variables(&mut self, variables: Variables) {
+        if variables == self.variables() {
+            return;
+        }
+        self.record(SessionEvent::Variables {
+            timestamp: Utc::now(),
+            variables,
+        });
+    }
+
+    /// Messages of the current branch not yet covered by a compaction summary.
+    pub fn active_messages(&self) -> Vec<Message> {
+        let start = self.compaction().map_or(0, |(_, index)| index);
+        self.branch()[start..]
+            .iter()
+            .map(|e| e.message.clone())
+            .collect()
+    }
+
+    /// Start the file with the header and the events recorded so far.
+    fn write_header(&self) {
+        let mut out = String::new();
         if let Ok(line) = serde_json::to_string(&self.header) {
-            let _ = std::fs::write(&self.path, format!("{line}\n"));
+            out.push_str(&line);
+            out.push('\n');
+        }
+        for event in &self.events {
+            if let Ok(line) = serde_json::to_string(event) {
+                out.push_str(&line);
+                out.push('\n');
+            }
+        }
+        let _ = std::fs::write(&self.path, out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A session whose file lives in a fresh temp data dir.
+    fn temp_session() -> Session {
+        let _guard = crate::config::env_lock().lock().expect("env lock poisoned");
+        let home = std::env::temp_dir().join(format!("limerence-session-{}", Uuid::new_v4()));
+        // SAFETY: guarded by the crate-wide env lock.
+        unsafe {
+            std::env::set_var("LIMERENCE_HOME", &home);
+        }
+        let session = Session::new("苏晚", "deepseek-chat");
+        // SAFETY: guarded by the crate-wide env lock.
+        unsafe {
+            std::env::remove_var("LIMERENCE_HOME");
+        }
+        session
+    }
+
+    fn texts(session: &Session) -> Vec<String> {
+        session
+            .messages()
+            .iter()
+            .map(|m| m.content_text().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn messages_follow_the_selected_branch_and_the_selection_survives_reload() {
+        let mut session = temp_session();
+        session.append(Message::user("你好"));
+        let user = session.leaf().unwrap().to_string();
+        session.append(Message::assistant("第一版"));
+        let first = session.leaf().unwrap().to_string();
+
+        session.checkout(Some(&user));
+        session.append(Message::assistant("第二版"));
+        assert_eq!(texts(&session), vec!["你好", "第二版"]);
+        assert_eq!(session.children(Some(&user)).len(), 2);
+        assert_eq!(session.leaves().len(), 2);
+
+        session.checkout(Some(&first));
+        assert_eq!(texts(&session), vec!["你好", "第一版"]);
+
+        let mut loaded = Session::load(&session.path).expect("session file");
+        assert_eq!(loaded.leaf(), Some(first.as_str()));
+        assert_eq!(texts(&loaded), vec!["你好", "第一版"]);
+
+        // New entries continue the selected branch.
+        loaded.append(Message::user("再说一遍"));
+        assert_eq!(texts(&loaded), vec!["你好", "第一版", "再说一遍"]);
+        let _ = std::fs::remove_dir_all(session.path.parent().unwrap().parent().unwrap());
+    }
+
+    #[test]
+    fn forking_before_the_first_entry_starts_a_new_root() {
+        let mut session = temp_session();
+        session.append(Message::user("我叫小明"));
+        session.append(Message::assistant("你好，小明。"));
+        let old_leaf = session.leaf().unwrap().to_string();
+
+        session.checkout(None);
+        session.append(Message::user("我叫小红"));
+        assert_eq!(texts(&session), vec!["我叫小红"]);
+        assert_eq!(session.children(None).len(), 2);
+
+        let first_root = session.children(None)[0].id.clone();
+        assert_eq!(session.latest_leaf_under(&first_root), old_leaf);
+        let _ = std::fs::remove_dir_all(session.path.parent().unwrap().parent().unwrap());
+    }
+
+    #[test]
+    fn events_before_the_first_entry_are_written_with_it() {
+        let mut session = temp_session();
+        session.set_greeting("早上好。".to_string());
+        session.set_variables(Variables::from([("心情".to_string(), "好".to_string())]));
+        assert!(!session.path().exists());
+
+        session.append(Message::user("早"));
+        let loaded = Session::load(&session.path().to_path_buf()).expect("saved session");
+        assert_eq!(loaded.greeting(), Some("早上好。"));
+        assert_eq!(loaded.variables()["心情"], "好");
+        assert_eq!(texts(&loaded), vec!["早"]);
+        let _ = std::fs::remove_dir_all(session.path.parent().unwrap().parent().unwrap());
+    }
+
+    #[test]
+    fn saved_sessions_are_listed_found_and_deleted() {
+        let _guard = crate::config::env_lock().lock().expect("env lock poisoned");
+        let home = std::env::temp_dir().join(format!("limerence-session-{}", Uuid::new_v4()));
+        // SAFETY: guarded by the crate-wide env lock.
+        unsafe {
+            std::env::set_var("LIMERENCE_HOME", &home);
+        }
+
+        let mut older = Session::new("苏晚", "deepseek-chat");
+        older.append(Message::user("\n今天好累\n想聊聊"));
+        let _empty = Session::new("苏晚", "deepseek-chat");
+        let mut newer = Session::new("林夕", "deepseek-chat");
+        newer.append(Message::user("早上好"));
+        newer.append(Message::assistant("早！"));
+
+        let summaries = Session::summaries();
+        let ids: Vec<_> = summaries.iter().map(|s| s.header.id.as_str()).collect();
+        assert_eq!(
+            ids,
+            vec![newer.header.id.as_str(), older.header.id.as_str()]
+        );
+        assert_eq!(summaries[0].message_count, 2);
+        assert_eq!(summaries[1].first_line, "今天好累");
+
+        assert_eq!(
+            Session::find("last").map(|s| s.header.id),
+            Some(newer.header.id.clone())
+        );
+        let prefix = &older.header.id[..8];
+        assert_eq!(Session::find(prefix).map(|s| s.messages().len()), Some(1));
+        assert!(Session::find("no-such-session").is_none());
+
+        Session::delete(&newer.header.id).expect("delete");
+        assert_eq!(Session::summaries().len(), 1);
+
+        // SAFETY: guarded by the crate-wide env lock.
+        unsafe {
+            std::env::remove_var("LIMERENCE_HOME");
         }
+        let _ = std::fs::remove_dir_all(home);
     }
 }
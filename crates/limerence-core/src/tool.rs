@@ -1,11 +1,12 @@
 use limerence_ai::ToolDef;
 use serde_json::json;
+use std::time::Duration;
 
 use crate::config::SearchConfig;
 use crate::memory::MemoryIndex;
 
 /// Execute a tool call and return the result string.
-pub fn execute_tool(
+pub async fn execute_tool(
     name: &str,
     args: &str,
     memory: &MemoryIndex,
@@ -17,7 +18,7 @@ pub fn execute_tool(
         "memory_search" => tool_memory_search(&args, memory),
         "memory_write" => tool_memory_write(&args, memory),
         "memory_get" => tool_memory_get(&args, memory),
-        "web_search" => tool_web_search(&args, search_config),
+        "web_search" => tool_web_search(&args, search_config).await,
         "note_write" => tool_note_write(&args),
         "note_read" => tool_note_read(&args),
         "file_read" => tool_file_read(&args),
@@ -26,6 +27,24 @@ pub fn execute_tool(
     }
 }
 
+/// Like `execute_tool`, but gives up after `timeout` with an error result,
+/// so one stuck tool cannot hang the turn. A zero `timeout` means no limit.
+pub async fn execute_tool_with_timeout(
+    name: &str,
+    args: &str,
+    memory: &MemoryIndex,
+    search_config: &SearchConfig,
+    timeout: Duration,
+) -> String {
+    if timeout.is_zero() {
+        return execute_tool(name, args, memory, search_config).await;
+    }
+    match tokio::time::timeout(timeout, execute_tool(name, args, memory, search_config)).await {
+        Ok(result) => result,
+        Err(_) => format!("工具 {name} 执行超时（{} 秒）。", timeout.as_secs_f64()),
+    }
+}
+
 /// Return all tool definitions.
 pub fn all_tool_defs() -> Vec<ToolDef> {
     vec![
@@ -275,18 +294,17 @@ fn tool_memory_get(args: &serde_json::Value, memory: &MemoryIndex) -> String {
     }
 }
 
-fn tool_web_search(args: &serde_json::Value, config: &SearchConfig) -> String {
+async fn tool_web_search(args: &serde_json::Value, config: &SearchConfig) -> String {
     let query = args["query"].as_str().unwrap_or("");
     if query.is_empty() {
         return "请提供搜索查询。".to_string();
     }
 
-    // Synchronous HTTP request for web search (runs in blocking context)
     match &config.engine {
-        e if e == "duckduckgo" => duckduckgo_search(query),
+        e if e == "duckduckgo" => duckduckgo_search(query).await,
         e if e == "searxng" => {
             if let Some(url) = &config.searxng_url {
-                searxng_search(query, url)
+                searxng_search(query, url).await
             } else {
                 "SearXNG URL 未配置。".to_string()
             }
@@ -295,11 +313,11 @@ fn tool_web_search(args: &serde_json::Value, config: &SearchConfig) -> String {
     }
 }
 
-fn duckduckgo_search(query: &str) -> String {
+async fn duckduckgo_search(query: &str) -> String {
     // Use DuckDuckGo HTML lite for simplicity
     let url = format!("https://html.duckduckgo.com/html/?q={}", urlencoded(query));
 
-    let client = reqwest::blocking::Client::builder()
+    let client = reqwest::Client::builder()
         .user_agent("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36")
         .build();
 
@@ -308,26 +326,26 @@ fn duckduckgo_search(query: &str) -> String {
         Err(e) => return format!("HTTP 客户端创建失败：{e}"),
     };
 
-    match client.get(&url).send() {
+    match client.get(&url).send().await {
         Ok(resp) => {
-            let body = resp.text().unwrap_or_default();
+            let body = resp.text().await.unwrap_or_default();
             parse_ddg_html(&body)
         }
         Err(e) => format!("搜索请求失败：{e}"),
     }
 }
 
-fn searxng_search(query: &str, base_url: &str) -> String {
+async fn searxng_search(query: &str, base_url: &str) -> String {
     let url = format!(
         "{}/search?q={}&format=json",
         base_url.trim_end_matches('/'),
         urlencoded(query)
     );
 
-    let client = reqwest::blocking::Client::new();
-    match client.get(&url).send() {
+    let client = reqwest::Client::new();
+    match client.get(&url).send().await {
         Ok(resp) => {
-            let json: serde_json::Value = resp.json().unwrap_or_default();
+            let json: serde_json::Value = resp.json().await.unwrap_or_default();
             if let Some(results) = json["results"].as_array() {
                 let mut output = String::new();
                 for (i, r) in results.iter().take(5).enumerate() {
@@ -502,13 +520,13 @@ fn urlencoded_decode(s: &str) -> String {
     let bytes = s.as_bytes();
     let mut i = 0;
     while i < bytes.len() {
-        if bytes[i] == b'%' && i + 2 < bytes.len() {
-            if let Ok(byte) = u8::from_str_radix(&String::from_utf8_lossy(&bytes[i + 1..i + 3]), 16)
-            {
-                result.push(byte);
-                i += 3;
-                continue;
-            }
+        if bytes[i] == b'%'
+            && i + 2 < bytes.len()
+            && let Ok(byte) = u8::from_str_radix(&String::from_utf8_lossy(&bytes[i + 1..i + 3]), 16)
+        {
+            result.push(byte);
+            i += 3;
+            continue;
         }
         if bytes[i] == b'+' {
             result.push(b' ');
@@ -543,7 +561,7 @@ fn strip_html_tags(s: &str) -> String {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use crate::config::SearchConfig;
     use crate::memory::{MemoryEntry, MemoryIndex};
@@ -585,8 +603,8 @@ mod tests {
         assert!(names.contains(&"file_write"));
     }
 
-    #[test]
-    fn file_write_rejects_memory_paths() {
+    #[tokio::test]
+    async fn file_write_rejects_memory_paths() {
         let memory = MemoryIndex::new();
         let search_config = SearchConfig::default();
         let args = json!({
@@ -595,15 +613,15 @@ mod tests {
         })
         .to_string();
 
-        let result = execute_tool("file_write", &args, &memory, &search_config);
+        let result = execute_tool("file_write", &args, &memory, &search_config).await;
         assert!(
             result.contains("memory_write"),
             "expected memory/ path rejection, got: {result}"
         );
     }
 
-    #[test]
-    fn memory_write_and_get_follow_memory_workflow() {
+    #[tokio::test]
+    async fn memory_write_and_get_follow_memory_workflow() {
         let temp = TempMemoryRoot::new();
         let memory = MemoryIndex::with_memory_root(temp.root.clone());
         let search_config = SearchConfig::default();
@@ -617,7 +635,8 @@ mod tests {
             .to_string(),
             &memory,
             &search_config,
-        );
+        )
+        .await;
         assert!(write_a.contains("记忆文件"));
 
         let write_b = execute_tool(
@@ -629,7 +648,8 @@ mod tests {
             .to_string(),
             &memory,
             &search_config,
-        );
+        )
+        .await;
         assert!(write_b.contains("追加"));
 
         let read = execute_tool(
@@ -642,13 +662,29 @@ mod tests {
             .to_string(),
             &memory,
             &search_config,
-        );
+        )
+        .await;
         assert!(read.contains("喜欢咖啡"));
         assert!(read.contains("喜欢散步"));
     }
 
-    #[test]
-    fn memory_search_includes_persistent_and_conversation_results() {
+    #[tokio::test]
+    async fn zero_timeout_runs_tools_without_a_limit() {
+        let temp = TempMemoryRoot::new();
+        let memory = MemoryIndex::with_memory_root(temp.root.clone());
+        let result = execute_tool_with_timeout(
+            "memory_get",
+            &json!({}).to_string(),
+            &memory,
+            &SearchConfig::default(),
+            Duration::ZERO,
+        )
+        .await;
+        assert!(!result.contains("超时"), "result: {result}");
+    }
+
+    #[tokio::test]
+    async fn memory_search_includes_persistent_and_conversation_results() {
         let temp = TempMemoryRoot::new();
         let mut memory = MemoryIndex::with_memory_root(temp.root.clone());
         let search_config = SearchConfig::default();
@@ -662,7 +698,8 @@ mod tests {
             .to_string(),
             &memory,
             &search_config,
-        );
+        )
+        .await;
 
         memory.add(MemoryEntry {
             session_id: "s1".to_string(),
@@ -680,14 +717,15 @@ mod tests {
             .to_string(),
             &memory,
             &search_config,
-        );
+        )
+        .await;
 
         assert!(result.contains("── 持久记忆 ──"), "result: {result}");
         assert!(result.contains("── 对话历史 ──"), "result: {result}");
     }
 
-    #[test]
-    fn memory_get_without_path_lists_markdown_files_only() {
+    #[tokio::test]
+    async fn memory_get_without_path_lists_markdown_files_only() {
         let temp = TempMemoryRoot::new();
         let memory = MemoryIndex::with_memory_root(temp.root.clone());
         let search_config = SearchConfig::default();
@@ -701,11 +739,49 @@ mod tests {
             .to_string(),
             &memory,
             &search_config,
-        );
+        )
+        .await;
         std::fs::write(temp.root.join("ignore.txt"), "x").expect("write non-markdown");
 
-        let list = execute_tool("memory_get", &json!({}).to_string(), &memory, &search_config);
+        let list = execute_tool(
+            "memory_get",
+            &json!({}).to_string(),
+            &memory,
+            &search_config,
+        )
+        .await;
         assert!(list.contains("memory/PROFILE.md"));
         assert!(!list.contains("ignore.txt"));
     }
+
+    /// A SearXNG endpoint that accepts connections and never answers.
+    pub(crate) fn stalled_searxng() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind stalled server");
+        let addr = listener.local_addr().expect("stalled server addr");
+        std::thread::spawn(move || {
+            let mut held = Vec::new();
+            for stream in listener.incoming() {
+                held.push(stream);
+            }
+        });
+        format!("http://{addr}")
+    }
+
+    #[tokio::test]
+    async fn stuck_tool_times_out_with_error_result() {
+        let memory = MemoryIndex::new();
+        let search_config = SearchConfig {
+            engine: "searxng".to_string(),
+            searxng_url: Some(stalled_searxng()),
+        };
+        let result = execute_tool_with_timeout(
+            "web_search",
+            &json!({"query": "咖啡"}).to_string(),
+            &memory,
+            &search_config,
+            Duration::from_millis(100),
+        )
+        .await;
+        assert_eq!(result, "工具 web_search 执行超时（0.1 秒）。");
+    }
 }
@@ -0,0 +1,203 @@
+use chrono::{DateTime, Local, NaiveDate, Utc};
+use limerence_ai::Usage;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::config::sessions_dir;
+use crate::session::{Session, SessionEvent};
+
+/// Price of a model, in any currency, per million tokens.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ModelPricing {
+    pub input_per_mtok: f64,
+    pub output_per_mtok: f64,
+    /// Price for prompt-cache hits; defaults to `input_per_mtok`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cached_input_per_mtok: Option<f64>,
+}
+
+impl ModelPricing {
+    pub fn cost(&self, usage: &Usage) -> f64 {
+        let cached = usage.cached_tokens.min(usage.prompt_tokens);
+        let uncached = usage.prompt_tokens - cached;
+        let cached_rate = self.cached_input_per_mtok.unwrap_or(self.input_per_mtok);
+        (uncached as f64 * self.input_per_mtok
+            + cached as f64 * cached_rate
+            + usage.completion_tokens as f64 * self.output_per_mtok)
+            / 1_000_000.0
+    }
+}
+
+/// Accumulated usage over any number of completions.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct UsageTotals {
+    pub calls: u64,
+    pub usage: Usage,
+    /// Sum of known costs; completions on unpriced models contribute nothing.
+    pub cost: f64,
+}
+
+impl UsageTotals {
+    pub fn add(&mut self, usage: Usage, cost: Option<f64>) {
+        self.calls += 1;
+        self.usage += usage;
+        self.cost += cost.unwrap_or(0.0);
+    }
+}
+
+/// Usage of a single session, from its recorded usage events.
+pub fn session_totals(session: &Session) -> UsageTotals {
+    let mut totals = UsageTotals::default();
+    for event in &session.events {
+        if let SessionEvent::Usage { usage, cost, .. } = event {
+            totals.add(*usage, *cost);
+        }
+    }
+    totals
+}
+
+/// One completion's usage, tagged with where it came from.
+#[derive(Debug, Clone)]
+pub struct UsageRecord {
+    pub session_id: String,
+    pub character: String,
+    pub model: String,
+    pub timestamp: DateTime<Utc>,
+    pub usage: Usage,
+    pub cost: Option<f64>,
+}
+
+/// Usage across sessions, for spend reports.
+#[derive(Debug, Clone, Default)]
+pub struct UsageLedger {
+    pub records: Vec<UsageRecord>,
+}
+
+impl UsageLedger {
+    /// Load usage from every session in the sessions directory.
+    pub fn load() -> Self {
+        Self::load_from_dir(&sessions_dir())
+    }
+
+    pub fn load_from_dir(dir: &Path) -> Self {
+        let mut ledger = Self::default();
+        if let Ok(entries) = std::fs::read_dir(dir) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.extension().is_some_and(|e| e == "jsonl")
+                    && let Some(session) = Session::load(&path)
+                {
+                    ledger.add_session(&session);
+                }
+            }
+        }
+        ledger
+    }
+
+    pub fn add_session(&mut self, session: &Session) {
+        for event in &session.events {
+            if let SessionEvent::Usage {
+                timestamp,
+                model,
+                usage,
+                cost,
+            } = event
+            {
+                self.records.push(UsageRecord {
+                    session_id: session.header.id.clone(),
+                    character: session.header.character.clone(),
+                    model: model.clone(),
+                    timestamp: *timestamp,
+                    usage: *usage,
+                    cost: *cost,
+                });
+            }
+        }
+    }
+
+    pub fn total(&self) -> UsageTotals {
+        self.group_by(|_| ()).remove(&()).unwrap_or_default()
+    }
+
+    pub fn by_session(&self) -> BTreeMap<String, UsageTotals> {
+        self.group_by(|r| r.session_id.clone())
+    }
+
+    pub fn by_character(&self) -> BTreeMap<String, UsageTotals> {
+        self.group_by(|r| r.character.clone())
+    }
+
+    pub fn by_model(&self) -> BTreeMap<String, UsageTotals> {
+        self.group_by(|r| r.model.clone())
+    }
+
+    /// Grouped by calendar day in local time.
+    pub fn by_day(&self) -> BTreeMap<NaiveDate, UsageTotals> {
+        self.group_by(|r| r.timestamp.with_timezone(&Local).date_naive())
+    }
+
+    fn group_by<K: Ord>(&self, key: impl Fn(&UsageRecord) -> K) -> BTreeMap<K, UsageTotals> {
+        let mut out: BTreeMap<K, UsageTotals> = BTreeMap::new();
+        for r in &self.records {
+            out.entry(key(r)).or_default().add(r.usage, r.cost);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(prompt: u64, completion: u64, cached: u64) -> Usage {
+        Usage {
+            prompt_tokens: prompt,
+            completion_tokens: completion,
+            cached_tokens: cached,
+        }
+    }
+
+    fn record(session: &str, character: &str, ts: &str, cost: f64) -> UsageRecord {
+        UsageRecord {
+            session_id: session.to_string(),
+            character: character.to_string(),
+            model: "deepseek-chat".to_string(),
+            timestamp: ts.parse().expect("timestamp"),
+            usage: usage(100, 10, 0),
+            cost: Some(cost),
+        }
+    }
+
+    #[test]
+    fn pricing_discounts_cached_prompt_tokens() {
+        let pricing = ModelPricing {
+            input_per_mtok: 2.0,
+            output_per_mtok: 8.0,
+            cached_input_per_mtok: Some(0.5),
+        };
+        let cost = pricing.cost(&usage(1_000_000, 500_000, 400_000));
+        // 600k uncached * 2 + 400k cached * 0.5 + 500k output * 8
+        assert!((cost - (1.2 + 0.2 + 4.0)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn ledger_groups_by_session_character_and_day() {
+        let ledger = UsageLedger {
+            records: vec![
+                record("s1", "苏晚", "2026-03-01T12:00:00Z", 0.01),
+                record("s1", "苏晚", "2026-03-01T12:05:00Z", 0.02),
+                record("s2", "奥琪", "2026-03-05T12:00:00Z", 0.04),
+            ],
+        };
+
+        let total = ledger.total();
+        assert_eq!(total.calls, 3);
+        assert_eq!(total.usage.prompt_tokens, 300);
+        assert!((total.cost - 0.07).abs() < 1e-9);
+
+        assert_eq!(ledger.by_session()["s1"].calls, 2);
+        assert!((ledger.by_character()["奥琪"].cost - 0.04).abs() < 1e-9);
+        assert_eq!(ledger.by_day().len(), 2);
+    }
+}
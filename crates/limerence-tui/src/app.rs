@@ -1,18 +1,47 @@
 use crossterm::event::{self, Event, KeyCode, KeyModifiers};
+use limerence_ai::{ApiError, ApiErrorKind, CancellationToken, ContentPart, Message};
+use limerence_core::blob::BlobStore;
+use limerence_core::config;
+use limerence_core::export::{self, ExportFormat};
+use limerence_core::preset::Preset;
+use limerence_core::regex_script::{RegexPlacement, RegexScripts, RegexTarget};
+use limerence_core::session::Session;
+use limerence_core::usage::UsageTotals;
 use limerence_core::{Agent, AgentEvent, CharacterCard, Config};
 use ratatui::DefaultTerminal;
+use std::path::PathBuf;
 use std::time::Duration;
 use tokio::sync::mpsc;
 
+use crate::browser::{BrowserAction, SessionBrowser};
 use crate::input;
 use crate::ui;
 
+/// What the agent should do in a turn.
+enum TurnRequest {
+    Message(Vec<ContentPart>),
+    /// Answer the latest user message again, as a new swipe.
+    Regenerate,
+    /// Replace a user message on a new branch and answer it.
+    Edit {
+        entry_id: String,
+        content: Vec<ContentPart>,
+    },
+}
+
 #[derive(Debug, Clone)]
 pub enum DisplayMessage {
     User(String),
     Assistant(String),
-    ToolCall { name: String },
-    ToolResult { name: String, result: String },
+    /// Model reasoning shown above its reply; collapsed unless toggled.
+    Reasoning(String),
+    ToolCall {
+        name: String,
+    },
+    ToolResult {
+        name: String,
+        result: String,
+    },
     System(String),
     Error(String),
 }
@@ -24,8 +53,18 @@ pub struct App {
     pub input: String,
     pub cursor_pos: usize,
     pub streaming_text: String,
+    pub streaming_reasoning: String,
+    /// Expand reasoning blocks (Ctrl+T).
+    pub show_reasoning: bool,
     pub is_streaming: bool,
     pub should_quit: bool,
+    /// Usage of the latest user turn (all completions it took).
+    pub turn_usage: UsageTotals,
+    pub session_usage: UsageTotals,
+    /// Messages left out of, or shortened in, the latest request to fit the context window.
+    pub context_trimmed: usize,
+    /// Session picker overlay (Ctrl+O).
+    pub browser: Option<SessionBrowser>,
 }
 
 impl App {
@@ -38,8 +77,14 @@ impl App {
             input: String::new(),
             cursor_pos: 0,
             streaming_text: String::new(),
+            streaming_reasoning: String::new(),
+            show_reasoning: false,
             is_streaming: false,
             should_quit: false,
+            turn_usage: UsageTotals::default(),
+            session_usage: UsageTotals::default(),
+            context_trimmed: 0,
+            browser: None,
         }
     }
 
@@ -55,9 +100,21 @@ impl App {
         let mut terminal = ratatui::init();
         terminal.clear()?;
 
-        if let Some(first_mes) = self.agent().first_message() {
-            self.messages
-                .push(DisplayMessage::Assistant(first_mes.to_string()));
+        // A resumed session has already filled the view.
+        if self.messages.is_empty()
+            && let Some(first_mes) = self.agent_mut().first_message()
+        {
+            self.messages.push(DisplayMessage::Assistant(first_mes));
+        }
+        if let Some(name) = self
+            .config
+            .preset
+            .for_character(self.agent().character_name())
+            && self.agent().preset().is_none()
+        {
+            self.messages.push(DisplayMessage::Error(format!(
+                "无法加载预设「{name}」，使用内置提示词。"
+            )));
         }
 
         let result = self.event_loop(&mut terminal).await;
@@ -77,28 +134,65 @@ impl App {
                 break;
             }
 
-            if event::poll(Duration::from_millis(50))? {
-                if let Event::Key(key) = event::read()? {
-                    match (key.modifiers, key.code) {
-                        (KeyModifiers::CONTROL, KeyCode::Char('c')) => {
-                            self.should_quit = true;
+            if event::poll(Duration::from_millis(50))?
+                && let Event::Key(key) = event::read()?
+            {
+                if key.modifiers == KeyModifiers::CONTROL && key.code == KeyCode::Char('c') {
+                    self.should_quit = true;
+                    continue;
+                }
+                if let Some(browser) = &mut self.browser {
+                    let action = browser.handle_key(key);
+                    self.handle_browser_action(action);
+                    continue;
+                }
+                match (key.modifiers, key.code) {
+                    (KeyModifiers::CONTROL, KeyCode::Char('o')) => {
+                        self.browser = Some(SessionBrowser::open());
+                    }
+                    (KeyModifiers::CONTROL, KeyCode::Char('t')) => {
+                        self.show_reasoning = !self.show_reasoning;
+                    }
+                    (KeyModifiers::CONTROL, KeyCode::Char('r')) => {
+                        let has_user_message = self
+                            .agent()
+                            .session()
+                            .messages()
+                            .iter()
+                            .any(|m| matches!(m, Message::User { .. }));
+                        if has_user_message {
+                            self.show_branch();
+                            self.truncate_after_last_user(false);
+                            self.run_turn(TurnRequest::Regenerate, terminal).await;
+                        } else {
+                            self.messages.push(DisplayMessage::System(
+                                "还没有可以重新生成的回复。".to_string(),
+                            ));
                         }
-                        (KeyModifiers::CONTROL, KeyCode::Char('n')) => {
-                            self.agent_mut().new_session();
-                            self.messages.clear();
-                            self.messages
-                                .push(DisplayMessage::System("新会话已开始。".to_string()));
-                            if let Some(first_mes) = self.agent().first_message() {
-                                self.messages
-                                    .push(DisplayMessage::Assistant(first_mes.to_string()));
-                            }
+                    }
+                    (KeyModifiers::ALT, KeyCode::Left | KeyCode::Right) => {
+                        let step = if key.code == KeyCode::Left { -1 } else { 1 };
+                        if self.agent_mut().swipe(step).is_some() {
+                            self.show_branch();
                         }
-                        _ => {
-                            if let Some(user_input) =
-                                input::handle_key_input(key, &mut self.input, &mut self.cursor_pos)
-                            {
-                                self.send_message(user_input, terminal).await;
-                            }
+                    }
+                    (KeyModifiers::CONTROL, KeyCode::Char('n')) => {
+                        self.agent_mut().new_session();
+                        self.messages.clear();
+                        self.turn_usage = UsageTotals::default();
+                        self.session_usage = UsageTotals::default();
+                        self.context_trimmed = 0;
+                        self.messages
+                            .push(DisplayMessage::System("新会话已开始。".to_string()));
+                        if let Some(first_mes) = self.agent_mut().first_message() {
+                            self.messages.push(DisplayMessage::Assistant(first_mes));
+                        }
+                    }
+                    _ => {
+                        if let Some(user_input) =
+                            input::handle_key_input(key, &mut self.input, &mut self.cursor_pos)
+                        {
+                            self.submit(user_input, terminal).await;
                         }
                     }
                 }
@@ -108,10 +202,274 @@ impl App {
         Ok(())
     }
 
+    /// Continue `session`, with `character` or else the card named in its header.
+    pub fn open_session(&mut self, session: Session, character: Option<CharacterCard>) {
+        let name = session.header.character.clone();
+        let character = character.or_else(|| CharacterCard::find_by_name(&name));
+        let missing = character.is_none();
+        let character = character.unwrap_or_else(|| self.agent().character().clone());
+        let when = session
+            .header
+            .timestamp
+            .with_timezone(&chrono::Local)
+            .format("%Y-%m-%d %H:%M");
+        let notice = format!("已恢复 {when} 与{name}的会话。");
+
+        self.agent_mut().resume_session(session, character);
+        self.show_branch();
+        self.messages.push(DisplayMessage::System(notice));
+        if missing {
+            self.messages.push(DisplayMessage::Error(format!(
+                "找不到角色卡「{name}」，以当前角色继续。"
+            )));
+        }
+        self.turn_usage = UsageTotals::default();
+        self.session_usage = self.agent().session_usage();
+        self.context_trimmed = 0;
+    }
+
+    fn handle_browser_action(&mut self, action: BrowserAction) {
+        match action {
+            BrowserAction::None => {}
+            BrowserAction::Close => self.browser = None,
+            BrowserAction::Open(summary) => {
+                self.browser = None;
+                match Session::load(&summary.path) {
+                    Some(session) => self.open_session(session, None),
+                    None => self.messages.push(DisplayMessage::Error(format!(
+                        "无法读取会话文件：{}",
+                        summary.path.display()
+                    ))),
+                }
+            }
+            BrowserAction::Delete(id) => {
+                let notice = if id == self.agent().session_id() {
+                    "不能删除正在进行的会话。".to_string()
+                } else {
+                    match self.agent_mut().delete_session(&id) {
+                        Ok(()) => {
+                            if let Some(browser) = &mut self.browser {
+                                browser.remove(&id);
+                            }
+                            "会话已删除。".to_string()
+                        }
+                        Err(e) => format!("删除失败：{e}"),
+                    }
+                };
+                if let Some(browser) = &mut self.browser {
+                    browser.notice = Some(notice);
+                }
+            }
+        }
+    }
+
+    /// Handle a line from the input box: a command or a message.
+    async fn submit(&mut self, user_input: String, terminal: &mut DefaultTerminal) {
+        if let Some(text) = user_input.strip_prefix("/edit ") {
+            self.edit_last_user_message(text.trim().to_string(), terminal)
+                .await;
+        } else if user_input == "/branches" {
+            self.list_branches();
+        } else if let Some(n) = user_input.strip_prefix("/branch ") {
+            self.switch_branch(n.trim());
+        } else if let Some(args) = user_input.strip_prefix("/export") {
+            self.export_session(args.trim());
+        } else if user_input == "/lore" {
+            self.list_lore();
+        } else if let Some(name) = user_input.strip_prefix("/preset") {
+            self.choose_preset(name.trim());
+        } else {
+            self.send_message(user_input, terminal).await;
+        }
+    }
+
+    async fn edit_last_user_message(&mut self, text: String, terminal: &mut DefaultTerminal) {
+        let entry_id = self
+            .agent()
+            .session()
+            .branch()
+            .into_iter()
+            .rfind(|e| matches!(e.message, Message::User { .. }))
+            .map(|e| e.id.clone());
+        let (Some(entry_id), false) = (entry_id, text.is_empty()) else {
+            self.messages.push(DisplayMessage::Error(
+                "用法：/edit <新内容>，替换最近一条消息并重新回答。".to_string(),
+            ));
+            return;
+        };
+        self.show_branch();
+        self.truncate_after_last_user(true);
+        let shown = self.shown(&text, RegexPlacement::UserInput, RegexTarget::Edited);
+        self.messages.push(DisplayMessage::User(shown));
+        let request = TurnRequest::Edit {
+            entry_id,
+            content: vec![ContentPart::text(text)],
+        };
+        self.run_turn(request, terminal).await;
+    }
+
+    fn list_branches(&mut self) {
+        let session = self.agent().session();
+        let leaves = session.leaves();
+        let current = session.leaf();
+        let mut lines = vec![format!("共 {} 个分支：", leaves.len())];
+        for (i, leaf) in leaves.iter().enumerate() {
+            let marker = if Some(leaf.id.as_str()) == current {
+                "*"
+            } else {
+                " "
+            };
+            let preview: String = leaf.message.content_text().chars().take(30).collect();
+            lines.push(format!(
+                "{marker}{} [{}] {preview}",
+                i + 1,
+                leaf.timestamp
+                    .with_timezone(&chrono::Local)
+                    .format("%m-%d %H:%M")
+            ));
+        }
+        lines.push("输入 /branch <序号> 切换。".to_string());
+        self.messages.push(DisplayMessage::System(lines.join("\n")));
+    }
+
+    fn switch_branch(&mut self, n: &str) {
+        let leaf = n
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| n.checked_sub(1))
+            .and_then(|i| self.agent().session().leaves().get(i).map(|e| e.id.clone()));
+        match leaf {
+            Some(leaf) => {
+                self.agent_mut().checkout(&leaf);
+                self.show_branch();
+            }
+            None => self.messages.push(DisplayMessage::Error(format!(
+                "没有第 {n} 个分支，输入 /branches 查看。"
+            ))),
+        }
+    }
+
+    /// `/preset`: list presets; `/preset <名称|路径>` switches to one and
+    /// `/preset off` back to the built-in prompt, for this run.
+    fn choose_preset(&mut self, name: &str) {
+        let message = match name {
+            "" => {
+                let current = self
+                    .agent()
+                    .preset()
+                    .map_or("无（内置提示词）", |p| p.name.as_str());
+                let available = Preset::list();
+                let available = if available.is_empty() {
+                    format!("{} 下没有预设。", config::presets_dir().display())
+                } else {
+                    format!("可用预设：{}", available.join("、"))
+                };
+                DisplayMessage::System(format!("当前预设：{current}\n{available}"))
+            }
+            "off" => {
+                self.agent_mut().set_preset(None);
+                DisplayMessage::System("已改用内置提示词。".to_string())
+            }
+            name => match Preset::find(name) {
+                Ok(preset) => {
+                    let text = format!("已切换到预设「{}」。", preset.name);
+                    self.agent_mut().set_preset(Some(preset));
+                    DisplayMessage::System(text)
+                }
+                Err(e) => DisplayMessage::Error(format!("无法加载预设「{name}」：{e}")),
+            },
+        };
+        self.messages.push(message);
+    }
+
+    /// `/lore`: the lorebook entries the latest reply was given.
+    fn list_lore(&mut self) {
+        let lore = self.agent().last_lore();
+        let text = if lore.is_empty() {
+            "上一轮没有触发世界书条目。".to_string()
+        } else {
+            format!(
+                "上一轮触发了 {} 个世界书条目：\n{}",
+                lore.len(),
+                lore.join("\n")
+            )
+        };
+        self.messages.push(DisplayMessage::System(text));
+    }
+
+    /// `/export [md|html|st] [path]`: write the current branch to a file.
+    fn export_session(&mut self, args: &str) {
+        let (first, rest) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+        let (format, path) = match first.parse::<ExportFormat>() {
+            Ok(format) => (Some(format), rest.trim()),
+            Err(_) => (None, args),
+        };
+        let path = (!path.is_empty()).then(|| PathBuf::from(path));
+        let format = format
+            .or_else(|| path.as_deref().and_then(ExportFormat::from_path))
+            .unwrap_or(ExportFormat::Markdown);
+        let message = match export::export(self.agent().session(), format, path.as_deref()) {
+            Ok(path) => DisplayMessage::System(format!("已导出到 {}", path.display())),
+            Err(e) => DisplayMessage::Error(format!("导出失败：{e}")),
+        };
+        self.messages.push(message);
+    }
+
+    /// Redraw the conversation from the session's current branch.
+    fn show_branch(&mut self) {
+        self.messages.clear();
+        if let Some(first_mes) = self.agent_mut().first_message() {
+            self.messages.push(DisplayMessage::Assistant(first_mes));
+        }
+        let history = self.agent().session().messages();
+        let shown = display_messages(&history, self.agent().regex_scripts());
+        self.messages.extend(shown);
+    }
+
+    /// Drop what follows the last user message from the display; with
+    /// `inclusive`, drop that message too.
+    fn truncate_after_last_user(&mut self, inclusive: bool) {
+        if let Some(i) = self
+            .messages
+            .iter()
+            .rposition(|m| matches!(m, DisplayMessage::User(_)))
+        {
+            self.messages.truncate(if inclusive { i } else { i + 1 });
+        }
+    }
+
     async fn send_message(&mut self, user_input: String, terminal: &mut DefaultTerminal) {
-        self.messages.push(DisplayMessage::User(user_input.clone()));
+        let (content, display) = match parse_image_command(&user_input) {
+            Some((path, caption)) => match BlobStore::open().import_image(&path) {
+                Ok(image) => {
+                    let name = path.file_name().unwrap_or_default().to_string_lossy();
+                    let mut display = format!("[图片：{name}]");
+                    let mut content = vec![image];
+                    if !caption.is_empty() {
+                        display.push_str(&format!("\n{caption}"));
+                        content.push(ContentPart::text(caption));
+                    }
+                    (content, display)
+                }
+                Err(e) => {
+                    self.messages
+                        .push(DisplayMessage::Error(format!("无法读取图片：{e}")));
+                    return;
+                }
+            },
+            None => (vec![ContentPart::text(user_input.clone())], user_input),
+        };
+
+        let shown = self.shown(&display, RegexPlacement::UserInput, RegexTarget::Stored);
+        self.messages.push(DisplayMessage::User(shown));
+        self.run_turn(TurnRequest::Message(content), terminal).await;
+    }
+
+    async fn run_turn(&mut self, request: TurnRequest, terminal: &mut DefaultTerminal) {
         self.streaming_text.clear();
         self.is_streaming = true;
+        self.turn_usage = UsageTotals::default();
+        self.context_trimmed = 0;
 
         let (event_tx, mut event_rx) = mpsc::unbounded_channel::<AgentEvent>();
 
@@ -121,8 +479,24 @@ impl App {
 
         let local = tokio::task::LocalSet::new();
 
+        // Esc / Ctrl+C cancel the turn; the agent then saves the partial reply
+        // and hands itself back, so nothing is lost.
+        let cancel = CancellationToken::new();
+        let agent_cancel = cancel.clone();
         let agent_handle = local.spawn_local(async move {
-            agent.process_message(user_input, event_tx).await;
+            match request {
+                TurnRequest::Message(content) => {
+                    agent
+                        .process_user_message(content, event_tx, &agent_cancel)
+                        .await
+                }
+                TurnRequest::Regenerate => agent.regenerate(event_tx, &agent_cancel).await,
+                TurnRequest::Edit { entry_id, content } => {
+                    agent
+                        .edit_user_message(&entry_id, content, event_tx, &agent_cancel)
+                        .await
+                }
+            }
             agent
         });
 
@@ -134,21 +508,20 @@ impl App {
                     let _ = terminal.draw(|frame| ui::draw(frame, self));
 
                     // Check keyboard
-                    if event::poll(Duration::from_millis(16)).unwrap_or(false) {
-                        if let Ok(Event::Key(key)) = event::read() {
-                            if key.code == KeyCode::Esc {
-                                self.flush_streaming();
-                                agent_handle.abort();
-                                break;
-                            }
-                            if key.modifiers == KeyModifiers::CONTROL
-                                && key.code == KeyCode::Char('c')
-                            {
-                                self.flush_streaming();
-                                self.should_quit = true;
-                                agent_handle.abort();
-                                break;
-                            }
+                    if event::poll(Duration::from_millis(16)).unwrap_or(false)
+                        && let Ok(Event::Key(key)) = event::read()
+                    {
+                        if key.code == KeyCode::Esc {
+                            cancel.cancel();
+                        }
+                        if key.modifiers == KeyModifiers::CONTROL && key.code == KeyCode::Char('t')
+                        {
+                            self.show_reasoning = !self.show_reasoning;
+                        }
+                        if key.modifiers == KeyModifiers::CONTROL && key.code == KeyCode::Char('c')
+                        {
+                            self.should_quit = true;
+                            cancel.cancel();
                         }
                     }
 
@@ -187,13 +560,13 @@ impl App {
             })
             .await;
 
-        // If agent was aborted (Esc/Ctrl+C), recover by creating a fresh one
+        // If the agent task panicked, recover by creating a fresh one
         if self.agent.is_none() {
-            // Agent was lost due to abort — recreate from config
             let character = CharacterCard::default_character();
             self.agent = Some(Agent::new(&self.config, character));
-            self.messages
-                .push(DisplayMessage::System("生成已中断。".to_string()));
+            self.messages.push(DisplayMessage::Error(
+                "对话异常结束，已重新创建会话。".to_string(),
+            ));
         }
 
         self.is_streaming = false;
@@ -202,9 +575,14 @@ impl App {
     fn handle_agent_event(&mut self, event: AgentEvent) -> bool {
         match event {
             AgentEvent::TextDelta(text) => {
+                self.flush_reasoning();
                 self.streaming_text.push_str(&text);
                 false
             }
+            AgentEvent::ReasoningDelta(text) => {
+                self.streaming_reasoning.push_str(&text);
+                false
+            }
             AgentEvent::ToolCallStart { name } => {
                 self.flush_streaming();
                 self.messages.push(DisplayMessage::ToolCall { name });
@@ -215,23 +593,188 @@ impl App {
                     .push(DisplayMessage::ToolResult { name, result });
                 false
             }
+            AgentEvent::ToolRoundLimit { rounds } => {
+                self.messages.push(DisplayMessage::System(format!(
+                    "工具调用已达 {rounds} 轮上限，要求模型直接回答。"
+                )));
+                false
+            }
+            AgentEvent::ToolLoopDetected { name } => {
+                self.messages.push(DisplayMessage::System(format!(
+                    "检测到重复调用 {name}，要求模型直接回答。"
+                )));
+                false
+            }
+            AgentEvent::Retry {
+                attempt,
+                max_retries,
+                delay_secs,
+                reason,
+            } => {
+                self.messages.push(DisplayMessage::System(format!(
+                    "请求失败（{reason}），{}秒后重试（{attempt}/{max_retries}）…",
+                    delay_secs.ceil() as u64
+                )));
+                false
+            }
+            AgentEvent::Compacted { replaced } => {
+                self.messages.push(DisplayMessage::System(format!(
+                    "较早的 {replaced} 条消息已压缩为摘要。"
+                )));
+                false
+            }
+            AgentEvent::ContextTrimmed { dropped, elided } => {
+                self.context_trimmed = dropped + elided;
+                false
+            }
+            AgentEvent::Usage { usage, cost } => {
+                self.turn_usage.add(usage, cost);
+                self.session_usage.add(usage, cost);
+                false
+            }
+            AgentEvent::ModelSwitch { from, to, reason } => {
+                let text = if reason.is_empty() {
+                    format!("模型已从 {from} 切换到 {to}。")
+                } else {
+                    format!("模型已从 {from} 切换到 {to}（{reason}）。")
+                };
+                self.messages.push(DisplayMessage::System(text));
+                false
+            }
             AgentEvent::Done => {
                 self.flush_streaming();
                 true
             }
+            AgentEvent::Cancelled => {
+                self.flush_streaming();
+                self.messages
+                    .push(DisplayMessage::System("生成已中断。".to_string()));
+                true
+            }
             AgentEvent::Error(e) => {
                 self.flush_streaming();
-                self.messages.push(DisplayMessage::Error(e));
+                self.messages
+                    .push(DisplayMessage::Error(describe_api_error(&e)));
                 true
             }
         }
     }
 
+    fn flush_reasoning(&mut self) {
+        if !self.streaming_reasoning.is_empty() {
+            let reasoning = std::mem::take(&mut self.streaming_reasoning);
+            let shown = self.shown(&reasoning, RegexPlacement::Reasoning, RegexTarget::Stored);
+            self.messages.push(DisplayMessage::Reasoning(shown));
+        }
+    }
+
     fn flush_streaming(&mut self) {
+        self.flush_reasoning();
         if !self.streaming_text.is_empty() {
-            self.messages.push(DisplayMessage::Assistant(
-                std::mem::take(&mut self.streaming_text),
-            ));
+            let text = std::mem::take(&mut self.streaming_text);
+            let shown = self.shown(&text, RegexPlacement::AiOutput, RegexTarget::Stored);
+            self.messages.push(DisplayMessage::Assistant(shown));
         }
     }
+
+    /// A new message as the chat view shows it: rewritten as it is stored
+    /// (`stored`), then by the display-only regex scripts.
+    fn shown(&self, text: &str, placement: RegexPlacement, stored: RegexTarget) -> String {
+        let scripts = self.agent().regex_scripts();
+        let text = scripts.apply(text, placement, stored);
+        scripts.apply(&text, placement, RegexTarget::Display { depth: Some(0) })
+    }
+}
+
+/// How stored messages look in the chat view, after the display-only regex
+/// scripts. Tool results are labelled with the name of the call they answer.
+fn display_messages(history: &[Message], scripts: &RegexScripts) -> Vec<DisplayMessage> {
+    let mut tool_names = std::collections::HashMap::new();
+    let mut out = Vec::new();
+    let mut depth = history
+        .iter()
+        .filter(|m| matches!(m, Message::User { .. } | Message::Assistant { .. }))
+        .count();
+    for msg in history {
+        if matches!(msg, Message::User { .. } | Message::Assistant { .. }) {
+            depth -= 1;
+        }
+        let shown = scripts.apply_message(msg.clone(), RegexTarget::Display { depth: Some(depth) });
+        match &shown {
+            Message::System { .. } => {}
+            Message::User { content } => {
+                let text = content
+                    .iter()
+                    .map(|part| part.as_text().unwrap_or("[图片]"))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                out.push(DisplayMessage::User(text));
+            }
+            Message::Assistant {
+                content,
+                reasoning,
+                tool_calls,
+            } => {
+                if let Some(reasoning) = reasoning.as_ref().filter(|r| !r.is_empty()) {
+                    out.push(DisplayMessage::Reasoning(reasoning.clone()));
+                }
+                if !content.is_empty() {
+                    out.push(DisplayMessage::Assistant(content.clone()));
+                }
+                for tc in tool_calls {
+                    tool_names.insert(tc.id.clone(), tc.function.name.clone());
+                    out.push(DisplayMessage::ToolCall {
+                        name: tc.function.name.clone(),
+                    });
+                }
+            }
+            Message::ToolResult {
+                tool_call_id,
+                content,
+            } => out.push(DisplayMessage::ToolResult {
+                name: tool_names.get(tool_call_id).cloned().unwrap_or_default(),
+                result: content.clone(),
+            }),
+        }
+    }
+    out
+}
+
+/// Parse `/image <path> [caption]`. Quote the path if it contains spaces; `~/` is expanded.
+fn parse_image_command(input: &str) -> Option<(PathBuf, String)> {
+    let rest = input.strip_prefix("/image")?;
+    if !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let rest = rest.trim_start();
+    let (path, caption) = match rest.strip_prefix('"') {
+        Some(quoted) => quoted.split_once('"')?,
+        None => rest.split_once(char::is_whitespace).unwrap_or((rest, "")),
+    };
+    if path.is_empty() {
+        return None;
+    }
+    let path = match (path.strip_prefix("~/"), std::env::home_dir()) {
+        (Some(relative), Some(home)) => home.join(relative),
+        _ => PathBuf::from(path),
+    };
+    Some((path, caption.trim().to_string()))
+}
+
+/// Turn an API error into a hint the user can act on, keeping the raw details.
+fn describe_api_error(err: &ApiError) -> String {
+    let hint = match err.kind {
+        ApiErrorKind::Auth => "API Key 无效或没有权限，请检查环境变量。",
+        ApiErrorKind::RateLimited => "请求过于频繁或额度已用尽，请稍后再试。",
+        ApiErrorKind::ContextLengthExceeded => "对话超出模型上下文长度，请开始新会话（Ctrl+N）。",
+        ApiErrorKind::ContentFiltered => "内容被模型的安全策略拦截。",
+        ApiErrorKind::ModelNotFound => "模型不存在，请检查 config.toml 中的模型 id。",
+        ApiErrorKind::Server => "服务端暂时不可用，请稍后再试。",
+        ApiErrorKind::InvalidRequest | ApiErrorKind::Unknown => "",
+    };
+    if hint.is_empty() {
+        err.to_string()
+    } else {
+        format!("{hint}（{err}）")
+    }
 }
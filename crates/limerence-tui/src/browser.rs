@@ -0,0 +1,111 @@
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use limerence_core::session::{Session, SessionSummary};
+
+/// What the app should do after a key press in the session browser.
+pub enum BrowserAction {
+    None,
+    Close,
+    Open(SessionSummary),
+    Delete(String),
+}
+
+/// Overlay listing saved sessions (Ctrl+O): type to filter, Enter to open,
+/// Ctrl+D twice to delete.
+pub struct SessionBrowser {
+    sessions: Vec<SessionSummary>,
+    pub query: String,
+    pub selected: usize,
+    /// Session waiting for a second Ctrl+D.
+    pub confirm_delete: Option<String>,
+    /// Result of the last action, shown under the list.
+    pub notice: Option<String>,
+}
+
+impl SessionBrowser {
+    pub fn open() -> Self {
+        Self {
+            sessions: Session::summaries(),
+            query: String::new(),
+            selected: 0,
+            confirm_delete: None,
+            notice: None,
+        }
+    }
+
+    /// Sessions whose character, first line, date or id contain the query.
+    pub fn visible(&self) -> Vec<&SessionSummary> {
+        let query = self.query.to_lowercase();
+        self.sessions
+            .iter()
+            .filter(|s| {
+                query.is_empty()
+                    || s.header.character.to_lowercase().contains(&query)
+                    || s.first_line.to_lowercase().contains(&query)
+                    || s.header.id.starts_with(&query)
+                    || date_label(s).contains(&query)
+            })
+            .collect()
+    }
+
+    pub fn remove(&mut self, id: &str) {
+        self.sessions.retain(|s| s.header.id != id);
+        self.selected = self.selected.min(self.visible().len().saturating_sub(1));
+    }
+
+    pub fn handle_key(&mut self, key: KeyEvent) -> BrowserAction {
+        let confirming = self.confirm_delete.take();
+        self.notice = None;
+        match (key.modifiers, key.code) {
+            (_, KeyCode::Esc) => BrowserAction::Close,
+            (_, KeyCode::Up) => {
+                self.selected = self.selected.saturating_sub(1);
+                BrowserAction::None
+            }
+            (_, KeyCode::Down) => {
+                if self.selected + 1 < self.visible().len() {
+                    self.selected += 1;
+                }
+                BrowserAction::None
+            }
+            (_, KeyCode::Enter) => match self.visible().get(self.selected) {
+                Some(summary) => BrowserAction::Open((*summary).clone()),
+                None => BrowserAction::None,
+            },
+            (KeyModifiers::CONTROL, KeyCode::Char('d')) => {
+                let Some(id) = self
+                    .visible()
+                    .get(self.selected)
+                    .map(|s| s.header.id.clone())
+                else {
+                    return BrowserAction::None;
+                };
+                if confirming.as_ref() == Some(&id) {
+                    BrowserAction::Delete(id)
+                } else {
+                    self.confirm_delete = Some(id);
+                    BrowserAction::None
+                }
+            }
+            (_, KeyCode::Backspace) => {
+                self.query.pop();
+                self.selected = 0;
+                BrowserAction::None
+            }
+            (KeyModifiers::NONE | KeyModifiers::SHIFT, KeyCode::Char(c)) => {
+                self.query.push(c);
+                self.selected = 0;
+                BrowserAction::None
+            }
+            _ => BrowserAction::None,
+        }
+    }
+}
+
+/// Local date and time a session was last updated, as shown in the list.
+pub fn date_label(summary: &SessionSummary) -> String {
+    summary
+        .updated
+        .with_timezone(&chrono::Local)
+        .format("%Y-%m-%d %H:%M")
+        .to_string()
+}
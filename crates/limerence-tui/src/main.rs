@@ -1,34 +1,114 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use limerence_core::export::ExportFormat;
+use std::path::PathBuf;
 
 mod app;
+mod browser;
 mod input;
 mod ui;
 
 #[derive(Parser)]
 #[command(name = "limerence", about = "极简 AI Waifu Agent")]
 struct Cli {
-    /// 角色卡 JSON 文件路径
+    /// 角色卡文件路径（JSON，或内嵌角色卡的 PNG；PNG 卡会连同头像保存到 ~/.limerence/characters/）
     #[arg(short, long)]
     character: Option<String>,
 
-    /// 继续上一个会话
-    #[arg(short, long)]
+    /// 继续已保存的会话：会话 id（或其前缀），或 last 表示最近一次
+    #[arg(short, long, value_name = "ID|last")]
     resume: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// 导出已保存的会话（当前分支）
+    Export {
+        /// 会话 id（或其前缀），或 last 表示最近一次
+        #[arg(value_name = "ID|last")]
+        session: String,
+
+        /// 格式：md、html 或 st（SillyTavern 聊天 JSONL）；默认按输出文件扩展名，否则 md
+        #[arg(short, long)]
+        format: Option<ExportFormat>,
+
+        /// 输出文件；默认写到 ~/.limerence/exports/
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// 导入 SillyTavern 聊天记录（JSONL）为新会话，并写入记忆索引
+    Import {
+        /// SillyTavern 聊天文件
+        file: PathBuf,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Cli::parse();
 
+    if let Some(Command::Export {
+        session,
+        format,
+        output,
+    }) = &cli.command
+    {
+        let found = limerence_core::session::Session::find(session)
+            .ok_or_else(|| format!("找不到会话：{session}"))?;
+        let format = format
+            .or_else(|| output.as_deref().and_then(ExportFormat::from_path))
+            .unwrap_or(ExportFormat::Markdown);
+        let path = limerence_core::export::export(&found, format, output.as_deref())?;
+        println!("已导出到 {}", path.display());
+        return Ok(());
+    }
+
+    if let Some(Command::Import { file }) = &cli.command {
+        let mut memory = limerence_core::memory::MemoryIndex::new();
+        let session = limerence_core::import::import_sillytavern(file, &mut memory)
+            .map_err(|e| format!("导入失败：{}：{e}", file.display()))?;
+        println!(
+            "已导入与{}的 {} 条消息。继续聊：limerence --resume {}",
+            session.header.character,
+            session.entries.len(),
+            &session.header.id[..8]
+        );
+        return Ok(());
+    }
+
     let config = limerence_core::Config::load();
 
-    let character = if let Some(path) = &cli.character {
-        limerence_core::CharacterCard::load(std::path::Path::new(path))?
-    } else {
-        limerence_core::CharacterCard::default_character()
+    let resumed = match &cli.resume {
+        Some(key) => Some(
+            limerence_core::session::Session::find(key)
+                .ok_or_else(|| format!("找不到会话：{key}（用 Ctrl+O 浏览已保存的会话）"))?,
+        ),
+        None => None,
+    };
+
+    let character = match cli.character.as_deref().map(std::path::Path::new) {
+        Some(path)
+            if path
+                .extension()
+                .is_some_and(|e| e.eq_ignore_ascii_case("png")) =>
+        {
+            Some(limerence_core::CharacterCard::install(path)?.0)
+        }
+        Some(path) => Some(limerence_core::CharacterCard::load(path)?),
+        None => None,
     };
 
-    let mut app = app::App::new(config, character);
+    let mut app = app::App::new(
+        config,
+        character
+            .clone()
+            .unwrap_or_else(limerence_core::CharacterCard::default_character),
+    );
+    if let Some(session) = resumed {
+        app.open_session(session, character);
+    }
 
     app.run().await?;
 
@@ -1,12 +1,15 @@
 use ratatui::{
+    Frame,
     layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
     text::{Line, Span, Text},
-    widgets::{Block, Borders, Paragraph, Wrap},
-    Frame,
+    widgets::{Block, Borders, Clear, Paragraph, Wrap},
 };
 
+use limerence_core::usage::UsageTotals;
+
 use crate::app::{App, DisplayMessage};
+use crate::browser::{SessionBrowser, date_label};
 
 fn agent_name(app: &App) -> &str {
     app.agent
@@ -30,6 +33,85 @@ pub fn draw(frame: &mut Frame, app: &App) {
     draw_messages(frame, app, chunks[1]);
     draw_input(frame, app, chunks[2]);
     draw_status_bar(frame, app, chunks[3]);
+
+    if let Some(browser) = &app.browser {
+        draw_browser(frame, browser, frame.area());
+    }
+}
+
+/// The session picker, drawn over the chat.
+fn draw_browser(frame: &mut Frame, browser: &SessionBrowser, area: Rect) {
+    let popup = Rect {
+        x: area.x + area.width / 10,
+        y: area.y + area.height / 8,
+        width: area.width - area.width / 5,
+        height: area.height - area.height / 4,
+    };
+    frame.render_widget(Clear, popup);
+
+    let dim = Style::default().fg(Color::DarkGray);
+    let mut lines = vec![
+        Line::from(vec![
+            Span::styled("搜索：", Style::default().fg(Color::Cyan)),
+            Span::raw(browser.query.as_str()),
+            Span::styled("▌", Style::default().fg(Color::Cyan)),
+        ]),
+        Line::from(""),
+    ];
+
+    let visible = browser.visible();
+    if visible.is_empty() {
+        lines.push(Line::from(Span::styled("  没有匹配的会话。", dim)));
+    }
+    // Keep the selection in view; two lines of header and two of footer.
+    let rows = popup.height.saturating_sub(6).max(1) as usize;
+    let start = (browser.selected + 1).saturating_sub(rows);
+    for (i, summary) in visible.iter().enumerate().skip(start).take(rows) {
+        let selected = i == browser.selected;
+        let deleting = browser.confirm_delete.as_deref() == Some(summary.header.id.as_str());
+        let style = if deleting {
+            Style::default().fg(Color::Red).add_modifier(Modifier::BOLD)
+        } else if selected {
+            Style::default()
+                .fg(Color::White)
+                .bg(Color::Rgb(50, 50, 70))
+                .add_modifier(Modifier::BOLD)
+        } else {
+            Style::default().fg(Color::Gray)
+        };
+        let first_line: String = summary.first_line.chars().take(40).collect();
+        lines.push(Line::from(Span::styled(
+            format!(
+                "{} {}  {}  {} 条  {first_line}",
+                if selected { "▶" } else { " " },
+                date_label(summary),
+                summary.header.character,
+                summary.message_count,
+            ),
+            style,
+        )));
+    }
+
+    lines.push(Line::from(""));
+    let footer = if let Some(notice) = &browser.notice {
+        Span::styled(notice.as_str(), Style::default().fg(Color::Yellow))
+    } else if browser.confirm_delete.is_some() {
+        Span::styled(
+            "再按一次 Ctrl+D 删除该会话，其他键取消",
+            Style::default().fg(Color::Red),
+        )
+    } else {
+        Span::styled("↑↓ 选择  Enter 打开  Ctrl+D 删除  Esc 关闭", dim)
+    };
+    lines.push(Line::from(footer));
+
+    let widget = Paragraph::new(Text::from(lines)).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .border_style(Style::default().fg(Color::Cyan))
+            .title(format!(" 会话（{}）", visible.len())),
+    );
+    frame.render_widget(widget, popup);
 }
 
 fn draw_title_bar(frame: &mut Frame, app: &App, area: Rect) {
@@ -48,10 +130,7 @@ fn draw_title_bar(frame: &mut Frame, app: &App, area: Rect) {
         Span::styled(" │ ", Style::default().fg(Color::DarkGray)),
         Span::styled(model, Style::default().fg(Color::Cyan)),
         Span::styled(" │ ", Style::default().fg(Color::DarkGray)),
-        Span::styled(
-            format!("记忆 {memory}"),
-            Style::default().fg(Color::Yellow),
-        ),
+        Span::styled(format!("记忆 {memory}"), Style::default().fg(Color::Yellow)),
         if app.is_streaming {
             Span::styled(" │ 生成中...", Style::default().fg(Color::Green))
         } else {
@@ -59,11 +138,8 @@ fn draw_title_bar(frame: &mut Frame, app: &App, area: Rect) {
         },
     ]);
 
-    let bar = Paragraph::new(title).style(
-        Style::default()
-            .bg(Color::Rgb(30, 30, 40))
-            .fg(Color::White),
-    );
+    let bar =
+        Paragraph::new(title).style(Style::default().bg(Color::Rgb(30, 30, 40)).fg(Color::White));
     frame.render_widget(bar, area);
 }
 
@@ -103,6 +179,10 @@ fn draw_messages(frame: &mut Frame, app: &App, area: Rect) {
                     )));
                 }
             }
+            DisplayMessage::Reasoning(text) => {
+                lines.push(Line::from(""));
+                push_reasoning(&mut lines, text, app.show_reasoning, false);
+            }
             DisplayMessage::ToolCall { name } => {
                 lines.push(Line::from(Span::styled(
                     format!("  ⚙ 调用工具：{name}"),
@@ -144,6 +224,16 @@ fn draw_messages(frame: &mut Frame, app: &App, area: Rect) {
         }
     }
 
+    if !app.streaming_reasoning.is_empty() {
+        lines.push(Line::from(""));
+        push_reasoning(
+            &mut lines,
+            &app.streaming_reasoning,
+            app.show_reasoning,
+            true,
+        );
+    }
+
     // Streaming text (currently being generated)
     if !app.streaming_text.is_empty() {
         lines.push(Line::from(""));
@@ -169,11 +259,7 @@ fn draw_messages(frame: &mut Frame, app: &App, area: Rect) {
     let total_lines = text.lines.len() as u16;
     let visible_height = area.height.saturating_sub(2);
 
-    let scroll = if total_lines > visible_height {
-        total_lines - visible_height
-    } else {
-        0
-    };
+    let scroll = total_lines.saturating_sub(visible_height);
 
     let messages_widget = Paragraph::new(text)
         .block(Block::default().borders(Borders::NONE))
@@ -183,6 +269,31 @@ fn draw_messages(frame: &mut Frame, app: &App, area: Rect) {
     frame.render_widget(messages_widget, area);
 }
 
+/// A reasoning block: a one-line summary, followed by the text when expanded.
+fn push_reasoning(lines: &mut Vec<Line>, text: &str, expanded: bool, in_progress: bool) {
+    let style = Style::default()
+        .fg(Color::DarkGray)
+        .add_modifier(Modifier::ITALIC);
+    let marker = if expanded { "▾" } else { "▸" };
+    let label = if in_progress {
+        "思考中…"
+    } else {
+        "思考过程"
+    };
+    lines.push(Line::from(Span::styled(
+        format!(
+            "  {marker} {label}（{} 字，Ctrl+T 展开/收起）",
+            text.chars().count()
+        ),
+        style,
+    )));
+    if expanded {
+        for l in text.lines() {
+            lines.push(Line::from(Span::styled(format!("  │ {l}"), style)));
+        }
+    }
+}
+
 fn draw_input(frame: &mut Frame, app: &App, area: Rect) {
     let input_text = if app.is_streaming {
         "（生成中... 按 Esc 中断）"
@@ -210,10 +321,7 @@ fn draw_input(frame: &mut Frame, app: &App, area: Rect) {
     frame.render_widget(input_widget, area);
 
     if !app.is_streaming {
-        frame.set_cursor_position((
-            area.x + app.cursor_pos as u16 + 1,
-            area.y + 1,
-        ));
+        frame.set_cursor_position((area.x + app.cursor_pos as u16 + 1, area.y + 1));
     }
 }
 
@@ -225,17 +333,41 @@ fn draw_status_bar(frame: &mut Frame, app: &App, area: Rect) {
         .unwrap_or("--------");
     let session_short = &session_id[..8.min(session_id.len())];
 
-    let status = Line::from(vec![
+    let mut spans = vec![
         Span::styled(
             format!(" 会话 {session_short}"),
             Style::default().fg(Color::DarkGray),
         ),
         Span::styled(" │ ", Style::default().fg(Color::DarkGray)),
-        Span::styled(
-            "Ctrl+N 新会话  Ctrl+C 退出  Esc 中断",
+    ];
+    if app.session_usage.calls > 0 {
+        spans.push(Span::styled(
+            format_usage(&app.turn_usage, &app.session_usage),
             Style::default().fg(Color::DarkGray),
-        ),
-    ]);
+        ));
+        spans.push(Span::styled(" │ ", Style::default().fg(Color::DarkGray)));
+    }
+    if let Some((index, total)) = app.agent.as_ref().and_then(|a| a.reply_position())
+        && total > 1
+    {
+        spans.push(Span::styled(
+            format!("回复 {}/{} (Alt+←/→)", index + 1, total),
+            Style::default().fg(Color::DarkGray),
+        ));
+        spans.push(Span::styled(" │ ", Style::default().fg(Color::DarkGray)));
+    }
+    if app.context_trimmed > 0 {
+        spans.push(Span::styled(
+            format!("上下文已满，{} 条消息已省略或截断", app.context_trimmed),
+            Style::default().fg(Color::Yellow),
+        ));
+        spans.push(Span::styled(" │ ", Style::default().fg(Color::DarkGray)));
+    }
+    spans.push(Span::styled(
+        "Ctrl+N 新会话  Ctrl+O 会话  Ctrl+R 重新生成  Ctrl+T 思考  Ctrl+C 退出  Esc 中断",
+        Style::default().fg(Color::DarkGray),
+    ));
+    let status = Line::from(spans);
 
     let bar = Paragraph::new(status).style(
         Style::default()
@@ -244,3 +376,15 @@ fn draw_status_bar(frame: &mut Frame, app: &App, area: Rect) {
     );
     frame.render_widget(bar, area);
 }
+
+fn format_usage(turn: &UsageTotals, session: &UsageTotals) -> String {
+    let mut text = format!(
+        "本轮 {} tokens  累计 {} tokens",
+        turn.usage.total_tokens(),
+        session.usage.total_tokens()
+    );
+    if session.cost > 0.0 {
+        text.push_str(&format!("  费用 {:.4}", session.cost));
+    }
+    text
+}